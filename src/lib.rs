@@ -1,4 +1,4 @@
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 #![deny(missing_docs)]
 #![doc = include_str!("../README.md")]
 
@@ -6,7 +6,8 @@
 //
 // - flash erase size is quite big, aka, this is a paged flash
 // - flash write size is quite small, so it writes words and not full pages
-// - flash read size is 1, so the flash is byte addressable
+// - flash read size is 1 for the queue, so the flash is byte addressable.
+//   The map only needs the write size to be a multiple of the read size.
 
 use core::{fmt::Debug, ops::Range};
 use embedded_storage::nor_flash::NorFlash;
@@ -15,12 +16,22 @@ use embedded_storage::nor_flash::NorFlash;
 // STM32 parts.
 const MAX_FLASH_WRITE_SIZE: usize = 32;
 
+#[macro_use]
+mod logging;
+
 pub mod map;
 pub mod queue;
+pub mod retry;
 
-#[cfg(test)]
-mod mock_flash;
+#[cfg(any(test, feature = "mock-flash"))]
+pub mod mock_flash;
 
+/// Find the first page with the given state, starting at the given page and wrapping around.
+///
+/// The pages are checked one by one. A binary search doesn't work on the ring:
+/// once a map has been filled, all pages but the partial open page and the buffer page are closed,
+/// so reading a closed page doesn't tell in which direction the partial open page is.
+/// Instead every page is checked with as few reads as possible, see [page_has_state].
 fn find_first_page<S: NorFlash>(
     flash: &mut S,
     flash_range: Range<u32>,
@@ -28,7 +39,7 @@ fn find_first_page<S: NorFlash>(
     page_state: PageState,
 ) -> Result<Option<usize>, Error<S::Error>> {
     for page_index in get_pages::<S>(flash_range.clone(), starting_page_index) {
-        if page_state == get_page_state::<S>(flash, flash_range.clone(), page_index)? {
+        if page_has_state::<S>(flash, flash_range.clone(), page_index, page_state)? {
             return Ok(Some(page_index));
         }
     }
@@ -68,6 +79,66 @@ fn calculate_page_address<S: NorFlash>(flash_range: Range<u32>, page_index: usiz
 fn calculate_page_end_address<S: NorFlash>(flash_range: Range<u32>, page_index: usize) -> u32 {
     flash_range.start + (S::ERASE_SIZE * (page_index + 1)) as u32
 }
+/// The address of the start marker of the page. This is the first `WRITE_SIZE` aligned word in the page.
+fn calculate_page_start_marker_address<S: NorFlash>(
+    flash_range: Range<u32>,
+    page_index: usize,
+) -> u32 {
+    next_multiple_of(
+        calculate_page_address::<S>(flash_range, page_index),
+        S::WRITE_SIZE as u32,
+    )
+}
+/// The address of the end marker of the page. This is the last `WRITE_SIZE` aligned word that fits in the page.
+///
+/// When the erase size is not a multiple of the write size, the bytes at the page edges
+/// that don't make up a whole aligned word are left unused.
+fn calculate_page_end_marker_address<S: NorFlash>(
+    flash_range: Range<u32>,
+    page_index: usize,
+) -> u32 {
+    let page_end_address = calculate_page_end_address::<S>(flash_range, page_index);
+    page_end_address - page_end_address % S::WRITE_SIZE as u32 - S::WRITE_SIZE as u32
+}
+/// The address of the first byte after the start marker
+fn calculate_page_data_start_address<S: NorFlash>(
+    flash_range: Range<u32>,
+    page_index: usize,
+) -> u32 {
+    calculate_page_start_marker_address::<S>(flash_range, page_index) + S::WRITE_SIZE as u32
+}
+/// The address of the first byte that can't be used for data because it's part of the end marker
+fn calculate_page_data_end_address<S: NorFlash>(flash_range: Range<u32>, page_index: usize) -> u32 {
+    calculate_page_end_marker_address::<S>(flash_range, page_index)
+}
+/// The amount of bytes in a page that are covered by whole `WRITE_SIZE` aligned words, for the worst page alignment.
+/// This equals the erase size when it's a multiple of the write size.
+const fn min_aligned_page_size<S: NorFlash>() -> usize {
+    if S::ERASE_SIZE % S::WRITE_SIZE == 0 {
+        S::ERASE_SIZE
+    } else {
+        (S::ERASE_SIZE / S::WRITE_SIZE).saturating_sub(1) * S::WRITE_SIZE
+    }
+}
+/// Returns true if the flash range is not empty and starts and ends on a page boundary
+fn is_page_aligned_range<S: NorFlash>(flash_range: &Range<u32>) -> bool {
+    flash_range.start % S::ERASE_SIZE as u32 == 0
+        && flash_range.end % S::ERASE_SIZE as u32 == 0
+        && flash_range.start < flash_range.end
+}
+/// Turn the outcome of a geometry validation into the result of a geometry check.
+///
+/// With the `strict-asserts` feature, which is on by default, an invalid geometry panics so bugs are caught loudly during development.
+/// Without it, [Error::InvalidFlashGeometry] is returned instead. Both come from the same validation, so they check the same things.
+#[track_caller]
+fn check_validated_geometry<E>(validation: Result<(), Error<E>>) -> Result<(), Error<E>> {
+    #[cfg(feature = "strict-asserts")]
+    if validation.is_err() {
+        panic!("The flash geometry or the flash range is invalid");
+    }
+
+    validation
+}
 fn calculate_page_index<S: NorFlash>(flash_range: Range<u32>, address: u32) -> usize {
     (address - flash_range.start) as usize / S::ERASE_SIZE
 }
@@ -77,17 +148,8 @@ fn get_page_state<S: NorFlash>(
     flash_range: Range<u32>,
     page_index: usize,
 ) -> Result<PageState, Error<S::Error>> {
-    let page_address = calculate_page_address::<S>(flash_range, page_index);
-
-    let mut buffer = [0; MAX_FLASH_WRITE_SIZE];
-    flash
-        .read(page_address, &mut buffer[..S::READ_SIZE])
-        .map_err(Error::Storage)?;
-    let start_marker = buffer[0];
-
-    if start_marker != MARKER {
-        #[cfg(feature = "defmt")]
-        defmt::trace!("Page {} is open", page_index);
+    if !is_page_start_marked::<S>(flash, flash_range.clone(), page_index)? {
+        trace!("Page {} is open", page_index);
 
         // The page start is not marked, so it is unused
         return Ok(PageState::Open);
@@ -96,25 +158,178 @@ fn get_page_state<S: NorFlash>(
     // The page start is marked, so it can be full or partially full
     // We need to look at the end marker to know
 
+    if !is_page_end_marked::<S>(flash, flash_range, page_index)? {
+        trace!("Page {} is partial open", page_index);
+        // The page end is not marked, so it is only partially filled and thus open
+        return Ok(PageState::PartialOpen);
+    }
+
+    trace!("Page {} is closed", page_index);
+    // Both start and end are marked, so this page is closed
+    Ok(PageState::Closed)
+}
+
+/// Check if the page has the given state. This gives the same answer as [get_page_state],
+/// but reads the marker that rules out the most pages first.
+///
+/// Once a map has been filled, most pages are closed. Their end marker is enough to know they're not partial open,
+/// so looking for the partial open page takes one read per closed page instead of two.
+fn page_has_state<S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    page_index: usize,
+    page_state: PageState,
+) -> Result<bool, Error<S::Error>> {
+    match page_state {
+        PageState::Open => Ok(!is_page_start_marked::<S>(flash, flash_range, page_index)?),
+        PageState::PartialOpen | PageState::Closed => {
+            if is_page_end_marked::<S>(flash, flash_range.clone(), page_index)?
+                != page_state.is_closed()
+            {
+                return Ok(false);
+            }
+
+            is_page_start_marked::<S>(flash, flash_range, page_index)
+        }
+    }
+}
+
+fn is_page_start_marked<S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    page_index: usize,
+) -> Result<bool, Error<S::Error>> {
+    let mut buffer = [0; MAX_FLASH_WRITE_SIZE];
     flash
         .read(
-            page_address + (S::ERASE_SIZE - S::READ_SIZE) as u32,
+            calculate_page_start_marker_address::<S>(flash_range, page_index),
             &mut buffer[..S::READ_SIZE],
         )
         .map_err(Error::Storage)?;
-    let end_marker = buffer[S::READ_SIZE - 1];
 
-    if end_marker != MARKER {
-        #[cfg(feature = "defmt")]
-        defmt::trace!("Page {} is partial open", page_index);
-        // The page end is not marked, so it is only partially filled and thus open
-        return Ok(PageState::PartialOpen);
+    Ok(buffer[0] == MARKER)
+}
+
+fn is_page_end_marked<S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    page_index: usize,
+) -> Result<bool, Error<S::Error>> {
+    // The marker byte is the last byte of the end marker word
+    let mut buffer = [0; MAX_FLASH_WRITE_SIZE];
+    flash
+        .read(
+            calculate_page_end_marker_address::<S>(flash_range, page_index)
+                + (S::WRITE_SIZE - S::READ_SIZE) as u32,
+            &mut buffer[..S::READ_SIZE],
+        )
+        .map_err(Error::Storage)?;
+
+    Ok(buffer[S::READ_SIZE - 1] == MARKER)
+}
+
+/// Check that the flash range doesn't overlap a region that must never be written, like the running firmware.
+///
+/// The crate can't know where the firmware or the vector table is, so this is a safety net for the integrator to wire up.
+/// Call it at startup with the flash range of every map and queue before using them:
+///
+/// ```rust,ignore
+/// check_forbidden_range(&flash_range, &(0x0800_0000..0x0804_0000))?;
+/// map::preflight(&mut flash, flash_range.clone())?;
+/// ```
+///
+/// Returns [Error::InvalidFlashGeometry] if the ranges overlap. An empty forbidden range never overlaps.
+pub fn check_forbidden_range<E>(
+    flash_range: &Range<u32>,
+    forbidden_range: &Range<u32>,
+) -> Result<(), Error<E>> {
+    if !forbidden_range.is_empty()
+        && flash_range.start < forbidden_range.end
+        && forbidden_range.start < flash_range.end
+    {
+        error!(
+            "The flash range {:#X}..{:#X} overlaps the forbidden range {:#X}..{:#X}",
+            flash_range.start, flash_range.end, forbidden_range.start, forbidden_range.end
+        );
+        return Err(Error::InvalidFlashGeometry);
     }
 
-    #[cfg(feature = "defmt")]
-    defmt::trace!("Page {} is closed", page_index);
-    // Both start and end are marked, so this page is closed
-    Ok(PageState::Closed)
+    Ok(())
+}
+
+/// Read the raw bytes of the start and end marker of a page, for diagnosing why a page is in an unexpected state.
+///
+/// Both the map and the queue mark a page with a start marker word when they start using it
+/// and with an end marker word when it's full. A marker word is `WRITE_SIZE` bytes big and is all `0x00` when written,
+/// except for the start marker of a map page which holds the geometry after its first byte.
+/// The page state is decided on the first byte of the start marker and the last byte of the end marker:
+///
+/// - Start marker not written: the page is open
+/// - Start marker written, end marker not written: the page is partially open and in use
+/// - Both written: the page is closed
+///
+/// Any other bytes in a marker point at a bad write or a wrong flash range.
+///
+/// Returns [Error::InvalidFlashGeometry] if the flash range isn't aligned to the erase size
+/// or if the page index is not in the flash range.
+pub fn read_page_markers<S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    page_index: usize,
+) -> Result<PageMarkers, Error<S::Error>> {
+    if flash_range.start % S::ERASE_SIZE as u32 != 0
+        || flash_range.end % S::ERASE_SIZE as u32 != 0
+        || S::WRITE_SIZE > MAX_FLASH_WRITE_SIZE
+        || page_index >= flash_range.len() / S::ERASE_SIZE
+    {
+        error!(
+            "Page {} is not a page of the flash range {:#X}..{:#X}",
+            page_index, flash_range.start, flash_range.end
+        );
+        return Err(Error::InvalidFlashGeometry);
+    }
+
+    let mut markers = PageMarkers {
+        start_marker: [0; MAX_FLASH_WRITE_SIZE],
+        end_marker: [0; MAX_FLASH_WRITE_SIZE],
+        len: S::WRITE_SIZE,
+    };
+
+    flash
+        .read(
+            calculate_page_start_marker_address::<S>(flash_range.clone(), page_index),
+            &mut markers.start_marker[..S::WRITE_SIZE],
+        )
+        .map_err(Error::Storage)?;
+    flash
+        .read(
+            calculate_page_end_marker_address::<S>(flash_range, page_index),
+            &mut markers.end_marker[..S::WRITE_SIZE],
+        )
+        .map_err(Error::Storage)?;
+
+    Ok(markers)
+}
+
+/// The raw marker bytes of a page. See [read_page_markers].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PageMarkers {
+    start_marker: [u8; MAX_FLASH_WRITE_SIZE],
+    end_marker: [u8; MAX_FLASH_WRITE_SIZE],
+    len: usize,
+}
+
+impl PageMarkers {
+    /// The bytes of the start marker word
+    pub fn start_marker(&self) -> &[u8] {
+        &self.start_marker[..self.len]
+    }
+
+    /// The bytes of the end marker word
+    pub fn end_marker(&self) -> &[u8] {
+        &self.end_marker[..self.len]
+    }
 }
 
 /// Fully closes a page by writing both the start and end marker
@@ -135,7 +350,7 @@ fn close_page<S: NorFlash>(
     // Close the end marker
     flash
         .write(
-            calculate_page_end_address::<S>(flash_range, page_index) - S::WRITE_SIZE as u32,
+            calculate_page_end_marker_address::<S>(flash_range, page_index),
             &buffer[..S::WRITE_SIZE],
         )
         .map_err(Error::Storage)?;
@@ -159,7 +374,7 @@ fn partial_close_page<S: NorFlash>(
     // Close the start marker
     flash
         .write(
-            calculate_page_address::<S>(flash_range, page_index),
+            calculate_page_start_marker_address::<S>(flash_range, page_index),
             &buffer[..S::WRITE_SIZE],
         )
         .map_err(Error::Storage)?;
@@ -203,6 +418,13 @@ impl PageState {
 
 const MARKER: u8 = 0;
 
+const fn next_multiple_of(value: u32, multiple: u32) -> u32 {
+    match value % multiple {
+        0 => value,
+        r => value + (multiple - r),
+    }
+}
+
 /// The main error type
 #[non_exhaustive]
 #[derive(Debug, PartialEq, Eq)]
@@ -220,79 +442,46 @@ pub enum Error<S> {
     BufferTooBig,
     /// A provided buffer was to small to be used
     BufferTooSmall,
+    /// The flash range or the flash itself doesn't meet the requirements of the datastructure.
+    /// This is only returned when the `strict-asserts` feature is disabled, otherwise these are asserts.
+    /// [check_forbidden_range] always returns it.
+    InvalidFlashGeometry,
+    /// The map in the flash range was written with a different `WRITE_SIZE` or `ERASE_SIZE` than the flash has now.
+    /// The items can't be read back reliably, so the flash is left untouched.
+    GeometryMismatch,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::map::{StorageItem, StorageItemError};
-
-    type MockFlash = mock_flash::MockFlashBase<4, 4, 64>;
-
-    #[derive(Debug, PartialEq, Eq)]
-    struct MockStorageItem {
-        key: u8,
-        value: u8,
-    }
-
-    #[derive(Debug, PartialEq, Eq)]
-    enum MockStorageItemError {
-        BufferTooSmall,
-        InvalidKey,
-    }
-
-    impl StorageItemError for MockStorageItemError {
-        fn is_buffer_too_small(&self) -> bool {
-            matches!(self, MockStorageItemError::BufferTooSmall)
+impl<S> core::fmt::Display for Error<S> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Storage(_) => f.write_str("the flash returned an error"),
+            Error::FullStorage => f.write_str("storage is full"),
+            Error::Corrupted => f.write_str("memory appears corrupted"),
+            Error::BufferTooBig => f.write_str("a provided buffer is too big"),
+            Error::BufferTooSmall => f.write_str("a provided buffer is too small"),
+            Error::InvalidFlashGeometry => f.write_str("the flash or the flash range is invalid"),
+            Error::GeometryMismatch => {
+                f.write_str("the flash range was written with a different write or erase size")
+            }
         }
     }
+}
 
-    impl StorageItem for MockStorageItem {
-        type Key = u8;
-
-        type Error = MockStorageItemError;
-
-        fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
-            if buffer.len() < 2 {
-                return Err(MockStorageItemError::BufferTooSmall);
-            }
-
-            // The serialized value must not be all 0xFF
-            if self.key == 0xFF {
-                return Err(MockStorageItemError::InvalidKey);
-            }
-
-            buffer[0] = self.key;
-            buffer[1] = self.value;
-
-            Ok(2)
+#[cfg(feature = "std")]
+impl<S: std::error::Error + 'static> std::error::Error for Error<S> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Storage(e) => Some(e),
+            _ => None,
         }
+    }
+}
 
-        fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error>
-        where
-            Self: Sized,
-        {
-            if buffer.len() < 2 {
-                return Err(MockStorageItemError::BufferTooSmall);
-            }
-
-            if buffer[0] == 0xFF {
-                return Err(MockStorageItemError::InvalidKey);
-            }
-
-            Ok((
-                Self {
-                    key: buffer[0],
-                    value: buffer[1],
-                },
-                2,
-            ))
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        fn key(&self) -> Self::Key {
-            self.key
-        }
-    }
+    type MockFlash = mock_flash::MockFlashBase<4, 4, 64>;
 
     #[test]
     fn test_find_pages() {
@@ -358,4 +547,95 @@ mod tests {
             None
         );
     }
+
+    #[test]
+    fn find_partial_open_page_reads() {
+        type ManyPagesFlash = mock_flash::MockFlashBase<32, 4, 64>;
+        let flash_range = 0x0000..0x2000;
+
+        // A filled map: only the partial open page and the buffer page after it are not closed
+        let mut flash = ManyPagesFlash::new();
+        for page_index in 0..32 {
+            match page_index {
+                20 => partial_close_page(&mut flash, flash_range.clone(), page_index).unwrap(),
+                21 => {}
+                _ => close_page(&mut flash, flash_range.clone(), page_index).unwrap(),
+            }
+        }
+
+        // Reading the full state of every page until the partial open page is found
+        flash.reset_counters();
+        for page_index in 0..32 {
+            if get_page_state(&mut flash, flash_range.clone(), page_index).unwrap()
+                == PageState::PartialOpen
+            {
+                break;
+            }
+        }
+        assert_eq!(flash.reads, 2 * 21);
+
+        // A closed page only needs its end marker read
+        flash.reset_counters();
+        assert_eq!(
+            find_first_page(&mut flash, flash_range.clone(), 0, PageState::PartialOpen).unwrap(),
+            Some(20)
+        );
+        assert_eq!(flash.reads, 20 + 2);
+
+        // The other states are still found the same
+        assert_eq!(
+            find_first_page(&mut flash, flash_range.clone(), 20, PageState::Closed).unwrap(),
+            Some(22)
+        );
+        assert_eq!(
+            find_first_page(&mut flash, flash_range.clone(), 0, PageState::Open).unwrap(),
+            Some(21)
+        );
+    }
+
+    #[test]
+    fn test_check_forbidden_range() {
+        let flash_range = 0x1000..0x2000;
+        let check = |forbidden_range| check_forbidden_range::<()>(&flash_range, &forbidden_range);
+
+        assert_eq!(check(0x0000..0x1000), Ok(()));
+        assert_eq!(check(0x2000..0x3000), Ok(()));
+        assert_eq!(check(0x1800..0x1800), Ok(()));
+        assert_eq!(check(0x0000..0x1001), Err(Error::InvalidFlashGeometry));
+        assert_eq!(check(0x1FFF..0x3000), Err(Error::InvalidFlashGeometry));
+        assert_eq!(check(0x1400..0x1800), Err(Error::InvalidFlashGeometry));
+        assert_eq!(check(0x0000..0x3000), Err(Error::InvalidFlashGeometry));
+    }
+
+    #[test]
+    fn test_read_page_markers() {
+        let mut flash = MockFlash::new();
+
+        partial_close_page(&mut flash, 0x000..0x400, 1).unwrap();
+        close_page(&mut flash, 0x000..0x400, 2).unwrap();
+        // A marker with a bad write
+        flash.write(0x300, &[0xF0, 0xFF, 0xFF, 0x0F]).unwrap();
+
+        let markers = (0..4)
+            .map(|page_index| read_page_markers(&mut flash, 0x000..0x400, page_index).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(markers[0].start_marker(), [0xFF; 4]);
+        assert_eq!(markers[0].end_marker(), [0xFF; 4]);
+        assert_eq!(markers[1].start_marker(), [MARKER; 4]);
+        assert_eq!(markers[1].end_marker(), [0xFF; 4]);
+        assert_eq!(markers[2].start_marker(), [MARKER; 4]);
+        assert_eq!(markers[2].end_marker(), [MARKER; 4]);
+        assert_eq!(markers[3].start_marker(), [0xF0, 0xFF, 0xFF, 0x0F]);
+        assert_eq!(markers[3].end_marker(), [0xFF; 4]);
+
+        assert_eq!(
+            read_page_markers(&mut flash, 0x000..0x400, 4),
+            Err(Error::InvalidFlashGeometry)
+        );
+        assert_eq!(
+            read_page_markers(&mut flash, 0x000..0x3FF, 0),
+            Err(Error::InvalidFlashGeometry)
+        );
+    }
 }
@@ -0,0 +1,31 @@
+//! Internal logging macros that log through `defmt` and/or `log`, depending on the enabled features.
+//! Without either feature, they expand to nothing.
+//!
+//! The format string has to work for both, so format everything that isn't a number or a string with `{:?}`.
+
+macro_rules! trace {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "defmt")]
+        ::defmt::trace!($($arg)*);
+        #[cfg(feature = "log")]
+        ::log::trace!($($arg)*);
+    }};
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "defmt")]
+        ::defmt::warn!($($arg)*);
+        #[cfg(feature = "log")]
+        ::log::warn!($($arg)*);
+    }};
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "defmt")]
+        ::defmt::error!($($arg)*);
+        #[cfg(feature = "log")]
+        ::log::error!($($arg)*);
+    }};
+}
@@ -41,10 +41,15 @@
 //! );
 //! ```
 
-use core::cell::RefCell;
+use arrayvec::ArrayVec;
+use core::{cell::RefCell, convert::Infallible, marker::PhantomData};
+use embedded_storage::nor_flash::MultiwriteNorFlash;
 
 use super::*;
 
+pub mod double_buffered;
+pub mod items;
+
 /// Get a storage item from the flash.
 /// Only the last stored item of the given key is returned.
 ///
@@ -57,740 +62,8472 @@ pub fn fetch_item<I: StorageItem, S: NorFlash>(
     Ok(fetch_item_with_location(flash, flash_range, search_key)?.map(|(item, _, _)| item))
 }
 
-/// Fetch the item, but with the address and the length too
-#[allow(clippy::type_complexity)]
-fn fetch_item_with_location<I: StorageItem, S: NorFlash>(
+/// Get a storage item from the flash, reading the pages with the given buffer. See [fetch_item].
+///
+/// [fetch_item] reads with a buffer on the stack that is as big as [StorageItem::MAX_SIZE].
+/// If the items that are stored are smaller, a smaller buffer saves stack. Or pass a static buffer to keep it off the stack.
+/// A buffer of [StorageItem::MAX_SIZE] bytes is always big enough.
+///
+/// The buffer needs to fit the biggest item that is stored in the map, not just the one you're fetching,
+/// because all newer items are read on the way. Otherwise [MapError::BufferTooSmall] is returned.
+pub fn fetch_item_with_buffer<I: StorageItem, S: NorFlash>(
     flash: &mut S,
     flash_range: Range<u32>,
     search_key: I::Key,
-) -> Result<Option<(I, u32, usize)>, MapError<I::Error, S::Error>> {
-    assert_eq!(flash_range.start % S::ERASE_SIZE as u32, 0);
-    assert_eq!(flash_range.end % S::ERASE_SIZE as u32, 0);
-    assert!(flash_range.end - flash_range.start >= S::ERASE_SIZE as u32 * 2);
+    buffer: &mut [u8],
+) -> Result<Option<I>, MapError<I::Error, S::Error>> {
+    Ok(
+        fetch_item_with_location_in(flash, flash_range, search_key, buffer)?
+            .map(|(item, _, _)| item),
+    )
+}
 
-    assert!(S::ERASE_SIZE >= S::WRITE_SIZE * 3);
-    assert_eq!(S::READ_SIZE, 1);
+/// Get a storage item from the flash, with control over what happens when a record can't be deserialized.
+///
+/// With [CorruptRecordPolicy::Abort], this is the same as [fetch_item].
+/// With [CorruptRecordPolicy::SkipRestOfPage], a record that can't be deserialized is treated as the end of its page
+/// and the search continues on the previous page. That way a single damaged record can't hide an older value of the key.
+/// Be aware that the returned value can then be older than the value in the damaged record.
+pub fn fetch_item_with_policy<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    search_key: I::Key,
+    corrupt_record_policy: CorruptRecordPolicy,
+) -> Result<Option<I>, MapError<I::Error, S::Error>> {
+    Ok(
+        find_newest_item(flash, flash_range, corrupt_record_policy, |item: &I| {
+            item.key() == search_key
+        })?
+        .filter(|(item, _, _)| !item.is_tombstone())
+        .map(|(item, _, _)| item),
+    )
+}
 
-    // We need to find the page we were last using. This should be the only partial open page.
-    let mut last_used_page =
-        find_first_page(flash, flash_range.clone(), 0, PageState::PartialOpen)?;
+/// What to do when a record can't be deserialized while searching. See [fetch_item_with_policy].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CorruptRecordPolicy {
+    /// Stop and return the error of the item. This is what all other functions do.
+    #[default]
+    Abort,
+    /// Ignore the rest of the page and continue searching on the previous page
+    SkipRestOfPage,
+}
 
-    #[cfg(feature = "defmt")]
-    defmt::trace!("Fetch item, last used page: {}", last_used_page);
+/// A flash that can be read through a shared reference, e.g. because it's memory mapped.
+///
+/// Implement this to fetch items with [fetch_item_shared] while other parts of the program hold a reference to the flash too.
+pub trait SharedReadNorFlash: NorFlash {
+    /// Read the bytes at the offset, like [ReadNorFlash::read](embedded_storage::nor_flash::ReadNorFlash::read)
+    fn read_shared(&self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error>;
+}
 
-    if last_used_page.is_none() {
-        // In the event that all pages are still open or the last used page was just closed, we search for the first open page.
-        // If the page one before that is closed, then that's the last used page.
-        if let Some(first_open_page) =
-            find_first_page(flash, flash_range.clone(), 0, PageState::Open)?
-        {
-            let previous_page = previous_page::<S>(flash_range.clone(), first_open_page);
-            if get_page_state(flash, flash_range.clone(), previous_page)?.is_closed() {
-                last_used_page = Some(previous_page);
-            } else {
-                // The page before the open page is not closed, so it must be open.
-                // This means that all pages are open and that we don't have any items yet.
-                return Ok(None);
-            }
-        } else {
-            // There are no open pages, so everything must be closed.
-            // Something is up and this should never happen.
-            // To recover, we will just erase all the flash.
-            return Err(MapError::Corrupted);
-        }
-    }
+/// Get a storage item from the flash through a shared reference. See [fetch_item].
+///
+/// Fetching only reads, so it doesn't need exclusive access to the flash. Storing still does.
+pub fn fetch_item_shared<I: StorageItem, S: SharedReadNorFlash>(
+    flash: &S,
+    flash_range: Range<u32>,
+    search_key: I::Key,
+) -> Result<Option<I>, MapError<I::Error, S::Error>> {
+    fetch_item(&mut SharedReader(flash), flash_range, search_key)
+}
 
-    // We must now find the most recent storage item with the key that was asked for.
-    // If we don't find it in the current page, then we check again in the previous page if that page is closed.
+/// Makes a [SharedReadNorFlash] usable by the functions that take a [NorFlash], as long as they only read
+struct SharedReader<'a, S>(&'a S);
 
-    let mut current_page_to_check = last_used_page.unwrap();
-    let mut newest_found_item = None;
+impl<S: SharedReadNorFlash> embedded_storage::nor_flash::ErrorType for SharedReader<'_, S> {
+    type Error = S::Error;
+}
 
-    let flash = RefCell::new(flash);
+impl<S: SharedReadNorFlash> embedded_storage::nor_flash::ReadNorFlash for SharedReader<'_, S> {
+    const READ_SIZE: usize = S::READ_SIZE;
 
-    loop {
-        for found_item_result in
-            read_page_items::<I, S>(&flash, flash_range.clone(), current_page_to_check)?
-        {
-            let found_item = found_item_result?;
-            if found_item.0.key() == search_key {
-                newest_found_item = Some(found_item);
-            }
-        }
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.0.read_shared(offset, bytes)
+    }
 
-        // We've found the item! We can stop searching
-        if newest_found_item.is_some() {
-            break;
-        }
+    fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+}
 
-        // We have not found the item. We've got to look in the previous page, but only if that page is closed and contains data.
-        let previous_page = previous_page::<S>(flash_range.clone(), current_page_to_check);
+impl<S: SharedReadNorFlash> NorFlash for SharedReader<'_, S> {
+    const WRITE_SIZE: usize = S::WRITE_SIZE;
+    const ERASE_SIZE: usize = S::ERASE_SIZE;
 
-        if get_page_state(*flash.borrow_mut(), flash_range.clone(), previous_page)?
-            != PageState::Closed
-        {
-            // We've looked through all the pages with data and couldn't find the item
-            return Ok(None);
-        }
+    fn erase(&mut self, _from: u32, _to: u32) -> Result<(), Self::Error> {
+        unreachable!("The shared reader is only used for fetching, which never erases")
+    }
 
-        current_page_to_check = previous_page;
+    fn write(&mut self, _offset: u32, _bytes: &[u8]) -> Result<(), Self::Error> {
+        unreachable!("The shared reader is only used for fetching, which never writes")
     }
+}
 
-    Ok(newest_found_item)
+/// Get the most recently stored item for which the predicate returns true.
+///
+/// This is the way to filter on metadata like a source, priority or timestamp.
+/// See the [StorageItem] docs for how to store that metadata.
+///
+/// This looks at every stored record, not only at the last value of every key.
+/// So the returned item can be an older value of a key that has been overwritten or removed since.
+/// Tombstones are never passed to the predicate.
+///
+/// If no item matches, None is returned.
+pub fn fetch_item_matching<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    mut predicate: impl FnMut(&I) -> bool,
+) -> Result<Option<I>, MapError<I::Error, S::Error>> {
+    Ok(find_newest_item(
+        flash,
+        flash_range,
+        CorruptRecordPolicy::Abort,
+        |item: &I| !item.is_tombstone() && predicate(item),
+    )?
+    .map(|(item, _, _)| item))
 }
 
-/// Store an item into flash memory.
-/// It will overwrite the last value that has the same key.
-/// The flash needs to be at least 2 pages long.
-pub fn store_item<I: StorageItem, S: NorFlash>(
+/// Get a storage item from the flash together with its age in pages.
+///
+/// The age is the amount of pages between the page the item is in and the page that is currently being written to.
+/// An age of 0 means the item is in the newest page. Every time a page fills up, the age of all older items goes up by one.
+/// This makes it a cheap proxy for how old a value is, without having to store timestamps.
+///
+/// Items that are still live get copied forward when their page is about to be erased,
+/// which resets their age to 0 even though their value hasn't changed.
+///
+/// If no value with the key is found, None is returned.
+#[allow(clippy::type_complexity)]
+pub fn fetch_item_with_age<I: StorageItem, S: NorFlash>(
     flash: &mut S,
     flash_range: Range<u32>,
-    item: I,
-) -> Result<(), MapError<I::Error, S::Error>> {
-    assert_eq!(flash_range.start % S::ERASE_SIZE as u32, 0);
-    assert_eq!(flash_range.end % S::ERASE_SIZE as u32, 0);
+    search_key: I::Key,
+) -> Result<Option<(I, usize)>, MapError<I::Error, S::Error>> {
+    let Some((item, address, _)) =
+        fetch_item_with_location(flash, flash_range.clone(), search_key)?
+    else {
+        return Ok(None);
+    };
+
+    // There is an item, so there must be a last used page
+    let last_used_page =
+        find_last_used_page(flash, flash_range.clone())?.ok_or(MapError::Corrupted)?;
+    let item_page = calculate_page_index::<S>(flash_range.clone(), address);
+    let page_count = flash_range.len() / S::ERASE_SIZE;
+
+    Ok(Some((
+        item,
+        (last_used_page + page_count - item_page) % page_count,
+    )))
+}
 
-    assert!(flash_range.len() / S::ERASE_SIZE >= 2);
+/// Get a storage item from the flash together with the amount of pages that had to be read to find it.
+///
+/// A fetch always reads the newest page first, so 1 is the best case. A high count means the newest value
+/// of the key is far back, which makes every fetch of it slow. When the item isn't found, the count is the
+/// amount of pages with data, since all of them had to be read.
+#[allow(clippy::type_complexity)]
+pub fn fetch_item_with_pages_scanned<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    search_key: I::Key,
+) -> Result<(Option<I>, usize), MapError<I::Error, S::Error>> {
+    let mut page_walker = PageWalker::new(flash, flash_range)?;
+    let item = with_item_buffer::<I, S, _>(|buffer| {
+        find_newest_item_with_walker(
+            &mut page_walker,
+            CorruptRecordPolicy::Abort,
+            |item: &I| item.key() == search_key,
+            buffer,
+        )
+    })?;
 
-    assert!(S::ERASE_SIZE >= S::WRITE_SIZE * 3);
-    assert_eq!(S::READ_SIZE, 1);
+    Ok((
+        item.filter(|(item, _, _)| !item.is_tombstone())
+            .map(|(item, _, _)| item),
+        page_walker.pages_walked(),
+    ))
+}
 
-    return store_item_inner::<I, S>(&RefCell::new(flash), flash_range, item, 0);
+/// Get a storage item from the flash together with the id of the record it was read from.
+///
+/// Keep the id and fetch again later to see if the value may have changed: if the id is the same, it's the same record.
+/// The id changes when the key is stored again, but also when the item is copied forward because its page is recycled.
+/// So a different id doesn't always mean a different value.
+///
+/// The id is the address of the record in flash. After the map has gone around all pages, a new record can end up
+/// at the same address. An id is only reliable for less than `page count - 1` pages worth of stores.
+///
+/// If no value with the key is found, None is returned.
+#[allow(clippy::type_complexity)]
+pub fn fetch_item_with_record_id<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    search_key: I::Key,
+) -> Result<Option<(I, RecordId)>, MapError<I::Error, S::Error>> {
+    Ok(fetch_item_with_location(flash, flash_range, search_key)?
+        .map(|(item, address, _)| (item, RecordId { address })))
+}
 
-    fn store_item_inner<'a, 'b, I: StorageItem, S: NorFlash>(
-        flash: &'a RefCell<&'b mut S>,
-        flash_range: Range<u32>,
-        item: I,
-        recursion_level: usize,
-    ) -> Result<(), MapError<I::Error, S::Error>>
-    where
-        'a: 'b,
-    {
-        #[cfg(feature = "defmt")]
-        defmt::trace!("Store item inner. Recursion: {}", recursion_level);
+/// Get a storage item from the flash together with the amount of flash bytes its record takes up.
+///
+/// The size is the serialized length of the item rounded up to whole `WRITE_SIZE` words,
+/// since that's how much space the record takes on its page. The page markers and the space other versions
+/// of the key take are not included.
+///
+/// If no value with the key is found, None is returned.
+#[allow(clippy::type_complexity)]
+pub fn fetch_item_with_size<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    search_key: I::Key,
+) -> Result<Option<(I, usize)>, MapError<I::Error, S::Error>> {
+    Ok(fetch_item_with_location(flash, flash_range, search_key)?
+        .map(|(item, _, size)| (item, size)))
+}
 
-        // Check if we're in an infinite recursion which happens when
-        if recursion_level == get_pages::<S>(flash_range.clone(), 0).count() {
-            return Err(MapError::FullStorage);
-        }
+/// Find the newest value of the key and read its stored bytes in parts with the returned [ItemReader],
+/// for items that are too big to deserialize at once or that you want to parse into a struct bit by bit.
+///
+/// The pages are read through the given buffer like [fetch_item_with_buffer] does, but the item doesn't have to fit in it.
+/// For that, `I` can be a view of the item that only deserializes the key and the length of the record
+/// and returns that length as its used bytes, even if it's longer than the buffer it got. The rest of the record is
+/// skipped without being read. The view has to be able to read every record in the map, so it must use the same format
+/// and its [StorageItem::MAX_SIZE] only needs to fit the key and the length:
+///
+/// ```rust,ignore
+/// let Some((header, mut reader)) = fetch_item_streaming::<MyItemHeader, _>(&mut flash, flash_range, key, &mut [0; 16])? else {
+///     return Ok(None);
+/// };
+///
+/// let mut chunk = [0; 16];
+/// while reader.remaining() > 0 {
+///     let len = reader.read(&mut chunk)?;
+///     parser.feed(&chunk[..len]);
+/// }
+/// ```
+///
+/// If no value with the key is found, None is returned.
+#[allow(clippy::type_complexity)]
+pub fn fetch_item_streaming<'a, I: StorageItem, S: NorFlash>(
+    flash: &'a mut S,
+    flash_range: Range<u32>,
+    search_key: I::Key,
+    buffer: &mut [u8],
+) -> Result<Option<(I, ItemReader<'a, S>)>, MapError<I::Error, S::Error>> {
+    let Some((item, address, len)) = find_newest_item_in(
+        flash,
+        flash_range,
+        CorruptRecordPolicy::Abort,
+        |item: &I| item.key() == search_key,
+        buffer,
+    )?
+    .filter(|(item, _, _)| !item.is_tombstone()) else {
+        return Ok(None);
+    };
+
+    Ok(Some((
+        item,
+        ItemReader {
+            flash,
+            address,
+            remaining: len,
+        },
+    )))
+}
 
-        let mut next_page_to_use = None;
+/// Reads the stored bytes of an item in parts. See [fetch_item_streaming].
+///
+/// It reads the whole record, so it ends with the padding up to a whole `WRITE_SIZE` word, which is `0xFF`.
+pub struct ItemReader<'a, S: NorFlash> {
+    flash: &'a mut S,
+    address: u32,
+    remaining: usize,
+}
 
-        // If there is a partial open page, we try to write in that first if there is enough space
-        if let Some(partial_open_page) = {
-            let mut flash = flash.borrow_mut(); // Out of band because of weird drop rules
-            find_first_page(*flash, flash_range.clone(), 0, PageState::PartialOpen)?
-        } {
-            #[cfg(feature = "defmt")]
-            defmt::trace!("Partial open page found: {}", partial_open_page);
+impl<S: NorFlash> ItemReader<'_, S> {
+    /// The amount of bytes that are left to read
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
 
-            // We've got to search where the free space is since the page starts with items present already
+    /// Read the next bytes of the record into the buffer.
+    /// Returns how many bytes were read, which is less than the buffer only at the end of the record.
+    ///
+    /// The buffer can be any size: the reads are aligned to `READ_SIZE` internally.
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, S::Error> {
+        let len = buffer.len().min(self.remaining);
+        let mut done = 0;
+
+        while done < len {
+            let misalignment = self.address as usize % S::READ_SIZE;
+
+            let chunk = if misalignment == 0 && len - done >= S::READ_SIZE {
+                // Read the whole reads straight into the buffer
+                let chunk = (len - done) / S::READ_SIZE * S::READ_SIZE;
+                self.flash
+                    .read(self.address, &mut buffer[done..done + chunk])?;
+                chunk
+            } else {
+                // The record is stored in whole words, so a whole read never goes past its end
+                let mut read_buffer = [0; MAX_FLASH_WRITE_SIZE];
+                let read_buffer = &mut read_buffer[..S::READ_SIZE];
+                self.flash
+                    .read(self.address - misalignment as u32, read_buffer)?;
+
+                let chunk = (S::READ_SIZE - misalignment).min(len - done);
+                buffer[done..done + chunk]
+                    .copy_from_slice(&read_buffer[misalignment..misalignment + chunk]);
+                chunk
+            };
 
-            let page_data_start_address =
-                calculate_page_address::<S>(flash_range.clone(), partial_open_page)
-                    + S::WRITE_SIZE as u32;
-            let page_data_end_address =
-                calculate_page_end_address::<S>(flash_range.clone(), partial_open_page)
-                    - S::WRITE_SIZE as u32;
+            done += chunk;
+            self.address += chunk as u32;
+            self.remaining -= chunk;
+        }
 
-            let mut last_start_address = page_data_start_address;
+        Ok(len)
+    }
+}
 
-            for found_item_result in
-                read_page_items::<I, S>(flash, flash_range.clone(), partial_open_page)?
-            {
-                let (_, item_address, item_size) = found_item_result?;
-                last_start_address = item_address + item_size as u32;
-            }
+/// Find the newest value of the key and pass its serialized bytes to `visitor` in chunks, e.g. to hash or send a big item
+/// without having it in RAM at once.
+///
+/// This reads the record with [fetch_item_streaming], so `I` is a view of the item like it is there
+/// and the chunks are at most as big as the buffer. The visitor gets the bytes in order and without the padding
+/// the map adds up to a whole `WRITE_SIZE` word. To know where the item ends, the view is deserialized from the first chunk
+/// again, so the used bytes it returns have to be the length of the whole item.
+///
+/// If no value with the key is found, None is returned and the visitor isn't called.
+pub fn fetch_item_chunks<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    search_key: I::Key,
+    buffer: &mut [u8],
+    mut visitor: impl FnMut(&[u8]),
+) -> Result<Option<I>, MapError<I::Error, S::Error>> {
+    let Some((item, mut reader)) =
+        fetch_item_streaming::<I, S>(flash, flash_range, search_key, buffer)?
+    else {
+        return Ok(None);
+    };
+
+    let read = reader.read(buffer).map_err(MapError::Storage)?;
+    let (_, mut remaining) = I::deserialize_from(&buffer[..read]).map_err(MapError::Item)?;
+
+    let mut chunk_len = read.min(remaining);
+    while chunk_len > 0 {
+        visitor(&buffer[..chunk_len]);
+        remaining -= chunk_len;
+
+        let len = remaining.min(buffer.len());
+        chunk_len = reader.read(&mut buffer[..len]).map_err(MapError::Storage)?;
+    }
 
-            let available_bytes_in_page = (page_data_end_address - last_start_address) as usize;
+    Ok(Some(item))
+}
 
-            let mut buffer = [0xFF; MAX_STORAGE_ITEM_SIZE];
-            match item
-                .serialize_into(&mut buffer[..MAX_STORAGE_ITEM_SIZE.min(available_bytes_in_page)])
-            {
-                Ok(mut used_bytes) => {
-                    // We can only write in whole words, so we round up the used bytes so the math works
-                    if used_bytes % S::WRITE_SIZE > 0 {
-                        used_bytes += S::WRITE_SIZE - (used_bytes % S::WRITE_SIZE);
-                    }
+/// The identity of a stored record. See [fetch_item_with_record_id].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RecordId {
+    /// The flash address of the record
+    pub address: u32,
+}
 
-                    flash
-                        .borrow_mut()
-                        .write(last_start_address, &buffer[..used_bytes])
-                        .map_err(MapError::Storage)?;
+/// Get the item that was stored last, whatever its key is.
+///
+/// This is the last record on the page that is being written to, or the last record of the page before it
+/// if that page has no items yet. It's useful to e.g. resume the most recent operation after a reset.
+///
+/// Items that are copied forward when a page is recycled are stored again.
+/// So right after a page has been recycled, this returns the last item that was copied instead of the last item you stored.
+///
+/// If there are no items, None is returned.
+pub fn fetch_newest_item<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+) -> Result<Option<I>, MapError<I::Error, S::Error>> {
+    let mut page_walker = PageWalker::new(flash, flash_range)?;
 
-                    #[cfg(feature = "defmt")]
-                    defmt::trace!("Item has been written ok");
+    with_item_buffer::<I, S, _>(|buffer| {
+        while let Some(page_index) = page_walker.next() {
+            let mut newest_item = None;
 
-                    return Ok(());
-                }
-                Err(e) if e.is_buffer_too_small() => {
-                    #[cfg(feature = "defmt")]
-                    defmt::trace!(
-                        "Partial open page is too small. Closing it now: {}",
-                        partial_open_page
-                    );
+            for item in page_walker.page_items::<I>(page_index?, buffer)? {
+                newest_item = Some(item?);
+            }
 
-                    // The item doesn't fit here, so we need to close this page and move to the next
-                    close_page(*flash.borrow_mut(), flash_range.clone(), partial_open_page)?;
-                    next_page_to_use = Some(next_page::<S>(flash_range.clone(), partial_open_page));
-                }
-                Err(e) => {
-                    return Err(MapError::Item(e));
-                }
+            if newest_item.is_some() {
+                return Ok(newest_item);
             }
         }
 
-        // If we get here, there was no partial page found or the partial page has now been closed because the item didn't fit.
-        // If there was a partial page, then we need to look at the next page. It's supposed to be open since it was the previous empty buffer page.
-        // The new buffer page has to be emptied if it was closed.
-        // If there was no partial page, we just use the first open page.
+        Ok(None)
+    })
+}
 
-        #[cfg(feature = "defmt")]
-        defmt::trace!("Next page to use: {}", next_page_to_use);
+/// Check if the map holds any items at all, e.g. to decide whether the first run setup has to be done.
+///
+/// This stops at the first item it finds, so it's cheaper than fetching or counting items.
+/// A fully erased range has no items. A tombstone counts as an item, see [remove_item].
+pub fn any_items<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+) -> Result<bool, MapError<I::Error, S::Error>> {
+    let mut page_walker = PageWalker::new(flash, flash_range)?;
+
+    with_item_buffer::<I, S, _>(|buffer| {
+        while let Some(page_index) = page_walker.next() {
+            if let Some(item) = page_walker.page_items::<I>(page_index?, buffer)?.next() {
+                item?;
+                return Ok(true);
+            }
+        }
 
-        match next_page_to_use {
-            Some(next_page_to_use) => {
-                let next_page_state =
-                    get_page_state(*flash.borrow_mut(), flash_range.clone(), next_page_to_use)?;
+        Ok(false)
+    })
+}
 
-                if !next_page_state.is_open() {
-                    // What was the previous buffer page was not open...
-                    return Err(MapError::Corrupted);
-                }
+/// Check if the key has a value, without returning it.
+///
+/// This searches like [fetch_item], from the newest page back, and stops at the first page that has the key.
+/// The items on the way are still deserialized, because only the item knows how long it is on flash,
+/// but none of them is returned. A key that has been removed with [remove_item] has no value.
+pub fn contains_key<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    search_key: I::Key,
+) -> Result<bool, MapError<I::Error, S::Error>> {
+    Ok(find_newest_version::<I, S>(flash, flash_range, search_key)?
+        .is_some_and(|(item, _, _)| !item.is_tombstone()))
+}
 
-                let next_buffer_page = next_page::<S>(flash_range.clone(), next_page_to_use);
-                let next_buffer_page_state =
-                    get_page_state(*flash.borrow_mut(), flash_range.clone(), next_buffer_page)?;
+/// Get a storage item from the flash, but only if it's not older than `ttl` pages.
+///
+/// This gives a coarse expiry without a clock, e.g. for a cache. The TTL is counted in page rotations
+/// (see [fetch_item_with_age]), not in wall time. How fast pages rotate depends on how much data is stored.
+/// An item with an age of exactly `ttl` is still returned.
+///
+/// The expiry is only applied when fetching. The map doesn't know the TTL of an item, so compaction can't drop it.
+/// Instead a live item that gets copied forward starts at age 0 again, which extends its life.
+/// For a hard upper bound, store a sequence number or timestamp in the item itself.
+///
+/// If no value with the key is found or it is expired, None is returned.
+pub fn fetch_item_with_ttl<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    search_key: I::Key,
+    ttl: usize,
+) -> Result<Option<I>, MapError<I::Error, S::Error>> {
+    Ok(fetch_item_with_age(flash, flash_range, search_key)?
+        .filter(|(_, age)| *age <= ttl)
+        .map(|(item, _)| item))
+}
 
-                if !next_buffer_page_state.is_open() {
-                    // We need to move the data from the next buffer page to the next_page_to_use, but only if that data
-                    // doesn't have a newer value somewhere else.
+/// Fetch an item together with a report of all its stored versions, to investigate e.g. why a value reverted.
+///
+/// The report lists the location of every version of the key in the pages that hold data, from oldest to newest
+/// in the order the pages are used. At most `N` versions are listed, the newest ones are kept.
+/// It also gives the address of the version the crate considers the newest, which is the one [fetch_item] returns.
+/// Normally that's the last listed version.
+///
+/// This scans all pages with data, so it's slower than [fetch_item]. Use it for diagnostics only.
+pub fn fetch_item_diagnostic<I: StorageItem, S: NorFlash, const N: usize>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    search_key: I::Key,
+) -> Result<ItemDiagnostic<I, N>, MapError<I::Error, S::Error>> {
+    let newest_version = find_newest_item(
+        flash,
+        flash_range.clone(),
+        CorruptRecordPolicy::Abort,
+        |item: &I| item.key() == search_key,
+    )?;
+    let search_key = newest_version.as_ref().map(|(item, _, _)| item.key());
+
+    let mut diagnostic = ItemDiagnostic {
+        newest_address: newest_version.as_ref().map(|(_, address, _)| *address),
+        item: newest_version
+            .filter(|(item, _, _)| !item.is_tombstone())
+            .map(|(item, _, _)| item),
+        versions: ArrayVec::new(),
+        version_count: 0,
+    };
+
+    let Some((oldest_page, page_count)) = find_pages_with_data(flash, flash_range.clone())? else {
+        return Ok(diagnostic);
+    };
 
-                    let mut next_page_write_address =
-                        calculate_page_address::<S>(flash_range.clone(), next_page_to_use)
-                            + S::WRITE_SIZE as u32;
+    let flash = RefCell::new(flash);
 
-                    for old_item in
-                        read_page_items::<I, S>(flash, flash_range.clone(), next_buffer_page)?
-                    {
-                        let (old_item, _, _) = old_item?;
-
-                        let Some((_, newest_version_address, newest_version_len)) =
-                            fetch_item_with_location::<I, S>(
-                                *flash.borrow_mut(),
-                                flash_range.clone(),
-                                old_item.key(),
-                            )?
-                        else {
-                            // What do you mean we can't find the item again?
-                            return Err(MapError::Corrupted);
-                        };
-
-                        let newest_old_item_page =
-                            calculate_page_index::<S>(flash_range.clone(), newest_version_address);
-
-                        if newest_old_item_page == next_buffer_page {
-                            // The newest version of this item is on the next buffer page, so we need to move it
-                            let mut buffer = [0xFF; MAX_STORAGE_ITEM_SIZE];
-                            flash
-                                .borrow_mut()
-                                .read(newest_version_address, &mut buffer[..newest_version_len])
-                                .map_err(MapError::Storage)?;
-
-                            // We don't have to watch for the end of the page, because the data we're writing here
-                            // is equal or less than the page
-                            flash
-                                .borrow_mut()
-                                .write(next_page_write_address, &buffer[..newest_version_len])
-                                .map_err(MapError::Storage)?;
-
-                            next_page_write_address += newest_version_len as u32;
-                        }
-                    }
+    with_item_buffer::<I, S, _>(|buffer| {
+        for page_index in get_pages::<S>(flash_range.clone(), oldest_page).take(page_count) {
+            for found_item_result in read_page_items_in::<I, S, _>(
+                &flash,
+                flash_range.clone(),
+                page_index,
+                &mut *buffer,
+            )? {
+                let (found_item, address, _) = found_item_result?;
 
-                    flash
-                        .borrow_mut()
-                        .erase(
-                            calculate_page_address::<S>(flash_range.clone(), next_buffer_page),
-                            calculate_page_end_address::<S>(flash_range.clone(), next_buffer_page),
-                        )
-                        .map_err(MapError::Storage)?;
+                if Some(found_item.key()) != search_key {
+                    continue;
                 }
 
-                partial_close_page(*flash.borrow_mut(), flash_range.clone(), next_page_to_use)?;
-            }
-            None => {
-                // There's no partial open page, so we just gotta turn the first open page into a partial open one
-                let first_open_page = match find_first_page(
-                    *flash.borrow_mut(),
-                    flash_range.clone(),
-                    0,
-                    PageState::Open,
-                )? {
-                    Some(first_open_page) => first_open_page,
-                    None => {
-                        #[cfg(feature = "defmt")]
-                        defmt::error!(
-                            "No open pages found for sequential storage in the range: {}",
-                            flash_range
-                        );
-                        // Uh oh, no open pages.
-                        // Something has gone wrong.
-                        // We should never get here.
-                        return Err(MapError::Corrupted);
-                    }
-                };
-
-                partial_close_page(*flash.borrow_mut(), flash_range.clone(), first_open_page)?;
+                if diagnostic.versions.is_full() && !diagnostic.versions.is_empty() {
+                    diagnostic.versions.remove(0);
+                }
+                let _ = diagnostic.versions.try_push(ItemVersion {
+                    address,
+                    page: page_index,
+                });
+                diagnostic.version_count += 1;
             }
         }
 
-        // If we get here, we just freshly partially closed a new page, so this should succeed
-        store_item_inner::<I, S>(flash, flash_range, item, recursion_level + 1)
-    }
+        Ok(diagnostic)
+    })
 }
 
-fn read_page_items<'a, 'b, I: StorageItem, S: NorFlash>(
-    flash: &'a RefCell<&'b mut S>,
-    flash_range: Range<u32>,
-    page_index: usize,
-) -> Result<
-    impl Iterator<Item = Result<(I, u32, usize), MapError<I::Error, S::Error>>> + 'a,
-    MapError<I::Error, S::Error>,
->
-where
-    'a: 'b,
-{
-    let mut read_buffer = [0xFF; MAX_STORAGE_ITEM_SIZE];
-    let mut used_read_buffer = 0;
-    let page_data_start_address =
-        calculate_page_address::<S>(flash_range.clone(), page_index) + S::WRITE_SIZE as u32;
-    let page_data_end_address =
-        calculate_page_end_address::<S>(flash_range.clone(), page_index) - S::WRITE_SIZE as u32;
-    let mut read_buffer_start_index_into_page = 0;
+/// The result of [fetch_item_diagnostic]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ItemDiagnostic<I, const N: usize> {
+    /// The item as returned by [fetch_item]
+    pub item: Option<I>,
+    /// The address of the version the crate considers the newest.
+    /// If the key has been removed, this is the address of the tombstone and the item is None.
+    pub newest_address: Option<u32>,
+    /// The newest `N` versions of the key from oldest to newest
+    pub versions: ArrayVec<ItemVersion, N>,
+    /// The amount of versions found, which can be more than are listed
+    pub version_count: usize,
+}
 
-    flash
-        .borrow_mut()
-        .read(
-            page_data_start_address,
-            &mut read_buffer[used_read_buffer
-                ..MAX_STORAGE_ITEM_SIZE
-                    .min((page_data_end_address - page_data_start_address) as usize)],
-        )
-        .map_err(MapError::Storage)?;
+/// The location of a version of an item. See [fetch_item_diagnostic].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ItemVersion {
+    /// The address of the record in flash
+    pub address: u32,
+    /// The index of the page the record is in
+    pub page: usize,
+}
 
-    Ok(core::iter::from_fn(move || {
-        // Now we deserialize the items from the buffer one by one
-        // Every time we proceed, we remove the bytes we used and fill the buffer back up
+/// Get the serialized bytes of the newest items of the given keys, packed back-to-back into `out`.
+///
+/// All keys are searched for in a single scan over the pages.
+/// The returned [PackLayout] gives the location of every key's bytes in `out` in the same order as the keys.
+/// Keys that have no value are None in the layout and take up no space.
+///
+/// The bytes are read straight from flash into `out`. Because of that, `out` needs room for every item
+/// rounded up to `WRITE_SIZE`, even though only the real serialized size is used.
+/// If it's too small, [MapError::BufferTooSmall] is returned.
+pub fn pack_items<I: StorageItem, S: NorFlash, const N: usize>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    keys: &[I::Key; N],
+    out: &mut [u8],
+) -> Result<PackLayout<N>, MapError<I::Error, S::Error>> {
+    check_geometry::<S>(flash_range.clone())?;
 
-        macro_rules! replenish_read_buffer {
-            () => {{
-                read_buffer.copy_within(used_read_buffer.., 0);
-                // We need to replenish the used_bytes at the end of the buffer
-                let replenish_slice = &mut read_buffer[MAX_STORAGE_ITEM_SIZE - used_read_buffer..];
+    let mut layout = PackLayout { items: [None; N] };
 
-                let replenish_start_address = page_data_start_address
-                    + read_buffer_start_index_into_page as u32
-                    + MAX_STORAGE_ITEM_SIZE as u32
-                    - used_read_buffer as u32;
+    // The address and length of the newest record of every key, or None inside if that's a tombstone
+    let mut locations: [Option<Option<(u32, usize)>>; N] = [None; N];
 
-                let unread_bytes_left_in_page =
-                    page_data_end_address.saturating_sub(replenish_start_address);
+    let mut page_walker = PageWalker::new(flash, flash_range)?;
+    with_item_buffer::<I, S, _>(|buffer| {
+        while let Some(page_index) = page_walker.next() {
+            // Later items in the page are newer, so we only commit what we found once the whole page has been read
+            let mut page_locations: [Option<Option<(u32, usize)>>; N] = [None; N];
 
-                let (read_slice, fill_slice) = replenish_slice
-                    .split_at_mut((unread_bytes_left_in_page as usize).min(replenish_slice.len()));
+            for found_item_result in
+                page_walker.page_items_with_location::<I>(page_index?, buffer)?
+            {
+                let (found_item, address, len) = found_item_result?;
+                let found_key = found_item.key();
 
-                if !read_slice.is_empty() {
-                    if let Err(e) = flash
-                        .borrow_mut()
-                        .read(replenish_start_address, read_slice)
-                        .map_err(MapError::Storage)
-                    {
-                        return Some(Err(e.into()));
+                for (key, page_location) in keys.iter().zip(page_locations.iter_mut()) {
+                    if *key == found_key {
+                        *page_location =
+                            Some((!found_item.is_tombstone()).then_some((address, len)));
                     }
                 }
-                fill_slice.fill(0xFF);
-                used_read_buffer = 0;
-            }};
-        }
+            }
 
-        if used_read_buffer == read_buffer.len() {
-            replenish_read_buffer!();
-        }
+            for (location, page_location) in locations.iter_mut().zip(page_locations) {
+                if location.is_none() {
+                    *location = page_location;
+                }
+            }
 
-        if read_buffer[used_read_buffer..].iter().all(|b| *b == 0xFF) {
-            // The entire buffer is in the erased state, so we know that the rest is empty
-            return None;
+            if locations.iter().all(Option::is_some) {
+                break;
+            }
         }
 
-        loop {
-            match I::deserialize_from(&read_buffer[used_read_buffer..]) {
-                Ok((item, mut used_bytes)) => {
-                    // We can only write in whole words, so we round up the used bytes so the math works
-                    if used_bytes % S::WRITE_SIZE > 0 {
-                        used_bytes += S::WRITE_SIZE - (used_bytes % S::WRITE_SIZE);
-                    }
+        Ok::<_, MapError<I::Error, S::Error>>(())
+    })?;
 
-                    let return_item = Ok((
-                        item,
-                        page_data_start_address + read_buffer_start_index_into_page as u32,
-                        used_bytes,
-                    ));
+    let mut flash = page_walker.flash.borrow_mut();
+    let mut offset = 0;
 
-                    read_buffer_start_index_into_page += used_bytes;
-                    used_read_buffer += used_bytes;
+    for (packed_item, location) in layout.items.iter_mut().zip(locations) {
+        let Some(Some((address, len))) = location else {
+            continue;
+        };
 
-                    break Some(return_item);
-                }
-                Err(e) if e.is_buffer_too_small() && used_read_buffer > 0 => {
-                    replenish_read_buffer!();
-                }
-                Err(e) => {
-                    return Some(Err(MapError::Item(e)));
+        let slot = out
+            .get_mut(offset..offset + len)
+            .ok_or(MapError::BufferTooSmall)?;
+        flash.read(address, slot).map_err(MapError::Storage)?;
+
+        // The length in flash is rounded up to whole words, so we find the real length of the item
+        let (_, used_bytes) = I::deserialize_from(slot).map_err(MapError::Item)?;
+
+        *packed_item = Some(PackedItem {
+            offset,
+            len: used_bytes,
+        });
+        offset += used_bytes;
+    }
+
+    Ok(layout)
+}
+
+/// The layout of the items packed by [pack_items]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PackLayout<const N: usize> {
+    /// The location of every key's item in the output buffer, in the same order as the keys.
+    /// None if the key has no value.
+    pub items: [Option<PackedItem>; N],
+}
+
+impl<const N: usize> PackLayout<N> {
+    /// The total amount of bytes used in the output buffer
+    pub fn used_bytes(&self) -> usize {
+        self.items
+            .iter()
+            .flatten()
+            .map(|item| item.offset + item.len)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// The location of an item in the output buffer of [pack_items]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PackedItem {
+    /// The offset in bytes into the output buffer
+    pub offset: usize,
+    /// The serialized length of the item in bytes
+    pub len: usize,
+}
+
+/// Fetch the item, but with the address and the length too
+#[allow(clippy::type_complexity)]
+fn fetch_item_with_location<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    search_key: I::Key,
+) -> Result<Option<(I, u32, usize)>, MapError<I::Error, S::Error>> {
+    with_item_buffer::<I, S, _>(|buffer| {
+        fetch_item_with_location_in(flash, flash_range, search_key, buffer)
+    })
+}
+
+/// Like [fetch_item_with_location], but with the given buffer to read the pages with
+#[allow(clippy::type_complexity)]
+fn fetch_item_with_location_in<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    search_key: I::Key,
+    read_buffer: &mut [u8],
+) -> Result<Option<(I, u32, usize)>, MapError<I::Error, S::Error>> {
+    // A key that has been removed has no value, even though older values may still be in the flash
+    Ok(
+        find_newest_version_in::<I, S>(flash, flash_range, search_key, read_buffer)?
+            .filter(|(item, _, _)| !item.is_tombstone()),
+    )
+}
+
+/// Find the newest record of the key with its address and length, even if it's a tombstone
+#[allow(clippy::type_complexity)]
+fn find_newest_version<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    search_key: I::Key,
+) -> Result<Option<(I, u32, usize)>, MapError<I::Error, S::Error>> {
+    with_item_buffer::<I, S, _>(|buffer| {
+        find_newest_version_in(flash, flash_range, search_key, buffer)
+    })
+}
+
+/// Like [find_newest_version], but with the given buffer to read the pages with
+#[allow(clippy::type_complexity)]
+fn find_newest_version_in<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    search_key: I::Key,
+    read_buffer: &mut [u8],
+) -> Result<Option<(I, u32, usize)>, MapError<I::Error, S::Error>> {
+    find_newest_item_in(
+        flash,
+        flash_range,
+        CorruptRecordPolicy::Abort,
+        |item: &I| item.key() == search_key,
+        read_buffer,
+    )
+}
+
+/// Find the newest item for which the predicate returns true, with its address and length
+#[allow(clippy::type_complexity)]
+fn find_newest_item<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    corrupt_record_policy: CorruptRecordPolicy,
+    predicate: impl FnMut(&I) -> bool,
+) -> Result<Option<(I, u32, usize)>, MapError<I::Error, S::Error>> {
+    with_item_buffer::<I, S, _>(|buffer| {
+        find_newest_item_in(flash, flash_range, corrupt_record_policy, predicate, buffer)
+    })
+}
+
+/// Like [find_newest_item], but with the given buffer to read the pages with
+#[allow(clippy::type_complexity)]
+fn find_newest_item_in<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    corrupt_record_policy: CorruptRecordPolicy,
+    predicate: impl FnMut(&I) -> bool,
+    read_buffer: &mut [u8],
+) -> Result<Option<(I, u32, usize)>, MapError<I::Error, S::Error>> {
+    let mut page_walker = PageWalker::new(flash, flash_range)?;
+    find_newest_item_with_walker(
+        &mut page_walker,
+        corrupt_record_policy,
+        predicate,
+        read_buffer,
+    )
+}
+
+#[allow(clippy::type_complexity)]
+fn find_newest_item_with_walker<I: StorageItem, S: NorFlash>(
+    page_walker: &mut PageWalker<'_, S>,
+    corrupt_record_policy: CorruptRecordPolicy,
+    mut predicate: impl FnMut(&I) -> bool,
+    read_buffer: &mut [u8],
+) -> Result<Option<(I, u32, usize)>, MapError<I::Error, S::Error>> {
+    // We must now find the most recent storage item with the key that was asked for.
+    // If we don't find it in the current page, then we check again in the previous page if that page is closed.
+    while let Some(page_index) = page_walker.next() {
+        let mut newest_found_item = None;
+
+        for found_item_result in read_page_items_in::<I, S, _>(
+            &page_walker.flash,
+            page_walker.flash_range.clone(),
+            page_index?,
+            &mut *read_buffer,
+        )? {
+            let found_item = match found_item_result {
+                Ok(found_item) => found_item,
+                Err(MapError::Item(_))
+                    if corrupt_record_policy == CorruptRecordPolicy::SkipRestOfPage =>
+                {
+                    warn!("Skipping the rest of a page after a record that can't be deserialized");
+                    break;
                 }
+                Err(e) => return Err(e),
+            };
+
+            if predicate(&found_item.0) {
+                newest_found_item = Some(found_item);
             }
         }
-    }))
+
+        // We've found the item! We can stop searching
+        if newest_found_item.is_some() {
+            return Ok(newest_found_item);
+        }
+    }
+
+    // We've looked through all the pages with data and couldn't find the item
+    Ok(None)
 }
 
-/// A way of serializing and deserializing items in the storage.
+/// Walks over the pages of a map that hold data, from the newest to the oldest.
 ///
-/// A serialized byte pattern of all `0xFF` is invalid and must never be the result of the `serialize_into` function
-/// and `deserialize_from` must always return an error for it.
+/// This is the order in which the map itself searches for items: it starts at the last used page
+/// and then goes back page by page for as long as the pages are closed.
+/// Use it to build your own queries and read the items of every page with [Self::page_items]:
 ///
-/// The given buffer to serialize in and deserialize from is never bigger than [MAX_STORAGE_ITEM_SIZE] bytes, so make sure the item is
-/// smaller than that.
-pub trait StorageItem {
-    /// The key type of the key-value pair
-    type Key: Eq;
-    /// The error type for serialization and deserialization
-    type Error: StorageItemError;
+/// ```rust,ignore
+/// let mut page_walker = PageWalker::new(&mut flash, flash_range.clone())?;
+/// let mut buffer = [0; MyCustomType::MAX_SIZE];
+///
+/// while let Some(page_index) = page_walker.next() {
+///     for item in page_walker.page_items::<MyCustomType>(page_index?, &mut buffer)? {
+///         // Within a page, the items are in the order they were stored in
+///     }
+/// }
+/// ```
+///
+/// The flash state is read lazily, so stopping early saves reads.
+pub struct PageWalker<'a, S: NorFlash> {
+    flash: RefCell<&'a mut S>,
+    flash_range: Range<u32>,
+    last_used_page: Option<usize>,
+    current_page: Option<usize>,
+    pages_walked: usize,
+    done: bool,
+}
 
-    /// Serialize the key-value item into the given buffer.
-    /// Returns the number of bytes the buffer was filled with or an error.
+impl<'a, S: NorFlash> PageWalker<'a, S> {
+    /// Create a walker for the map in the flash range. This reads the flash to find the last used page.
+    pub fn new(flash: &'a mut S, flash_range: Range<u32>) -> Result<Self, Error<S::Error>> {
+        check_geometry::<S>(flash_range.clone())?;
+
+        let last_used_page = find_last_used_page(flash, flash_range.clone())?;
+
+        Ok(Self::with_last_used_page(
+            flash,
+            flash_range,
+            last_used_page,
+        ))
+    }
+
+    /// Create a walker that starts at the given last used page without reading the flash to find it
+    fn with_last_used_page(
+        flash: &'a mut S,
+        flash_range: Range<u32>,
+        last_used_page: Option<usize>,
+    ) -> Self {
+        Self {
+            flash: RefCell::new(flash),
+            flash_range,
+            last_used_page,
+            current_page: None,
+            pages_walked: 0,
+            // All pages are still open, so we don't have any items yet
+            done: last_used_page.is_none(),
+        }
+    }
+
+    /// The amount of pages the walker has returned so far
+    pub fn pages_walked(&self) -> usize {
+        self.pages_walked
+    }
+
+    /// Read the items of the page with the given index, in the order they were stored in.
     ///
-    /// The serialized bytes must not all be `0xFF`. One way to prevent this is to serialize an extra 0 byte at the end if that is the case.
-    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error>;
-    /// Deserialize the key-value item from the given buffer.
-    /// The buffer is likely bigger than the size of the item.
-    fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error>
-    where
-        Self: Sized;
+    /// The page is read through the buffer. A buffer of [StorageItem::MAX_SIZE] bytes is always big enough,
+    /// otherwise it needs to fit the biggest item of the page or [MapError::BufferTooSmall] is returned.
+    #[allow(clippy::type_complexity)]
+    pub fn page_items<'b, I: StorageItem>(
+        &self,
+        page_index: usize,
+        buffer: &'b mut [u8],
+    ) -> Result<
+        impl Iterator<Item = Result<I, MapError<I::Error, S::Error>>> + use<'_, 'a, 'b, I, S>,
+        MapError<I::Error, S::Error>,
+    > {
+        Ok(self
+            .page_items_with_location::<I>(page_index, buffer)?
+            .map(|item| item.map(|(item, _, _)| item)))
+    }
 
-    /// The key of the key-value item. It is used by the storage to know what the key of this item is.
-    fn key(&self) -> Self::Key;
+    #[allow(clippy::type_complexity)]
+    fn page_items_with_location<'b, I: StorageItem>(
+        &self,
+        page_index: usize,
+        buffer: &'b mut [u8],
+    ) -> Result<
+        impl Iterator<Item = Result<(I, u32, usize), MapError<I::Error, S::Error>>>
+            + use<'_, 'a, 'b, I, S>,
+        MapError<I::Error, S::Error>,
+    > {
+        read_page_items_in::<I, S, _>(&self.flash, self.flash_range.clone(), page_index, buffer)
+    }
 }
 
-/// The maximum size in bytes that a storage item can be
-pub const MAX_STORAGE_ITEM_SIZE: usize = 512;
+impl<S: NorFlash> Iterator for PageWalker<'_, S> {
+    type Item = Result<usize, Error<S::Error>>;
 
-/// A trait that the storage item error needs to implement
-pub trait StorageItemError: Debug {
-    /// Returns true if the error indicates that the buffer is too small to contain the storage item
-    fn is_buffer_too_small(&self) -> bool;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let last_used_page = self.last_used_page?;
+
+        let Some(current_page) = self.current_page else {
+            self.current_page = Some(last_used_page);
+            self.pages_walked += 1;
+            return Some(Ok(last_used_page));
+        };
+
+        // We only go back to the previous page if that page is closed and contains data
+        let previous_page = previous_page::<S>(self.flash_range.clone(), current_page);
+
+        if previous_page == last_used_page {
+            self.done = true;
+            return None;
+        }
+
+        match get_page_state(
+            *self.flash.borrow_mut(),
+            self.flash_range.clone(),
+            previous_page,
+        ) {
+            Ok(PageState::Closed) => {
+                self.current_page = Some(previous_page);
+                self.pages_walked += 1;
+                Some(Ok(previous_page))
+            }
+            Ok(_) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
 }
 
-/// The main error type
-#[non_exhaustive]
-#[derive(Debug, PartialEq, Eq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub enum MapError<I, S> {
-    /// A storage item error
-    Item(I),
-    /// An error in the storage (flash)
-    Storage(S),
-    /// The item cannot be stored anymore because the storage is full.
-    /// If you get this error some data may be lost.
-    FullStorage,
-    /// It's been detected that the memory is likely corrupted.
-    /// You may want to erase the memory to recover.
-    Corrupted,
-    /// A provided buffer was to big to be used
-    BufferTooBig,
-    /// A provided buffer was to small to be used
-    BufferTooSmall,
+/// Iterate over the newest value of every key in the map, e.g. to dump the whole map over a serial link.
+///
+/// Every key is returned once. Keys that have been removed with [remove_item] are not returned.
+/// The pages are walked from the newest to the oldest like [PageWalker] does and the items of every page
+/// in the order they were stored in, so the order only depends on what's in the flash.
+///
+/// No memory is needed to remember which keys have been returned already.
+/// Instead every item is looked up again to check that it's the newest version of its key, like [fetch_item] does,
+/// and the page is read from the start again for every item that is returned.
+/// So this does a lot of reads. Don't use it in a hot path. [fetch_all_items_with_keys] reads a lot less.
+///
+/// After an error is returned, the iterator is done.
+pub fn fetch_all_items<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+) -> Result<LiveItems<'_, I, S>, MapError<I::Error, S::Error>> {
+    fetch_all_items_with_keys::<I, S, 0>(flash, flash_range)
 }
 
-impl<S, I> From<super::Error<S>> for MapError<I, S> {
-    fn from(value: super::Error<S>) -> Self {
-        match value {
-            Error::Storage(e) => Self::Storage(e),
-            Error::FullStorage => Self::FullStorage,
-            Error::Corrupted => Self::Corrupted,
-            Error::BufferTooBig => Self::BufferTooBig,
-            Error::BufferTooSmall => Self::BufferTooSmall,
+/// Iterate over the newest value of every key in the map like [fetch_all_items] does,
+/// remembering up to `N` keys so the items don't have to be looked up again.
+///
+/// The keys of every page that has been read are kept in the iterator, so a key of an older page is skipped when it's one of them.
+/// Within a page, an item is only returned if the rest of the page doesn't have a newer version of it,
+/// so only that one page is read again instead of all the pages before it.
+///
+/// When a page has more new keys than fit, this falls back to looking up every item of the older pages like [fetch_all_items].
+/// So pick `N` for the amount of keys the map holds, the items come out the same either way.
+///
+/// After an error is returned, the iterator is done.
+pub fn fetch_all_items_with_keys<I: StorageItem, S: NorFlash, const N: usize>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+) -> Result<LiveItems<'_, I, S, N>, MapError<I::Error, S::Error>> {
+    Ok(LiveItems {
+        page_walker: PageWalker::new(flash, flash_range)?,
+        current: None,
+        seen_keys: ArrayVec::new(),
+        all_keys_seen: N > 0,
+        done: false,
+    })
+}
+
+/// The iterator of [fetch_all_items] and [fetch_all_items_with_keys]
+pub struct LiveItems<'a, I: StorageItem, S: NorFlash, const N: usize = 0> {
+    page_walker: PageWalker<'a, S>,
+    /// The page that is being read and the address of the item that was returned last
+    current: Option<(usize, u32)>,
+    /// The keys of the pages that have been read, including the removed ones
+    seen_keys: ArrayVec<I::Key, N>,
+    /// False once the keys of a page didn't fit. The items are looked up from then on.
+    all_keys_seen: bool,
+    done: bool,
+}
+
+impl<I: StorageItem, S: NorFlash, const N: usize> Iterator for LiveItems<'_, I, S, N> {
+    type Item = Result<I, MapError<I::Error, S::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let result = with_item_buffer::<I, S, _>(|buffer| self.next_live_item(buffer));
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
         }
+
+        result
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl<I: StorageItem, S: NorFlash, const N: usize> LiveItems<'_, I, S, N> {
+    #[allow(clippy::type_complexity)]
+    fn next_live_item(
+        &mut self,
+        buffer: &mut [u8],
+    ) -> Option<Result<I, MapError<I::Error, S::Error>>> {
+        loop {
+            let (page_index, start_address) = match self.current {
+                Some((page_index, address)) => (page_index, address + 1),
+                None => match self.page_walker.next()? {
+                    Ok(page_index) => (page_index, 0),
+                    Err(e) => return Some(Err(e.into())),
+                },
+            };
 
-    type MockFlashBig = mock_flash::MockFlashBase<4, 4, 256>;
-    type MockFlashTiny = mock_flash::MockFlashBase<2, 1, 32>;
+            let page_items = match self
+                .page_walker
+                .page_items_with_location::<I>(page_index, &mut *buffer)
+            {
+                Ok(page_items) => page_items,
+                Err(e) => return Some(Err(e)),
+            };
+            for found_item_result in page_items {
+                let (item, address, _) = match found_item_result {
+                    Ok(found_item) => found_item,
+                    Err(e) => return Some(Err(e)),
+                };
 
-    #[derive(Debug, PartialEq, Eq)]
-    struct MockStorageItem {
-        key: u8,
-        value: Vec<u8>,
+                if address < start_address {
+                    continue;
+                }
+
+                let is_live = if !self.all_keys_seen {
+                    fetch_item_with_location::<I, S>(
+                        *self.page_walker.flash.borrow_mut(),
+                        self.page_walker.flash_range.clone(),
+                        item.key(),
+                    )
+                    .map(|newest_version| {
+                        newest_version.map(|(_, address, _)| address) == Some(address)
+                    })
+                } else if item.is_tombstone() || self.seen_keys.contains(&item.key()) {
+                    Ok(false)
+                } else {
+                    self.is_newest_in_page(page_index, address, &item)
+                };
+
+                match is_live {
+                    Ok(true) => {
+                        self.current = Some((page_index, address));
+                        return Some(Ok(item));
+                    }
+                    Ok(false) => {}
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+
+            if self.all_keys_seen {
+                if let Err(e) = self.remember_page_keys(page_index) {
+                    return Some(Err(e));
+                }
+            }
+
+            // We've returned all live items of this page, so continue with the previous page
+            self.current = None;
+        }
     }
 
-    #[derive(Debug, PartialEq, Eq)]
-    enum MockStorageItemError {
-        BufferTooSmall,
-        InvalidKey,
-        BufferTooBig,
+    /// Returns true if the rest of the page after the address has no newer version of the item
+    fn is_newest_in_page(
+        &self,
+        page_index: usize,
+        address: u32,
+        item: &I,
+    ) -> Result<bool, MapError<I::Error, S::Error>> {
+        with_item_buffer::<I, S, _>(|buffer| {
+            for found_item_result in self
+                .page_walker
+                .page_items_with_location::<I>(page_index, buffer)?
+            {
+                let (found_item, found_address, _) = found_item_result?;
+
+                if found_address > address && found_item.key() == item.key() {
+                    return Ok(false);
+                }
+            }
+
+            Ok(true)
+        })
+    }
+
+    /// Add the keys of the page to the seen keys, or stop using them if they don't fit
+    fn remember_page_keys(
+        &mut self,
+        page_index: usize,
+    ) -> Result<(), MapError<I::Error, S::Error>> {
+        with_item_buffer::<I, S, _>(|buffer| {
+            for found_item_result in self
+                .page_walker
+                .page_items_with_location::<I>(page_index, buffer)?
+            {
+                let (found_item, _, _) = found_item_result?;
+                let key = found_item.key();
+
+                if !self.seen_keys.contains(&key) && self.seen_keys.try_push(key).is_err() {
+                    self.all_keys_seen = false;
+                    return Ok(());
+                }
+            }
+
+            Ok(())
+        })
+    }
+}
+
+/// Iterate over all records of the map, every version of every key, from the newest to the oldest.
+///
+/// The map doesn't store sequence numbers, so the order comes from where the records are:
+/// the pages are walked from the newest to the oldest like [PageWalker] does and the records of every page from the last to the first.
+/// Items that have been copied forward when their page was recycled show up at the moment they were copied.
+///
+/// Records can only be read from the start of a page, so the page is read again for every record that is returned.
+/// This makes it a lot slower than going through the pages with [PageWalker]. It's meant for diagnostics,
+/// e.g. to reconstruct what happened in what order after an incident.
+///
+/// After an error is returned, the iterator is done.
+pub fn iter_by_recency<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+) -> Result<RecencyIter<'_, I, S>, MapError<I::Error, S::Error>> {
+    Ok(RecencyIter {
+        page_walker: PageWalker::new(flash, flash_range)?,
+        current: None,
+        done: false,
+        _item: PhantomData,
+    })
+}
+
+/// The iterator of [iter_by_recency]
+pub struct RecencyIter<'a, I, S: NorFlash> {
+    page_walker: PageWalker<'a, S>,
+    /// The page that is being read and the address of the record that was returned last
+    current: Option<(usize, u32)>,
+    done: bool,
+    _item: PhantomData<I>,
+}
+
+impl<I: StorageItem, S: NorFlash> Iterator for RecencyIter<'_, I, S> {
+    type Item = Result<(I, RecordId), MapError<I::Error, S::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let result = with_item_buffer::<I, S, _>(|buffer| self.next_record(buffer));
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
+        }
+
+        result
+    }
+}
+
+impl<I: StorageItem, S: NorFlash> RecencyIter<'_, I, S> {
+    #[allow(clippy::type_complexity)]
+    fn next_record(
+        &mut self,
+        buffer: &mut [u8],
+    ) -> Option<Result<(I, RecordId), MapError<I::Error, S::Error>>> {
+        loop {
+            let (page_index, end_address) = match self.current {
+                Some(current) => current,
+                None => match self.page_walker.next()? {
+                    Ok(page_index) => (page_index, u32::MAX),
+                    Err(e) => return Some(Err(e.into())),
+                },
+            };
+
+            // Find the last record before the one we returned last
+            let mut previous_record = None;
+
+            let page_items = match self
+                .page_walker
+                .page_items_with_location::<I>(page_index, &mut *buffer)
+            {
+                Ok(page_items) => page_items,
+                Err(e) => return Some(Err(e)),
+            };
+            for found_item_result in page_items {
+                match found_item_result {
+                    Ok((item, address, _)) if address < end_address => {
+                        previous_record = Some((item, address))
+                    }
+                    Ok(_) => break,
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+
+            match previous_record {
+                Some((item, address)) => {
+                    self.current = Some((page_index, address));
+                    return Some(Ok((item, RecordId { address })));
+                }
+                // We've returned all records of this page, so continue with the previous page
+                None => self.current = None,
+            }
+        }
+    }
+}
+
+/// Iterate over every version of a key that is still in the flash, from the oldest to the newest.
+///
+/// This shows how the value of a key changed over time, as far as the old versions haven't been erased yet.
+/// When a page is recycled, its old versions are gone. Only the newest version of the key is copied forward,
+/// and that copy shows up at the moment it was copied. Tombstones are returned too, see [remove_item].
+///
+/// Records can only be read from the start of a page, so the page is read again for every version that is returned.
+/// It's meant for debugging, not for a hot path.
+///
+/// After an error is returned, the iterator is done.
+pub fn fetch_item_history<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    search_key: I::Key,
+) -> Result<ItemHistory<'_, I, S>, MapError<I::Error, S::Error>> {
+    check_geometry::<S>(flash_range.clone())?;
+
+    let (page_index, pages_left) =
+        find_pages_with_data(flash, flash_range.clone())?.unwrap_or((0, 0));
+
+    Ok(ItemHistory {
+        flash: RefCell::new(flash),
+        flash_range,
+        search_key,
+        page_index,
+        pages_left,
+        last_address: None,
+        done: false,
+    })
+}
+
+/// The iterator of [fetch_item_history]
+pub struct ItemHistory<'a, I: StorageItem, S: NorFlash> {
+    flash: RefCell<&'a mut S>,
+    flash_range: Range<u32>,
+    search_key: I::Key,
+    /// The page that is being read
+    page_index: usize,
+    /// The amount of pages with data that haven't been read yet, including the current one
+    pages_left: usize,
+    /// The address of the version that was returned last, if it's on the current page
+    last_address: Option<u32>,
+    done: bool,
+}
+
+impl<I: StorageItem, S: NorFlash> Iterator for ItemHistory<'_, I, S> {
+    type Item = Result<(I, RecordId), MapError<I::Error, S::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let result = with_item_buffer::<I, S, _>(|buffer| self.next_version(buffer));
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
+        }
+
+        result
+    }
+}
+
+impl<I: StorageItem, S: NorFlash> ItemHistory<'_, I, S> {
+    #[allow(clippy::type_complexity)]
+    fn next_version(
+        &mut self,
+        buffer: &mut [u8],
+    ) -> Option<Result<(I, RecordId), MapError<I::Error, S::Error>>> {
+        while self.pages_left > 0 {
+            let page_items = match read_page_items_in::<I, S, _>(
+                &self.flash,
+                self.flash_range.clone(),
+                self.page_index,
+                &mut *buffer,
+            ) {
+                Ok(page_items) => page_items,
+                Err(e) => return Some(Err(e)),
+            };
+            for found_item_result in page_items {
+                let (item, address, _) = match found_item_result {
+                    Ok(found_item) => found_item,
+                    Err(e) => return Some(Err(e)),
+                };
+
+                if self
+                    .last_address
+                    .is_some_and(|last_address| address <= last_address)
+                    || item.key() != self.search_key
+                {
+                    continue;
+                }
+
+                self.last_address = Some(address);
+                return Some(Ok((item, RecordId { address })));
+            }
+
+            // We've returned all versions on this page, so continue with the next page
+            self.page_index = next_page::<S>(self.flash_range.clone(), self.page_index);
+            self.pages_left -= 1;
+            self.last_address = None;
+        }
+
+        None
+    }
+}
+
+/// Count the records that are byte-for-byte the same as the record right before them on the same page.
+///
+/// Storing the exact same item twice in a row doesn't break anything because the newest value wins,
+/// but it does waste space. A count higher than 0 points at code that stores items more often than needed,
+/// for example because of a retry.
+pub fn count_adjacent_duplicates<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+) -> Result<usize, MapError<I::Error, S::Error>> {
+    check_geometry::<S>(flash_range.clone())?;
+
+    let mut page_walker = PageWalker::new(flash, flash_range)?;
+    let mut duplicates = 0;
+
+    with_item_buffer::<I, S, _>(|buffer| {
+        while let Some(page_index) = page_walker.next() {
+            let mut previous_record = None;
+
+            for found_item_result in
+                page_walker.page_items_with_location::<I>(page_index?, buffer)?
+            {
+                let (_, address, size) = found_item_result?;
+
+                if let Some((previous_address, previous_size)) = previous_record {
+                    if previous_size == size
+                        && flash_regions_equal(
+                            *page_walker.flash.borrow_mut(),
+                            previous_address,
+                            address,
+                            size,
+                        )?
+                    {
+                        duplicates += 1;
+                    }
+                }
+
+                previous_record = Some((address, size));
+            }
+        }
+
+        Ok(duplicates)
+    })
+}
+
+/// Find the pages that hold the newest value of any key.
+///
+/// The returned extent is the run of pages from the oldest page with a live value up to the last used page.
+/// The pages before it only hold outdated values. Returns None if there are no items.
+///
+/// This can be used to move a map to another flash range while copying as little as possible:
+///
+/// 1. Get the extent of the source range with this function.
+/// 2. Erase the whole destination range. It must have the same page size and the same amount of pages.
+/// 3. Copy every page of the extent, including the page markers, to the *same page index* in the destination.
+///
+/// All other pages stay erased, so the destination is a valid map with the same items as the source.
+pub fn live_extent<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+) -> Result<Option<LiveExtent>, MapError<I::Error, S::Error>> {
+    check_geometry::<S>(flash_range.clone())?;
+
+    let mut page_walker = PageWalker::new(flash, flash_range.clone())?;
+    let mut oldest_live_page = None;
+
+    with_item_buffer::<I, S, _>(|buffer| {
+        while let Some(page_index) = page_walker.next() {
+            let page_index = page_index?;
+
+            for found_item_result in
+                page_walker.page_items_with_location::<I>(page_index, buffer)?
+            {
+                let (item, address, _) = found_item_result?;
+
+                let newest_address = fetch_item_with_location::<I, S>(
+                    *page_walker.flash.borrow_mut(),
+                    flash_range.clone(),
+                    item.key(),
+                )?
+                .map(|(_, newest_address, _)| newest_address);
+
+                // A tombstone is never live, so it's not returned as the newest address
+                if newest_address == Some(address) {
+                    oldest_live_page = Some((page_index, page_walker.pages_walked()));
+                    break;
+                }
+            }
+        }
+
+        Ok::<_, MapError<I::Error, S::Error>>(())
+    })?;
+
+    Ok(page_walker.last_used_page.and_then(|newest_page| {
+        oldest_live_page.map(|(oldest_page, page_count)| LiveExtent {
+            oldest_page,
+            newest_page,
+            page_count,
+        })
+    }))
+}
+
+/// The pages that hold live data. See [live_extent].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LiveExtent {
+    /// The index of the oldest page with live data
+    pub oldest_page: usize,
+    /// The index of the last used page
+    pub newest_page: usize,
+    /// The amount of pages in the extent
+    pub page_count: usize,
+}
+
+impl LiveExtent {
+    /// The page indices of the extent from oldest to newest. They may wrap around the end of the flash range.
+    pub fn pages<S: NorFlash>(&self, flash_range: Range<u32>) -> impl Iterator<Item = usize> {
+        get_pages::<S>(flash_range, self.oldest_page).take(self.page_count)
+    }
+}
+
+/// Check that two maps hold the same live data: the same keys with the same serialized values.
+///
+/// This is meant to verify a map after it has been copied to another range, e.g. as described at [live_extent].
+/// The maps can be in different ranges and even on different flash chips, as long as they have the same error type.
+/// The values are compared by their serialized bytes, so the write sizes of the flashes don't need to match.
+///
+/// Every live item of one map is looked up in the other, so this does a lot of reads. Don't use it in a hot path.
+pub fn maps_equal<I: StorageItem, S1: NorFlash, S2: NorFlash<Error = S1::Error>>(
+    flash_a: &mut S1,
+    flash_range_a: Range<u32>,
+    flash_b: &mut S2,
+    flash_range_b: Range<u32>,
+) -> Result<bool, MapError<I::Error, S1::Error>> {
+    let mut live_count_a = 0;
+
+    let all_found = for_each_live_item::<I, S1>(flash_a, flash_range_a, |item_a| {
+        live_count_a += 1;
+
+        let Some(item_b) = fetch_item::<I, S2>(flash_b, flash_range_b.clone(), item_a.key())?
+        else {
+            return Ok(false);
+        };
+
+        with_item_buffer::<I, S1, _>(|buffer_a| {
+            with_item_buffer::<I, S2, _>(|buffer_b| {
+                let used_a = item_a.serialize_into(buffer_a).map_err(MapError::Item)?;
+                let used_b = item_b.serialize_into(buffer_b).map_err(MapError::Item)?;
+
+                Ok(buffer_a[..used_a] == buffer_b[..used_b])
+            })
+        })
+    })?;
+
+    if !all_found {
+        return Ok(false);
+    }
+
+    // All keys of a are in b with the same value, so b can only differ by having more keys
+    let mut live_count_b = 0;
+    for_each_live_item::<I, S2>(flash_b, flash_range_b, |_| {
+        live_count_b += 1;
+        Ok(true)
+    })?;
+
+    Ok(live_count_a == live_count_b)
+}
+
+/// Call `f` with the newest version of every key in the map.
+///
+/// Stops early and returns false as soon as `f` returns false.
+fn for_each_live_item<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    mut f: impl FnMut(I) -> Result<bool, MapError<I::Error, S::Error>>,
+) -> Result<bool, MapError<I::Error, S::Error>> {
+    let Some(last_used_page) = find_last_used_page(flash, flash_range.clone())? else {
+        return Ok(true);
+    };
+
+    let flash = RefCell::new(flash);
+    let mut current_page_to_check = last_used_page;
+
+    with_item_buffer::<I, S, _>(|buffer| loop {
+        for found_item_result in read_page_items_in::<I, S, _>(
+            &flash,
+            flash_range.clone(),
+            current_page_to_check,
+            &mut *buffer,
+        )? {
+            let (item, address, _) = found_item_result?;
+
+            let newest_address = fetch_item_with_location::<I, S>(
+                *flash.borrow_mut(),
+                flash_range.clone(),
+                item.key(),
+            )?
+            .map(|(_, newest_address, _)| newest_address);
+
+            if newest_address == Some(address) && !f(item)? {
+                return Ok(false);
+            }
+        }
+
+        let previous_page = previous_page::<S>(flash_range.clone(), current_page_to_check);
+
+        if previous_page == last_used_page
+            || get_page_state(*flash.borrow_mut(), flash_range.clone(), previous_page)?
+                != PageState::Closed
+        {
+            return Ok(true);
+        }
+
+        current_page_to_check = previous_page;
+    })
+}
+
+/// Returns true if the two regions of flash contain the same bytes
+fn flash_regions_equal<S: NorFlash>(
+    flash: &mut S,
+    mut left_address: u32,
+    mut right_address: u32,
+    length: usize,
+) -> Result<bool, Error<S::Error>> {
+    let mut left_buffer = [0; MAX_FLASH_WRITE_SIZE];
+    let mut right_buffer = [0; MAX_FLASH_WRITE_SIZE];
+    let mut remaining_length = length;
+
+    while remaining_length > 0 {
+        let read_length = remaining_length.min(MAX_FLASH_WRITE_SIZE);
+
+        flash
+            .read(left_address, &mut left_buffer[..read_length])
+            .map_err(Error::Storage)?;
+        flash
+            .read(right_address, &mut right_buffer[..read_length])
+            .map_err(Error::Storage)?;
+
+        if left_buffer[..read_length] != right_buffer[..read_length] {
+            return Ok(false);
+        }
+
+        left_address += read_length as u32;
+        right_address += read_length as u32;
+        remaining_length -= read_length;
+    }
+
+    Ok(true)
+}
+
+/// Returns true if the flash at the address holds the given bytes.
+/// The last read is rounded up to `READ_SIZE`, so the flash after the bytes has to be readable up to there.
+fn flash_equals<S: NorFlash>(
+    flash: &mut S,
+    mut address: u32,
+    bytes: &[u8],
+) -> Result<bool, Error<S::Error>> {
+    let mut buffer = [0; MAX_FLASH_WRITE_SIZE];
+
+    for chunk in bytes.chunks(MAX_FLASH_WRITE_SIZE) {
+        let read_buffer = &mut buffer[..chunk.len().next_multiple_of(S::READ_SIZE)];
+        flash.read(address, read_buffer).map_err(Error::Storage)?;
+
+        if read_buffer[..chunk.len()] != *chunk {
+            return Ok(false);
+        }
+
+        address += chunk.len() as u32;
+    }
+
+    Ok(true)
+}
+
+/// Find the oldest page with data and the amount of pages with data, which are the pages up to the last used page.
+///
+/// Returns None if all pages are open, which means there are no items.
+fn find_pages_with_data<S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+) -> Result<Option<(usize, usize)>, Error<S::Error>> {
+    let Some(last_used_page) = find_last_used_page(flash, flash_range.clone())? else {
+        return Ok(None);
+    };
+
+    // Walk back to the oldest page with data
+    let mut oldest_page = last_used_page;
+    let mut page_count = 1;
+    loop {
+        let previous_page = previous_page::<S>(flash_range.clone(), oldest_page);
+        if previous_page == last_used_page
+            || !get_page_state(flash, flash_range.clone(), previous_page)?.is_closed()
+        {
+            break;
+        }
+        oldest_page = previous_page;
+        page_count += 1;
+    }
+
+    Ok(Some((oldest_page, page_count)))
+}
+
+/// Find the page that was used last. This is the page with the newest items.
+/// Its geometry stamp is checked, see [check_geometry_stamp].
+///
+/// Returns None if all pages are open, which means there are no items.
+fn find_last_used_page<S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+) -> Result<Option<usize>, Error<S::Error>> {
+    // We need to find the page we were last using. This should be the only partial open page.
+    let last_used_page = find_partial_open_page(flash, flash_range.clone())?;
+
+    trace!("Last used page: {:?}", last_used_page);
+
+    if last_used_page.is_none() {
+        // In the event that all pages are still open or the last used page was just closed, we search for an open page
+        // that comes right after a closed page. That closed page is the last used page.
+        // The open pages can wrap around the end of the range, so the first open page is not always the one.
+        let mut open_page_found = false;
+
+        for page_index in get_pages::<S>(flash_range.clone(), 0) {
+            if !get_page_state(flash, flash_range.clone(), page_index)?.is_open() {
+                continue;
+            }
+
+            open_page_found = true;
+
+            let previous_page = previous_page::<S>(flash_range.clone(), page_index);
+            if get_page_state(flash, flash_range.clone(), previous_page)?.is_closed() {
+                check_geometry_stamp(flash, flash_range, previous_page)?;
+                return Ok(Some(previous_page));
+            }
+        }
+
+        if open_page_found {
+            // No open page comes after a closed page, so all pages must be open.
+            // This means that we don't have any items yet.
+            return Ok(None);
+        }
+
+        // There are no open pages, so everything must be closed.
+        // Something is up and this should never happen.
+        // We don't touch the flash. It's up to the user to decide how to recover.
+        error!(
+            "No open pages found for sequential storage in the range: {:?}",
+            flash_range
+        );
+        return Err(Error::Corrupted);
+    }
+
+    Ok(last_used_page)
+}
+
+/// Store an item into flash memory.
+/// It will overwrite the last value that has the same key.
+/// The flash needs to be at least 2 pages long.
+///
+/// Storing may need to move to a new page a couple of times, at most [max_store_page_moves] times.
+/// This is done in a loop, so the stack usage doesn't grow with it.
+/// A store holds a buffer of [StorageItem::MAX_SIZE] bytes on the stack, see [store_item_with_buffer] for what it's used for.
+/// So budget about that plus some bookkeeping. The exact frame size depends on the compiler, so measure it if you need to be sure.
+/// To keep it off the stack or make it smaller, use [store_item_with_buffer].
+///
+/// If a previous store was interrupted while it was moving to a new page, e.g. by a power loss,
+/// this finishes preparing the new page and its buffer page first.
+///
+/// An item that doesn't even fit in an empty page returns [MapError::InvalidFlashGeometry] before the flash is touched.
+/// The pages are too small for it, so moving to a new page would never help.
+pub fn store_item<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    item: I,
+) -> Result<(), MapError<I::Error, S::Error>> {
+    with_item_buffer::<I, S, _>(|buffer| store_item_with_buffer(flash, flash_range, item, buffer))
+}
+
+/// Store an item into flash memory with the given buffer instead of one on the stack. See [store_item].
+///
+/// The buffer is used to serialize the item in, to read the pages with and to copy items with when an old page is being cleared.
+/// While an old page is being cleared, its items are read one by one. The newest version of every item is looked up
+/// and the item is copied in between, after which the page is read from where it was again.
+///
+/// The buffer needs to fit the biggest item that is stored in the map, rounded up to `WRITE_SIZE`.
+/// Otherwise [MapError::BufferTooSmall] is returned.
+/// A buffer of `I::MAX_SIZE` bytes rounded up to `WRITE_SIZE` is always big enough, see [StorageItem::MAX_SIZE].
+pub fn store_item_with_buffer<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    item: I,
+    buffer: &mut [u8],
+) -> Result<(), MapError<I::Error, S::Error>> {
+    check_geometry::<S>(flash_range.clone())?;
+
+    let buffer = check_store_buffer::<I, S>(flash_range.clone(), &item, buffer)?;
+
+    store_item_at(flash, flash_range, &item, 0, 1, buffer, None).map(|_| ())
+}
+
+/// Store an item into flash memory, keeping `buffer_pages` open pages after the page that is being written to.
+/// [store_item] keeps 1.
+///
+/// When the map moves to a new page, the page after the buffer pages is recycled: its live items are copied
+/// to the new page and it's erased. With more buffer pages, the page that is recycled is older,
+/// so more of its items have been overwritten already and fewer bytes have to be copied.
+/// It doesn't make the map erase less often though: every move to a new page still erases a page.
+/// The price is a page of capacity for every extra buffer page.
+///
+/// The extra buffer pages are opened when the map moves to a new page, as far as the live items of their pages fit
+/// in the new page. Whatever doesn't fit is left for the next move, so switching to more buffer pages
+/// doesn't cost a burst of erases. Reading the map doesn't depend on the amount of buffer pages,
+/// so a store with fewer buffer pages simply uses the extra ones. Use the same amount for every store to keep them.
+/// [next_buffer_page], [prepare_next_buffer_page] and [max_item_size_that_fits] assume 1 buffer page.
+///
+/// The amount has to be at least 1 and less than the amount of pages, otherwise [MapError::InvalidFlashGeometry] is returned.
+pub fn store_item_with_buffer_pages<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    item: I,
+    buffer_pages: usize,
+) -> Result<(), MapError<I::Error, S::Error>> {
+    check_geometry::<S>(flash_range.clone())?;
+
+    let page_count = flash_range.len() / S::ERASE_SIZE;
+    if buffer_pages == 0 || buffer_pages >= page_count {
+        return Err(MapError::InvalidFlashGeometry);
+    }
+
+    with_item_buffer::<I, S, _>(|buffer| {
+        let buffer = check_store_buffer::<I, S>(flash_range.clone(), &item, buffer)?;
+
+        store_item_at(flash, flash_range, &item, 0, buffer_pages, buffer, None).map(|_| ())
+    })
+}
+
+/// Get the part of the buffer of a store that is used, and serialize the item into it to check that it fits
+fn check_store_buffer<'b, I: StorageItem, S: NorFlash>(
+    flash_range: Range<u32>,
+    item: &I,
+    buffer: &'b mut [u8],
+) -> Result<&'b mut [u8], MapError<I::Error, S::Error>> {
+    check_store_buffer_with_limit::<I, S>(flash_range, item, buffer, usize::MAX)
+}
+
+/// Like [check_store_buffer], but an item that is bigger than `max_len` returns [MapError::ItemTooBig]
+fn check_store_buffer_with_limit<'b, I: StorageItem, S: NorFlash>(
+    flash_range: Range<u32>,
+    item: &I,
+    buffer: &'b mut [u8],
+    max_len: usize,
+) -> Result<&'b mut [u8], MapError<I::Error, S::Error>> {
+    check_item_geometry::<I, S>(flash_range.clone())?;
+
+    // Whole words, so the padded item always fits in the buffer it was serialized in
+    let len = buffer
+        .len()
+        .min(I::MAX_SIZE.next_multiple_of(S::WRITE_SIZE))
+        / S::WRITE_SIZE
+        * S::WRITE_SIZE;
+    let buffer = &mut buffer[..len];
+
+    let page_size = I::MAX_SIZE.min(usable_page_size::<S>(flash_range));
+    let serialize_len = page_size.min(len);
+    match item.serialize_into(&mut buffer[..serialize_len.min(max_len)]) {
+        Ok(_) => {}
+        // The limit is what the item ran into first, whether it would have fit in the page or not
+        Err(e) if e.is_buffer_too_small() && max_len <= serialize_len => {
+            return Err(MapError::ItemTooBig)
+        }
+        Err(e) if e.is_buffer_too_small() && len < page_size => {
+            return Err(MapError::BufferTooSmall)
+        }
+        Err(e) if e.is_buffer_too_small() => return Err(MapError::InvalidFlashGeometry),
+        Err(e) => return Err(MapError::Item(e)),
+    }
+
+    Ok(buffer)
+}
+
+/// The result of one attempt to store an item
+enum StoreAttempt {
+    /// The item was stored in the partial open page with this index
+    Stored(usize),
+    /// The item didn't fit, so the page with this index has been made the new partial open page
+    Moved(usize),
+}
+
+/// Store the serialized item, starting at the given partial open page if it's known.
+/// Returns the index of the partial open page the item ended up in.
+#[allow(clippy::too_many_arguments)]
+fn store_item_at<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    item: &I,
+    reserve: usize,
+    buffer_pages: usize,
+    buffer: &mut [u8],
+    mut partial_open_page: Option<usize>,
+) -> Result<usize, MapError<I::Error, S::Error>> {
+    let flash = RefCell::new(flash);
+
+    // When the item has not been stored after moving through every page once, the storage is full
+    for _ in 0..max_store_page_moves::<S>(flash_range.clone()) {
+        match try_store_item::<I, S>(
+            &flash,
+            flash_range.clone(),
+            item,
+            reserve,
+            buffer_pages,
+            buffer,
+            partial_open_page,
+        )? {
+            StoreAttempt::Stored(page_index) => {
+                #[cfg(debug_assertions)]
+                debug_assert_buffer_page(*flash.borrow_mut(), flash_range);
+
+                return Ok(page_index);
+            }
+            StoreAttempt::Moved(page_index) => partial_open_page = Some(page_index),
+        }
+    }
+
+    return Err(MapError::FullStorage);
+
+    /// Store the item in the partial open page. If it doesn't fit with `reserve` bytes left after it, move to the next page.
+    /// The partial open page is searched for if it's not given.
+    #[allow(clippy::too_many_arguments)]
+    fn try_store_item<'a, 'b, I: StorageItem, S: NorFlash>(
+        flash: &'a RefCell<&'b mut S>,
+        flash_range: Range<u32>,
+        item: &I,
+        reserve: usize,
+        buffer_pages: usize,
+        buffer: &mut [u8],
+        partial_open_page: Option<usize>,
+    ) -> Result<StoreAttempt, MapError<I::Error, S::Error>>
+    where
+        'a: 'b,
+    {
+        let mut next_page_to_use = None;
+
+        let partial_open_page = match partial_open_page {
+            Some(partial_open_page) => Some(partial_open_page),
+            None => {
+                let mut flash = flash.borrow_mut(); // Out of band because of weird drop rules
+                find_partial_open_page(*flash, flash_range.clone())?
+            }
+        };
+
+        // If there is a partial open page, we try to write in that first if there is enough space
+        if let Some(partial_open_page) = partial_open_page {
+            trace!("Partial open page found: {}", partial_open_page);
+
+            // We've got to search where the free space is since the page starts with items present already
+
+            let page_data_end_address =
+                calculate_page_data_end_address::<S>(flash_range.clone(), partial_open_page);
+
+            let (last_start_address, open_batch) = find_records_end::<I, S>(
+                flash,
+                flash_range.clone(),
+                partial_open_page,
+                &mut *buffer,
+            )?;
+
+            let available_bytes_in_page = (page_data_end_address - last_start_address) as usize;
+
+            // The padding up to a whole word must be erased bytes
+            buffer.fill(0xFF);
+            // Nothing may follow a batch that was never committed, or it would count as part of the batch.
+            // So we act like the page is full.
+            let serialize_len = if open_batch {
+                0
+            } else {
+                buffer
+                    .len()
+                    .min(available_bytes_in_page.saturating_sub(reserve))
+            };
+            match item.serialize_into(&mut buffer[..serialize_len]) {
+                Ok(mut used_bytes) => {
+                    // We can only write in whole words, so we round up the used bytes so the math works
+                    if used_bytes % S::WRITE_SIZE > 0 {
+                        used_bytes += S::WRITE_SIZE - (used_bytes % S::WRITE_SIZE);
+                    }
+
+                    flash
+                        .borrow_mut()
+                        .write(last_start_address, &buffer[..used_bytes])
+                        .map_err(MapError::Storage)?;
+
+                    trace!("Item has been written ok");
+
+                    return Ok(StoreAttempt::Stored(partial_open_page));
+                }
+                Err(e) if e.is_buffer_too_small() => {
+                    trace!(
+                        "Partial open page is too small. Closing it now: {}",
+                        partial_open_page
+                    );
+
+                    // The item doesn't fit here, so we need to close this page and move to the next
+                    close_page(*flash.borrow_mut(), flash_range.clone(), partial_open_page)?;
+                    next_page_to_use = Some(next_page::<S>(flash_range.clone(), partial_open_page));
+                }
+                Err(e) => {
+                    return Err(MapError::Item(e));
+                }
+            }
+        }
+
+        // If we get here, there was no partial page found or the partial page has now been closed because the item didn't fit.
+        let next_page_to_use =
+            move_to_next_page::<I, S>(flash, flash_range, next_page_to_use, buffer_pages, buffer)?;
+
+        // We just freshly partially closed a new page, so the next attempt should succeed
+        Ok(StoreAttempt::Moved(next_page_to_use))
+    }
+}
+
+/// Move to the page after the partial open page that has just been closed and partially close it.
+///
+/// If there was a partial page, then we need to look at the next page. It's supposed to be open since it was the previous empty buffer page.
+/// The new buffer page has to be emptied if it was closed.
+/// If there was no partial page, we just use the first open page.
+///
+/// Returns the index of the new partial open page.
+fn move_to_next_page<'a, 'b, I: StorageItem, S: NorFlash>(
+    flash: &'a RefCell<&'b mut S>,
+    flash_range: Range<u32>,
+    mut next_page_to_use: Option<usize>,
+    buffer_pages: usize,
+    buffer: &mut [u8],
+) -> Result<usize, MapError<I::Error, S::Error>>
+where
+    'a: 'b,
+{
+    if next_page_to_use.is_none() {
+        // Without a partial open page, a previous store may have been interrupted right after it closed
+        // the last used page. The buffer page after it may then still have to be cleared.
+        // So we continue with the page after the last used page, like we'd just closed it.
+        if let Some(last_used_page) = find_last_used_page(*flash.borrow_mut(), flash_range.clone())?
+        {
+            next_page_to_use = Some(next_page::<S>(flash_range.clone(), last_used_page));
+        }
+    }
+
+    trace!("Next page to use: {:?}", next_page_to_use);
+
+    match next_page_to_use {
+        Some(next_page_to_use) => {
+            let next_page_state =
+                get_page_state(*flash.borrow_mut(), flash_range.clone(), next_page_to_use)?;
+
+            if !next_page_state.is_open() {
+                // What was the previous buffer page was not open...
+                return Err(MapError::Corrupted);
+            }
+
+            let next_buffer_page = next_page::<S>(flash_range.clone(), next_page_to_use);
+            let next_buffer_page_state =
+                get_page_state(*flash.borrow_mut(), flash_range.clone(), next_buffer_page)?;
+
+            if !next_buffer_page_state.is_open() {
+                // We need to move the data from the next buffer page to the next_page_to_use, but only if that data
+                // doesn't have a newer value somewhere else.
+
+                let mut next_page_write_address =
+                    calculate_page_data_start_address::<S>(flash_range.clone(), next_page_to_use);
+
+                // If we got interrupted while moving the items before, some of them have been moved already.
+                // They're moved in the same order every time, so we skip that many and continue after them.
+                let mut items_already_moved = 0;
+                for moved_item in read_page_records_in::<I, S, _>(
+                    flash,
+                    flash_range.clone(),
+                    next_page_to_use,
+                    &mut *buffer,
+                )? {
+                    let (_, item_address, item_size) = moved_item?;
+                    next_page_write_address = item_address + item_size as u32;
+                    items_already_moved += 1;
+                }
+
+                let mut old_records =
+                    PageRecords::new::<I, S>(flash_range.clone(), next_buffer_page, &mut *buffer);
+                loop {
+                    let old_item = old_records.next_item::<I, S>(*flash.borrow_mut());
+                    let Some(old_item) = old_item else {
+                        break;
+                    };
+                    let (old_item, old_item_address, _) = old_item?;
+
+                    // The lookup and the copy use the buffer too, the page is read again after them
+                    let buffer = old_records.lend_buffer();
+
+                    let old_item_key = old_item.key();
+                    let Some((_, newest_version_address, newest_version_len)) =
+                        find_newest_item_in::<I, S>(
+                            *flash.borrow_mut(),
+                            flash_range.clone(),
+                            CorruptRecordPolicy::Abort,
+                            |item: &I| item.key() == old_item_key,
+                            &mut *buffer,
+                        )?
+                    else {
+                        // What do you mean we can't find the item again?
+                        return Err(MapError::Corrupted);
+                    };
+
+                    // Only the newest version needs to be moved and it must only be moved once,
+                    // even if older versions of it are on the page too.
+                    // A tombstone is dropped: this is the oldest page, so all older values of its key are erased with it.
+                    if newest_version_address == old_item_address && !old_item.is_tombstone() {
+                        if items_already_moved > 0 {
+                            items_already_moved -= 1;
+                            continue;
+                        }
+
+                        // The newest version of this item is on the next buffer page, so we need to move it
+                        let copy_buffer = buffer
+                            .get_mut(..newest_version_len)
+                            .ok_or(MapError::BufferTooSmall)?;
+                        flash
+                            .borrow_mut()
+                            .read(newest_version_address, copy_buffer)
+                            .map_err(MapError::Storage)?;
+
+                        // We don't have to watch for the end of the page, because the data we're writing here
+                        // is equal or less than the page
+                        flash
+                            .borrow_mut()
+                            .write(next_page_write_address, copy_buffer)
+                            .map_err(MapError::Storage)?;
+
+                        next_page_write_address += newest_version_len as u32;
+                    }
+                }
+
+                flash
+                    .borrow_mut()
+                    .erase(
+                        calculate_page_address::<S>(flash_range.clone(), next_buffer_page),
+                        calculate_page_end_address::<S>(flash_range.clone(), next_buffer_page),
+                    )
+                    .map_err(MapError::Storage)?;
+            }
+
+            partial_close_map_page(*flash.borrow_mut(), flash_range.clone(), next_page_to_use)?;
+
+            open_extra_buffer_pages::<I, S>(
+                *flash.borrow_mut(),
+                flash_range,
+                next_page_to_use,
+                buffer_pages,
+                buffer,
+            )?;
+
+            Ok(next_page_to_use)
+        }
+        None => {
+            // No page has been used yet, so we start at the first page
+            partial_close_map_page(*flash.borrow_mut(), flash_range.clone(), 0)?;
+
+            Ok(0)
+        }
+    }
+}
+
+/// Open the buffer pages after the first one, oldest first, by copying the live items of their pages
+/// to the partial open page and erasing them.
+///
+/// This stops at the first page whose live items don't fit anymore. The next move to a new page continues.
+fn open_extra_buffer_pages<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    partial_open_page: usize,
+    buffer_pages: usize,
+    buffer: &mut [u8],
+) -> Result<(), MapError<I::Error, S::Error>> {
+    let mut page_index = next_page::<S>(flash_range.clone(), partial_open_page);
+
+    for _ in 1..buffer_pages {
+        page_index = next_page::<S>(flash_range.clone(), page_index);
+
+        if get_page_state(flash, flash_range.clone(), page_index)?.is_open() {
+            continue;
+        }
+
+        // The pages before it are open, so this is the oldest page with data
+        let live_bytes =
+            page_live_bytes::<I, S>(flash, flash_range.clone(), page_index, &mut *buffer)?;
+        if !recycle_oldest_page::<I, S>(
+            flash,
+            flash_range.clone(),
+            page_index,
+            live_bytes,
+            partial_open_page,
+            &mut *buffer,
+        )? {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Find the address where the records of the page end, and whether they end in a batch that was never committed.
+/// A damaged record still takes up its space, so it counts too.
+#[allow(clippy::type_complexity)]
+fn find_records_end<I: StorageItem, S: NorFlash>(
+    flash: &RefCell<&mut S>,
+    flash_range: Range<u32>,
+    page_index: usize,
+    read_buffer: &mut [u8],
+) -> Result<(u32, bool), MapError<I::Error, S::Error>> {
+    let mut records_end = calculate_page_data_start_address::<S>(flash_range.clone(), page_index);
+    let mut open_batch = false;
+
+    for record in read_page_records_in::<I, S, _>(flash, flash_range, page_index, read_buffer)? {
+        let (item, record_address, record_len) = record?;
+        records_end = record_address + record_len as u32;
+
+        match item.and_then(|item| item.batch_marker()) {
+            Some(BatchMarker::Begin) => open_batch = true,
+            Some(BatchMarker::Commit) => open_batch = false,
+            None => {}
+        }
+    }
+
+    Ok((records_end, open_batch))
+}
+
+/// A map in a flash range that remembers which page is the partial open page between calls.
+///
+/// [fetch_item] and [store_item] have to search the pages for the partial open page on every call.
+/// [Map::fetch_item] and [Map::store_item] give the same results, but only search for it on the first call
+/// and after an error. A store that moves to a new page updates it.
+///
+/// The map borrows the flash for as long as it lives, so nothing else can change the flash behind its back.
+///
+/// ```rust,ignore
+/// let mut map = Map::new(&mut flash, flash_range.clone());
+///
+/// for item in items {
+///     map.store_item(item)?;
+/// }
+///
+/// let value = map.fetch_item::<MyCustomType>(key)?;
+/// ```
+pub struct Map<'a, S: NorFlash> {
+    flash: &'a mut S,
+    flash_range: Range<u32>,
+    partial_open_page: Option<usize>,
+}
+
+impl<'a, S: NorFlash> Map<'a, S> {
+    /// Create the map for the flash range. Nothing is read from flash until the first call.
+    pub fn new(flash: &'a mut S, flash_range: Range<u32>) -> Self {
+        Self {
+            flash,
+            flash_range,
+            partial_open_page: None,
+        }
+    }
+
+    /// Get a storage item from the flash. See [fetch_item].
+    pub fn fetch_item<I: StorageItem>(
+        &mut self,
+        search_key: I::Key,
+    ) -> Result<Option<I>, MapError<I::Error, S::Error>> {
+        check_geometry::<S>(self.flash_range.clone())?;
+
+        let mut page_walker = match self.partial_open_page()? {
+            // The partial open page is always the last used page
+            Some(partial_open_page) => PageWalker::with_last_used_page(
+                self.flash,
+                self.flash_range.clone(),
+                Some(partial_open_page),
+            ),
+            None => PageWalker::new(self.flash, self.flash_range.clone())?,
+        };
+
+        let newest_item = with_item_buffer::<I, S, _>(|buffer| {
+            find_newest_item_with_walker(
+                &mut page_walker,
+                CorruptRecordPolicy::Abort,
+                |item: &I| item.key() == search_key,
+                buffer,
+            )
+        })?;
+
+        Ok(newest_item
+            .filter(|(item, _, _)| !item.is_tombstone())
+            .map(|(item, _, _)| item))
+    }
+
+    /// Store an item into flash memory. See [store_item].
+    pub fn store_item<I: StorageItem>(
+        &mut self,
+        item: I,
+    ) -> Result<(), MapError<I::Error, S::Error>> {
+        check_geometry::<S>(self.flash_range.clone())?;
+
+        let partial_open_page = self.partial_open_page()?;
+
+        with_item_buffer::<I, S, _>(|buffer| {
+            let buffer = check_store_buffer::<I, S>(self.flash_range.clone(), &item, buffer)?;
+
+            // After an error we don't know what state the pages are in, so we search again the next time
+            self.partial_open_page = None;
+            self.partial_open_page = Some(store_item_at(
+                self.flash,
+                self.flash_range.clone(),
+                &item,
+                0,
+                1,
+                buffer,
+                partial_open_page,
+            )?);
+
+            Ok(())
+        })
+    }
+
+    /// The cached partial open page, or search for it if it's not known
+    fn partial_open_page(&mut self) -> Result<Option<usize>, Error<S::Error>> {
+        if self.partial_open_page.is_none() {
+            self.partial_open_page = find_partial_open_page(self.flash, self.flash_range.clone())?;
+        }
+
+        Ok(self.partial_open_page)
+    }
+}
+
+/// Check that after a store there's exactly one partial open page and that the page after it is open,
+/// so the next store has a buffer page to work with. Panics if it's not.
+///
+/// This is only compiled in with debug assertions. A flash error skips the check.
+#[cfg(debug_assertions)]
+fn debug_assert_buffer_page<S: NorFlash>(flash: &mut S, flash_range: Range<u32>) {
+    let mut partial_open_page = None;
+
+    for page_index in get_pages::<S>(flash_range.clone(), 0) {
+        let Ok(page_state) = get_page_state(flash, flash_range.clone(), page_index) else {
+            return;
+        };
+
+        if page_state.is_partial_open() {
+            assert!(
+                partial_open_page.is_none(),
+                "Pages {} and {page_index} are both partial open after a store",
+                partial_open_page.unwrap_or_default()
+            );
+            partial_open_page = Some(page_index);
+        }
+    }
+
+    let partial_open_page = partial_open_page.expect("There's no partial open page after a store");
+    let buffer_page = next_page::<S>(flash_range.clone(), partial_open_page);
+
+    if let Ok(buffer_page_state) = get_page_state(flash, flash_range, buffer_page) {
+        assert!(
+            buffer_page_state.is_open(),
+            "The buffer page {buffer_page} after the partial open page {partial_open_page} is {buffer_page_state:?} after a store"
+        );
+    }
+}
+
+/// Store an item into flash memory, but only if its serialized size is within the limit for its key.
+///
+/// The `max_len` callback gets the key of the item and returns the maximum serialized size in bytes for it.
+/// If the item is bigger, [MapError::ItemTooBig] is returned and nothing is written.
+/// This protects against a bug that stores a huge value which then pushes out a lot of small values.
+///
+/// The size is checked while the item is serialized to check that it fits in a page, which a store does anyway.
+/// So this doesn't serialize the item more often than [store_item].
+pub fn store_item_with_size_limit<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    item: I,
+    max_len: impl FnOnce(&I::Key) -> usize,
+) -> Result<(), MapError<I::Error, S::Error>> {
+    check_geometry::<S>(flash_range.clone())?;
+
+    let max_len = max_len(&item.key());
+
+    with_item_buffer::<I, S, _>(|buffer| {
+        let buffer =
+            check_store_buffer_with_limit::<I, S>(flash_range.clone(), &item, buffer, max_len)?;
+
+        store_item_at(flash, flash_range, &item, 0, 1, buffer, None).map(|_| ())
+    })
+}
+
+/// The maximum amount of times [store_item] closes the partial open page and moves to the next one for the given flash range.
+///
+/// When the item has not been stored after moving through every page once, [MapError::FullStorage] is returned. So this equals the amount of pages in the range.
+/// The moves happen in a loop, not by recursion, so they don't affect the stack usage.
+///
+/// This is a `const fn`, so it can be used at compile time, e.g. to bound the work of a store:
+///
+/// ```rust,ignore
+/// const MOVES: usize = max_store_page_moves::<SomeFlashChip>(0x1000..0x2000);
+/// ```
+pub const fn max_store_page_moves<S: NorFlash>(flash_range: Range<u32>) -> usize {
+    (flash_range.end - flash_range.start) as usize / S::ERASE_SIZE
+}
+
+/// Update the value of a key with a closure.
+///
+/// The current value is fetched and passed to `f`, which gets None if the key has no value yet.
+/// If `f` returns an item, it's stored. If it returns None, nothing is stored and the current value stays.
+/// To delete the key when `f` returns None, use [update_or_remove] with a [RemovableStorageItem] instead.
+///
+/// Returns true if an item has been stored.
+///
+/// The partial open page is looked up once and used for both the fetch and the store, like [Map] does.
+/// This is a fetch followed by a store, so it's not atomic.
+/// Make sure nothing else stores to the map in between, e.g. by holding the lock of the flash.
+pub fn update<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    key: I::Key,
+    f: impl FnOnce(Option<I>) -> Option<I>,
+) -> Result<bool, MapError<I::Error, S::Error>> {
+    let mut map = Map::new(flash, flash_range);
+    let current = map.fetch_item::<I>(key)?;
+
+    match f(current) {
+        Some(new) => {
+            map.store_item(new)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Update the value of a key with a closure, or remove the key when the closure returns None.
+///
+/// This works like [update], except that a key that has a value is removed like [remove_item] does when `f` returns None.
+/// If the key had no value, nothing is stored.
+///
+/// Returns true if an item or a tombstone has been stored.
+pub fn update_or_remove<I: RemovableStorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    key: I::Key,
+    f: impl FnOnce(Option<I>) -> Option<I>,
+) -> Result<bool, MapError<I::Error, S::Error>> {
+    let mut map = Map::new(flash, flash_range);
+    let current = map.fetch_item::<I>(key)?;
+    // The key isn't Clone, so we take the key of the current value to remove it
+    let current_key = current.as_ref().map(|item| item.key());
+
+    match (f(current), current_key) {
+        (Some(new), _) => map.store_item(new)?,
+        (None, Some(key)) => {
+            let tombstone = I::tombstone(key);
+            debug_assert!(
+                tombstone.is_tombstone(),
+                "The tombstone of the item must be recognized by `StorageItem::is_tombstone`"
+            );
+
+            map.store_item(tombstone)?;
+        }
+        (None, None) => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+/// Store an item into flash memory, but only if its key has no value yet.
+///
+/// Returns true if the item has been stored. This is useful for e.g. setting factory defaults
+/// without overwriting the changes a user made later.
+///
+/// This is a fetch followed by a store, so it's not atomic.
+/// If something else stores the same key in between, that value gets overwritten.
+pub fn store_item_if_absent<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    item: I,
+) -> Result<bool, MapError<I::Error, S::Error>> {
+    with_item_buffer::<I, S, _>(|buffer| {
+        if fetch_item_with_location_in::<I, S>(flash, flash_range.clone(), item.key(), buffer)?
+            .is_some()
+        {
+            return Ok(false);
+        }
+
+        store_item_with_buffer(flash, flash_range, item, buffer)?;
+
+        Ok(true)
+    })
+}
+
+/// Store an item into flash memory, but only if it differs from the value its key already has.
+///
+/// The item is serialized and compared with the bytes of the newest stored record of its key.
+/// If they're the same, nothing is written and false is returned. Otherwise the item is stored and true is returned.
+/// This avoids wearing out the flash when e.g. a whole config is stored periodically while little of it changes.
+///
+/// The records are compared by their length in whole `WRITE_SIZE` words and their serialized bytes,
+/// so the padding after an item doesn't count as a difference.
+///
+/// This is a fetch followed by a store, so it's not atomic.
+pub fn store_item_if_changed<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    item: I,
+) -> Result<bool, MapError<I::Error, S::Error>> {
+    with_item_buffer::<I, S, _>(|buffer| {
+        if let Some((_, address, stored_len)) =
+            fetch_item_with_location_in::<I, S>(flash, flash_range.clone(), item.key(), buffer)?
+        {
+            let used_bytes = item.serialize_into(buffer).map_err(MapError::Item)?;
+
+            // The stored length is in whole words, so the reads stay within the record
+            if used_bytes.next_multiple_of(S::WRITE_SIZE) == stored_len
+                && flash_equals(flash, address, &buffer[..used_bytes])?
+            {
+                return Ok(false);
+            }
+        }
+
+        store_item_with_buffer(flash, flash_range, item, buffer)?;
+
+        Ok(true)
+    })
+}
+
+/// Store an item into flash memory and report whether its key already had a value.
+///
+/// This works the same as [store_item], but it first searches for the key like [fetch_item] does.
+/// Because of that extra scan, only use this function when you need the [StoreOutcome].
+pub fn store_item_with_outcome<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    item: I,
+) -> Result<StoreOutcome, MapError<I::Error, S::Error>> {
+    with_item_buffer::<I, S, _>(|buffer| {
+        let outcome = match fetch_item_with_location_in::<I, S>(
+            flash,
+            flash_range.clone(),
+            item.key(),
+            buffer,
+        )? {
+            Some(_) => StoreOutcome::Updated,
+            None => StoreOutcome::Inserted,
+        };
+
+        store_item_with_buffer(flash, flash_range, item, buffer)?;
+
+        Ok(outcome)
+    })
+}
+
+/// Store a batch of items so that either all of them or none of them are stored, even if the power is lost halfway.
+/// Use it for items that only make sense together, e.g. a pointer and the item it points to.
+///
+/// The items are stored between two marker records, see [BatchStorageItem]:
+///
+/// ```text
+/// [Begin] [item 0] [item 1] ... [item n] [Commit]
+/// ```
+///
+/// The commit marker is stored last. When the map reads a page and finds a begin marker, it first looks ahead for the commit marker.
+/// If the records of the page end or another begin marker comes first, the batch was interrupted
+/// and its items are skipped like damaged records. Nothing is ever stored after an interrupted batch, the next store
+/// moves to the next page instead. Once committed, the items are normal items: when their page is recycled,
+/// the newest versions are copied forward one by one without the markers.
+///
+/// The whole batch is stored in a single page, so it has to fit in an empty page next to the page markers.
+/// A batch that doesn't returns [MapError::InvalidFlashGeometry] before the flash is touched.
+/// An empty batch stores nothing.
+///
+/// The iterator is cloned, because the items are serialized once to measure the batch before any of them are stored.
+/// Finding a batch costs reading it twice, so don't make every store a batch.
+pub fn store_items<I: BatchStorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    items: impl Iterator<Item = I> + Clone,
+) -> Result<(), MapError<I::Error, S::Error>> {
+    check_geometry::<S>(flash_range.clone())?;
+
+    let begin = I::new_batch_marker(BatchMarker::Begin);
+    let commit = I::new_batch_marker(BatchMarker::Commit);
+    debug_assert_eq!(
+        (begin.batch_marker(), commit.batch_marker()),
+        (Some(BatchMarker::Begin), Some(BatchMarker::Commit)),
+        "The batch markers must be recognized by `StorageItem::batch_marker`"
+    );
+
+    with_item_buffer::<I, S, _>(|buffer| {
+        let begin_len = batch_record_len::<I, S>(flash_range.clone(), &begin, buffer)?;
+        let mut batch_len =
+            begin_len + batch_record_len::<I, S>(flash_range.clone(), &commit, buffer)?;
+        let mut item_count = 0;
+        for item in items.clone() {
+            batch_len += batch_record_len::<I, S>(flash_range.clone(), &item, buffer)?;
+            item_count += 1;
+        }
+
+        if item_count == 0 {
+            return Ok(());
+        }
+
+        if batch_len > usable_page_size::<S>(flash_range.clone()) {
+            return Err(MapError::InvalidFlashGeometry);
+        }
+
+        let buffer = check_store_buffer::<I, S>(flash_range.clone(), &begin, buffer)?;
+
+        // The rest of the batch is kept free after the begin marker, so it's stored in the same page
+        let partial_open_page = store_item_at(
+            flash,
+            flash_range.clone(),
+            &begin,
+            batch_len - begin_len,
+            1,
+            &mut *buffer,
+            None,
+        )?;
+
+        let flash = RefCell::new(flash);
+        let (mut write_address, _) =
+            find_records_end::<I, S>(&flash, flash_range, partial_open_page, &mut *buffer)?;
+
+        for item in items.chain(core::iter::once(commit)) {
+            // The padding up to a whole word must be erased bytes
+            buffer.fill(0xFF);
+            let used_bytes = item
+                .serialize_into(buffer)
+                .map_err(MapError::Item)?
+                .next_multiple_of(S::WRITE_SIZE);
+
+            flash
+                .borrow_mut()
+                .write(write_address, &buffer[..used_bytes])
+                .map_err(MapError::Storage)?;
+            write_address += used_bytes as u32;
+        }
+
+        return Ok(());
+
+        /// Serialize the item like a store does and return the length of its record
+        fn batch_record_len<I: StorageItem, S: NorFlash>(
+            flash_range: Range<u32>,
+            item: &I,
+            buffer: &mut [u8],
+        ) -> Result<usize, MapError<I::Error, S::Error>> {
+            let buffer = check_store_buffer::<I, S>(flash_range, item, buffer)?;
+            let used_bytes = item.serialize_into(buffer).map_err(MapError::Item)?;
+            Ok(used_bytes.next_multiple_of(S::WRITE_SIZE))
+        }
+    })
+}
+
+/// Remove the value of a key from the map.
+///
+/// This stores a tombstone for the key, after which fetching the key returns None.
+/// The space of the old values and the tombstone itself is reclaimed once their page is recycled:
+/// a tombstone is never copied forward, because all older values of its key are erased together with it.
+///
+/// Returns true if the key had a value. If it didn't, nothing is stored.
+///
+/// Functions that list records instead of values, like [iter_by_recency] and [fetch_newest_item],
+/// return the tombstone like any other item.
+pub fn remove_item<I: RemovableStorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    key: I::Key,
+) -> Result<bool, MapError<I::Error, S::Error>> {
+    with_item_buffer::<I, S, _>(|buffer| {
+        let Some((item, _, _)) =
+            fetch_item_with_location_in::<I, S>(flash, flash_range.clone(), key, buffer)?
+        else {
+            return Ok(false);
+        };
+
+        let tombstone = I::tombstone(item.key());
+        debug_assert!(
+            tombstone.is_tombstone(),
+            "The tombstone of the item must be recognized by `StorageItem::is_tombstone`"
+        );
+
+        store_item_with_buffer(flash, flash_range, tombstone, buffer)?;
+
+        Ok(true)
+    })
+}
+
+/// Remove the value of a key from the map by marking its records in place, instead of storing a tombstone.
+///
+/// This is only possible on flash that allows a word to be written more than once between erases.
+/// Every stored version of the key is marked with [InPlaceRemovableStorageItem::mark_removed], so no older value can show up.
+/// The versions are marked from the oldest to the newest, so if this gets interrupted, the key keeps its value.
+/// Nothing is appended to the map, so this never moves to a new page or erases one.
+///
+/// Returns true if the key had a value. If it didn't, nothing is written.
+/// Returns [MapError::Corrupted] if a marked record still reads back as an item, e.g. because the flash didn't take the write.
+/// If [InPlaceRemovableStorageItem::mark_removed] makes a record that the map doesn't skip, that record isn't written
+/// and [MapError::Corrupted] is returned too.
+pub fn remove_item_in_place<I: InPlaceRemovableStorageItem, S: MultiwriteNorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    key: I::Key,
+) -> Result<bool, MapError<I::Error, S::Error>> {
+    with_item_buffer::<I, S, _>(|buffer| {
+        let Some((item, _, _)) =
+            fetch_item_with_location_in::<I, S>(flash, flash_range.clone(), key, buffer)?
+        else {
+            return Ok(false);
+        };
+
+        let is_skipped = |record: &[u8]| matches!(I::deserialize_from(record), Err(e) if e.damaged_record_len().is_some());
+
+        // Every record takes at least a word, so there can't be more versions than words
+        for _ in 0..flash_range.len() / S::WRITE_SIZE {
+            let Some((address, len)) =
+                find_oldest_version::<I, S>(flash, flash_range.clone(), item.key(), buffer)?
+            else {
+                return Ok(true);
+            };
+
+            let record = &mut buffer[..len];
+            flash.read(address, record).map_err(MapError::Storage)?;
+
+            I::mark_removed(record);
+            if !is_skipped(record) {
+                // Writing it would change the value instead of removing it
+                error!(
+                    "The record at {:#X} marked with `InPlaceRemovableStorageItem::mark_removed` is not skipped by the map",
+                    address
+                );
+                return Err(MapError::Corrupted);
+            }
+
+            flash.write(address, record).map_err(MapError::Storage)?;
+
+            // If the flash didn't take the write, the same record would be found again forever
+            flash.read(address, record).map_err(MapError::Storage)?;
+            if !is_skipped(record) {
+                error!(
+                    "The removed record at {:#X} still reads back as an item",
+                    address
+                );
+                return Err(MapError::Corrupted);
+            }
+        }
+
+        Err(MapError::Corrupted)
+    })
+}
+
+/// Find the address and length of the oldest record of the key, tombstones included.
+/// The pages are read with the given buffer.
+#[allow(clippy::type_complexity)]
+fn find_oldest_version<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    search_key: I::Key,
+    read_buffer: &mut [u8],
+) -> Result<Option<(u32, usize)>, MapError<I::Error, S::Error>> {
+    let mut page_walker = PageWalker::new(flash, flash_range.clone())?;
+    let mut oldest_version = None;
+
+    // The pages are walked from the newest to the oldest, so the last page with the key has the oldest version
+    while let Some(page_index) = page_walker.next() {
+        for found_item in read_page_items_in::<I, S, _>(
+            &page_walker.flash,
+            flash_range.clone(),
+            page_index?,
+            &mut *read_buffer,
+        )? {
+            let (found_item, address, len) = found_item?;
+
+            if found_item.key() == search_key {
+                oldest_version = Some((address, len));
+                break;
+            }
+        }
+    }
+
+    Ok(oldest_version)
+}
+
+/// Erase the whole flash range so the map starts out empty again.
+///
+/// **All stored items are lost.**
+///
+/// The map never erases anything on its own when it detects corruption.
+/// It returns [MapError::Corrupted] and leaves the flash untouched so you can decide what to do,
+/// e.g. alert someone or try to read out the data first.
+/// Only call this function if losing all data is acceptable.
+///
+/// This erases the range with [clear], so it checks the flash geometry the same way.
+pub fn recover_by_erase<S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+) -> Result<(), Error<S::Error>> {
+    clear(flash, flash_range)
+}
+
+/// Remove all items from the map by erasing every page of the flash range, e.g. for a factory reset.
+///
+/// **All stored items are lost.** Afterwards the map is empty, the same as a map that has never been used.
+///
+/// This checks the flash geometry like the other functions do, and erases the pages one by one
+/// so it also works with flash that can only erase one page at a time.
+pub fn clear<S: NorFlash>(flash: &mut S, flash_range: Range<u32>) -> Result<(), Error<S::Error>> {
+    check_geometry::<S>(flash_range.clone())?;
+
+    for page_index in get_pages::<S>(flash_range.clone(), 0) {
+        flash
+            .erase(
+                calculate_page_address::<S>(flash_range.clone(), page_index),
+                calculate_page_end_address::<S>(flash_range.clone(), page_index),
+            )
+            .map_err(Error::Storage)?;
+    }
+
+    Ok(())
+}
+
+/// Check that the flash range can be used by the map without panicking or running into corruption right away.
+///
+/// This is meant to be called once at startup before the range is trusted, e.g. by a bootloader.
+/// It only reads the flash and never writes or erases anything.
+///
+/// The checks are:
+/// - The geometry checks that the other functions assert on. These return [MapError::InvalidFlashGeometry] here.
+/// - There is exactly one or no partial open page and there is at least one open page.
+/// - The page after the partial open page is open, so it can serve as the buffer page.
+/// - All used pages were written with the current geometry, or [MapError::GeometryMismatch] is returned.
+///
+/// Otherwise [MapError::Corrupted] is returned. The items themselves are not deserialized.
+pub fn preflight<S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+) -> Result<(), MapError<Infallible, S::Error>> {
+    validate_geometry::<S>(flash_range.clone())?;
+
+    let mut partial_open_page = None;
+
+    for page_index in get_pages::<S>(flash_range.clone(), 0) {
+        let page_state = get_page_state(flash, flash_range.clone(), page_index)?;
+
+        if page_state.is_partial_open() {
+            if partial_open_page.is_some() {
+                error!("Found more than one partial open page");
+                return Err(MapError::Corrupted);
+            }
+
+            partial_open_page = Some(page_index);
+        }
+
+        if !page_state.is_open() {
+            check_geometry_stamp(flash, flash_range.clone(), page_index)?;
+        }
+    }
+
+    // This errors when there are no open pages
+    find_last_used_page(flash, flash_range.clone())?;
+
+    if let Some(partial_open_page) = partial_open_page {
+        let buffer_page = next_page::<S>(flash_range.clone(), partial_open_page);
+
+        if !get_page_state(flash, flash_range, buffer_page)?.is_open() {
+            error!("The page after the partial open page is not open");
+            return Err(MapError::Corrupted);
+        }
+    }
+
+    Ok(())
+}
+
+/// Bring the map into a state where no store operation is halfway done, e.g. before the flash is powered down for deep sleep.
+///
+/// If a store was interrupted right after it closed the last used page, there's no partial open page.
+/// This then finishes moving to the next page like the next store would: the page after the closed page is opened
+/// and the live items of the new buffer page are copied into it before that page is erased.
+/// That's why the item type is needed.
+///
+/// Otherwise this checks that the page after the partial open page is open, like [preflight] does,
+/// and that the partial open page was written with the current geometry.
+///
+/// When this returns `Ok`, the next [store_item] starts from a consistent state and a power loss before it
+/// can't leave the map in a state it has to repair. It doesn't protect against a power loss during later writes.
+///
+/// The partial open page is deliberately not closed. The map always keeps one partial open page and one open page after it.
+/// Closing it early would only waste the rest of the page, because the next store would open a new page right away.
+///
+/// This is idempotent. It only writes when a store was interrupted, otherwise it only reads the page markers.
+pub fn quiesce<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+) -> Result<(), MapError<I::Error, S::Error>> {
+    validate_item_geometry::<I, S>(flash_range.clone())?;
+
+    let Some(partial_open_page) = find_partial_open_page(flash, flash_range.clone())? else {
+        if find_last_used_page(flash, flash_range.clone())?.is_some() {
+            // Without a page to use, the last used page is picked up from the flash
+            with_item_buffer::<I, S, _>(|buffer| {
+                move_to_next_page::<I, S>(&RefCell::new(flash), flash_range, None, 1, buffer)
+            })?;
+        }
+
+        return Ok(());
+    };
+
+    let buffer_page = next_page::<S>(flash_range.clone(), partial_open_page);
+    if !get_page_state(flash, flash_range, buffer_page)?.is_open() {
+        error!("The page after the partial open page is not open");
+        return Err(MapError::Corrupted);
+    }
+
+    Ok(())
+}
+
+/// Try to bring a corrupted map back into a consistent state, losing as little data as possible.
+///
+/// The map trusts the page markers to know which pages are in use, so a damaged marker makes it return [MapError::Corrupted]
+/// or even miss items. This re-derives the state of every page from its contents instead:
+/// a page with any data on it is in use and a page that is fully erased is open.
+/// The biggest group of open pages in the ring is taken as the open pages, so the page before it is the last used page.
+///
+/// - The pages in use are marked closed, except for the last used page which is left or marked partial open.
+/// - Erased pages in the group of open pages that still have a marker are erased again.
+///   Other erased pages are marked closed, so they're read as empty pages.
+/// - If every page has data, there's no room for the buffer page. The page with the most free space at its end
+///   is taken as the last used page and the page after it, the oldest one, is erased. Its items are lost.
+///
+/// The markers are written over the damaged ones, so this needs a flash that allows a word to be written again.
+///
+/// If the markers agree with the contents of the pages, nothing is written.
+/// Returns the amount of pages with data that had to be erased, so 0 means no items were lost.
+/// Use [recover_by_erase] if the map is still corrupted after this.
+pub fn try_repair<S: MultiwriteNorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+) -> Result<usize, MapError<Infallible, S::Error>> {
+    validate_geometry::<S>(flash_range.clone())?;
+
+    let page_count = flash_range.len() / S::ERASE_SIZE;
+    let is_used = |flash: &mut S, page_index| -> Result<bool, Error<S::Error>> {
+        Ok(page_data_used_len(flash, flash_range.clone(), page_index)? > 0)
+    };
+
+    let consistent = match preflight(flash, flash_range.clone()) {
+        Ok(()) => true,
+        Err(MapError::Corrupted) => false,
+        Err(e) => return Err(e),
+    };
+
+    if consistent {
+        // An open page can only have data if a store got interrupted while moving items to it,
+        // which is only ever the page after the last used page
+        let interrupted_page = find_last_used_page(flash, flash_range.clone())?
+            .map(|last_used_page| next_page::<S>(flash_range.clone(), last_used_page));
+
+        let mut markers_agree = true;
+        for page_index in get_pages::<S>(flash_range.clone(), 0) {
+            if Some(page_index) != interrupted_page
+                && get_page_state(flash, flash_range.clone(), page_index)?.is_open()
+                && is_used(flash, page_index)?
+            {
+                markers_agree = false;
+                break;
+            }
+        }
+
+        if markers_agree {
+            return Ok(0);
+        }
+    }
+
+    warn!("Repairing the page markers of the map");
+
+    // Find the biggest group of pages without data
+    let mut open_pages: Option<(usize, usize)> = None;
+    let mut any_used = false;
+    for page_index in get_pages::<S>(flash_range.clone(), 0) {
+        if is_used(flash, page_index)? {
+            any_used = true;
+            continue;
+        }
+
+        if !is_used(flash, previous_page::<S>(flash_range.clone(), page_index))? {
+            // Not the start of a group
+            continue;
+        }
+
+        let mut len = 0;
+        let mut current_page = page_index;
+        while len < page_count && !is_used(flash, current_page)? {
+            len += 1;
+            current_page = next_page::<S>(flash_range.clone(), current_page);
+        }
+
+        if open_pages.is_none_or(|(_, biggest_len)| len > biggest_len) {
+            open_pages = Some((page_index, len));
+        }
+    }
+
+    let mut erased_pages = 0;
+
+    let (first_open_page, open_page_count) = match open_pages {
+        Some(open_pages) => open_pages,
+        // Nothing has been stored, so all pages must be open
+        None if !any_used => (0, page_count),
+        None => {
+            let mut last_used_page = 0;
+            let mut most_free_bytes = 0;
+            for page_index in get_pages::<S>(flash_range.clone(), 0) {
+                let page_data_size =
+                    (calculate_page_data_end_address::<S>(flash_range.clone(), page_index)
+                        - calculate_page_data_start_address::<S>(flash_range.clone(), page_index))
+                        as usize;
+                let free_bytes =
+                    page_data_size - page_data_used_len(flash, flash_range.clone(), page_index)?;
+
+                if free_bytes > most_free_bytes {
+                    last_used_page = page_index;
+                    most_free_bytes = free_bytes;
+                }
+            }
+
+            let oldest_page = next_page::<S>(flash_range.clone(), last_used_page);
+
+            error!(
+                "Erasing page {} to make room for the buffer page",
+                oldest_page
+            );
+
+            flash
+                .erase(
+                    calculate_page_address::<S>(flash_range.clone(), oldest_page),
+                    calculate_page_end_address::<S>(flash_range.clone(), oldest_page),
+                )
+                .map_err(MapError::Storage)?;
+            erased_pages += 1;
+
+            (oldest_page, 1)
+        }
+    };
+
+    let last_used_page = previous_page::<S>(flash_range.clone(), first_open_page);
+
+    for page_index in get_pages::<S>(flash_range.clone(), 0) {
+        let is_open_page =
+            (page_index + page_count - first_open_page) % page_count < open_page_count;
+        let page_state = get_page_state(flash, flash_range.clone(), page_index)?;
+
+        if is_open_page {
+            if !page_state.is_open() {
+                // There's no data on it, so only the markers are erased
+                flash
+                    .erase(
+                        calculate_page_address::<S>(flash_range.clone(), page_index),
+                        calculate_page_end_address::<S>(flash_range.clone(), page_index),
+                    )
+                    .map_err(MapError::Storage)?;
+            }
+        } else if page_index == last_used_page && is_used(flash, page_index)? {
+            partial_close_map_page(flash, flash_range.clone(), page_index)?;
+        } else {
+            close_page(flash, flash_range.clone(), page_index)?;
+        }
+    }
+
+    Ok(erased_pages)
+}
+
+/// The amount of bytes from the start of the data of a page up to and including the last byte that isn't erased.
+fn page_data_used_len<S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    page_index: usize,
+) -> Result<usize, Error<S::Error>> {
+    let page_data_start_address =
+        calculate_page_data_start_address::<S>(flash_range.clone(), page_index);
+    let page_data_end_address = calculate_page_data_end_address::<S>(flash_range, page_index);
+
+    let mut used_len = 0;
+    let mut buffer = [0xFF; MAX_FLASH_WRITE_SIZE];
+    for address in (page_data_start_address..page_data_end_address).step_by(buffer.len()) {
+        let len = buffer.len().min((page_data_end_address - address) as usize);
+        flash
+            .read(address, &mut buffer[..len])
+            .map_err(Error::Storage)?;
+
+        if let Some(last_used_byte) = buffer[..len].iter().rposition(|byte| *byte != 0xFF) {
+            used_len = (address - page_data_start_address) as usize + last_used_byte + 1;
+        }
+    }
+
+    Ok(used_len)
+}
+
+/// Get the amount of pages in every state and how many bytes are used and free, e.g. to decide whether to store more telemetry.
+///
+/// The used bytes of the partial open page are found from the word-aligned framing of the records:
+/// everything up to the last word that isn't erased is used. So no item type is needed.
+/// If the last item ends in whole words of `0xFF`, those words can't be told apart from erased space and count as free.
+/// A closed page counts as fully used, even though the end of it may have been too small for the next item.
+///
+/// The bytes that are used include old values and removed keys. They're only freed when their page is recycled.
+/// So the used bytes say how much has been written, not how much live data there is.
+///
+/// The buffer page after the partial open page is always kept open, so moving into the page after it doesn't erase anything.
+/// Its bytes are neither used nor free. So once the free bytes run out, the next store that moves to a new page erases one.
+pub fn storage_stats<S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+) -> Result<StorageStats, Error<S::Error>> {
+    check_geometry::<S>(flash_range.clone())?;
+
+    // The first store makes the first page the partial open page
+    let buffer_page = match find_last_used_page(flash, flash_range.clone())? {
+        Some(last_used_page) => {
+            let next_page_to_use = next_page::<S>(flash_range.clone(), last_used_page);
+
+            if get_page_state(flash, flash_range.clone(), last_used_page)?.is_partial_open() {
+                next_page_to_use
+            } else {
+                // A store was interrupted after closing the last used page, the next store moves on first
+                next_page::<S>(flash_range.clone(), next_page_to_use)
+            }
+        }
+        None => next_page::<S>(flash_range.clone(), 0),
+    };
+
+    let mut stats = StorageStats::default();
+
+    for page_index in get_pages::<S>(flash_range.clone(), 0) {
+        let page_data_size = (calculate_page_data_end_address::<S>(flash_range.clone(), page_index)
+            - calculate_page_data_start_address::<S>(flash_range.clone(), page_index))
+            as usize;
+
+        match get_page_state(flash, flash_range.clone(), page_index)? {
+            PageState::Open => {
+                stats.open_pages += 1;
+
+                if page_index != buffer_page {
+                    stats.free_bytes += page_data_size;
+                }
+            }
+            PageState::Closed => {
+                stats.closed_pages += 1;
+                stats.used_bytes += page_data_size;
+            }
+            PageState::PartialOpen => {
+                stats.partial_open_pages += 1;
+
+                // The records are written in whole words
+                let used_bytes = page_data_used_len(flash, flash_range.clone(), page_index)?
+                    .next_multiple_of(S::WRITE_SIZE)
+                    .min(page_data_size);
+
+                stats.used_bytes += used_bytes;
+                stats.free_bytes += page_data_size - used_bytes;
+            }
+        }
+    }
+
+    Ok(stats)
+}
+
+/// How full the map is. See [storage_stats].
+///
+/// The bytes only count the space for items, so not the page markers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StorageStats {
+    /// The amount of open pages, including the buffer page the map always keeps open
+    pub open_pages: usize,
+    /// The amount of partial open pages, which is 1 once something has been stored
+    pub partial_open_pages: usize,
+    /// The amount of closed pages
+    pub closed_pages: usize,
+    /// The amount of bytes that have been written
+    pub used_bytes: usize,
+    /// The amount of bytes that can still be written without erasing a page.
+    /// This doesn't include the buffer page.
+    pub free_bytes: usize,
+}
+
+/// The page that gets erased the next time the map moves to a new page. See [next_buffer_page].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NextBufferPage {
+    /// The index of the page
+    pub page_index: usize,
+    /// True if the page holds data and has to be erased. False if it's open already.
+    pub needs_erase: bool,
+    /// The amount of bytes of the items on the page that are still the newest version of their key.
+    /// These have to be moved before the page can be erased.
+    pub live_bytes: usize,
+}
+
+/// Find the page that gets erased the next time the map moves to a new page.
+///
+/// When the partial open page is full, the open page after it is used next.
+/// The page after that one then becomes the new buffer page, so it's erased after its live items have been moved.
+/// Returns None if there's no partial open page yet.
+pub fn next_buffer_page<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+) -> Result<Option<NextBufferPage>, MapError<I::Error, S::Error>> {
+    with_item_buffer::<I, S, _>(|buffer| next_buffer_page_in::<I, S>(flash, flash_range, buffer))
+}
+
+/// Like [next_buffer_page], but with the given buffer to read the pages with
+fn next_buffer_page_in<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    buffer: &mut [u8],
+) -> Result<Option<NextBufferPage>, MapError<I::Error, S::Error>> {
+    check_geometry::<S>(flash_range.clone())?;
+
+    let Some(partial_open_page) = find_partial_open_page(flash, flash_range.clone())? else {
+        return Ok(None);
+    };
+
+    let page_index = next_page::<S>(
+        flash_range.clone(),
+        next_page::<S>(flash_range.clone(), partial_open_page),
+    );
+    let needs_erase = !get_page_state(flash, flash_range.clone(), page_index)?.is_open();
+
+    let live_bytes = if needs_erase {
+        page_live_bytes::<I, S>(flash, flash_range, page_index, buffer)?
+    } else {
+        0
+    };
+
+    Ok(Some(NextBufferPage {
+        page_index,
+        needs_erase,
+        live_bytes,
+    }))
+}
+
+/// The size of the biggest item that can be stored right now without getting [MapError::FullStorage],
+/// e.g. to make a value smaller and try again after a failed store.
+///
+/// An item that fits in the free space at the end of the partial open page is stored there.
+/// A bigger item makes the map move to the buffer page, which is fully erased. The live items of the [next_buffer_page]
+/// are moved into it first, so the rest of that page is what's left for the item.
+/// The size is the biggest of the two, counted like [store_item] does, so in whole words.
+/// It's the length of the serialized item that fits.
+///
+/// Before anything has been stored, this is the size of an empty page.
+/// An item that is bigger than the returned size may still fit after the store moved on to more pages,
+/// but an item of at most this size always fits.
+pub fn max_item_size_that_fits<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+) -> Result<usize, MapError<I::Error, S::Error>> {
+    check_geometry::<S>(flash_range.clone())?;
+
+    let page_size = usable_page_size::<S>(flash_range.clone());
+    with_item_buffer::<I, S, _>(|buffer| {
+        let Some(next_buffer_page) =
+            next_buffer_page_in::<I, S>(flash, flash_range.clone(), buffer)?
+        else {
+            return Ok(page_size);
+        };
+
+        let partial_open_page = previous_page::<S>(
+            flash_range.clone(),
+            previous_page::<S>(flash_range.clone(), next_buffer_page.page_index),
+        );
+
+        let flash = RefCell::new(flash);
+        let (records_end, open_batch) =
+            find_records_end::<I, S>(&flash, flash_range.clone(), partial_open_page, buffer)?;
+
+        // Nothing is stored after a batch that was never committed
+        let free_in_partial_open_page = if open_batch {
+            0
+        } else {
+            (calculate_page_data_end_address::<S>(flash_range, partial_open_page) - records_end)
+                as usize
+        };
+
+        let free_after_move = if next_buffer_page.needs_erase {
+            page_size.saturating_sub(next_buffer_page.live_bytes)
+        } else {
+            page_size
+        };
+
+        Ok(free_in_partial_open_page.max(free_after_move))
+    })
+}
+
+/// Do the expensive part of moving to a new page now, e.g. when the system is idle.
+///
+/// The live items of the [next_buffer_page] are stored again in the partial open page, after which the page is erased.
+/// The store that fills up the partial open page then doesn't have to erase anything.
+///
+/// Returns true if the next buffer page is open now. It returns false and changes nothing if there's no partial open page,
+/// if the live items don't fit in the partial open page, if the partial open page ends in a batch that was never committed
+/// or if the range only has 2 pages. In that case the work is left to the store as normal.
+pub fn prepare_next_buffer_page<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+) -> Result<bool, MapError<I::Error, S::Error>> {
+    with_item_buffer::<I, S, _>(|buffer| {
+        let Some(next_buffer_page) =
+            next_buffer_page_in::<I, S>(flash, flash_range.clone(), buffer)?
+        else {
+            return Ok(false);
+        };
+
+        if !next_buffer_page.needs_erase {
+            return Ok(true);
+        }
+
+        let partial_open_page = previous_page::<S>(
+            flash_range.clone(),
+            previous_page::<S>(flash_range.clone(), next_buffer_page.page_index),
+        );
+
+        if partial_open_page == next_buffer_page.page_index {
+            // There are only 2 pages, so the next buffer page is the page we're using now
+            return Ok(false);
+        }
+
+        recycle_oldest_page::<I, S>(
+            flash,
+            flash_range,
+            next_buffer_page.page_index,
+            next_buffer_page.live_bytes,
+            partial_open_page,
+            buffer,
+        )
+    })
+}
+
+/// The amount of bytes of the items on the page that are still the newest version of their key.
+/// The buffer is used to read the page and to look up the newest versions with.
+fn page_live_bytes<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    page_index: usize,
+    buffer: &mut [u8],
+) -> Result<usize, MapError<I::Error, S::Error>> {
+    let mut records = PageRecords::new::<I, S>(flash_range.clone(), page_index, buffer);
+    let mut live_bytes = 0;
+
+    while let Some(item) = records.next_item::<I, S>(flash) {
+        let (item, item_address, _) = item?;
+
+        let Some((_, newest_version_address, newest_version_len)) = find_newest_version_in::<I, S>(
+            flash,
+            flash_range.clone(),
+            item.key(),
+            records.lend_buffer(),
+        )?
+        else {
+            return Err(MapError::Corrupted);
+        };
+
+        if newest_version_address == item_address && !item.is_tombstone() {
+            live_bytes += newest_version_len;
+        }
+    }
+
+    Ok(live_bytes)
+}
+
+/// Copy the live items of the oldest page with data into the partial open page and erase the oldest page.
+/// Returns false and changes nothing if the live items don't fit
+/// or if the partial open page ends in a batch that was never committed.
+///
+/// Tombstones are dropped, because all older values of their key are erased together with them.
+/// If this gets interrupted, the items that have been copied already are newer than the ones on the oldest page,
+/// so they aren't live there anymore and aren't copied again.
+fn recycle_oldest_page<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    oldest_page: usize,
+    live_bytes: usize,
+    partial_open_page: usize,
+    buffer: &mut [u8],
+) -> Result<bool, MapError<I::Error, S::Error>> {
+    let (mut write_address, open_batch) = find_records_end::<I, S>(
+        &RefCell::new(&mut *flash),
+        flash_range.clone(),
+        partial_open_page,
+        &mut *buffer,
+    )?;
+
+    if open_batch {
+        // Nothing may be stored after a batch that was never committed, the store will move to the next page
+        return Ok(false);
+    }
+
+    let page_data_end_address =
+        calculate_page_data_end_address::<S>(flash_range.clone(), partial_open_page);
+    if live_bytes > (page_data_end_address - write_address) as usize {
+        return Ok(false);
+    }
+
+    let mut old_records = PageRecords::new::<I, S>(flash_range.clone(), oldest_page, buffer);
+    while let Some(old_item) = old_records.next_item::<I, S>(flash) {
+        let (old_item, old_item_address, _) = old_item?;
+
+        // The lookup and the copy use the buffer too, the page is read again after them
+        let buffer = old_records.lend_buffer();
+
+        let Some((_, newest_version_address, newest_version_len)) =
+            find_newest_version_in::<I, S>(flash, flash_range.clone(), old_item.key(), buffer)?
+        else {
+            return Err(MapError::Corrupted);
+        };
+
+        if newest_version_address == old_item_address && !old_item.is_tombstone() {
+            let copy_buffer = buffer
+                .get_mut(..newest_version_len)
+                .ok_or(MapError::BufferTooSmall)?;
+            flash
+                .read(newest_version_address, copy_buffer)
+                .map_err(MapError::Storage)?;
+            flash
+                .write(write_address, copy_buffer)
+                .map_err(MapError::Storage)?;
+
+            write_address += newest_version_len as u32;
+        }
+    }
+
+    flash
+        .erase(
+            calculate_page_address::<S>(flash_range.clone(), oldest_page),
+            calculate_page_end_address::<S>(flash_range, oldest_page),
+        )
+        .map_err(MapError::Storage)?;
+
+    Ok(true)
+}
+
+/// Reclaim the space of old values and removed keys now, e.g. when the system is idle or before a burst of stores.
+///
+/// The partial open page is closed and every page with data is recycled, oldest first:
+/// its live items are stored again in the newest page and the page is erased.
+/// Afterwards the map only holds the newest value of every key, the items are unchanged.
+///
+/// This erases every page with data once, so it's not something to do after every store.
+///
+/// It's safe to interrupt this, e.g. by a power loss. Items that were copied already are then stored twice,
+/// but the copy is the newest version so they keep their value. Moving to a new page picks up where it left off
+/// like it does for a store. Call this again to finish the compaction.
+pub fn compact<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+) -> Result<(), MapError<I::Error, S::Error>> {
+    check_geometry::<S>(flash_range.clone())?;
+
+    if find_pages_with_data(flash, flash_range.clone())?.is_none() {
+        return Ok(());
+    }
+
+    with_item_buffer::<I, S, _>(|buffer| {
+        // Start with a fresh page, so the page we were using gets recycled too
+        let next_page_to_use = find_partial_open_page(flash, flash_range.clone())?
+            .map(|partial_open_page| {
+                close_page(flash, flash_range.clone(), partial_open_page)?;
+                Ok::<_, Error<S::Error>>(next_page::<S>(flash_range.clone(), partial_open_page))
+            })
+            .transpose()?;
+
+        let first_compacted_page = move_to_next_page::<I, S>(
+            &RefCell::new(&mut *flash),
+            flash_range.clone(),
+            next_page_to_use,
+            1,
+            buffer,
+        )?;
+        let mut partial_open_page = first_compacted_page;
+
+        // Every page before the first compacted page holds old data
+        while let Some((oldest_page, _)) = find_pages_with_data(flash, flash_range.clone())? {
+            if oldest_page == first_compacted_page {
+                break;
+            }
+
+            let live_bytes =
+                page_live_bytes::<I, S>(flash, flash_range.clone(), oldest_page, buffer)?;
+            if recycle_oldest_page::<I, S>(
+                flash,
+                flash_range.clone(),
+                oldest_page,
+                live_bytes,
+                partial_open_page,
+                buffer,
+            )? {
+                continue;
+            }
+
+            // The live items don't fit, so we move to the next page. That recycles the oldest page too.
+            close_page(flash, flash_range.clone(), partial_open_page)?;
+            partial_open_page = move_to_next_page::<I, S>(
+                &RefCell::new(&mut *flash),
+                flash_range.clone(),
+                Some(next_page::<S>(flash_range.clone(), partial_open_page)),
+                1,
+                buffer,
+            )?;
+        }
+
+        Ok(())
+    })
+}
+
+/// Grow the flash range of a map, e.g. when a firmware update made its partition bigger.
+///
+/// The new range must contain the old range, otherwise [MapError::InvalidFlashGeometry] is returned.
+/// The added pages are erased if they aren't already. The pages of the old range are never written to,
+/// so if this gets interrupted the old range is still a valid map and this can simply be called again.
+/// Call it only once though: after it has returned `Ok`, the added pages are part of the map.
+///
+/// The map uses its pages as a ring, so the added pages end up between the last and the first page of the old range.
+/// If the items wrap around the end of the old range, the added pages are closed without any items in them.
+/// The map then skips over them until they get recycled like any other page, which happens when the map
+/// has gone around the ring once.
+pub fn extend_range<S: NorFlash>(
+    flash: &mut S,
+    old_range: Range<u32>,
+    new_range: Range<u32>,
+) -> Result<(), MapError<Infallible, S::Error>> {
+    check_geometry::<S>(old_range.clone())?;
+    check_geometry::<S>(new_range.clone())?;
+
+    if new_range.start > old_range.start || new_range.end < old_range.end {
+        return Err(MapError::InvalidFlashGeometry);
+    }
+
+    let old_page_count = old_range.len() / S::ERASE_SIZE;
+    let new_page_count = new_range.len() / S::ERASE_SIZE;
+    let first_old_page = calculate_page_index::<S>(new_range.clone(), old_range.start);
+
+    // The added pages, in the order they come in after the last page of the old range
+    let added_pages =
+        (old_page_count..new_page_count).map(|index| (first_old_page + index) % new_page_count);
+
+    let mut buffer = [0; MAX_FLASH_WRITE_SIZE];
+    for page_index in added_pages.clone() {
+        let page_address = calculate_page_address::<S>(new_range.clone(), page_index);
+        let page_end_address = calculate_page_end_address::<S>(new_range.clone(), page_index);
+
+        let mut erased = true;
+        for address in (page_address..page_end_address).step_by(buffer.len()) {
+            let len = buffer.len().min((page_end_address - address) as usize);
+            flash
+                .read(address, &mut buffer[..len])
+                .map_err(MapError::Storage)?;
+
+            if buffer[..len].iter().any(|byte| *byte != 0xFF) {
+                erased = false;
+                break;
+            }
+        }
+
+        if !erased {
+            flash
+                .erase(page_address, page_end_address)
+                .map_err(MapError::Storage)?;
+        }
+    }
+
+    let first_page_state = get_page_state(flash, old_range.clone(), 0)?;
+    let last_page_state = get_page_state(flash, old_range, old_page_count - 1)?;
+
+    if !first_page_state.is_open() && !last_page_state.is_open() {
+        // The items continue from the last to the first page of the old range.
+        // Closed pages are walked over when searching, so closing the added pages keeps the items connected.
+        for page_index in added_pages {
+            close_page(flash, new_range.clone(), page_index)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the flash geometry and the flash range at the start of every map function.
+///
+/// This checks the same things as [validate_geometry]. With the `strict-asserts` feature, which is on by default,
+/// a problem panics so bugs are caught loudly during development. Without it, [Error::InvalidFlashGeometry] is returned instead.
+#[track_caller]
+fn check_geometry<S: NorFlash>(flash_range: Range<u32>) -> Result<(), Error<S::Error>> {
+    check_validated_geometry(validate_geometry::<S>(flash_range))
+}
+
+/// Check the flash geometry and the flash range, without ever panicking
+fn validate_geometry<S: NorFlash>(flash_range: Range<u32>) -> Result<(), Error<S::Error>> {
+    let valid = is_page_aligned_range::<S>(&flash_range)
+        && flash_range.len() / S::ERASE_SIZE >= 2
+        && S::WRITE_SIZE <= MAX_FLASH_WRITE_SIZE
+        && min_aligned_page_size::<S>() >= S::WRITE_SIZE * 3
+        && S::WRITE_SIZE % S::READ_SIZE == 0
+        && MAX_FLASH_WRITE_SIZE % S::READ_SIZE == 0;
+
+    if !valid {
+        return Err(Error::InvalidFlashGeometry);
+    }
+
+    Ok(())
+}
+
+/// Check that the item type fits the flash. See [validate_item_geometry].
+#[track_caller]
+fn check_item_geometry<I: StorageItem, S: NorFlash>(
+    flash_range: Range<u32>,
+) -> Result<(), Error<S::Error>> {
+    check_validated_geometry(validate_item_geometry::<I, S>(flash_range))
+}
+
+/// Check that an item type that can be bigger than [MAX_STORAGE_ITEM_SIZE] fits in a usable page, without ever panicking.
+///
+/// The buffers on the stack are as big as [StorageItem::MAX_SIZE], so a bigger maximum than a page can hold is a mistake.
+/// Items with a smaller maximum are checked one by one when they're stored instead.
+fn validate_item_geometry<I: StorageItem, S: NorFlash>(
+    flash_range: Range<u32>,
+) -> Result<(), Error<S::Error>> {
+    validate_geometry::<S>(flash_range.clone())?;
+
+    if I::MAX_SIZE > MAX_STORAGE_ITEM_SIZE
+        && I::MAX_SIZE.next_multiple_of(S::WRITE_SIZE) > usable_page_size::<S>(flash_range)
+    {
+        return Err(Error::InvalidFlashGeometry);
+    }
+
+    Ok(())
+}
+
+/// Estimate how many erases a store pattern causes, for modelling the lifetime of the flash.
+///
+/// - `range_len`: The length of the flash range in bytes
+/// - `item_size`: The serialized size of the items that are stored
+/// - `stores_per_period`: How many items are stored in the period you're interested in, e.g. an hour
+///
+/// Every item is rounded up to a multiple of `WRITE_SIZE` and items never span two pages.
+/// A page has `ERASE_SIZE - 2 * WRITE_SIZE` bytes available because of the page markers.
+/// Once the storage has been filled once, every page that fills up causes one page erase.
+/// So:
+///
+/// ```text
+/// items_per_page = floor((ERASE_SIZE - 2 * WRITE_SIZE) / round_up(item_size, WRITE_SIZE))
+/// erases_per_period = stores_per_period / items_per_page
+/// erases_per_page_per_period = erases_per_period / (range_len / ERASE_SIZE)
+/// ```
+///
+/// The expected lifetime in periods is the erase cycles of your flash divided by `erases_per_page_per_period`.
+///
+/// This assumes a steady state where the newest value of a key is never on the oldest page.
+/// When you have many keys that are rarely updated, their values are copied to new pages once in a while
+/// which takes up space and increases the real erase rate.
+///
+/// Returns None if an item of the given size doesn't fit in a page.
+pub fn estimate_erase_rate<S: NorFlash>(
+    range_len: u32,
+    item_size: usize,
+    stores_per_period: f32,
+) -> Option<EraseRateEstimate> {
+    let page_count = range_len as usize / S::ERASE_SIZE;
+    let usable_bytes_per_page = S::ERASE_SIZE.checked_sub(2 * S::WRITE_SIZE)?;
+    let stored_item_size = item_size.div_ceil(S::WRITE_SIZE) * S::WRITE_SIZE;
+
+    if page_count == 0 || stored_item_size == 0 {
+        return None;
+    }
+
+    let items_per_page = usable_bytes_per_page / stored_item_size;
+
+    if items_per_page == 0 {
+        return None;
+    }
+
+    let erases_per_period = stores_per_period / items_per_page as f32;
+
+    Some(EraseRateEstimate {
+        erases_per_period,
+        erases_per_page_per_period: erases_per_period / page_count as f32,
+    })
+}
+
+/// The result of [estimate_erase_rate]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EraseRateEstimate {
+    /// The total amount of page erases in the period
+    pub erases_per_period: f32,
+    /// The amount of times every page is erased in the period.
+    /// This is what determines the lifetime of the flash.
+    pub erases_per_page_per_period: f32,
+}
+
+/// The outcome of [store_item_with_outcome]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StoreOutcome {
+    /// The key had no value yet and a new one has been stored
+    Inserted,
+    /// The key already had a value and it has been overwritten
+    Updated,
+}
+
+/// The start marker word of a map page. The first byte is the marker that makes the page partial open.
+/// The rest of the word is the geometry stamp: the write size as a u8 followed by the erase size as a little endian u32,
+/// as far as it fits. The remaining bytes are 0.
+fn start_marker_word<S: NorFlash>() -> [u8; MAX_FLASH_WRITE_SIZE] {
+    let mut word = [MARKER; MAX_FLASH_WRITE_SIZE];
+    let erase_size = (S::ERASE_SIZE as u32).to_le_bytes();
+    let stamp = [
+        S::WRITE_SIZE as u8,
+        erase_size[0],
+        erase_size[1],
+        erase_size[2],
+        erase_size[3],
+    ];
+
+    let stamp_len = stamp.len().min(S::WRITE_SIZE - 1);
+    word[1..1 + stamp_len].copy_from_slice(&stamp[..stamp_len]);
+    word
+}
+
+/// Partially close a map page by writing its start marker word with the geometry stamp
+fn partial_close_map_page<S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    page_index: usize,
+) -> Result<(), Error<S::Error>> {
+    if !get_page_state(flash, flash_range.clone(), page_index)?.is_open() {
+        return Ok(());
+    }
+
+    flash
+        .write(
+            calculate_page_start_marker_address::<S>(flash_range, page_index),
+            &start_marker_word::<S>()[..S::WRITE_SIZE],
+        )
+        .map_err(Error::Storage)
+}
+
+/// Check that the page was written with the same write and erase size as the flash has now.
+///
+/// The map does this once when it finds the page it used last, which is the page it mounts the range from.
+/// The stamp takes up the bytes of the start marker word after the marker, so it costs no space in the page.
+///
+/// A start marker without a stamp is fine. Its bytes are all 0 when it was written by a version of this crate
+/// that didn't write the stamp yet, or when the page was closed without ever being used, e.g. by [extend_range].
+/// With a write size of 1 there's no room for the stamp, so nothing can be checked.
+fn check_geometry_stamp<S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    page_index: usize,
+) -> Result<(), Error<S::Error>> {
+    // The marker word is a whole amount of reads, because the write size is a multiple of the read size
+    let mut word = [0xFF; MAX_FLASH_WRITE_SIZE];
+    flash
+        .read(
+            calculate_page_start_marker_address::<S>(flash_range, page_index),
+            &mut word[..S::WRITE_SIZE],
+        )
+        .map_err(Error::Storage)?;
+
+    let stamp = &word[1..S::WRITE_SIZE];
+    if stamp == &start_marker_word::<S>()[1..S::WRITE_SIZE]
+        || stamp.iter().all(|byte| *byte == MARKER)
+    {
+        return Ok(());
+    }
+
+    error!(
+        "Page {} was written with a different write or erase size: {:?}",
+        page_index, stamp
+    );
+    Err(Error::GeometryMismatch)
+}
+
+/// Find the partial open page like [find_first_page] does and check its geometry stamp
+fn find_partial_open_page<S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+) -> Result<Option<usize>, Error<S::Error>> {
+    let partial_open_page = find_first_page(flash, flash_range.clone(), 0, PageState::PartialOpen)?;
+
+    if let Some(partial_open_page) = partial_open_page {
+        check_geometry_stamp(flash, flash_range, partial_open_page)?;
+    }
+
+    Ok(partial_open_page)
+}
+
+/// The amount of bytes an item can use in the smallest page of the range
+fn usable_page_size<S: NorFlash>(flash_range: Range<u32>) -> usize {
+    (0..flash_range.len() / S::ERASE_SIZE)
+        .map(|page_index| {
+            (calculate_page_data_end_address::<S>(flash_range.clone(), page_index)
+                - calculate_page_data_start_address::<S>(flash_range.clone(), page_index))
+                as usize
+        })
+        .min()
+        .unwrap_or(0)
+}
+
+/// The size of the buffers on the stack: [StorageItem::MAX_SIZE] rounded up to whole words.
+/// No item is bigger than a page, so the buffer is never bigger than a page either.
+const fn item_buffer_size<I: StorageItem, S: NorFlash>() -> usize {
+    let max_size = if I::MAX_SIZE < S::ERASE_SIZE {
+        I::MAX_SIZE
+    } else {
+        S::ERASE_SIZE
+    };
+
+    max_size.next_multiple_of(S::WRITE_SIZE)
+}
+
+/// The biggest buffer [with_item_buffer] can put on the stack
+const MAX_ITEM_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Call `f` with an erased buffer on the stack that fits every item of the type. See [item_buffer_size].
+///
+/// The length of an array can't depend on `I::MAX_SIZE` yet, so the buffer is the smallest power of two that is big enough.
+/// Only that buffer takes up stack.
+fn with_item_buffer<I: StorageItem, S: NorFlash, R>(f: impl FnOnce(&mut [u8]) -> R) -> R {
+    const {
+        assert!(
+            item_buffer_size::<I, S>() <= MAX_ITEM_BUFFER_SIZE,
+            "The items are too big for a buffer on the stack, use the `_with_buffer` functions"
+        )
+    };
+
+    match const { item_buffer_size::<I, S>().next_power_of_two() } {
+        ..=16 => call_with_buffer::<16, R>(f),
+        32 => call_with_buffer::<32, R>(f),
+        64 => call_with_buffer::<64, R>(f),
+        128 => call_with_buffer::<128, R>(f),
+        256 => call_with_buffer::<256, R>(f),
+        512 => call_with_buffer::<512, R>(f),
+        1024 => call_with_buffer::<1024, R>(f),
+        2048 => call_with_buffer::<2048, R>(f),
+        4096 => call_with_buffer::<4096, R>(f),
+        8192 => call_with_buffer::<8192, R>(f),
+        16384 => call_with_buffer::<16384, R>(f),
+        32768 => call_with_buffer::<32768, R>(f),
+        65536 => call_with_buffer::<65536, R>(f),
+        131072 => call_with_buffer::<131072, R>(f),
+        _ => call_with_buffer::<MAX_ITEM_BUFFER_SIZE, R>(f),
+    }
+}
+
+/// Not inlined, so the buffers of the other sizes don't end up in the stack frame of [with_item_buffer]
+#[inline(never)]
+fn call_with_buffer<const N: usize, R>(f: impl FnOnce(&mut [u8]) -> R) -> R {
+    f(&mut [0xFF; N])
+}
+
+/// Read the items of a page with the given buffer as the window into the page.
+///
+/// An item that doesn't fit in a buffer that is smaller than [StorageItem::MAX_SIZE] returns [MapError::BufferTooSmall].
+#[allow(clippy::type_complexity)]
+fn read_page_items_in<'a, 'b, I: StorageItem, S: NorFlash, B: AsRef<[u8]> + AsMut<[u8]>>(
+    flash: &'a RefCell<&'b mut S>,
+    flash_range: Range<u32>,
+    page_index: usize,
+    read_buffer: B,
+) -> Result<
+    impl Iterator<Item = Result<(I, u32, usize), MapError<I::Error, S::Error>>> + use<'a, 'b, I, S, B>,
+    MapError<I::Error, S::Error>,
+> {
+    Ok(
+        read_page_records_in::<I, S, B>(flash, flash_range, page_index, read_buffer)?.filter_map(
+            |record| match record {
+                Ok((Some(item), _, _)) if item.batch_marker().is_some() => None,
+                Ok((Some(item), address, len)) => Some(Ok((item, address, len))),
+                Ok((None, _, _)) => None,
+                Err(e) => Some(Err(e)),
+            },
+        ),
+    )
+}
+
+/// Like [read_page_items_in], but damaged records are returned too, without an item.
+/// So this also returns where the records of the page end when the last one is damaged.
+/// See [StorageItemError::damaged_record_len].
+///
+/// The batch markers are returned as items. The records of a batch that wasn't committed are returned without an item,
+/// like damaged records. See [store_items].
+#[allow(clippy::type_complexity)]
+fn read_page_records_in<'a, 'b, I: StorageItem, S: NorFlash, B: AsRef<[u8]> + AsMut<[u8]>>(
+    flash: &'a RefCell<&'b mut S>,
+    flash_range: Range<u32>,
+    page_index: usize,
+    read_buffer: B,
+) -> Result<
+    impl Iterator<Item = Result<(Option<I>, u32, usize), MapError<I::Error, S::Error>>>
+        + use<'a, 'b, I, S, B>,
+    MapError<I::Error, S::Error>,
+> {
+    let mut page_records = PageRecords::new::<I, S>(flash_range, page_index, read_buffer);
+
+    Ok(core::iter::from_fn(move || {
+        page_records.next_record::<I, S>(*flash.borrow_mut())
+    }))
+}
+
+/// The records of a page, read through a window into the page. See [read_page_records_in].
+///
+/// Unlike the iterator, this doesn't hold on to the flash, and the buffer can be used for something else
+/// in between two records with [Self::lend_buffer].
+struct PageRecords<B> {
+    page_reader: PageReader<B>,
+    /// If this many bytes are erased, there can't be an item anymore
+    probe_len: usize,
+    done: bool,
+    /// Inside a batch, whether it has been committed
+    batch: Option<bool>,
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> PageRecords<B> {
+    fn new<I: StorageItem, S: NorFlash>(
+        flash_range: Range<u32>,
+        page_index: usize,
+        read_buffer: B,
+    ) -> Self {
+        let page_reader = PageReader {
+            // Only whole reads fit in the window and no item is bigger than its max size
+            capacity: read_buffer
+                .as_ref()
+                .len()
+                .min(I::MAX_SIZE.next_multiple_of(S::READ_SIZE))
+                / S::READ_SIZE
+                * S::READ_SIZE,
+            read_buffer,
+            buffer_start_address: calculate_page_data_start_address::<S>(
+                flash_range.clone(),
+                page_index,
+            ),
+            page_data_end_address: calculate_page_data_end_address::<S>(flash_range, page_index),
+            used: 0,
+            valid: 0,
+        };
+
+        // An item that fills the whole buffer can't be all erased bytes either
+        let probe_len = I::MAX_LEADING_ERASED_BYTES
+            .saturating_add(1)
+            .min(page_reader.capacity());
+
+        Self {
+            page_reader,
+            probe_len,
+            done: false,
+            batch: None,
+        }
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn next_record<I: StorageItem, S: NorFlash>(
+        &mut self,
+        flash: &mut S,
+    ) -> Option<Result<(Option<I>, u32, usize), MapError<I::Error, S::Error>>> {
+        let record = read_next_record::<I, S, B>(
+            &mut self.page_reader,
+            flash,
+            self.probe_len,
+            &mut self.done,
+        )?;
+
+        let marker = match &record {
+            Ok((Some(item), _, _)) => item.batch_marker(),
+            _ => None,
+        };
+
+        match marker {
+            Some(BatchMarker::Begin) => {
+                // Look ahead for the commit marker and then come back to read the batch itself
+                let batch_address = self.page_reader.unused_address();
+                let committed = loop {
+                    match read_next_record::<I, S, B>(
+                        &mut self.page_reader,
+                        flash,
+                        self.probe_len,
+                        &mut self.done,
+                    ) {
+                        Some(Ok((Some(item), _, _))) => match item.batch_marker() {
+                            Some(BatchMarker::Commit) => break true,
+                            Some(BatchMarker::Begin) => break false,
+                            None => {}
+                        },
+                        Some(Ok((None, _, _))) => {}
+                        // The batch runs up to the end of the page, so it was interrupted
+                        None | Some(Err(_)) => break false,
+                    }
+                };
+
+                self.page_reader.seek(batch_address);
+                self.done = false;
+                self.batch = Some(committed);
+            }
+            Some(BatchMarker::Commit) => self.batch = None,
+            None if self.batch == Some(false) => {
+                // A record of a batch that was never committed takes up its space, but it was never stored
+                return Some(record.map(|(_, address, len)| (None, address, len)));
+            }
+            None => {}
+        }
+
+        Some(record)
+    }
+
+    /// Like [Self::next_record], but only the items, like [read_page_items_in] returns them
+    #[allow(clippy::type_complexity)]
+    fn next_item<I: StorageItem, S: NorFlash>(
+        &mut self,
+        flash: &mut S,
+    ) -> Option<Result<(I, u32, usize), MapError<I::Error, S::Error>>> {
+        loop {
+            match self.next_record::<I, S>(flash)? {
+                Ok((Some(item), address, len)) if item.batch_marker().is_none() => {
+                    return Some(Ok((item, address, len)))
+                }
+                Ok(_) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+
+    /// Use the buffer for something else until the next record is read.
+    /// The page after the last record is read into it again then.
+    fn lend_buffer(&mut self) -> &mut [u8] {
+        self.page_reader.seek(self.page_reader.unused_address());
+        self.page_reader.read_buffer.as_mut()
+    }
+}
+
+/// Read the next record of the page.
+/// Returns None and sets `done` when the rest of the page is erased.
+#[allow(clippy::type_complexity)]
+fn read_next_record<I: StorageItem, S: NorFlash, B: AsRef<[u8]> + AsMut<[u8]>>(
+    page_reader: &mut PageReader<B>,
+    flash: &mut S,
+    probe_len: usize,
+    done: &mut bool,
+) -> Option<Result<(Option<I>, u32, usize), MapError<I::Error, S::Error>>> {
+    // We deserialize the items one by one.
+    // Only as much of the page is read as is needed to do so.
+
+    if *done {
+        return None;
+    }
+
+    let available = match page_reader.fill(flash, probe_len) {
+        Ok(available) => available,
+        Err(e) => return Some(Err(MapError::Storage(e))),
+    };
+
+    if page_reader.unused()[..available.min(probe_len)]
+        .iter()
+        .all(|b| *b == 0xFF)
+    {
+        // The rest of the page is in the erased state, so we know that the rest is empty
+        *done = true;
+        return None;
+    }
+
+    let mut wanted = probe_len
+        .max(MAX_FLASH_WRITE_SIZE)
+        .min(page_reader.capacity());
+    let mut available = match page_reader.fill(flash, wanted) {
+        Ok(available) => available,
+        Err(e) => return Some(Err(MapError::Storage(e))),
+    };
+
+    loop {
+        match I::deserialize_from(&page_reader.unused()[..available]) {
+            Ok((item, mut used_bytes)) => {
+                // We can only write in whole words, so we round up the used bytes so the math works
+                if used_bytes % S::WRITE_SIZE > 0 {
+                    used_bytes += S::WRITE_SIZE - (used_bytes % S::WRITE_SIZE);
+                }
+
+                let item_address = page_reader.unused_address();
+                page_reader.used += used_bytes;
+
+                break Some(Ok((Some(item), item_address, used_bytes)));
+            }
+            Err(e) if e.is_buffer_too_small() => {
+                // Read more of the page and try again
+                wanted = (wanted * 2).min(page_reader.capacity());
+
+                let previously_available = available;
+                available = match page_reader.fill(flash, wanted) {
+                    Ok(available) => available,
+                    Err(e) => return Some(Err(MapError::Storage(e))),
+                };
+
+                if available == previously_available {
+                    // There's nothing more to read
+                    *done = true;
+
+                    if available == page_reader.capacity() && page_reader.capacity() < I::MAX_SIZE {
+                        // The item may still be valid, but it's bigger than the buffer we were given
+                        return Some(Err(MapError::BufferTooSmall));
+                    }
+
+                    return Some(Err(MapError::Item(e)));
+                }
+            }
+            Err(e) => {
+                if let Some(mut record_len) = e
+                    .damaged_record_len()
+                    .filter(|record_len| (1..=available).contains(record_len))
+                {
+                    warn!("Damaged record at {}", page_reader.unused_address());
+
+                    // The record was written in whole words too
+                    if record_len % S::WRITE_SIZE > 0 {
+                        record_len += S::WRITE_SIZE - (record_len % S::WRITE_SIZE);
+                    }
+
+                    let record_address = page_reader.unused_address();
+                    page_reader.used += record_len;
+
+                    break Some(Ok((None, record_address, record_len)));
+                }
+
+                *done = true;
+                return Some(Err(MapError::Item(e)));
+            }
+        }
+    }
+}
+
+/// A window of the data of a page that is read lazily
+///
+/// All reads are whole multiples of `READ_SIZE` at aligned addresses.
+/// Items start at whole words and the write size is a multiple of the read size, so the window always starts aligned.
+struct PageReader<B> {
+    read_buffer: B,
+    /// The usable size of the buffer, a whole amount of reads
+    capacity: usize,
+    /// The flash address of the first byte in the buffer
+    buffer_start_address: u32,
+    page_data_end_address: u32,
+    /// The amount of bytes in the buffer that have been deserialized. Can be more than `valid` because of padding.
+    used: usize,
+    /// The amount of bytes in the buffer that have been read from flash
+    valid: usize,
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> PageReader<B> {
+    /// The size of the window
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Read more of the page so at least `len` bytes after the used bytes are valid, as far as the page allows.
+    /// Returns the amount of valid bytes after the used bytes.
+    fn fill<S: NorFlash>(&mut self, flash: &mut S, len: usize) -> Result<usize, S::Error> {
+        let len = len.min(self.capacity());
+
+        if self.used + len > self.capacity() {
+            // Move the unused bytes to the front to make room
+            if self.used < self.valid {
+                self.read_buffer
+                    .as_mut()
+                    .copy_within(self.used..self.valid, 0);
+            }
+            self.buffer_start_address += self.used as u32;
+            self.valid = self.valid.saturating_sub(self.used);
+            self.used = 0;
+        }
+
+        let read_start = self.valid.max(self.used);
+        let read_address = self.buffer_start_address + read_start as u32;
+        let bytes_left_in_page = self.page_data_end_address.saturating_sub(read_address) as usize;
+        let read_end = (self.used + len)
+            .next_multiple_of(S::READ_SIZE)
+            .min(read_start + bytes_left_in_page);
+
+        if read_end > read_start {
+            flash.read(
+                read_address,
+                &mut self.read_buffer.as_mut()[read_start..read_end],
+            )?;
+            self.valid = read_end;
+        }
+
+        Ok(self.valid.saturating_sub(self.used))
+    }
+
+    /// Move the window to the given address of the page, which must be the start of a record.
+    /// The bytes after it are read again.
+    fn seek(&mut self, address: u32) {
+        self.buffer_start_address = address;
+        self.used = 0;
+        self.valid = 0;
+    }
+
+    fn unused(&self) -> &[u8] {
+        &self.read_buffer.as_ref()[self.used.min(self.valid)..self.valid]
+    }
+
+    fn unused_address(&self) -> u32 {
+        self.buffer_start_address + self.used as u32
+    }
+}
+
+/// A way of serializing and deserializing items in the storage.
+///
+/// A serialized byte pattern of all `0xFF` is invalid and must never be the result of the `serialize_into` function
+/// and `deserialize_from` must always return an error for it.
+///
+/// The given buffer to serialize in and deserialize from is never bigger than [StorageItem::MAX_SIZE] bytes, so make sure the item is
+/// smaller than that.
+///
+/// An item is also never split over pages, so it has to fit on a single page next to the page markers.
+/// A record that spanned pages would be torn in half when its oldest page is recycled,
+/// and the buffer page would have to be big enough to take in every fragment.
+/// To store a blob that's bigger than that, split it over multiple keys, e.g. by making the key a `(blob, chunk index)` pair,
+/// and store the chunks one by one.
+///
+/// The storage doesn't store anything besides the serialized bytes.
+/// If you need extra metadata per record, like a source, a priority or a timestamp,
+/// make it a field of the item and serialize it along with the value.
+/// You can then search on it with [fetch_item_matching].
+pub trait StorageItem {
+    /// The key type of the key-value pair
+    type Key: Eq;
+    /// The error type for serialization and deserialization
+    type Error: StorageItemError;
+
+    /// Serialize the key-value item into the given buffer.
+    /// Returns the number of bytes the buffer was filled with or an error.
+    ///
+    /// The serialized bytes must not all be `0xFF`. One way to prevent this is to serialize an extra 0 byte at the end if that is the case,
+    /// which is what [items::ensure_not_erased] does. A key or value of `0xFF` is fine on its own.
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error>;
+    /// Deserialize the key-value item from the given buffer.
+    /// The buffer is likely bigger than the size of the item.
+    fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error>
+    where
+        Self: Sized;
+
+    /// The key of the key-value item. It is used by the storage to know what the key of this item is.
+    fn key(&self) -> Self::Key;
+
+    /// The maximum size in bytes of a serialized item.
+    ///
+    /// The map never serializes into or reads more than this per item, so a small value saves reads,
+    /// and the `_with_buffer` functions only use this much of their buffer.
+    /// The functions that keep their buffer on the stack size it by this too, rounded up to a power of two
+    /// and at most a page. So a small value saves stack as well.
+    ///
+    /// A value bigger than [MAX_STORAGE_ITEM_SIZE] has to fit in an empty page, rounded up to `WRITE_SIZE`.
+    /// Otherwise the map returns [MapError::InvalidFlashGeometry] when storing, or panics with the `strict-asserts` feature.
+    const MAX_SIZE: usize = MAX_STORAGE_ITEM_SIZE;
+
+    /// The maximum amount of `0xFF` bytes a serialized item can start with.
+    ///
+    /// When looking for the next item in a page, the storage first reads this amount of bytes plus one.
+    /// If they are all `0xFF`, it knows the rest of the page is empty without reading it.
+    /// The default is safe for every item, but means up to [StorageItem::MAX_SIZE] bytes are read every time.
+    /// If your serialized items never start with `0xFF`, e.g. because the first byte is a key or length that can't be `0xFF`,
+    /// set this to 0 to read a lot less on slow flash.
+    ///
+    /// If this is set too low, items are missed.
+    const MAX_LEADING_ERASED_BYTES: usize = Self::MAX_SIZE;
+
+    /// Returns true if this item is a tombstone, which marks its key as removed. See [remove_item].
+    ///
+    /// A key whose newest record is a tombstone has no value. The default returns false,
+    /// which is right for items that don't implement [RemovableStorageItem].
+    fn is_tombstone(&self) -> bool {
+        false
+    }
+
+    /// Returns the marker if this item is a batch marker, which frames the records of a batch. See [store_items].
+    ///
+    /// The default returns None, which is right for items that don't implement [BatchStorageItem].
+    fn batch_marker(&self) -> Option<BatchMarker> {
+        None
+    }
+}
+
+/// A [StorageItem] that can be removed from the map with [remove_item].
+pub trait RemovableStorageItem: StorageItem {
+    /// Create a tombstone for the key. [StorageItem::is_tombstone] must return true for it.
+    ///
+    /// A tombstone is stored like any other item, so it must serialize to something that can be deserialized again.
+    /// Keep it small, it takes up space until its page is recycled.
+    fn tombstone(key: Self::Key) -> Self;
+}
+
+/// A [StorageItem] that can be stored in batches with [store_items].
+pub trait BatchStorageItem: StorageItem {
+    /// Create a batch marker. [StorageItem::batch_marker] must return the marker for it.
+    ///
+    /// A marker is stored like any other item, but it's never returned as an item and has no key.
+    /// Keep it small, e.g. a single word, so it can't be torn in half by a power loss.
+    fn new_batch_marker(marker: BatchMarker) -> Self;
+}
+
+/// The marker records that frame a batch. See [store_items].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BatchMarker {
+    /// Stored before the items of the batch
+    Begin,
+    /// Stored after all items of the batch. Without it, the batch was never stored.
+    Commit,
+}
+
+/// A [StorageItem] that can be removed from the map by marking its stored records, with [remove_item_in_place].
+///
+/// The serialized item needs room for a marker, e.g. a flag bit that is 1 for every stored item.
+pub trait InPlaceRemovableStorageItem: StorageItem {
+    /// Mark the stored record of an item as removed. The record is as long as the item was serialized, rounded up to whole words.
+    ///
+    /// The marked record is written over the old one, so bits can only be cleared and never set.
+    /// [StorageItem::deserialize_from] must return an error for the marked record for which
+    /// [StorageItemError::damaged_record_len] returns the length of the record, so the map skips it.
+    fn mark_removed(record: &mut [u8]);
+}
+
+/// The default maximum size in bytes that a storage item can be. See [StorageItem::MAX_SIZE].
+pub const MAX_STORAGE_ITEM_SIZE: usize = 512;
+
+/// A trait that the storage item error needs to implement
+pub trait StorageItemError: Debug {
+    /// Returns true if the error indicates that the buffer is too small to contain the storage item
+    fn is_buffer_too_small(&self) -> bool;
+
+    /// Returns the length of the record if the error indicates that the record has been damaged after it was stored,
+    /// e.g. because its checksum doesn't match. The map then skips the record as if it was never stored,
+    /// so an older value of the key is found instead. See [items::Crc].
+    ///
+    /// This is also how a record that has been marked with [InPlaceRemovableStorageItem::mark_removed] is skipped.
+    ///
+    /// By default this returns None, which makes the error stop the search like any other error.
+    fn damaged_record_len(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// The main error type
+///
+/// With the `std` feature, it implements `std::error::Error` when the item and flash errors do,
+/// so it can be returned with `?` from a function that returns e.g. a `Box<dyn Error>`:
+///
+/// ```rust
+/// # #[cfg(feature = "mock-flash")]
+/// # {
+/// use sequential_storage::map::{fetch_item, items::ArrayItem};
+/// use sequential_storage::mock_flash::MockFlashBase;
+///
+/// fn read_config(flash: &mut MockFlashBase<4, 4, 256>) -> Result<[u8; 4], Box<dyn std::error::Error>> {
+///     let item = fetch_item::<ArrayItem<4>, _>(flash, 0x000..0x1000, 0)?;
+///     Ok(item.map_or([0; 4], |item| item.value))
+/// }
+///
+/// assert_eq!(read_config(&mut MockFlashBase::new()).unwrap(), [0; 4]);
+/// # }
+/// ```
+#[non_exhaustive]
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MapError<I, S> {
+    /// A storage item error
+    Item(I),
+    /// An error in the storage (flash)
+    Storage(S),
+    /// The item cannot be stored anymore because the storage is full.
+    /// If you get this error some data may be lost.
+    FullStorage,
+    /// It's been detected that the memory is likely corrupted.
+    /// The flash is left untouched.
+    /// You may want to erase the memory with [recover_by_erase] to recover, but this loses all data.
+    /// [try_repair] may be able to fix it without that.
+    Corrupted,
+    /// A provided buffer was to big to be used
+    BufferTooBig,
+    /// A provided buffer was to small to be used
+    BufferTooSmall,
+    /// The item is bigger than the size limit for its key
+    ItemTooBig,
+    /// The flash range was written with a different `WRITE_SIZE` or `ERASE_SIZE` than the flash has now.
+    /// The items can't be read back reliably, so the flash is left untouched.
+    GeometryMismatch,
+    /// The flash range or the flash itself doesn't meet the requirements of the map,
+    /// e.g. the range isn't aligned to pages or is less than 2 pages long
+    InvalidFlashGeometry,
+}
+
+impl<S, I> From<super::Error<S>> for MapError<I, S> {
+    fn from(value: super::Error<S>) -> Self {
+        match value {
+            Error::Storage(e) => Self::Storage(e),
+            Error::FullStorage => Self::FullStorage,
+            Error::Corrupted => Self::Corrupted,
+            Error::BufferTooBig => Self::BufferTooBig,
+            Error::BufferTooSmall => Self::BufferTooSmall,
+            Error::InvalidFlashGeometry => Self::InvalidFlashGeometry,
+            Error::GeometryMismatch => Self::GeometryMismatch,
+        }
+    }
+}
+
+impl<I, S> core::fmt::Display for MapError<I, S> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MapError::Item(_) => f.write_str("the item could not be serialized or deserialized"),
+            MapError::Storage(_) => f.write_str("the flash returned an error"),
+            MapError::FullStorage => f.write_str("storage is full"),
+            MapError::Corrupted => f.write_str("memory appears corrupted"),
+            MapError::BufferTooBig => f.write_str("a provided buffer is too big"),
+            MapError::BufferTooSmall => f.write_str("a provided buffer is too small"),
+            MapError::ItemTooBig => {
+                f.write_str("the item is bigger than the size limit for its key")
+            }
+            MapError::GeometryMismatch => {
+                f.write_str("the flash range was written with a different write or erase size")
+            }
+            MapError::InvalidFlashGeometry => {
+                f.write_str("the flash or the flash range is invalid")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<I: std::error::Error + 'static, S: std::error::Error + 'static> std::error::Error
+    for MapError<I, S>
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MapError::Item(e) => Some(e),
+            MapError::Storage(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) type MockFlashBig = mock_flash::MockFlashBase<4, 4, 256>;
+    pub(crate) type MockFlashTiny = mock_flash::MockFlashBase<2, 1, 32>;
+
+    #[derive(Debug, PartialEq, Eq)]
+    pub(crate) struct MockStorageItem {
+        pub(crate) key: u8,
+        pub(crate) value: Vec<u8>,
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    pub(crate) enum MockStorageItemError {
+        BufferTooSmall,
+        InvalidKey,
+        BufferTooBig,
+    }
+
+    impl StorageItemError for MockStorageItemError {
+        fn is_buffer_too_small(&self) -> bool {
+            matches!(self, MockStorageItemError::BufferTooSmall)
+        }
+    }
+
+    impl StorageItem for MockStorageItem {
+        type Key = u8;
+
+        type Error = MockStorageItemError;
+
+        // The key can't be 0xFF
+        const MAX_LEADING_ERASED_BYTES: usize = 0;
+
+        fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+            if buffer.len() < 2 + self.value.len() {
+                return Err(MockStorageItemError::BufferTooSmall);
+            }
+
+            if self.value.len() > 255 {
+                return Err(MockStorageItemError::BufferTooBig);
+            }
+
+            // The serialized value must not be all 0xFF
+            if self.key == 0xFF {
+                return Err(MockStorageItemError::InvalidKey);
+            }
+
+            buffer[0] = self.key;
+            buffer[1] = self.value.len() as u8;
+            buffer[2..][..self.value.len()].copy_from_slice(&self.value);
+
+            Ok(2 + self.value.len())
+        }
+
+        fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error>
+        where
+            Self: Sized,
+        {
+            if buffer.len() < 2 {
+                return Err(MockStorageItemError::BufferTooSmall);
+            }
+
+            if buffer[0] == 0xFF {
+                return Err(MockStorageItemError::InvalidKey);
+            }
+
+            let len = buffer[1];
+
+            if buffer.len() < 2 + len as usize {
+                return Err(MockStorageItemError::BufferTooSmall);
+            }
+
+            Ok((
+                Self {
+                    key: buffer[0],
+                    value: buffer[2..][..len as usize].to_vec(),
+                },
+                2 + len as usize,
+            ))
+        }
+
+        fn key(&self) -> Self::Key {
+            self.key
+        }
+    }
+
+    #[test]
+    fn store_and_fetch() {
+        check_store_and_fetch(MockFlashBig::new());
+    }
+
+    #[test]
+    fn store_and_fetch_read_size() {
+        check_store_and_fetch(mock_flash::MockFlashBase::<4, 4, 256, 4>::new());
+    }
+
+    fn check_store_and_fetch<const READ_BYTES: usize>(
+        mut flash: mock_flash::MockFlashBase<4, 4, 256, READ_BYTES>,
+    ) {
+        let flash_range = 0x000..0x1000;
+
+        let item = fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0).unwrap();
+        assert_eq!(item, None);
+
+        let item = fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 60).unwrap();
+        assert_eq!(item, None);
+
+        let item = fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0xFF).unwrap();
+        assert_eq!(item, None);
+
+        store_item::<_, _>(
+            &mut flash,
+            flash_range.clone(),
+            MockStorageItem {
+                key: 0,
+                value: vec![5],
+            },
+        )
+        .unwrap();
+        store_item::<_, _>(
+            &mut flash,
+            flash_range.clone(),
+            MockStorageItem {
+                key: 0,
+                value: vec![5, 6],
+            },
+        )
+        .unwrap();
+
+        let item = fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.key, 0);
+        assert_eq!(item.value, vec![5, 6]);
+
+        store_item::<_, _>(
+            &mut flash,
+            flash_range.clone(),
+            MockStorageItem {
+                key: 1,
+                value: vec![2, 2, 2, 2, 2, 2],
+            },
+        )
+        .unwrap();
+
+        let item = fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.key, 0);
+        assert_eq!(item.value, vec![5, 6]);
+
+        let item = fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 1)
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.key, 1);
+        assert_eq!(item.value, vec![2, 2, 2, 2, 2, 2]);
+
+        for index in 0..4000 {
+            store_item::<_, _>(
+                &mut flash,
+                flash_range.clone(),
+                MockStorageItem {
+                    key: (index % 10) as u8,
+                    value: vec![(index % 10) as u8 * 2; index % 10],
+                },
+            )
+            .unwrap();
+        }
+
+        for i in 0..10 {
+            let item = fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), i)
+                .unwrap()
+                .unwrap();
+            assert_eq!(item.key, i);
+            assert_eq!(item.value, vec![(i % 10) * 2; (i % 10) as usize]);
+        }
+
+        for _ in 0..4000 {
+            store_item::<_, _>(
+                &mut flash,
+                flash_range.clone(),
+                MockStorageItem {
+                    key: 11,
+                    value: vec![0; 10],
+                },
+            )
+            .unwrap();
+        }
+
+        for i in 0..10 {
+            let item = fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), i)
+                .unwrap()
+                .unwrap();
+            assert_eq!(item.key, i);
+            assert_eq!(item.value, vec![(i % 10) * 2; (i % 10) as usize]);
+        }
+
+        println!(
+            "Erases: {}, reads: {}, writes: {}",
+            flash.erases, flash.reads, flash.writes
+        );
+    }
+
+    #[test]
+    fn map_error_display() {
+        let error = MapError::<items::ArrayItemError, mock_flash::MockFlashError>::Storage(
+            mock_flash::MockFlashError::OutOfBounds,
+        );
+        assert_eq!(error.to_string(), "the flash returned an error");
+        assert_eq!(
+            MapError::<(), ()>::from(Error::FullStorage).to_string(),
+            "storage is full"
+        );
+
+        #[cfg(feature = "std")]
+        assert_eq!(
+            std::error::Error::source(&error).unwrap().to_string(),
+            "the operation is out of bounds"
+        );
+    }
+
+    #[test]
+    fn store_too_many_items() {
+        const UPPER_BOUND: u8 = 6;
+
+        let mut tiny_flash = MockFlashTiny::new();
+
+        for i in 0..UPPER_BOUND {
+            let item = MockStorageItem {
+                key: i,
+                value: vec![i; i as usize],
+            };
+            println!("Storing {item:?}");
+
+            store_item::<_, _>(&mut tiny_flash, 0x00..0x40, item).unwrap();
+        }
+
+        assert_eq!(
+            store_item::<_, _>(
+                &mut tiny_flash,
+                0x00..0x40,
+                MockStorageItem {
+                    key: UPPER_BOUND,
+                    value: vec![0; UPPER_BOUND as usize],
+                },
+            ),
+            Err(MapError::FullStorage)
+        );
+
+        for i in 0..UPPER_BOUND {
+            let item = fetch_item::<MockStorageItem, _>(&mut tiny_flash, 0x00..0x40, i)
+                .unwrap()
+                .unwrap();
+
+            println!("Fetched {item:?}");
+
+            assert_eq!(item.value, vec![i; i as usize]);
+        }
+    }
+
+    #[test]
+    fn store_item_bigger_than_page() {
+        let mut tiny_flash = MockFlashTiny::new();
+        let flash_range = 0x00..0x40;
+
+        store_item(
+            &mut tiny_flash,
+            flash_range.clone(),
+            MockStorageItem {
+                key: 0,
+                value: vec![0; 4],
+            },
+        )
+        .unwrap();
+        tiny_flash.reset_counters();
+
+        // The pages have less room than the item needs, so it can never be stored
+        assert_eq!(
+            store_item(
+                &mut tiny_flash,
+                flash_range.clone(),
+                MockStorageItem {
+                    key: 1,
+                    value: vec![1; 32],
+                },
+            ),
+            Err(MapError::InvalidFlashGeometry)
+        );
+        assert_eq!(tiny_flash.erases, 0);
+        assert_eq!(tiny_flash.writes, 0);
+
+        assert_eq!(
+            fetch_item::<MockStorageItem, _>(&mut tiny_flash, flash_range, 0)
+                .unwrap()
+                .unwrap()
+                .value,
+            vec![0; 4]
+        );
+    }
+
+    #[test]
+    fn store_too_many_items_big() {
+        const UPPER_BOUND: u8 = 74;
+
+        let mut big_flash = MockFlashBig::new();
+
+        for i in 0..UPPER_BOUND {
+            let item = MockStorageItem {
+                key: i,
+                value: vec![i; i as usize],
+            };
+            println!("Storing {item:?}");
+
+            store_item::<_, _>(&mut big_flash, 0x0000..0x1000, item).unwrap();
+        }
+
+        assert_eq!(
+            store_item::<_, _>(
+                &mut big_flash,
+                0x0000..0x1000,
+                MockStorageItem {
+                    key: UPPER_BOUND,
+                    value: vec![0; UPPER_BOUND as usize],
+                },
+            ),
+            Err(MapError::FullStorage)
+        );
+
+        for i in 0..UPPER_BOUND {
+            let item = fetch_item::<MockStorageItem, _>(&mut big_flash, 0x0000..0x1000, i)
+                .unwrap()
+                .unwrap();
+
+            println!("Fetched {item:?}");
+
+            assert_eq!(item.value, vec![i; i as usize]);
+        }
+    }
+
+    #[test]
+    fn store_with_outcome() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        assert_eq!(
+            store_item_with_outcome(
+                &mut flash,
+                flash_range.clone(),
+                MockStorageItem {
+                    key: 0,
+                    value: vec![1],
+                },
+            ),
+            Ok(StoreOutcome::Inserted)
+        );
+        assert_eq!(
+            store_item_with_outcome(
+                &mut flash,
+                flash_range.clone(),
+                MockStorageItem {
+                    key: 0,
+                    value: vec![2],
+                },
+            ),
+            Ok(StoreOutcome::Updated)
+        );
+        assert_eq!(
+            store_item_with_outcome(
+                &mut flash,
+                flash_range.clone(),
+                MockStorageItem {
+                    key: 1,
+                    value: vec![3],
+                },
+            ),
+            Ok(StoreOutcome::Inserted)
+        );
+
+        let item = fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.value, vec![2]);
+    }
+
+    #[test]
+    fn fetch_matching() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        assert_eq!(
+            fetch_item_matching::<MockStorageItem, _>(&mut flash, flash_range.clone(), |_| true),
+            Ok(None)
+        );
+
+        // The first value byte is used as a priority
+        for (key, priority) in [(0, 1), (1, 0), (2, 1), (3, 0)] {
+            store_item(
+                &mut flash,
+                flash_range.clone(),
+                MockStorageItem {
+                    key,
+                    value: vec![priority, key],
+                },
+            )
+            .unwrap();
+        }
+
+        let item =
+            fetch_item_matching::<MockStorageItem, _>(&mut flash, flash_range.clone(), |item| {
+                item.value[0] == 1
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.key, 2);
+
+        let item =
+            fetch_item_matching::<MockStorageItem, _>(&mut flash, flash_range.clone(), |item| {
+                item.value[0] == 0
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.key, 3);
+
+        assert_eq!(
+            fetch_item_matching::<MockStorageItem, _>(&mut flash, flash_range.clone(), |item| {
+                item.value[0] == 2
+            }),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn corrupted_flash_is_left_alone() {
+        let mut flash = MockFlashTiny::new();
+        let flash_range = 0x00..0x40;
+
+        // Close all pages, which is never a valid state
+        close_page(&mut flash, flash_range.clone(), 0).unwrap();
+        close_page(&mut flash, flash_range.clone(), 1).unwrap();
+        let corrupted_bytes = flash.as_bytes().to_vec();
+        let erases = flash.erases;
+        let writes = flash.writes;
+
+        assert_eq!(
+            fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0),
+            Err(MapError::Corrupted)
+        );
+        assert_eq!(
+            store_item(
+                &mut flash,
+                flash_range.clone(),
+                MockStorageItem {
+                    key: 0,
+                    value: vec![1],
+                },
+            ),
+            Err(MapError::Corrupted)
+        );
+
+        assert_eq!(flash.as_bytes(), &corrupted_bytes[..]);
+        assert_eq!(flash.erases, erases);
+        assert_eq!(flash.writes, writes);
+
+        recover_by_erase(&mut flash, flash_range.clone()).unwrap();
+        assert!(flash.as_bytes().iter().all(|byte| *byte == 0xFF));
+
+        assert_eq!(
+            fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn clear_map() {
+        let mut flash = MockFlashBig::new();
+        // Only the first two pages, the rest of the flash is used for something else
+        let flash_range = 0x000..0x800;
+        flash.as_bytes_mut()[0x800..].fill(0xAB);
+
+        let item = |key, value| MockStorageItem {
+            key,
+            value: vec![value; 10],
+        };
+
+        for i in 0..100 {
+            store_item(&mut flash, flash_range.clone(), item(i % 10, i)).unwrap();
+        }
+
+        let erases = flash.erases;
+        clear(&mut flash, flash_range.clone()).unwrap();
+        assert_eq!(flash.erases, erases + 2);
+
+        assert!(flash.as_bytes()[..0x800].iter().all(|byte| *byte == 0xFF));
+        assert!(flash.as_bytes()[0x800..].iter().all(|byte| *byte == 0xAB));
+
+        for key in 0..10 {
+            assert_eq!(
+                fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), key),
+                Ok(None)
+            );
+        }
+
+        // The map can be used again right away
+        store_item(&mut flash, flash_range.clone(), item(0, 1)).unwrap();
+        assert_eq!(
+            fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0),
+            Ok(Some(item(0, 1)))
+        );
+    }
+
+    #[test]
+    fn page_moves_is_const() {
+        const TINY_MOVES: usize = max_store_page_moves::<MockFlashTiny>(0x00..0x40);
+        const BIG_MOVES: usize = max_store_page_moves::<MockFlashBig>(0x000..0x1000);
+
+        assert_eq!(TINY_MOVES, 2);
+        assert_eq!(BIG_MOVES, 4);
+        assert_eq!(
+            BIG_MOVES,
+            get_pages::<MockFlashBig>(0x000..0x1000, 0).count()
+        );
+    }
+
+    #[test]
+    fn erase_rate_estimate_matches_mock() {
+        const STORES: usize = 10_000;
+
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        // 2 bytes of header and 8 of value is 10 bytes, which is stored as 12
+        let estimate =
+            estimate_erase_rate::<MockFlashBig>(flash_range.len() as u32, 10, STORES as f32)
+                .unwrap();
+        assert_eq!(estimate.erases_per_period, STORES as f32 / 84.0);
+        assert_eq!(
+            estimate.erases_per_page_per_period,
+            estimate.erases_per_period / 4.0
+        );
+
+        for i in 0..STORES {
+            store_item(
+                &mut flash,
+                flash_range.clone(),
+                MockStorageItem {
+                    key: 0,
+                    value: vec![i as u8; 8],
+                },
+            )
+            .unwrap();
+        }
+
+        // The first pages are filled without erasing anything
+        let error = (flash.erases as f32 - estimate.erases_per_period).abs();
+        assert!(
+            error <= 4.0,
+            "{} vs {}",
+            flash.erases,
+            estimate.erases_per_period
+        );
+
+        assert_eq!(estimate_erase_rate::<MockFlashTiny>(0x40, 31, 1.0), None);
+    }
+
+    #[test]
+    fn wear_is_spread_evenly() {
+        type MockFlashHuge = mock_flash::MockFlashBase<8, 4, 256>;
+        let mut flash = MockFlashHuge::new();
+        let flash_range = 0x0000..0x2000;
+
+        for i in 0..10_000u32 {
+            store_item(
+                &mut flash,
+                flash_range.clone(),
+                MockStorageItem {
+                    key: (i % 20) as u8,
+                    value: vec![i as u8; 8],
+                },
+            )
+            .unwrap();
+        }
+
+        // The pages are used as a ring, so every page is erased once per round
+        let page_erases = flash.page_erase_counts();
+        assert_eq!(page_erases.len(), 8);
+        assert_eq!(page_erases.iter().sum::<u32>(), flash.erases);
+
+        let min = *page_erases.iter().min().unwrap();
+        let max = *page_erases.iter().max().unwrap();
+        assert!(min > 10, "{page_erases:?}");
+        assert!(max - min <= 1, "{page_erases:?}");
+
+        flash.reset_counters();
+        assert_eq!(flash.page_erase_counts(), &[0; 8]);
+    }
+
+    #[test]
+    fn more_buffer_pages() {
+        type MockFlashHuge = mock_flash::MockFlashBase<8, 4, 256>;
+        let mut flash = MockFlashHuge::new();
+        let flash_range = 0x0000..0x2000;
+
+        let item = |key, value| MockStorageItem {
+            key,
+            value: vec![value; 8],
+        };
+
+        for buffer_pages in [0, 8] {
+            assert_eq!(
+                store_item_with_buffer_pages(
+                    &mut flash,
+                    flash_range.clone(),
+                    item(0, 0),
+                    buffer_pages
+                ),
+                Err(MapError::InvalidFlashGeometry)
+            );
+        }
+        assert_eq!(flash.writes, 0);
+
+        // The amount of open pages right after the partial open page
+        let open_buffer_pages = |flash: &mut MockFlashHuge| {
+            let partial_open_page =
+                find_first_page(flash, flash_range.clone(), 0, PageState::PartialOpen)
+                    .unwrap()
+                    .unwrap();
+            let mut page_index = partial_open_page;
+            let mut open_pages = 0;
+            loop {
+                page_index = next_page::<MockFlashHuge>(flash_range.clone(), page_index);
+                if page_index == partial_open_page
+                    || !get_page_state(flash, flash_range.clone(), page_index)
+                        .unwrap()
+                        .is_open()
+                {
+                    return open_pages;
+                }
+                open_pages += 1;
+            }
+        };
+
+        let mut values = [None; 20];
+        let mut i = 0u32;
+        let mut store = |flash: &mut MockFlashHuge, buffer_pages| {
+            let key = (i % 20) as u8;
+            store_item_with_buffer_pages(
+                flash,
+                flash_range.clone(),
+                item(key, i as u8),
+                buffer_pages,
+            )
+            .unwrap();
+            values[key as usize] = Some(i as u8);
+            i += 1;
+
+            for (key, value) in values.iter().enumerate() {
+                assert_eq!(
+                    fetch_item::<MockStorageItem, _>(flash, flash_range.clone(), key as u8)
+                        .unwrap(),
+                    value.map(|value| item(key as u8, value))
+                );
+            }
+        };
+
+        // Go around the ring a couple of times
+        for _ in 0..2000 {
+            store(&mut flash, 3);
+            assert!(open_buffer_pages(&mut flash) >= 3);
+        }
+
+        // Fewer buffer pages fill up the extra ones
+        for _ in 0..1000 {
+            store(&mut flash, 1);
+        }
+        assert_eq!(open_buffer_pages(&mut flash), 1);
+
+        // And more buffer pages are opened again one move to a new page at a time
+        for _ in 0..200 {
+            store(&mut flash, 3);
+        }
+        assert!(open_buffer_pages(&mut flash) >= 3);
+    }
+
+    #[test]
+    fn detect_adjacent_duplicates() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        assert_eq!(
+            count_adjacent_duplicates::<MockStorageItem, _>(&mut flash, flash_range.clone()),
+            Ok(0)
+        );
+
+        let item = |key, value| MockStorageItem {
+            key,
+            value: vec![value; 3],
+        };
+
+        store_item(&mut flash, flash_range.clone(), item(0, 1)).unwrap();
+        store_item(&mut flash, flash_range.clone(), item(1, 1)).unwrap();
+        store_item(&mut flash, flash_range.clone(), item(0, 1)).unwrap();
+        assert_eq!(
+            count_adjacent_duplicates::<MockStorageItem, _>(&mut flash, flash_range.clone()),
+            Ok(0)
+        );
+
+        // The same item twice in a row
+        store_item(&mut flash, flash_range.clone(), item(0, 1)).unwrap();
+        assert_eq!(
+            count_adjacent_duplicates::<MockStorageItem, _>(&mut flash, flash_range.clone()),
+            Ok(1)
+        );
+
+        // Same key, but another value
+        store_item(&mut flash, flash_range.clone(), item(0, 2)).unwrap();
+        assert_eq!(
+            count_adjacent_duplicates::<MockStorageItem, _>(&mut flash, flash_range.clone()),
+            Ok(1)
+        );
+
+        assert_eq!(
+            fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0),
+            Ok(Some(item(0, 2)))
+        );
+    }
+
+    #[test]
+    fn copy_live_extent() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        assert_eq!(
+            live_extent::<MockStorageItem, _>(&mut flash, flash_range.clone()),
+            Ok(None)
+        );
+
+        store_item(
+            &mut flash,
+            flash_range.clone(),
+            MockStorageItem {
+                key: 0xAA,
+                value: vec![0xAA; 4],
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            live_extent::<MockStorageItem, _>(&mut flash, flash_range.clone()),
+            Ok(Some(LiveExtent {
+                oldest_page: 0,
+                newest_page: 0,
+                page_count: 1
+            }))
+        );
+
+        for i in 0..500 {
+            store_item(
+                &mut flash,
+                flash_range.clone(),
+                MockStorageItem {
+                    key: (i % 5) as u8,
+                    value: vec![i as u8; 20],
+                },
+            )
+            .unwrap();
+        }
+
+        let extent = live_extent::<MockStorageItem, _>(&mut flash, flash_range.clone())
+            .unwrap()
+            .unwrap();
+        // The key stored first has been moved along with the pages, the others are all on the newest page
+        assert!(extent.page_count < 4, "{extent:?}");
+
+        let mut copy = MockFlashBig::new();
+        for page in extent.pages::<MockFlashBig>(flash_range.clone()) {
+            let page_range = page * 0x400..(page + 1) * 0x400;
+            copy.as_bytes_mut()[page_range.clone()].copy_from_slice(&flash.as_bytes()[page_range]);
+        }
+
+        for key in [0, 1, 2, 3, 4, 0xAA] {
+            assert_eq!(
+                fetch_item::<MockStorageItem, _>(&mut copy, flash_range.clone(), key),
+                fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), key),
+            );
+        }
+        store_item(
+            &mut copy,
+            flash_range.clone(),
+            MockStorageItem {
+                key: 0,
+                value: vec![0; 4],
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn last_used_page_with_wrapping_open_pages() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        // Page 1 and 2 are closed, page 3 and 0 are open
+        close_page(&mut flash, flash_range.clone(), 1).unwrap();
+        close_page(&mut flash, flash_range.clone(), 2).unwrap();
+
+        assert_eq!(
+            find_last_used_page(&mut flash, flash_range.clone()),
+            Ok(Some(2))
+        );
+
+        store_item(
+            &mut flash,
+            flash_range.clone(),
+            MockStorageItem {
+                key: 0,
+                value: vec![1],
+            },
+        )
+        .unwrap();
+
+        // The page after the last used page must be used
+        assert_eq!(
+            get_page_state(&mut flash, flash_range.clone(), 3),
+            Ok(PageState::PartialOpen)
+        );
+        assert_eq!(
+            get_page_state(&mut flash, flash_range.clone(), 0),
+            Ok(PageState::Open)
+        );
+    }
+
+    #[test]
+    fn store_with_size_limit() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        // Key 0 may be 2 + 4 bytes, the other keys 2 + 16 bytes
+        let max_len = |key: &u8| if *key == 0 { 6 } else { 18 };
+
+        store_item_with_size_limit(
+            &mut flash,
+            flash_range.clone(),
+            MockStorageItem {
+                key: 0,
+                value: vec![0; 4],
+            },
+            max_len,
+        )
+        .unwrap();
+        store_item_with_size_limit(
+            &mut flash,
+            flash_range.clone(),
+            MockStorageItem {
+                key: 1,
+                value: vec![1; 16],
+            },
+            max_len,
+        )
+        .unwrap();
+
+        let writes = flash.writes;
+        assert_eq!(
+            store_item_with_size_limit(
+                &mut flash,
+                flash_range.clone(),
+                MockStorageItem {
+                    key: 0,
+                    value: vec![2; 5],
+                },
+                max_len,
+            ),
+            Err(MapError::ItemTooBig)
+        );
+        assert_eq!(flash.writes, writes);
+
+        assert_eq!(
+            fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0)
+                .unwrap()
+                .unwrap()
+                .value,
+            vec![0; 4]
+        );
+
+        // Even an item that is too big for the page or the buffer is too big for its limit first
+        assert_eq!(
+            store_item_with_size_limit(
+                &mut flash,
+                flash_range.clone(),
+                MockStorageItem {
+                    key: 1,
+                    value: vec![1; 2000],
+                },
+                max_len,
+            ),
+            Err(MapError::ItemTooBig)
+        );
+        assert_eq!(flash.writes, writes);
+    }
+
+    #[test]
+    fn size_limit_serializes_like_a_store() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        static SERIALIZATIONS: AtomicUsize = AtomicUsize::new(0);
+
+        #[derive(Debug)]
+        struct CountedItem(MockStorageItem);
+
+        impl StorageItem for CountedItem {
+            type Key = u8;
+
+            type Error = MockStorageItemError;
+
+            fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+                SERIALIZATIONS.fetch_add(1, Ordering::Relaxed);
+                self.0.serialize_into(buffer)
+            }
+
+            fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error> {
+                MockStorageItem::deserialize_from(buffer).map(|(item, len)| (Self(item), len))
+            }
+
+            fn key(&self) -> Self::Key {
+                self.0.key
+            }
+        }
+
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+        let item = || {
+            CountedItem(MockStorageItem {
+                key: 0,
+                value: vec![0; 10],
+            })
+        };
+
+        store_item(&mut flash, flash_range.clone(), item()).unwrap();
+        let store_serializations = SERIALIZATIONS.swap(0, Ordering::Relaxed);
+
+        store_item_with_size_limit(&mut flash, flash_range.clone(), item(), |_| 12).unwrap();
+        assert_eq!(SERIALIZATIONS.load(Ordering::Relaxed), store_serializations);
+    }
+
+    #[test]
+    fn erase_budget() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let store = |flash: &mut MockFlashBig, count| {
+            for i in 0..count {
+                store_item(
+                    flash,
+                    flash_range.clone(),
+                    MockStorageItem {
+                        key: (i % 4) as u8,
+                        value: vec![i as u8; 10],
+                    },
+                )
+                .unwrap();
+            }
+        };
+
+        // Nothing needs to be erased while the flash fills up for the first time
+        flash.assert_erases_under(1, |flash| store(flash, 200));
+
+        flash.reset_counters();
+        assert_eq!((flash.erases, flash.reads, flash.writes), (0, 0, 0));
+
+        // A page holds 84 items of 12 bytes and a full page triggers an erase
+        flash.assert_erases_under(1000 / 84 + 2, |flash| store(flash, 1000));
+    }
+
+    #[test]
+    #[should_panic]
+    fn erase_budget_exceeded() {
+        let mut flash = MockFlashBig::new();
+
+        flash.assert_erases_under(1, |flash| {
+            recover_by_erase(flash, 0x000..0x1000).unwrap();
+        });
+    }
+
+    #[test]
+    fn fetch_with_age() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        assert_eq!(
+            fetch_item_with_age::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0).unwrap(),
+            None
+        );
+
+        let item = |key| MockStorageItem {
+            key,
+            value: vec![key; 10],
+        };
+
+        store_item(&mut flash, flash_range.clone(), item(0)).unwrap();
+        assert_eq!(
+            fetch_item_with_age::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0).unwrap(),
+            Some((item(0), 0))
+        );
+
+        // A page holds 84 items of 12 bytes, so this fills up the first two pages
+        for _ in 0..84 * 2 - 1 {
+            store_item(&mut flash, flash_range.clone(), item(1)).unwrap();
+        }
+        assert_eq!(
+            fetch_item_with_age::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0).unwrap(),
+            Some((item(0), 1))
+        );
+
+        store_item(&mut flash, flash_range.clone(), item(1)).unwrap();
+        assert_eq!(
+            fetch_item_with_age::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0).unwrap(),
+            Some((item(0), 2))
+        );
+        assert_eq!(
+            fetch_item_with_age::<MockStorageItem, _>(&mut flash, flash_range.clone(), 1).unwrap(),
+            Some((item(1), 0))
+        );
+
+        // Fill up the rest so the first page gets erased and the item is copied forward
+        for _ in 0..84 * 2 {
+            store_item(&mut flash, flash_range.clone(), item(1)).unwrap();
+        }
+        let (fetched, age) =
+            fetch_item_with_age::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0)
+                .unwrap()
+                .unwrap();
+        assert_eq!(fetched, item(0));
+        assert!(age < 2, "{age}");
+    }
+
+    #[test]
+    fn geometry_mismatch() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        store_item(
+            &mut flash,
+            flash_range.clone(),
+            MockStorageItem {
+                key: 0,
+                value: vec![5; 10],
+            },
+        )
+        .unwrap();
+
+        // The spare bytes of the start marker hold the write size and the low bytes of the erase size
+        assert_eq!(flash.as_bytes()[0..4], [MARKER, 4, 0x00, 0x04]);
+
+        // The same bytes, but read by firmware that thinks the write size is 2
+        let mut other_write_size = mock_flash::MockFlashBase::<4, 2, 512>::new();
+        other_write_size
+            .as_bytes_mut()
+            .copy_from_slice(flash.as_bytes());
+
+        assert_eq!(
+            fetch_item::<MockStorageItem, _>(&mut other_write_size, flash_range.clone(), 0),
+            Err(MapError::GeometryMismatch)
+        );
+        assert_eq!(
+            store_item(
+                &mut other_write_size,
+                flash_range.clone(),
+                MockStorageItem {
+                    key: 0,
+                    value: vec![6; 10],
+                },
+            ),
+            Err(MapError::GeometryMismatch)
+        );
+
+        // The same bytes, but read by firmware that thinks the erase size is half as big
+        let mut other_erase_size = mock_flash::MockFlashBase::<8, 4, 128>::new();
+        other_erase_size
+            .as_bytes_mut()
+            .copy_from_slice(flash.as_bytes());
+
+        assert_eq!(
+            fetch_item::<MockStorageItem, _>(&mut other_erase_size, flash_range.clone(), 0),
+            Err(MapError::GeometryMismatch)
+        );
+
+        // The original geometry still reads fine
+        assert_eq!(
+            fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0)
+                .unwrap()
+                .unwrap()
+                .value,
+            vec![5; 10]
+        );
+
+        // A page written before the stamp existed has only markers in its start marker word
+        flash.as_bytes_mut()[1..4].fill(MARKER);
+        assert_eq!(
+            fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0)
+                .unwrap()
+                .unwrap()
+                .value,
+            vec![5; 10]
+        );
+        assert_eq!(preflight(&mut flash, flash_range.clone()), Ok(()));
+    }
+
+    #[test]
+    fn pack_items_in_one_buffer() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let mut out = [0; 64];
+        assert_eq!(
+            pack_items::<MockStorageItem, _, 2>(&mut flash, flash_range.clone(), &[0, 1], &mut out)
+                .unwrap(),
+            PackLayout { items: [None; 2] }
+        );
+
+        // Store enough to span multiple pages, with older values of the keys on older pages
+        for i in 0..300u32 {
+            store_item(
+                &mut flash,
+                flash_range.clone(),
+                MockStorageItem {
+                    key: (i % 3) as u8,
+                    value: vec![i as u8; (i % 3) as usize + 1],
+                },
+            )
+            .unwrap();
+        }
+
+        let layout = pack_items::<MockStorageItem, _, 4>(
+            &mut flash,
+            flash_range.clone(),
+            &[2, 9, 0, 1],
+            &mut out,
+        )
+        .unwrap();
+
+        assert_eq!(layout.items[1], None);
+        assert_eq!(layout.used_bytes(), 5 + 3 + 4);
+
+        for (index, key) in [(0, 2), (2, 0), (3, 1)] {
+            let packed = layout.items[index].unwrap();
+            let expected = fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), key)
+                .unwrap()
+                .unwrap();
+
+            let mut expected_bytes = [0; 64];
+            let expected_len = expected.serialize_into(&mut expected_bytes).unwrap();
+
+            assert_eq!(
+                out[packed.offset..][..packed.len],
+                expected_bytes[..expected_len]
+            );
+        }
+
+        assert_eq!(
+            pack_items::<MockStorageItem, _, 3>(
+                &mut flash,
+                flash_range.clone(),
+                &[0, 1, 2],
+                &mut out[..8]
+            ),
+            Err(MapError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn preflight_checks() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        preflight(&mut flash, flash_range.clone()).unwrap();
+
+        for i in 0..200 {
+            store_item(
+                &mut flash,
+                flash_range.clone(),
+                MockStorageItem {
+                    key: (i % 5) as u8,
+                    value: vec![i as u8; 10],
+                },
+            )
+            .unwrap();
+        }
+        preflight(&mut flash, flash_range.clone()).unwrap();
+
+        assert_eq!(
+            preflight(&mut flash, 0x000..0x400),
+            Err(MapError::InvalidFlashGeometry)
+        );
+        assert_eq!(
+            preflight(&mut flash, 0x004..0x1000),
+            Err(MapError::InvalidFlashGeometry)
+        );
+        assert_eq!(
+            preflight(
+                &mut mock_flash::MockFlashBase::<4, 16, 2>::new(),
+                0x00..0x80
+            ),
+            Err(MapError::InvalidFlashGeometry)
+        );
+
+        // The buffer page after the partial open page (page 2) is no longer open
+        let mut corrupted = flash.clone();
+        corrupted.as_bytes_mut()[0xC00..0xC04].fill(MARKER);
+        assert_eq!(
+            preflight(&mut corrupted, flash_range.clone()),
+            Err(MapError::Corrupted)
+        );
+
+        // Two partial open pages
+        let mut corrupted = MockFlashBig::new();
+        corrupted.as_bytes_mut()[0x000..0x004].fill(MARKER);
+        corrupted.as_bytes_mut()[0x800..0x804].fill(MARKER);
+        assert_eq!(
+            preflight(&mut corrupted, flash_range.clone()),
+            Err(MapError::Corrupted)
+        );
+
+        // No open pages
+        let mut corrupted = MockFlashBig::new();
+        for page in corrupted.as_bytes_mut().chunks_mut(0x400) {
+            page[..4].fill(MARKER);
+            page[0x400 - 4..].fill(MARKER);
+        }
+        assert_eq!(
+            preflight(&mut corrupted, flash_range.clone()),
+            Err(MapError::Corrupted)
+        );
+
+        // A page with another geometry
+        let mut corrupted = flash.clone();
+        corrupted.as_bytes_mut()[0x001] = 2;
+        assert_eq!(
+            preflight(&mut corrupted, flash_range.clone()),
+            Err(MapError::GeometryMismatch)
+        );
+
+        // Nothing was written or erased
+        assert_eq!(corrupted.erases, flash.erases);
+        assert_eq!(corrupted.writes, flash.writes);
+    }
+
+    /// A flash with pages of 100 bytes and a write size of 8, so the pages don't end on a word boundary
+    #[derive(Debug)]
+    struct OddPageFlash {
+        bytes: Vec<u8>,
+    }
+
+    impl embedded_storage::nor_flash::ErrorType for OddPageFlash {
+        type Error = mock_flash::MockFlashError;
+    }
+
+    impl embedded_storage::nor_flash::ReadNorFlash for OddPageFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let data = self
+                .bytes
+                .get(offset as usize..offset as usize + bytes.len())
+                .ok_or(mock_flash::MockFlashError::OutOfBounds)?;
+            bytes.copy_from_slice(data);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.bytes.len()
+        }
+    }
+
+    impl embedded_storage::nor_flash::NorFlash for OddPageFlash {
+        const WRITE_SIZE: usize = 8;
+        const ERASE_SIZE: usize = 100;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            if (from as usize) % Self::ERASE_SIZE != 0 || (to as usize) % Self::ERASE_SIZE != 0 {
+                return Err(mock_flash::MockFlashError::NotAligned);
+            }
+
+            self.bytes
+                .get_mut(from as usize..to as usize)
+                .ok_or(mock_flash::MockFlashError::OutOfBounds)?
+                .fill(0xFF);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            if (offset as usize) % Self::WRITE_SIZE != 0 || bytes.len() % Self::WRITE_SIZE != 0 {
+                return Err(mock_flash::MockFlashError::NotAligned);
+            }
+
+            // A write may never cross a page boundary
+            let page_end = (offset as usize / Self::ERASE_SIZE + 1) * Self::ERASE_SIZE;
+            if offset as usize + bytes.len() > page_end {
+                return Err(mock_flash::MockFlashError::OutOfBounds);
+            }
+
+            let data = self
+                .bytes
+                .get_mut(offset as usize..offset as usize + bytes.len())
+                .ok_or(mock_flash::MockFlashError::OutOfBounds)?;
+
+            for (target, source) in data.iter_mut().zip(bytes) {
+                if *target != 0xFF {
+                    return Err(mock_flash::MockFlashError::NotWritable(offset));
+                }
+                *target = *source;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn erase_size_not_a_multiple_of_write_size() {
+        let mut flash = OddPageFlash {
+            bytes: vec![0xFF; 400],
+        };
+        let flash_range = 0..400;
+
+        preflight(&mut flash, flash_range.clone()).unwrap();
+
+        for i in 0..100u32 {
+            store_item(
+                &mut flash,
+                flash_range.clone(),
+                MockStorageItem {
+                    key: (i % 4) as u8,
+                    value: vec![i as u8; (i % 4) as usize + 3],
+                },
+            )
+            .unwrap();
+
+            for key in 0..4.min(i as u8 + 1) {
+                let value = i as u8 - (i as u8 - key) % 4;
+                assert_eq!(
+                    fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), key)
+                        .unwrap()
+                        .unwrap()
+                        .value,
+                    vec![value; key as usize + 3]
+                );
+            }
+        }
+
+        preflight(&mut flash, flash_range.clone()).unwrap();
+
+        // The second page starts at 100, so its start marker is at the first aligned word of 104
+        assert!(flash.bytes[100..104].iter().all(|byte| *byte == 0xFF));
+    }
+
+    #[test]
+    fn page_close_op_log() {
+        use mock_flash::FlashOp;
+
+        let mut flash = MockFlashTiny::new();
+        let flash_range = 0x00..0x40;
+
+        let item = |value| MockStorageItem {
+            key: 0,
+            value: vec![value; 3],
+        };
+
+        // The data of a page is 30 bytes, so 6 items of 5 bytes fill it up
+        for value in 0..6 {
+            store_item(&mut flash, flash_range.clone(), item(value)).unwrap();
+        }
+
+        flash.record_ops(true);
+        store_item(&mut flash, flash_range.clone(), item(6)).unwrap();
+
+        let changes = flash
+            .take_op_log()
+            .into_iter()
+            .filter(|op| !matches!(op, FlashOp::Read { .. }))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            changes,
+            [
+                // Close the end of the full page
+                FlashOp::Write {
+                    address: 0x1F,
+                    len: 1
+                },
+                // Copy the newest value from the page that becomes the buffer page
+                FlashOp::Write {
+                    address: 0x21,
+                    len: 5
+                },
+                FlashOp::Erase {
+                    address: 0x00,
+                    len: 0x20
+                },
+                // Open the next page
+                FlashOp::Write {
+                    address: 0x20,
+                    len: 1
+                },
+                // Store the new item
+                FlashOp::Write {
+                    address: 0x26,
+                    len: 5
+                },
+            ]
+        );
+
+        flash.record_ops(false);
+        fetch_item::<MockStorageItem, _>(&mut flash, flash_range, 0).unwrap();
+        assert!(flash.take_op_log().is_empty());
+    }
+
+    #[test]
+    fn fetch_diagnostic() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let diagnostic =
+            fetch_item_diagnostic::<MockStorageItem, _, 4>(&mut flash, flash_range.clone(), 0)
+                .unwrap();
+        assert_eq!(diagnostic.item, None);
+        assert!(diagnostic.versions.is_empty());
+
+        // 84 items of 12 bytes fit in a page, so key 0 is stored on the first two pages
+        for i in 0..100u32 {
+            store_item(
+                &mut flash,
+                flash_range.clone(),
+                MockStorageItem {
+                    key: (i % 90) as u8,
+                    value: vec![i as u8; 10],
+                },
+            )
+            .unwrap();
+        }
+
+        let diagnostic =
+            fetch_item_diagnostic::<MockStorageItem, _, 4>(&mut flash, flash_range.clone(), 0)
+                .unwrap();
+
+        assert_eq!(diagnostic.item.unwrap().value, vec![90; 10]);
+        assert_eq!(diagnostic.version_count, 2);
+        assert_eq!(
+            diagnostic.versions.as_slice(),
+            [
+                ItemVersion {
+                    address: 0x004,
+                    page: 0
+                },
+                ItemVersion {
+                    address: 0x404 + 6 * 12,
+                    page: 1
+                },
+            ]
+        );
+        assert_eq!(
+            diagnostic.newest_address,
+            Some(diagnostic.versions.last().unwrap().address)
+        );
+
+        // Only the newest version is kept when the list is full
+        let diagnostic =
+            fetch_item_diagnostic::<MockStorageItem, _, 1>(&mut flash, flash_range.clone(), 0)
+                .unwrap();
+        assert_eq!(diagnostic.version_count, 2);
+        assert_eq!(diagnostic.versions[0].page, 1);
+    }
+
+    #[test]
+    fn fetch_history() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let history = |flash: &mut MockFlashBig, key| {
+            fetch_item_history::<MockStorageItem, _>(flash, flash_range.clone(), key)
+                .unwrap()
+                .map(|version| version.unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert!(history(&mut flash, 0).is_empty());
+
+        // 84 items of 12 bytes fit in a page, so the versions are spread over two pages
+        for i in 0..100u32 {
+            store_item(
+                &mut flash,
+                flash_range.clone(),
+                MockStorageItem {
+                    key: (i % 20) as u8,
+                    value: vec![(i / 20) as u8; 10],
+                },
+            )
+            .unwrap();
+        }
+
+        let versions = history(&mut flash, 3);
+        assert_eq!(
+            versions
+                .iter()
+                .map(|(item, _)| item.value[0])
+                .collect::<Vec<_>>(),
+            [0, 1, 2, 3, 4]
+        );
+        assert_eq!(
+            versions[0].1,
+            RecordId {
+                address: 0x004 + 3 * 12
+            }
+        );
+        assert!(versions.windows(2).all(|pair| pair[0].1 != pair[1].1));
+        assert!(history(&mut flash, 20).is_empty());
+
+        // Go around the ring, so the oldest versions are erased
+        for i in 100..1000u32 {
+            store_item(
+                &mut flash,
+                flash_range.clone(),
+                MockStorageItem {
+                    key: (i % 20) as u8,
+                    value: vec![(i / 20) as u8; 10],
+                },
+            )
+            .unwrap();
+        }
+
+        let values = history(&mut flash, 3)
+            .into_iter()
+            .map(|(item, _)| item.value[0])
+            .collect::<Vec<_>>();
+        assert!(values.len() < 50, "{values:?}");
+        assert!(
+            values.windows(2).all(|pair| pair[0] < pair[1]),
+            "{values:?}"
+        );
+        assert_eq!(values.last(), Some(&49));
+        assert_eq!(
+            values.len(),
+            fetch_item_diagnostic::<MockStorageItem, _, 0>(&mut flash, flash_range.clone(), 3)
+                .unwrap()
+                .version_count
+        );
+    }
+
+    #[test]
+    fn update_with_closure() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let increment = |item: Option<MockStorageItem>| {
+            let mut item = item.unwrap_or(MockStorageItem {
+                key: 0,
+                value: vec![0],
+            });
+            item.value[0] += 1;
+            Some(item)
+        };
+
+        for _ in 0..3 {
+            assert!(update(&mut flash, flash_range.clone(), 0, increment).unwrap());
+        }
+
+        // The partial open page is only looked up once, so this reads less than a fetch and a store
+        let mut other_flash = flash.clone();
+        flash.reset_counters();
+        other_flash.reset_counters();
+        assert!(update(&mut flash, flash_range.clone(), 0, increment).unwrap());
+        let item =
+            increment(fetch_item(&mut other_flash, flash_range.clone(), 0).unwrap()).unwrap();
+        store_item(&mut other_flash, flash_range.clone(), item).unwrap();
+        assert!(flash.reads < other_flash.reads);
+        assert_eq!(flash.as_bytes(), other_flash.as_bytes());
+
+        assert_eq!(
+            fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0)
+                .unwrap()
+                .unwrap()
+                .value,
+            vec![4]
+        );
+
+        let writes = flash.writes;
+        assert!(!update(
+            &mut flash,
+            flash_range.clone(),
+            0,
+            |item: Option<MockStorageItem>| {
+                assert_eq!(item.unwrap().value, vec![4]);
+                None
+            }
+        )
+        .unwrap());
+        assert_eq!(flash.writes, writes);
+    }
+
+    #[test]
+    fn update_or_remove_with_closure() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let item = |key, value| MockRemovableItem {
+            key,
+            value: Some([value; 10]),
+        };
+        let fetch = |flash: &mut MockFlashBig, key| {
+            fetch_item::<MockRemovableItem, _>(flash, flash_range.clone(), key).unwrap()
+        };
+
+        assert!(
+            update_or_remove(&mut flash, flash_range.clone(), 0, |current| {
+                assert_eq!(current, None);
+                Some(item(0, 1))
+            })
+            .unwrap()
+        );
+        assert_eq!(fetch(&mut flash, 0), Some(item(0, 1)));
+
+        // None removes the value
+        assert!(update_or_remove(
+            &mut flash,
+            flash_range.clone(),
+            0,
+            |current: Option<MockRemovableItem>| {
+                assert_eq!(current, Some(item(0, 1)));
+                None
+            }
+        )
+        .unwrap());
+        assert_eq!(fetch(&mut flash, 0), None);
+
+        // There's nothing left to remove, so nothing is stored
+        flash.reset_counters();
+        assert!(!update_or_remove::<MockRemovableItem, _>(
+            &mut flash,
+            flash_range.clone(),
+            0,
+            |_| None
+        )
+        .unwrap());
+        assert_eq!(flash.writes, 0);
+
+        // A removed key can be updated again
+        assert!(
+            update_or_remove(&mut flash, flash_range.clone(), 0, |current| {
+                assert_eq!(current, None);
+                Some(item(0, 2))
+            })
+            .unwrap()
+        );
+        assert_eq!(fetch(&mut flash, 0), Some(item(0, 2)));
+    }
+
+    #[test]
+    fn compare_maps() {
+        let mut flash_a = MockFlashBig::new();
+        let mut flash_b = mock_flash::MockFlashBase::<4, 1, 1024>::new();
+        let flash_range = 0x000..0x1000;
+
+        let item = |key, value| MockStorageItem {
+            key,
+            value: vec![value; key as usize],
+        };
+
+        assert!(maps_equal::<MockStorageItem, _, _>(
+            &mut flash_a,
+            flash_range.clone(),
+            &mut flash_b,
+            flash_range.clone()
+        )
+        .unwrap());
+
+        // The maps get the same values, but with a different history
+        for i in 0..100u8 {
+            store_item(&mut flash_a, flash_range.clone(), item(i % 10, i)).unwrap();
+        }
+        for key in 0..10 {
+            store_item(&mut flash_b, flash_range.clone(), item(key, 90 + key)).unwrap();
+        }
+
+        let equal = |flash_a: &mut MockFlashBig,
+                     flash_b: &mut mock_flash::MockFlashBase<4, 1, 1024>| {
+            maps_equal::<MockStorageItem, _, _>(
+                flash_a,
+                flash_range.clone(),
+                flash_b,
+                flash_range.clone(),
+            )
+            .unwrap()
+        };
+
+        assert!(equal(&mut flash_a, &mut flash_b));
+
+        // Different value
+        store_item(&mut flash_b, flash_range.clone(), item(3, 0)).unwrap();
+        assert!(!equal(&mut flash_a, &mut flash_b));
+        store_item(&mut flash_b, flash_range.clone(), item(3, 93)).unwrap();
+        assert!(equal(&mut flash_a, &mut flash_b));
+
+        // Extra key in b
+        store_item(&mut flash_b, flash_range.clone(), item(10, 0)).unwrap();
+        assert!(!equal(&mut flash_a, &mut flash_b));
+
+        // Extra key in a
+        store_item(&mut flash_a, flash_range.clone(), item(10, 0)).unwrap();
+        store_item(&mut flash_a, flash_range.clone(), item(11, 0)).unwrap();
+        assert!(!equal(&mut flash_a, &mut flash_b));
+    }
+
+    #[test]
+    fn walk_pages() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        assert_eq!(
+            PageWalker::new(&mut flash, flash_range.clone())
+                .unwrap()
+                .count(),
+            0
+        );
+
+        let store = |flash: &mut MockFlashBig, count| {
+            for i in 0..count {
+                store_item(
+                    flash,
+                    flash_range.clone(),
+                    MockStorageItem {
+                        key: (i % 200) as u8,
+                        value: vec![i as u8; 10],
+                    },
+                )
+                .unwrap();
+            }
+        };
+
+        // 84 items of 12 bytes fit in a page, so this uses pages 0 and 1
+        store(&mut flash, 100);
+        let pages = PageWalker::new(&mut flash, flash_range.clone())
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(pages, [1, 0]);
+
+        // Page 3 is in use now, so page 0 has been erased to serve as the buffer page
+        store(&mut flash, 200);
+        let pages = PageWalker::new(&mut flash, flash_range.clone())
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(pages, [3, 2, 1]);
+
+        // A custom query: count the items with a value of 160 in the newest page
+        let mut page_walker = PageWalker::new(&mut flash, flash_range.clone()).unwrap();
+        let newest_page = page_walker.next().unwrap().unwrap();
+        let count = page_walker
+            .page_items::<MockStorageItem>(newest_page, &mut [0; 16])
+            .unwrap()
+            .filter(|item| item.as_ref().unwrap().value[0] == 160)
+            .count();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn store_if_absent() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let item = |value| MockStorageItem {
+            key: 0,
+            value: vec![value],
+        };
+
+        assert!(store_item_if_absent(&mut flash, flash_range.clone(), item(1)).unwrap());
+        store_item(&mut flash, flash_range.clone(), item(2)).unwrap();
+
+        let writes = flash.writes;
+        assert!(!store_item_if_absent(&mut flash, flash_range.clone(), item(3)).unwrap());
+        assert_eq!(flash.writes, writes);
+
+        assert_eq!(
+            fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0).unwrap(),
+            Some(item(2))
+        );
+    }
+
+    #[test]
+    fn store_if_changed() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        // 3 bytes serialized, so there's a byte of padding on flash
+        let item = |value| MockStorageItem {
+            key: 0,
+            value: vec![value],
+        };
+
+        assert!(store_item_if_changed(&mut flash, flash_range.clone(), item(1)).unwrap());
+
+        let writes = flash.writes;
+        for _ in 0..10 {
+            assert!(!store_item_if_changed(&mut flash, flash_range.clone(), item(1)).unwrap());
+        }
+        assert_eq!(flash.writes, writes);
+
+        // Same length, different value
+        assert!(store_item_if_changed(&mut flash, flash_range.clone(), item(2)).unwrap());
+        assert_eq!(
+            fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0).unwrap(),
+            Some(item(2))
+        );
+
+        // Different length
+        let longer_item = || MockStorageItem {
+            key: 0,
+            value: vec![2, 2],
+        };
+        assert!(store_item_if_changed(&mut flash, flash_range.clone(), longer_item()).unwrap());
+
+        let writes = flash.writes;
+        assert!(!store_item_if_changed(&mut flash, flash_range.clone(), longer_item()).unwrap());
+        assert_eq!(flash.writes, writes);
+        assert_eq!(
+            fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0).unwrap(),
+            Some(longer_item())
+        );
+    }
+
+    /// The mock item, but without telling the storage it never starts with 0xFF
+    #[derive(Debug, PartialEq, Eq)]
+    struct UnprobedItem(MockStorageItem);
+
+    impl StorageItem for UnprobedItem {
+        type Key = u8;
+        type Error = MockStorageItemError;
+
+        fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+            self.0.serialize_into(buffer)
+        }
+
+        fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error> {
+            MockStorageItem::deserialize_from(buffer).map(|(item, len)| (Self(item), len))
+        }
+
+        fn key(&self) -> Self::Key {
+            self.0.key
+        }
+    }
+
+    #[test]
+    fn probe_for_erased_bytes() {
+        use mock_flash::FlashOp;
+
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        store_item(
+            &mut flash,
+            flash_range.clone(),
+            MockStorageItem {
+                key: 0,
+                value: vec![0; 10],
+            },
+        )
+        .unwrap();
+
+        let bytes_read = |flash: &mut MockFlashBig, fetch: &dyn Fn(&mut MockFlashBig)| {
+            flash.record_ops(true);
+            fetch(flash);
+            let bytes_read = flash
+                .take_op_log()
+                .iter()
+                .map(|op| match op {
+                    FlashOp::Read { len, .. } => *len,
+                    _ => 0,
+                })
+                .sum::<usize>();
+            flash.record_ops(false);
+            bytes_read
+        };
+
+        let probed = bytes_read(&mut flash, &|flash| {
+            assert!(
+                fetch_item::<MockStorageItem, _>(flash, flash_range.clone(), 1)
+                    .unwrap()
+                    .is_none()
+            );
+        });
+        let unprobed = bytes_read(&mut flash, &|flash| {
+            assert!(fetch_item::<UnprobedItem, _>(flash, flash_range.clone(), 1)
+                .unwrap()
+                .is_none());
+        });
+
+        assert!(probed < 100, "{probed}");
+        assert!(unprobed >= MAX_STORAGE_ITEM_SIZE, "{unprobed}");
+    }
+
+    #[test]
+    fn fetch_through_shared_reference() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        for key in 0..4 {
+            store_item(
+                &mut flash,
+                flash_range.clone(),
+                MockStorageItem {
+                    key,
+                    value: vec![key; 10],
+                },
+            )
+            .unwrap();
+        }
+
+        let reader_a = &flash;
+        let reader_b = &flash;
+
+        for key in 0..4 {
+            let item_a =
+                fetch_item_shared::<MockStorageItem, _>(reader_a, flash_range.clone(), key)
+                    .unwrap();
+            let item_b =
+                fetch_item_shared::<MockStorageItem, _>(reader_b, flash_range.clone(), key)
+                    .unwrap();
+
+            assert_eq!(item_a.unwrap().value, vec![key; 10]);
+            assert_eq!(item_b.unwrap().value, vec![key; 10]);
+        }
+        assert_eq!(
+            fetch_item_shared::<MockStorageItem, _>(reader_a, flash_range.clone(), 4).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn skip_corrupt_record() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let item = |key, value| MockStorageItem {
+            key,
+            value: vec![value; 10],
+        };
+
+        // 84 items of 12 bytes fit in a page, so key 0 gets a value on page 0 and on page 1
+        for i in 0..90u8 {
+            store_item(&mut flash, flash_range.clone(), item(i % 85, i)).unwrap();
+        }
+        // The newest value of key 0 is the second item on page 1. Damage the key of the record before it.
+        // The item has to be read without probing, or the damaged record would look like erased flash.
+        let data_start = 0x400 + 4;
+        flash.as_bytes_mut()[data_start] = 0xFF;
+
+        assert_eq!(
+            fetch_item::<UnprobedItem, _>(&mut flash, flash_range.clone(), 0),
+            Err(MapError::Item(MockStorageItemError::InvalidKey))
+        );
+        assert_eq!(
+            fetch_item_with_policy::<UnprobedItem, _>(
+                &mut flash,
+                flash_range.clone(),
+                0,
+                CorruptRecordPolicy::Abort
+            ),
+            Err(MapError::Item(MockStorageItemError::InvalidKey))
+        );
+
+        // The older value on page 0 can still be found
+        assert_eq!(
+            fetch_item_with_policy::<UnprobedItem, _>(
+                &mut flash,
+                flash_range.clone(),
+                0,
+                CorruptRecordPolicy::SkipRestOfPage
+            )
+            .unwrap()
+            .map(|item| item.0),
+            Some(item(0, 0))
+        );
+    }
+
+    #[test]
+    fn fetch_with_ttl() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let item = |key, value| MockStorageItem {
+            key,
+            value: vec![value; 10],
+        };
+
+        store_item(&mut flash, flash_range.clone(), item(0, 0)).unwrap();
+
+        assert_eq!(
+            fetch_item_with_ttl::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0, 0)
+                .unwrap(),
+            Some(item(0, 0))
+        );
+
+        // 84 items fit in a page, so this fills the first page and puts the last item on the next one
+        for i in 0..84 {
+            store_item(&mut flash, flash_range.clone(), item(1, i)).unwrap();
+        }
+
+        assert_eq!(
+            fetch_item_with_ttl::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0, 0)
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            fetch_item_with_ttl::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0, 1)
+                .unwrap(),
+            Some(item(0, 0))
+        );
+        assert_eq!(
+            fetch_item_with_ttl::<MockStorageItem, _>(&mut flash, flash_range.clone(), 2, 1)
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn quiesce_checks_the_pages() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        // Nothing to do on an empty map
+        quiesce::<MockStorageItem, _>(&mut flash, flash_range.clone()).unwrap();
+        assert_eq!(flash.writes, 0);
+
+        // A partial open page without items is consistent, so nothing is written
+        partial_close_map_page(&mut flash, flash_range.clone(), 0).unwrap();
+        quiesce::<MockStorageItem, _>(&mut flash, flash_range.clone()).unwrap();
+        assert_eq!(flash.writes, 1);
+
+        let item = |key: u8| MockStorageItem {
+            key,
+            value: vec![key; 10],
+        };
+
+        // 84 items fit in a page, so pages 0 and 1 get closed and page 2 is partial open
+        for key in 0..(2 * 84 + 10) {
+            store_item(&mut flash, flash_range.clone(), item(key)).unwrap();
+        }
+
+        // The buffer page isn't open
+        let mut corrupted = flash.clone();
+        close_page(&mut corrupted, flash_range.clone(), 3).unwrap();
+        assert_eq!(
+            quiesce::<MockStorageItem, _>(&mut corrupted, flash_range.clone()),
+            Err(MapError::Corrupted)
+        );
+
+        // Like a store that got interrupted right after closing the partial open page.
+        // Page 3 has to be opened and page 0 has to be recycled to be the new buffer page.
+        close_page(&mut flash, flash_range.clone(), 2).unwrap();
+        assert_eq!(
+            find_partial_open_page(&mut flash, flash_range.clone()),
+            Ok(None)
+        );
+        quiesce::<MockStorageItem, _>(&mut flash, flash_range.clone()).unwrap();
+        assert_eq!(
+            find_partial_open_page(&mut flash, flash_range.clone()),
+            Ok(Some(3))
+        );
+        assert!(get_page_state(&mut flash, flash_range.clone(), 0)
+            .unwrap()
+            .is_open());
+        assert_eq!(preflight(&mut flash, flash_range.clone()), Ok(()));
+
+        // Done now, so a second call doesn't write anything
+        flash.reset_counters();
+        quiesce::<MockStorageItem, _>(&mut flash, flash_range.clone()).unwrap();
+        assert_eq!((flash.writes, flash.erases), (0, 0));
+
+        for key in 0..(2 * 84 + 10) {
+            assert_eq!(
+                fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), key).unwrap(),
+                Some(item(key))
+            );
+        }
+    }
+
+    #[test]
+    fn repair_damaged_markers() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let item = |key: u8| MockStorageItem {
+            key,
+            value: vec![key; 10],
+        };
+
+        // 84 items fit in a page, so pages 0 and 1 get closed and page 2 is partial open
+        for key in 0..(2 * 84 + 10) {
+            store_item(&mut flash, flash_range.clone(), item(key)).unwrap();
+        }
+        let assert_all_keys = |flash: &mut MockFlashBig| {
+            for key in 0..(2 * 84 + 10) {
+                assert_eq!(
+                    fetch_item::<MockStorageItem, _>(flash, flash_range.clone(), key).unwrap(),
+                    Some(item(key))
+                );
+            }
+        };
+
+        // Nothing to repair
+        flash.reset_counters();
+        assert_eq!(try_repair(&mut flash, flash_range.clone()), Ok(0));
+        assert_eq!((flash.writes, flash.erases), (0, 0));
+
+        // A damaged start marker makes page 1 look open, which hides it and the pages before it
+        flash.as_bytes_mut()[0x400] = 0x01;
+        assert_eq!(
+            fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0).unwrap(),
+            None
+        );
+        assert_eq!(try_repair(&mut flash, flash_range.clone()), Ok(0));
+        assert_all_keys(&mut flash);
+
+        // The buffer page got markers, but it has no data so it can be erased
+        close_page(&mut flash, flash_range.clone(), 3).unwrap();
+        assert_eq!(
+            preflight(&mut flash, flash_range.clone()),
+            Err(MapError::Corrupted)
+        );
+        assert_eq!(try_repair(&mut flash, flash_range.clone()), Ok(0));
+        assert_eq!(preflight(&mut flash, flash_range.clone()), Ok(()));
+        assert_all_keys(&mut flash);
+
+        // The partial open page got closed and every page has data,
+        // so the page after the one with the most free space has to go
+        for key in 0..10 {
+            store_item(&mut flash, flash_range.clone(), item(key)).unwrap();
+        }
+        close_page(&mut flash, flash_range.clone(), 2).unwrap();
+        partial_close_map_page(&mut flash, flash_range.clone(), 3).unwrap();
+        let mut buffer = [0; 12];
+        let used_bytes = item(200).serialize_into(&mut buffer).unwrap();
+        flash.write(0xC00 + 4, &buffer[..used_bytes]).unwrap();
+        flash.reset_counters();
+        assert_eq!(try_repair(&mut flash, flash_range.clone()), Ok(1));
+        assert_eq!(flash.erases, 1);
+        assert_eq!(preflight(&mut flash, flash_range.clone()), Ok(()));
+
+        // Page 0 was the oldest page. The keys on the other pages are still there.
+        for key in 84..(2 * 84 + 10) {
+            assert_eq!(
+                fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), key).unwrap(),
+                Some(item(key))
+            );
+        }
+        store_item(&mut flash, flash_range.clone(), item(0)).unwrap();
+        assert_eq!(
+            fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0).unwrap(),
+            Some(item(0))
+        );
+    }
+
+    #[test]
+    fn fetch_pages_scanned() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let item = |key, value| MockStorageItem {
+            key,
+            value: vec![value; 10],
+        };
+
+        assert_eq!(
+            fetch_item_with_pages_scanned::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0)
+                .unwrap(),
+            (None, 0)
+        );
+
+        // 84 items fit in a page, so key 0 ends up on the first page and key 1 fills up the second page
+        store_item(&mut flash, flash_range.clone(), item(0, 0)).unwrap();
+        for i in 0..90 {
+            store_item(&mut flash, flash_range.clone(), item(1, i)).unwrap();
+        }
+
+        assert_eq!(
+            fetch_item_with_pages_scanned::<MockStorageItem, _>(&mut flash, flash_range.clone(), 1)
+                .unwrap(),
+            (Some(item(1, 89)), 1)
+        );
+        assert_eq!(
+            fetch_item_with_pages_scanned::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0)
+                .unwrap(),
+            (Some(item(0, 0)), 2)
+        );
+        assert_eq!(
+            fetch_item_with_pages_scanned::<MockStorageItem, _>(&mut flash, flash_range.clone(), 2)
+                .unwrap(),
+            (None, 2)
+        );
+    }
+
+    #[test]
+    fn fetch_record_id() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let item = |key, value| MockStorageItem {
+            key,
+            value: vec![value; 10],
+        };
+
+        assert_eq!(
+            fetch_item_with_record_id::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0)
+                .unwrap(),
+            None
+        );
+
+        store_item(&mut flash, flash_range.clone(), item(0, 0)).unwrap();
+        let (fetched, record_id) =
+            fetch_item_with_record_id::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0)
+                .unwrap()
+                .unwrap();
+        assert_eq!(fetched, item(0, 0));
+        // The first item comes right after the start marker and the geometry stamp
+        assert_eq!(record_id, RecordId { address: 4 });
+
+        // Other keys don't change the record
+        store_item(&mut flash, flash_range.clone(), item(1, 1)).unwrap();
+        assert_eq!(
+            fetch_item_with_record_id::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0)
+                .unwrap(),
+            Some((item(0, 0), record_id))
+        );
+
+        // Storing the same value again is a new record
+        store_item(&mut flash, flash_range.clone(), item(0, 0)).unwrap();
+        let (_, new_record_id) =
+            fetch_item_with_record_id::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0)
+                .unwrap()
+                .unwrap();
+        assert_ne!(new_record_id, record_id);
+    }
+
+    /// A view of a [MockStorageItem] that only deserializes the key and the length
+    #[derive(Debug, PartialEq, Eq)]
+    struct MockStorageItemHeader {
+        key: u8,
+        value_len: usize,
+    }
+
+    impl StorageItem for MockStorageItemHeader {
+        type Key = u8;
+
+        type Error = MockStorageItemError;
+
+        const MAX_SIZE: usize = 2;
+        const MAX_LEADING_ERASED_BYTES: usize = 0;
+
+        fn serialize_into(&self, _buffer: &mut [u8]) -> Result<usize, Self::Error> {
+            unimplemented!("the header is only read")
+        }
+
+        fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error> {
+            match buffer {
+                [0xFF, ..] => Err(MockStorageItemError::InvalidKey),
+                [key, value_len, ..] => Ok((
+                    Self {
+                        key: *key,
+                        value_len: *value_len as usize,
+                    },
+                    2 + *value_len as usize,
+                )),
+                _ => Err(MockStorageItemError::BufferTooSmall),
+            }
+        }
+
+        fn key(&self) -> Self::Key {
+            self.key
+        }
+    }
+
+    #[test]
+    fn fetch_streaming() {
+        // Reads have to be whole words
+        let mut flash = mock_flash::MockFlashBase::<4, 4, 256, 4>::new();
+        let flash_range = 0x000..0x1000;
+
+        let value = |key: u8, len: usize| (0..len).map(|i| key ^ i as u8).collect::<Vec<_>>();
+
+        for (key, len) in [(0, 0), (1, 5), (2, 200), (1, 255), (3, 13)] {
+            let item = MockStorageItem {
+                key,
+                value: value(key, len),
+            };
+            store_item(&mut flash, flash_range.clone(), item).unwrap();
+        }
+
+        for (key, len) in [(0, 0), (1, 255), (2, 200), (3, 13)] {
+            // The items are a lot bigger than the buffer
+            let (header, mut reader) = fetch_item_streaming::<MockStorageItemHeader, _>(
+                &mut flash,
+                flash_range.clone(),
+                key,
+                &mut [0xFF; 8],
+            )
+            .unwrap()
+            .unwrap();
+            assert_eq!(header.value_len, len);
+            assert_eq!(reader.remaining(), (2 + len).next_multiple_of(4));
+
+            // Read in odd chunks to cross the read boundaries
+            let mut stored = Vec::new();
+            let mut chunk = [0; 3];
+            while reader.remaining() > 0 {
+                let read = reader.read(&mut chunk).unwrap();
+                stored.extend_from_slice(&chunk[..read]);
+            }
+            assert_eq!(reader.read(&mut chunk), Ok(0));
+
+            assert_eq!(stored[..2], [key, len as u8]);
+            assert_eq!(stored[2..][..len], value(key, len));
+            assert!(stored[2 + len..].iter().all(|byte| *byte == 0xFF));
+        }
+
+        assert!(fetch_item_streaming::<MockStorageItemHeader, _>(
+            &mut flash,
+            flash_range.clone(),
+            4,
+            &mut [0xFF; 8],
+        )
+        .unwrap()
+        .is_none());
+    }
+
+    #[test]
+    fn fetch_chunks() {
+        // Reads have to be whole words
+        let mut flash = mock_flash::MockFlashBase::<4, 4, 256, 4>::new();
+        let flash_range = 0x000..0x1000;
+
+        let value = |key: u8, len: usize| (0..len).map(|i| key ^ i as u8).collect::<Vec<_>>();
+
+        for (key, len) in [(0, 0), (1, 5), (2, 200), (1, 255), (3, 13)] {
+            let item = MockStorageItem {
+                key,
+                value: value(key, len),
+            };
+            store_item(&mut flash, flash_range.clone(), item).unwrap();
+        }
+
+        for (key, len) in [(0, 0), (1, 255), (2, 200), (3, 13)] {
+            // An odd buffer size, so the chunks cross the read boundaries
+            let mut chunks = Vec::new();
+            let header = fetch_item_chunks::<MockStorageItemHeader, _>(
+                &mut flash,
+                flash_range.clone(),
+                key,
+                &mut [0xFF; 7],
+                |chunk| {
+                    assert!(!chunk.is_empty() && chunk.len() <= 7);
+                    chunks.push(chunk.to_vec());
+                },
+            )
+            .unwrap()
+            .unwrap();
+            assert_eq!(header.value_len, len);
+
+            // The item without the padding
+            let stored = chunks.concat();
+            assert_eq!(stored.len(), 2 + len);
+            assert_eq!(stored[..2], [key, len as u8]);
+            assert_eq!(stored[2..], value(key, len));
+        }
+
+        assert_eq!(
+            fetch_item_chunks::<MockStorageItemHeader, _>(
+                &mut flash,
+                flash_range.clone(),
+                4,
+                &mut [0xFF; 7],
+                |_| panic!("There's no item to visit"),
+            ),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn fetch_size() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        assert_eq!(
+            fetch_item_with_size::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0).unwrap(),
+            None
+        );
+
+        for len in 0..10 {
+            let item = || MockStorageItem {
+                key: len as u8,
+                value: vec![0xAA; len],
+            };
+            store_item(&mut flash, flash_range.clone(), item()).unwrap();
+            let item = item();
+
+            let mut buffer = [0; 32];
+            let serialized_len = item.serialize_into(&mut buffer).unwrap();
+
+            let (fetched, size) = fetch_item_with_size::<MockStorageItem, _>(
+                &mut flash,
+                flash_range.clone(),
+                item.key,
+            )
+            .unwrap()
+            .unwrap();
+            assert_eq!(fetched, item);
+            assert_eq!(size, serialized_len.next_multiple_of(4), "{len}");
+        }
+
+        // The sizes add up to the space the records take on the page
+        let first_address =
+            fetch_item_with_record_id::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0)
+                .unwrap()
+                .unwrap()
+                .1
+                .address;
+        let last_address =
+            fetch_item_with_record_id::<MockStorageItem, _>(&mut flash, flash_range.clone(), 9)
+                .unwrap()
+                .unwrap()
+                .1
+                .address;
+        let total_size = (0..9)
+            .map(|key| {
+                fetch_item_with_size::<MockStorageItem, _>(&mut flash, flash_range.clone(), key)
+                    .unwrap()
+                    .unwrap()
+                    .1
+            })
+            .sum::<usize>();
+        assert_eq!((last_address - first_address) as usize, total_size);
+    }
+
+    #[test]
+    fn iterate_by_recency() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let item = |i: usize| MockStorageItem {
+            key: (i % 50) as u8,
+            value: vec![i as u8; 10],
+        };
+
+        assert_eq!(
+            iter_by_recency::<MockStorageItem, _>(&mut flash, flash_range.clone())
+                .unwrap()
+                .count(),
+            0
+        );
+
+        // More than fits in one page, but not enough to recycle a page
+        for i in 0..100 {
+            store_item(&mut flash, flash_range.clone(), item(i)).unwrap();
+        }
+
+        let records = iter_by_recency::<MockStorageItem, _>(&mut flash, flash_range.clone())
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(
+            records[0].1,
+            fetch_item_with_record_id::<MockStorageItem, _>(&mut flash, flash_range, 49)
+                .unwrap()
+                .unwrap()
+                .1
+        );
+        assert_eq!(
+            records
+                .into_iter()
+                .map(|(item, _)| item)
+                .collect::<Vec<_>>(),
+            (0..100).rev().map(item).collect::<Vec<_>>()
+        );
+    }
+
+    /// An item with a 10 byte value that can be removed
+    #[derive(Debug, PartialEq, Eq)]
+    struct MockRemovableItem {
+        key: u8,
+        value: Option<[u8; 10]>,
+    }
+
+    impl StorageItem for MockRemovableItem {
+        type Key = u8;
+
+        type Error = MockStorageItemError;
+
+        // The key can't be 0xFF
+        const MAX_LEADING_ERASED_BYTES: usize = 0;
+
+        fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+            if self.key == 0xFF {
+                return Err(MockStorageItemError::InvalidKey);
+            }
+
+            let len = if self.value.is_some() { 12 } else { 2 };
+            if buffer.len() < len {
+                return Err(MockStorageItemError::BufferTooSmall);
+            }
+
+            buffer[0] = self.key;
+            buffer[1] = self.value.is_some() as u8;
+            if let Some(value) = self.value {
+                buffer[2..12].copy_from_slice(&value);
+            }
+
+            Ok(len)
+        }
+
+        fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error> {
+            match buffer {
+                [0xFF, ..] => Err(MockStorageItemError::InvalidKey),
+                [key, 0, ..] => Ok((
+                    Self {
+                        key: *key,
+                        value: None,
+                    },
+                    2,
+                )),
+                [key, 1, value @ ..] if value.len() >= 10 => Ok((
+                    Self {
+                        key: *key,
+                        value: Some(value[..10].try_into().unwrap()),
+                    },
+                    12,
+                )),
+                _ => Err(MockStorageItemError::BufferTooSmall),
+            }
+        }
+
+        fn key(&self) -> Self::Key {
+            self.key
+        }
+
+        fn is_tombstone(&self) -> bool {
+            self.value.is_none()
+        }
+    }
+
+    impl RemovableStorageItem for MockRemovableItem {
+        fn tombstone(key: Self::Key) -> Self {
+            Self { key, value: None }
+        }
+    }
+
+    #[test]
+    fn remove_and_reclaim() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let item = |key, value| MockRemovableItem {
+            key,
+            value: Some([value; 10]),
+        };
+        let fetch = |flash: &mut MockFlashBig, key| {
+            fetch_item::<MockRemovableItem, _>(flash, flash_range.clone(), key).unwrap()
+        };
+
+        store_item(&mut flash, flash_range.clone(), item(0, 0)).unwrap();
+        store_item(&mut flash, flash_range.clone(), item(1, 1)).unwrap();
+
+        assert!(remove_item::<MockRemovableItem, _>(&mut flash, flash_range.clone(), 0).unwrap());
+        assert_eq!(fetch(&mut flash, 0), None);
+        assert_eq!(fetch(&mut flash, 1), Some(item(1, 1)));
+
+        // There's nothing left to remove, so nothing is stored
+        flash.reset_counters();
+        assert!(!remove_item::<MockRemovableItem, _>(&mut flash, flash_range.clone(), 0).unwrap());
+        assert_eq!(flash.writes, 0);
+
+        let layout = pack_items::<MockRemovableItem, _, 2>(
+            &mut flash,
+            flash_range.clone(),
+            &[0, 1],
+            &mut [0; 32],
+        )
+        .unwrap();
+        assert_eq!(layout.items[0], None);
+        assert!(layout.items[1].is_some());
+
+        // A removed key can be stored again
+        store_item(&mut flash, flash_range.clone(), item(2, 2)).unwrap();
+        assert!(remove_item::<MockRemovableItem, _>(&mut flash, flash_range.clone(), 2).unwrap());
+        store_item(&mut flash, flash_range.clone(), item(2, 3)).unwrap();
+        assert_eq!(fetch(&mut flash, 2), Some(item(2, 3)));
+
+        // Go around the ring, so the pages with the removed value and its tombstone get recycled
+        for i in 0..400 {
+            store_item(&mut flash, flash_range.clone(), item(1, i as u8)).unwrap();
+        }
+
+        assert_eq!(fetch(&mut flash, 0), None);
+        assert_eq!(fetch(&mut flash, 2), Some(item(2, 3)));
+        assert!(
+            iter_by_recency::<MockRemovableItem, _>(&mut flash, flash_range.clone())
+                .unwrap()
+                .all(|record| record.unwrap().0.key != 0),
+            "The removed value and its tombstone must have been reclaimed"
+        );
+    }
+
+    #[test]
+    fn contains_removed_key() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let item = |key, value| MockRemovableItem {
+            key,
+            value: Some([value; 10]),
+        };
+        let contains = |flash: &mut MockFlashBig, key| {
+            contains_key::<MockRemovableItem, _>(flash, flash_range.clone(), key).unwrap()
+        };
+
+        assert!(!contains(&mut flash, 0));
+
+        // Key 0 ends up on the first page, key 1 fills up the rest of it and the next page
+        store_item(&mut flash, flash_range.clone(), item(0, 0)).unwrap();
+        for i in 0..100 {
+            store_item(&mut flash, flash_range.clone(), item(1, i)).unwrap();
+        }
+        assert!(contains(&mut flash, 0));
+        assert!(contains(&mut flash, 1));
+        assert!(!contains(&mut flash, 2));
+
+        assert!(remove_item::<MockRemovableItem, _>(&mut flash, flash_range.clone(), 0).unwrap());
+        assert!(!contains(&mut flash, 0));
+
+        store_item(&mut flash, flash_range.clone(), item(0, 1)).unwrap();
+        assert!(contains(&mut flash, 0));
+    }
+
+    /// An item with a 10 byte value that has a flag byte which is cleared when it's removed in place
+    #[derive(Debug, PartialEq, Eq)]
+    struct MockMarkableItem {
+        key: u8,
+        value: [u8; 10],
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum MockMarkableItemError {
+        BufferTooSmall,
+        InvalidFlag,
+        Removed,
+    }
+
+    impl StorageItemError for MockMarkableItemError {
+        fn is_buffer_too_small(&self) -> bool {
+            matches!(self, MockMarkableItemError::BufferTooSmall)
+        }
+
+        fn damaged_record_len(&self) -> Option<usize> {
+            matches!(self, MockMarkableItemError::Removed).then_some(12)
+        }
+    }
+
+    const MOCK_MARKABLE_ITEM_LIVE: u8 = 0xFE;
+    const MOCK_MARKABLE_ITEM_REMOVED: u8 = 0x7E;
+
+    impl StorageItem for MockMarkableItem {
+        type Key = u8;
+
+        type Error = MockMarkableItemError;
+
+        // The flag is never 0xFF
+        const MAX_LEADING_ERASED_BYTES: usize = 0;
+
+        fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+            if buffer.len() < 12 {
+                return Err(MockMarkableItemError::BufferTooSmall);
+            }
+
+            buffer[0] = MOCK_MARKABLE_ITEM_LIVE;
+            buffer[1] = self.key;
+            buffer[2..12].copy_from_slice(&self.value);
+
+            Ok(12)
+        }
+
+        fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error> {
+            if buffer.len() < 12 {
+                return Err(MockMarkableItemError::BufferTooSmall);
+            }
+
+            match buffer[0] {
+                MOCK_MARKABLE_ITEM_LIVE => Ok((
+                    Self {
+                        key: buffer[1],
+                        value: buffer[2..12].try_into().unwrap(),
+                    },
+                    12,
+                )),
+                MOCK_MARKABLE_ITEM_REMOVED => Err(MockMarkableItemError::Removed),
+                _ => Err(MockMarkableItemError::InvalidFlag),
+            }
+        }
+
+        fn key(&self) -> Self::Key {
+            self.key
+        }
+    }
+
+    impl InPlaceRemovableStorageItem for MockMarkableItem {
+        fn mark_removed(record: &mut [u8]) {
+            record[0] &= MOCK_MARKABLE_ITEM_REMOVED;
+        }
+    }
+
+    #[test]
+    fn remove_in_place() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let item = |key, value| MockMarkableItem {
+            key,
+            value: [value; 10],
+        };
+        let fetch = |flash: &mut MockFlashBig, key| {
+            fetch_item::<MockMarkableItem, _>(flash, flash_range.clone(), key).unwrap()
+        };
+        let remove = |flash: &mut MockFlashBig, key| {
+            remove_item_in_place::<MockMarkableItem, _>(flash, flash_range.clone(), key).unwrap()
+        };
+
+        // Key 0 gets a value on the first page and one on the second page, with key 1 in between
+        store_item(&mut flash, flash_range.clone(), item(0, 0)).unwrap();
+        for i in 1..84 {
+            store_item(&mut flash, flash_range.clone(), item(1, i)).unwrap();
+        }
+        store_item(&mut flash, flash_range.clone(), item(0, 1)).unwrap();
+        store_item(&mut flash, flash_range.clone(), item(2, 2)).unwrap();
+
+        // Both values are marked in place, nothing is appended
+        flash.reset_counters();
+        assert!(remove(&mut flash, 0));
+        assert_eq!((flash.writes, flash.erases), (2, 0));
+        assert_eq!(fetch(&mut flash, 0), None);
+        assert_eq!(fetch(&mut flash, 1), Some(item(1, 83)));
+        assert_eq!(fetch(&mut flash, 2), Some(item(2, 2)));
+
+        // There's nothing left to remove, so nothing is written
+        flash.reset_counters();
+        assert!(!remove(&mut flash, 0));
+        assert_eq!(flash.writes, 0);
+
+        // The marked records keep their space and are dropped when their page is recycled
+        store_item(&mut flash, flash_range.clone(), item(0, 3)).unwrap();
+        assert_eq!(fetch(&mut flash, 0), Some(item(0, 3)));
+        assert!(remove(&mut flash, 0));
+        for i in 0..400 {
+            store_item(&mut flash, flash_range.clone(), item(1, i as u8)).unwrap();
+        }
+
+        assert_eq!(fetch(&mut flash, 0), None);
+        assert_eq!(fetch(&mut flash, 2), Some(item(2, 2)));
+        assert_eq!(fetch(&mut flash, 1), Some(item(1, (399 % 256) as u8)));
+    }
+
+    /// A flash that reports writes as done without changing anything, like a flash with stuck bits
+    struct IgnoringWrites<'a>(&'a mut MockFlashBig);
+
+    impl embedded_storage::nor_flash::ErrorType for IgnoringWrites<'_> {
+        type Error = mock_flash::MockFlashError;
+    }
+
+    impl embedded_storage::nor_flash::ReadNorFlash for IgnoringWrites<'_> {
+        const READ_SIZE: usize = MockFlashBig::READ_SIZE;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            self.0.read(offset, bytes)
+        }
+
+        fn capacity(&self) -> usize {
+            self.0.capacity()
+        }
+    }
+
+    impl embedded_storage::nor_flash::NorFlash for IgnoringWrites<'_> {
+        const WRITE_SIZE: usize = MockFlashBig::WRITE_SIZE;
+        const ERASE_SIZE: usize = MockFlashBig::ERASE_SIZE;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            self.0.erase(from, to)
+        }
+
+        fn write(&mut self, _offset: u32, _bytes: &[u8]) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    impl MultiwriteNorFlash for IgnoringWrites<'_> {}
+
+    #[test]
+    fn remove_in_place_write_not_taken() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let item = || MockMarkableItem {
+            key: 0,
+            value: [1; 10],
+        };
+        store_item(&mut flash, flash_range.clone(), item()).unwrap();
+
+        // The marked record reads back as the item, which must not make the removal loop forever
+        assert_eq!(
+            remove_item_in_place::<MockMarkableItem, _>(
+                &mut IgnoringWrites(&mut flash),
+                flash_range.clone(),
+                0
+            ),
+            Err(MapError::Corrupted)
+        );
+        assert_eq!(
+            fetch_item::<MockMarkableItem, _>(&mut flash, flash_range.clone(), 0),
+            Ok(Some(item()))
+        );
+    }
+
+    /// A [MockMarkableItem] whose removal mark changes the value instead of making the map skip the record
+    #[derive(Debug, PartialEq, Eq)]
+    struct BadlyMarkedItem(MockMarkableItem);
+
+    impl StorageItem for BadlyMarkedItem {
+        type Key = u8;
+
+        type Error = MockMarkableItemError;
+
+        fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+            self.0.serialize_into(buffer)
+        }
+
+        fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error> {
+            MockMarkableItem::deserialize_from(buffer).map(|(item, len)| (Self(item), len))
+        }
+
+        fn key(&self) -> Self::Key {
+            self.0.key
+        }
+    }
+
+    impl InPlaceRemovableStorageItem for BadlyMarkedItem {
+        fn mark_removed(record: &mut [u8]) {
+            record[2] = 0;
+        }
+    }
+
+    #[test]
+    fn remove_in_place_bad_mark() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let item = || {
+            BadlyMarkedItem(MockMarkableItem {
+                key: 0,
+                value: [1; 10],
+            })
+        };
+        store_item(&mut flash, flash_range.clone(), item()).unwrap();
+        let writes = flash.writes;
+
+        // The mark is checked before it's written, so it doesn't panic and the value stays
+        assert_eq!(
+            remove_item_in_place::<BadlyMarkedItem, _>(&mut flash, flash_range.clone(), 0),
+            Err(MapError::Corrupted)
+        );
+        assert_eq!(flash.writes, writes);
+        assert_eq!(
+            fetch_item::<BadlyMarkedItem, _>(&mut flash, flash_range.clone(), 0),
+            Ok(Some(item()))
+        );
+    }
+
+    #[test]
+    fn fetch_all_live_items() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let item = |key, value| MockRemovableItem {
+            key,
+            value: Some([value; 10]),
+        };
+        let fetch_all = |flash: &mut MockFlashBig| {
+            fetch_all_items::<MockRemovableItem, _>(flash, flash_range.clone())
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+
+        assert_eq!(fetch_all(&mut flash), vec![]);
+
+        // Enough versions to spread the keys over multiple pages and recycle some
+        for i in 0..300u32 {
+            store_item(
+                &mut flash,
+                flash_range.clone(),
+                item((i % 7) as u8, i as u8),
+            )
+            .unwrap();
+        }
+        assert!(remove_item::<MockRemovableItem, _>(&mut flash, flash_range.clone(), 3).unwrap());
+
+        let mut all_items = fetch_all(&mut flash);
+        assert_eq!(fetch_all(&mut flash), all_items);
+
+        all_items.sort_by_key(|item| item.key);
+        let expected = [0, 1, 2, 4, 5, 6]
+            .into_iter()
+            .map(|key| {
+                fetch_item::<MockRemovableItem, _>(&mut flash, flash_range.clone(), key)
+                    .unwrap()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(all_items, expected);
+    }
+
+    #[test]
+    fn fetch_all_live_items_with_keys() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let item = |key, value| MockRemovableItem {
+            key,
+            value: Some([value; 10]),
+        };
+
+        // Keys that are only stored once, so they end up in older pages and get copied forward
+        for key in 10..40 {
+            store_item(&mut flash, flash_range.clone(), item(key, key)).unwrap();
+        }
+        for i in 0..300u32 {
+            store_item(
+                &mut flash,
+                flash_range.clone(),
+                item((i % 7) as u8, i as u8),
+            )
+            .unwrap();
+        }
+        assert!(remove_item::<MockRemovableItem, _>(&mut flash, flash_range.clone(), 3).unwrap());
+        assert!(remove_item::<MockRemovableItem, _>(&mut flash, flash_range.clone(), 20).unwrap());
+
+        flash.reset_counters();
+        let all_items = fetch_all_items::<MockRemovableItem, _>(&mut flash, flash_range.clone())
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let rescan_reads = flash.reads;
+        assert_eq!(all_items.len(), 7 - 1 + 30 - 1);
+
+        // All keys fit, so the items don't have to be looked up in the older pages
+        flash.reset_counters();
+        assert_eq!(
+            fetch_all_items_with_keys::<MockRemovableItem, _, 64>(&mut flash, flash_range.clone())
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            all_items
+        );
+        assert!(flash.reads < rescan_reads);
+
+        // The keys of the first page don't fit, so the older pages are looked up like without keys
+        assert_eq!(
+            fetch_all_items_with_keys::<MockRemovableItem, _, 4>(&mut flash, flash_range.clone())
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            all_items
+        );
+    }
+
+    #[test]
+    fn stats_of_storage() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+        // The page minus the markers
+        const PAGE_DATA_SIZE: usize = 1024 - 4 - 4;
+
+        let item = |key| MockStorageItem {
+            key,
+            value: vec![key; 10],
+        };
+
+        assert_eq!(
+            storage_stats(&mut flash, flash_range.clone()).unwrap(),
+            StorageStats {
+                open_pages: 4,
+                partial_open_pages: 0,
+                closed_pages: 0,
+                used_bytes: 0,
+                // The second page is the buffer page of the first store
+                free_bytes: 3 * PAGE_DATA_SIZE,
+            }
+        );
+
+        for i in 0..10 {
+            store_item(&mut flash, flash_range.clone(), item(i)).unwrap();
+        }
+        assert_eq!(
+            storage_stats(&mut flash, flash_range.clone()).unwrap(),
+            StorageStats {
+                open_pages: 3,
+                partial_open_pages: 1,
+                closed_pages: 0,
+                used_bytes: 10 * 12,
+                free_bytes: 3 * PAGE_DATA_SIZE - 10 * 12,
+            }
+        );
+
+        // Fill the map up to the last item that fits before the buffer page has to be used
+        for i in 0..(3 * 84 - 10 - 1) {
+            store_item(&mut flash, flash_range.clone(), item((i % 100) as u8)).unwrap();
+        }
+        let stats = storage_stats(&mut flash, flash_range.clone()).unwrap();
+        assert_eq!(
+            (
+                stats.open_pages,
+                stats.partial_open_pages,
+                stats.closed_pages
+            ),
+            (1, 1, 2)
+        );
+        assert_eq!(stats.used_bytes, 2 * PAGE_DATA_SIZE + 83 * 12);
+        // The last open page is the buffer page
+        assert_eq!(stats.used_bytes + stats.free_bytes, 3 * PAGE_DATA_SIZE);
+
+        // The free bytes are enough for exactly one more item before a page is erased
+        assert_eq!(stats.free_bytes / 12, 1);
+        let erases = flash.erases;
+        store_item(&mut flash, flash_range.clone(), item(0)).unwrap();
+        assert_eq!(flash.erases, erases);
+        store_item(&mut flash, flash_range.clone(), item(1)).unwrap();
+        assert_eq!(flash.erases, erases + 1);
+    }
+
+    #[test]
+    fn store_and_fetch_with_buffer() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        // The biggest item is 12 bytes, which is a whole amount of words
+        const ITEM_SIZE: usize = 12;
+        let item = |key, value| MockStorageItem {
+            key,
+            value: vec![value; ITEM_SIZE - 2],
+        };
+
+        assert_eq!(
+            store_item_with_buffer(
+                &mut flash,
+                flash_range.clone(),
+                item(0, 0),
+                &mut [0; ITEM_SIZE - 1]
+            ),
+            Err(MapError::BufferTooSmall)
+        );
+
+        // Go around the ring, so old pages are cleared with the buffer too
+        let mut buffer = [0; ITEM_SIZE];
+        for i in 0..400 {
+            store_item_with_buffer(
+                &mut flash,
+                flash_range.clone(),
+                item((i % 20) as u8, i as u8),
+                &mut buffer,
+            )
+            .unwrap();
+        }
+
+        let mut buffer = [0; ITEM_SIZE];
+        for key in 0..20 {
+            assert_eq!(
+                fetch_item_with_buffer::<MockStorageItem, _>(
+                    &mut flash,
+                    flash_range.clone(),
+                    key,
+                    &mut buffer
+                )
+                .unwrap(),
+                fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), key).unwrap()
+            );
+        }
+        assert_eq!(
+            fetch_item_with_buffer::<MockStorageItem, _>(
+                &mut flash,
+                flash_range.clone(),
+                0,
+                &mut buffer[..ITEM_SIZE - 1]
+            ),
+            Err(MapError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn item_bigger_than_default_max_size() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        type BigItem = items::ArrayItem<600>;
+        const ITEM_SIZE: usize = BigItem::MAX_SIZE.next_multiple_of(4);
+        const { assert!(ITEM_SIZE > MAX_STORAGE_ITEM_SIZE) };
+
+        let item = |key, value| BigItem {
+            key,
+            value: [value; 600],
+        };
+
+        // The stack buffers are as big as the items, so the functions without a buffer work too.
+        // Only one item fits on a page, so every store moves to a new page and copies the other key forward.
+        for i in 0..10 {
+            store_item(&mut flash, flash_range.clone(), item(i % 2, i)).unwrap();
+        }
+
+        let mut buffer = [0xFF; ITEM_SIZE];
+        for key in 0..2 {
+            assert_eq!(
+                fetch_item::<BigItem, _>(&mut flash, flash_range.clone(), key).unwrap(),
+                Some(item(key, 8 + key))
+            );
+            assert_eq!(
+                fetch_item_with_buffer::<BigItem, _>(
+                    &mut flash,
+                    flash_range.clone(),
+                    key,
+                    &mut buffer
+                )
+                .unwrap(),
+                Some(item(key, 8 + key))
+            );
+        }
+        assert_eq!(
+            fetch_all_items::<BigItem, _>(&mut flash, flash_range.clone())
+                .unwrap()
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn store_only_uses_the_given_buffer() {
+        let mut flash = mock_flash::MockFlashBase::<8, 4, 256>::new();
+        let flash_range = 0x0000..0x2000;
+
+        // The items are bigger than the buffer of a store with the default maximum size.
+        // A store reads, looks up and copies the items with the given buffer only, so one of the item size is enough.
+        type BigItem = items::ArrayItem<600>;
+        const ITEM_SIZE: usize = BigItem::MAX_SIZE.next_multiple_of(4);
+        const { assert!(ITEM_SIZE > MAX_STORAGE_ITEM_SIZE) };
+
+        let item = |key, value| BigItem {
+            key,
+            value: [value; 600],
+        };
+
+        // Only one item fits on a page. Key 0 is never stored again, so it's copied forward every time its page
+        // is recycled, after it has been looked up over all the pages in between.
+        let mut buffer = [0xFF; ITEM_SIZE];
+        store_item_with_buffer(&mut flash, flash_range.clone(), item(0, 0), &mut buffer).unwrap();
+        for i in 1..30 {
+            store_item_with_buffer(
+                &mut flash,
+                flash_range.clone(),
+                item(1 + i % 2, i),
+                &mut buffer,
+            )
+            .unwrap();
+        }
+
+        for (key, value) in [(0, 0), (1, 28), (2, 29)] {
+            assert_eq!(
+                fetch_item_with_buffer::<BigItem, _>(
+                    &mut flash,
+                    flash_range.clone(),
+                    key,
+                    &mut buffer
+                )
+                .unwrap(),
+                Some(item(key, value))
+            );
+        }
+
+        assert_eq!(
+            store_item_with_buffer(
+                &mut flash,
+                flash_range.clone(),
+                item(0, 1),
+                &mut buffer[..ITEM_SIZE - 4]
+            ),
+            Err(MapError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn map_handle_caches_partial_open_page() {
+        let flash_range = 0x000..0x1000;
+        let mut uncached_flash = MockFlashBig::new();
+        let mut cached_flash = MockFlashBig::new();
+
+        let item = |i: u32| MockStorageItem {
+            key: (i % 50) as u8,
+            value: vec![i as u8; 10],
+        };
+
+        let mut map = Map::new(&mut cached_flash, flash_range.clone());
+
+        // Enough to go around the pages a couple of times
+        for i in 0..1000 {
+            store_item(&mut uncached_flash, flash_range.clone(), item(i)).unwrap();
+            map.store_item(item(i)).unwrap();
+
+            let key = (i % 7) as u8;
+            assert_eq!(
+                map.fetch_item::<MockStorageItem>(key).unwrap(),
+                fetch_item::<MockStorageItem, _>(&mut uncached_flash, flash_range.clone(), key)
+                    .unwrap()
+            );
+        }
+        assert_eq!(map.fetch_item::<MockStorageItem>(50).unwrap(), None);
+
+        assert_eq!(cached_flash.as_bytes(), uncached_flash.as_bytes());
+        assert_eq!(cached_flash.erases, uncached_flash.erases);
+        assert!(
+            cached_flash.reads < uncached_flash.reads,
+            "{} cached reads vs {} uncached reads",
+            cached_flash.reads,
+            uncached_flash.reads
+        );
+    }
+
+    #[test]
+    fn fetch_newest() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let item = |key, value| MockStorageItem {
+            key,
+            value: vec![value; 10],
+        };
+
+        assert_eq!(
+            fetch_newest_item::<MockStorageItem, _>(&mut flash, flash_range.clone()).unwrap(),
+            None
+        );
+
+        store_item(&mut flash, flash_range.clone(), item(1, 0)).unwrap();
+        store_item(&mut flash, flash_range.clone(), item(0, 1)).unwrap();
+        assert_eq!(
+            fetch_newest_item::<MockStorageItem, _>(&mut flash, flash_range.clone()).unwrap(),
+            Some(item(0, 1))
+        );
+
+        // 84 items fit in a page, so the last one fills up the first page
+        for i in 2..84 {
+            store_item(&mut flash, flash_range.clone(), item(i, i)).unwrap();
+        }
+        assert_eq!(
+            fetch_newest_item::<MockStorageItem, _>(&mut flash, flash_range.clone()).unwrap(),
+            Some(item(83, 83))
+        );
+
+        // Like a store that got interrupted right after moving to the next page
+        close_page(&mut flash, flash_range.clone(), 0).unwrap();
+        partial_close_page(&mut flash, flash_range.clone(), 1).unwrap();
+        assert_eq!(
+            fetch_newest_item::<MockStorageItem, _>(&mut flash, flash_range.clone()).unwrap(),
+            Some(item(83, 83))
+        );
+
+        store_item(&mut flash, flash_range.clone(), item(84, 84)).unwrap();
+        assert_eq!(
+            fetch_newest_item::<MockStorageItem, _>(&mut flash, flash_range.clone()).unwrap(),
+            Some(item(84, 84))
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "strict-asserts"))]
+    fn invalid_geometry_is_an_error() {
+        let mut flash = MockFlashBig::new();
+
+        assert_eq!(
+            fetch_item::<MockStorageItem, _>(&mut flash, 0x000..0x400, 0),
+            Err(MapError::InvalidFlashGeometry)
+        );
+        assert_eq!(
+            store_item(
+                &mut flash,
+                0x004..0x1000,
+                MockStorageItem {
+                    key: 0,
+                    value: vec![0; 10],
+                }
+            ),
+            Err(MapError::InvalidFlashGeometry)
+        );
+        assert_eq!(
+            recover_by_erase(&mut flash, 0x000..0x404),
+            Err(Error::InvalidFlashGeometry)
+        );
+        // An item type that can be bigger than a page, even though this item isn't
+        assert_eq!(
+            store_item(
+                &mut flash,
+                0x000..0x1000,
+                items::VarintKeyItem::<2000> {
+                    key: 0,
+                    value: ArrayVec::new(),
+                }
+            ),
+            Err(MapError::InvalidFlashGeometry)
+        );
+        assert_eq!(flash.writes + flash.erases, 0);
+    }
+
+    #[test]
+    #[cfg(feature = "strict-asserts")]
+    #[should_panic(expected = "The flash geometry or the flash range is invalid")]
+    fn strict_asserts_check_the_same_geometry() {
+        // Writes of more than MAX_FLASH_WRITE_SIZE bytes are rejected by validate_geometry, so they must panic too
+        let mut flash = mock_flash::MockFlashBase::<2, 64, 16>::new();
+        let _ = fetch_item::<MockStorageItem, _>(&mut flash, 0x000..0x800, 0);
+    }
+
+    #[test]
+    #[cfg(feature = "strict-asserts")]
+    #[should_panic(expected = "The flash geometry or the flash range is invalid")]
+    fn strict_asserts_check_the_item_size() {
+        // The item is small, but its type can be bigger than a page
+        let mut flash = MockFlashBig::new();
+        let _ = store_item(
+            &mut flash,
+            0x000..0x1000,
+            items::VarintKeyItem::<2000> {
+                key: 0,
+                value: ArrayVec::new(),
+            },
+        );
+    }
+
+    #[test]
+    fn extend_full_map() {
+        type MockFlashHuge = mock_flash::MockFlashBase<8, 4, 256>;
+
+        let mut flash = MockFlashHuge::new();
+        let old_range = 0x000..0x1000;
+        let new_range = 0x000..0x2000;
+
+        let item = |key, value| MockStorageItem {
+            key,
+            value: vec![value; 10],
+        };
+
+        // Go around the ring at least once, so the items wrap around the end of the range
+        for i in 0..200 {
+            store_item(&mut flash, old_range.clone(), item((i % 10) as u8, i as u8)).unwrap();
+        }
+        let mut key = 10;
+        while store_item(&mut flash, old_range.clone(), item(key, key)).is_ok() {
+            key += 1;
+        }
+        assert_eq!(
+            store_item(&mut flash, old_range.clone(), item(key, key)),
+            Err(MapError::FullStorage)
+        );
+        assert!(!get_page_state(&mut flash, old_range.clone(), 0)
+            .unwrap()
+            .is_open());
+        assert!(!get_page_state(&mut flash, old_range.clone(), 3)
+            .unwrap()
+            .is_open());
+
+        assert_eq!(
+            extend_range(&mut flash, old_range.clone(), 0x400..0x2000),
+            Err(MapError::InvalidFlashGeometry)
+        );
+        extend_range(&mut flash, old_range.clone(), new_range.clone()).unwrap();
+
+        // All items fit in the old range, so storing them all again needs the extra pages
+        let stored_keys = key;
+        for key in 10..stored_keys {
+            store_item(&mut flash, new_range.clone(), item(key, !key)).unwrap();
+        }
+
+        for i in 190..200 {
+            assert_eq!(
+                fetch_item::<MockStorageItem, _>(&mut flash, new_range.clone(), (i % 10) as u8)
+                    .unwrap(),
+                Some(item((i % 10) as u8, i as u8))
+            );
+        }
+        for key in 10..stored_keys {
+            assert_eq!(
+                fetch_item::<MockStorageItem, _>(&mut flash, new_range.clone(), key).unwrap(),
+                Some(item(key, !key))
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "The buffer page 1 after the partial open page 0 is Closed")]
+    fn buffer_page_invariant_is_checked() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let item = || MockStorageItem {
+            key: 0,
+            value: vec![0; 10],
+        };
+
+        store_item(&mut flash, flash_range.clone(), item()).unwrap();
+        close_page(&mut flash, flash_range.clone(), 1).unwrap();
+        store_item(&mut flash, flash_range.clone(), item()).unwrap();
+    }
+
+    #[test]
+    fn store_after_interrupted_page_change() {
+        /// Loses power after a number of writes and erases. Those that happen are assumed to be complete.
+        struct PowerLossFlash<'a> {
+            flash: &'a mut MockFlashBig,
+            operations_left: usize,
+        }
+
+        impl PowerLossFlash<'_> {
+            fn operate(&mut self) -> Result<(), mock_flash::MockFlashError> {
+                self.operations_left = self
+                    .operations_left
+                    .checked_sub(1)
+                    .ok_or(mock_flash::MockFlashError::OutOfBounds)?;
+                Ok(())
+            }
+        }
+
+        impl embedded_storage::nor_flash::ErrorType for PowerLossFlash<'_> {
+            type Error = mock_flash::MockFlashError;
+        }
+
+        impl embedded_storage::nor_flash::ReadNorFlash for PowerLossFlash<'_> {
+            const READ_SIZE: usize = MockFlashBig::READ_SIZE;
+
+            fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+                self.flash.read(offset, bytes)
+            }
+
+            fn capacity(&self) -> usize {
+                self.flash.capacity()
+            }
+        }
+
+        impl embedded_storage::nor_flash::NorFlash for PowerLossFlash<'_> {
+            const WRITE_SIZE: usize = MockFlashBig::WRITE_SIZE;
+            const ERASE_SIZE: usize = MockFlashBig::ERASE_SIZE;
+
+            fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+                self.operate()?;
+                self.flash.erase(from, to)
+            }
+
+            fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+                self.operate()?;
+                self.flash.write(offset, bytes)
+            }
+        }
+
+        let flash_range = 0x000..0x1000;
+        let item = |key, value| MockStorageItem {
+            key,
+            value: vec![value; 10],
+        };
+
+        // Fill the map up to the point where the next store closes a page and clears the buffer page
+        let mut flash = MockFlashBig::new();
+        let mut values = [0; 20];
+        for i in 0.. {
+            let key = (i % 20) as u8;
+
+            let mut trial = flash.clone();
+            store_item(&mut trial, flash_range.clone(), item(key, i as u8)).unwrap();
+            if trial.erases > 0 {
+                break;
+            }
+
+            flash = trial;
+            values[key as usize] = i as u8;
+        }
+
+        let mut trial = flash.clone();
+        trial.record_ops(true);
+        store_item(&mut trial, flash_range.clone(), item(0, 0xAA)).unwrap();
+        let operation_count = trial
+            .take_op_log()
+            .iter()
+            .filter(|op| !matches!(op, mock_flash::FlashOp::Read { .. }))
+            .count();
+        assert!(operation_count > 3, "{operation_count}");
+
+        for operations_left in 0..operation_count {
+            let mut interrupted = flash.clone();
+            store_item(
+                &mut PowerLossFlash {
+                    flash: &mut interrupted,
+                    operations_left,
+                },
+                flash_range.clone(),
+                item(0, 0xAA),
+            )
+            .unwrap_err();
+
+            store_item(&mut interrupted, flash_range.clone(), item(1, 0xBB)).unwrap();
+
+            let fetch = |flash: &mut MockFlashBig, key| {
+                fetch_item::<MockStorageItem, _>(flash, flash_range.clone(), key)
+                    .unwrap()
+                    .unwrap()
+            };
+
+            // The interrupted item may or may not have been stored
+            let value_0 = fetch(&mut interrupted, 0).value[0];
+            assert!(
+                value_0 == values[0] || value_0 == 0xAA,
+                "{operations_left}: {value_0}"
+            );
+            assert_eq!(
+                fetch(&mut interrupted, 1),
+                item(1, 0xBB),
+                "{operations_left}"
+            );
+            for key in 2..20 {
+                assert_eq!(
+                    fetch(&mut interrupted, key),
+                    item(key, values[key as usize]),
+                    "{operations_left}"
+                );
+            }
+
+            // Keep going for a while to make sure the buffer page is still in order
+            for i in 0..200 {
+                store_item(&mut interrupted, flash_range.clone(), item(20 + i % 20, 0)).unwrap();
+            }
+            assert_eq!(
+                fetch(&mut interrupted, 1),
+                item(1, 0xBB),
+                "{operations_left}"
+            );
+        }
+    }
+
+    #[test]
+    fn power_loss_during_store() {
+        let flash_range = 0x000..0x1000;
+
+        // With the CRC, a torn record is skipped instead of read as a wrong value.
+        // Every item takes 12 bytes, so 84 fit in a page.
+        let item = |key, value| {
+            items::Crc::new(MockStorageItem {
+                key,
+                value: vec![value; 8],
+            })
+        };
+        let fetch = |flash: &mut MockFlashBig, key| {
+            fetch_item::<items::Crc<MockStorageItem>, _>(flash, flash_range.clone(), key).unwrap()
+        };
+
+        // Go around the ring once, so the stores around the next page change also have to move items
+        let mut flash = MockFlashBig::new();
+        for i in 0..(4 * 84 + 80) {
+            store_item(
+                &mut flash,
+                flash_range.clone(),
+                item((i % 20) as u8, i as u8),
+            )
+            .unwrap();
+        }
+
+        // Lose the power at every operation of the stores around the page change
+        for i in 0..10 {
+            let key = (i % 20) as u8;
+            let new_value = 200 + i as u8;
+
+            for operations in 0.. {
+                let mut interrupted_flash = flash.clone();
+                interrupted_flash.fail_after(Some(operations));
+                let result = store_item(
+                    &mut interrupted_flash,
+                    flash_range.clone(),
+                    item(key, new_value),
+                );
+                interrupted_flash.fail_after(None);
+
+                // Every key has its old or its new value, never a wrong one
+                for other_key in 0..20 {
+                    let fetched = fetch(&mut interrupted_flash, other_key);
+                    if other_key == key && fetched == Some(item(key, new_value)) {
+                        continue;
+                    }
+                    assert_eq!(
+                        fetched,
+                        fetch(&mut flash, other_key),
+                        "Key {other_key} after losing the power after {operations} operations of store {i}"
+                    );
+                }
+
+                // The map can be used again
+                store_item(&mut interrupted_flash, flash_range.clone(), item(key, 0)).unwrap();
+                assert_eq!(fetch(&mut interrupted_flash, key), Some(item(key, 0)));
+
+                if result.is_ok() {
+                    break;
+                }
+                assert_eq!(
+                    result,
+                    Err(MapError::Storage(mock_flash::MockFlashError::PowerLoss))
+                );
+            }
+
+            store_item(&mut flash, flash_range.clone(), item(key, new_value)).unwrap();
+        }
+    }
+
+    /// An item with a 1 byte value that can be stored in batches. Every record is a single word.
+    #[derive(Debug, PartialEq, Eq)]
+    enum MockBatchItem {
+        Item { key: u8, value: u8 },
+        Marker(BatchMarker),
+    }
+
+    impl StorageItem for MockBatchItem {
+        type Key = u8;
+
+        type Error = MockStorageItemError;
+
+        // The tag can't be 0xFF
+        const MAX_LEADING_ERASED_BYTES: usize = 0;
+
+        fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+            if buffer.len() < 4 {
+                return Err(MockStorageItemError::BufferTooSmall);
+            }
+
+            buffer[..4].copy_from_slice(&match self {
+                MockBatchItem::Item { key, value } => [0, *key, *value, 0],
+                MockBatchItem::Marker(BatchMarker::Begin) => [1, 0, 0, 0],
+                MockBatchItem::Marker(BatchMarker::Commit) => [2, 0, 0, 0],
+            });
+
+            Ok(4)
+        }
+
+        fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error> {
+            match buffer {
+                [0, key, value, 0, ..] => Ok((
+                    MockBatchItem::Item {
+                        key: *key,
+                        value: *value,
+                    },
+                    4,
+                )),
+                [1, 0, 0, 0, ..] => Ok((MockBatchItem::Marker(BatchMarker::Begin), 4)),
+                [2, 0, 0, 0, ..] => Ok((MockBatchItem::Marker(BatchMarker::Commit), 4)),
+                [_, _, _, _, ..] => Err(MockStorageItemError::InvalidKey),
+                _ => Err(MockStorageItemError::BufferTooSmall),
+            }
+        }
+
+        fn key(&self) -> Self::Key {
+            match self {
+                MockBatchItem::Item { key, .. } => *key,
+                MockBatchItem::Marker(_) => 0xFF,
+            }
+        }
+
+        fn batch_marker(&self) -> Option<BatchMarker> {
+            match self {
+                MockBatchItem::Item { .. } => None,
+                MockBatchItem::Marker(marker) => Some(*marker),
+            }
+        }
     }
 
-    impl StorageItemError for MockStorageItemError {
-        fn is_buffer_too_small(&self) -> bool {
-            matches!(self, MockStorageItemError::BufferTooSmall)
+    impl BatchStorageItem for MockBatchItem {
+        fn new_batch_marker(marker: BatchMarker) -> Self {
+            MockBatchItem::Marker(marker)
         }
     }
 
-    impl StorageItem for MockStorageItem {
-        type Key = u8;
+    #[test]
+    fn power_loss_during_batch() {
+        let flash_range = 0x000..0x1000;
 
-        type Error = MockStorageItemError;
+        let batch = |value| (0..8).map(move |key| MockBatchItem::Item { key, value });
+        let fetch_values = |flash: &mut MockFlashBig| {
+            (0..8)
+                .map(|key| {
+                    match fetch_item::<MockBatchItem, _>(flash, flash_range.clone(), key).unwrap() {
+                        Some(MockBatchItem::Item { value, .. }) => value,
+                        item => panic!("Unexpected item {item:?}"),
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
 
-        fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
-            if buffer.len() < 2 + self.value.len() {
-                return Err(MockStorageItemError::BufferTooSmall);
-            }
+        // Go around the ring once and stop close to the end of a page, so the first batch has to move to the next page.
+        // Every record takes 4 bytes, so 252 fit in a page.
+        let mut flash = MockFlashBig::new();
+        for i in 0..(4 * 252 + 245) {
+            let item = MockBatchItem::Item {
+                key: (i % 8) as u8,
+                value: i as u8,
+            };
+            store_item(&mut flash, flash_range.clone(), item).unwrap();
+        }
 
-            if self.value.len() > 255 {
-                return Err(MockStorageItemError::BufferTooBig);
-            }
+        for i in 0..4 {
+            let old_values = fetch_values(&mut flash);
+            let new_value = 200 + i;
 
-            // The serialized value must not be all 0xFF
-            if self.key == 0xFF {
-                return Err(MockStorageItemError::InvalidKey);
+            // Lose the power at every operation of the batch
+            for operations in 0.. {
+                let mut interrupted_flash = flash.clone();
+                interrupted_flash.fail_after(Some(operations));
+                let result = store_items(
+                    &mut interrupted_flash,
+                    flash_range.clone(),
+                    batch(new_value),
+                );
+                interrupted_flash.fail_after(None);
+
+                // Either the whole batch is stored or none of it
+                let values = fetch_values(&mut interrupted_flash);
+                if result.is_ok() {
+                    assert_eq!(values, [new_value; 8]);
+                } else {
+                    assert_eq!(
+                        result,
+                        Err(MapError::Storage(mock_flash::MockFlashError::PowerLoss))
+                    );
+                    assert_eq!(
+                        values, old_values,
+                        "After losing the power after {operations} operations of batch {i}"
+                    );
+                }
+
+                // The map can be used again and the interrupted batch stays invisible
+                let item = MockBatchItem::Item { key: 0, value: 1 };
+                store_item(&mut interrupted_flash, flash_range.clone(), item).unwrap();
+                let values_after_store = fetch_values(&mut interrupted_flash);
+                assert_eq!(values_after_store[0], 1);
+                assert_eq!(values_after_store[1..], values[1..]);
+                store_items(&mut interrupted_flash, flash_range.clone(), batch(2)).unwrap();
+                assert_eq!(fetch_values(&mut interrupted_flash), [2; 8]);
+
+                if result.is_ok() {
+                    break;
+                }
             }
 
-            buffer[0] = self.key;
-            buffer[1] = self.value.len() as u8;
-            buffer[2..][..self.value.len()].copy_from_slice(&self.value);
+            store_items(&mut flash, flash_range.clone(), batch(new_value)).unwrap();
+        }
 
-            Ok(2 + self.value.len())
+        // The markers are never returned as items
+        assert_eq!(
+            fetch_all_items::<MockBatchItem, _>(&mut flash, flash_range.clone())
+                .unwrap()
+                .count(),
+            8
+        );
+
+        let too_big = (0..255).map(|key| MockBatchItem::Item { key, value: 0 });
+        assert_eq!(
+            store_items(&mut flash, flash_range.clone(), too_big),
+            Err(MapError::InvalidFlashGeometry)
+        );
+    }
+
+    #[test]
+    fn bit_flip_in_page_marker() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let item = |key, value| MockStorageItem {
+            key,
+            value: vec![value; 10],
+        };
+        let fetch = |flash: &mut MockFlashBig, key| {
+            fetch_item::<MockStorageItem, _>(flash, flash_range.clone(), key)
+                .unwrap()
+                .unwrap()
+                .value[0]
+        };
+
+        // 84 items fit in a page, so the last 16 items are on the second page
+        for i in 0..100 {
+            store_item(&mut flash, flash_range.clone(), item(i % 10, i)).unwrap();
         }
+        assert_eq!(fetch(&mut flash, 0), 90);
 
-        fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error>
-        where
-            Self: Sized,
-        {
-            if buffer.len() < 2 {
-                return Err(MockStorageItemError::BufferTooSmall);
-            }
+        // With a damaged end marker, the first page looks partial open. So the second page looks unused.
+        flash.flip_bit(1023, 3);
+        assert_eq!(
+            preflight(&mut flash, flash_range.clone()),
+            Err(MapError::Corrupted)
+        );
+        assert_eq!(fetch(&mut flash, 0), 80);
 
-            if buffer[0] == 0xFF {
-                return Err(MockStorageItemError::InvalidKey);
-            }
+        assert_eq!(try_repair(&mut flash, flash_range.clone()), Ok(0));
+        assert_eq!(preflight(&mut flash, flash_range.clone()), Ok(()));
+        assert_eq!(fetch(&mut flash, 0), 90);
+    }
 
-            let len = buffer[1];
+    #[test]
+    fn random_bit_flips_with_crc() {
+        let flash_range = 0x000..0x1000;
 
-            if buffer.len() < 2 + len as usize {
-                return Err(MockStorageItemError::BufferTooSmall);
-            }
+        let item = |key, value| {
+            items::Crc::new(MockStorageItem {
+                key,
+                value: vec![value; 8],
+            })
+        };
 
-            Ok((
-                Self {
-                    key: buffer[0],
-                    value: buffer[2..][..len as usize].to_vec(),
-                },
-                2 + len as usize,
-            ))
+        let mut flash = MockFlashBig::new();
+        for i in 0..100 {
+            store_item(&mut flash, flash_range.clone(), item(i % 10, i)).unwrap();
         }
 
-        fn key(&self) -> Self::Key {
-            self.key
+        // Flip bits in the stamps and the items of the two pages with data
+        for seed in 0..100 {
+            let mut flash = flash.clone();
+            let mut flips = flash.flip_random_bits(4..1020, 2, seed);
+            flips.extend(flash.flip_random_bits(1024 + 4..2048 - 4, 2, seed));
+
+            for key in 0..10 {
+                // An error is fine, but an item must have been stored like this
+                if let Ok(Some(fetched)) = fetch_item::<items::Crc<MockStorageItem>, _>(
+                    &mut flash,
+                    flash_range.clone(),
+                    key,
+                ) {
+                    let value = fetched.item.value[0];
+                    assert_eq!(fetched, item(key, value), "{flips:?}");
+                    assert_eq!(value % 10, key, "{flips:?}");
+                }
+            }
         }
     }
 
     #[test]
-    fn store_and_fetch() {
+    fn any_items_in_map() {
         let mut flash = MockFlashBig::new();
         let flash_range = 0x000..0x1000;
 
-        let item = fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0).unwrap();
-        assert_eq!(item, None);
-
-        let item = fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 60).unwrap();
-        assert_eq!(item, None);
+        assert!(!any_items::<MockStorageItem, _>(&mut flash, flash_range.clone()).unwrap());
 
-        let item = fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0xFF).unwrap();
-        assert_eq!(item, None);
+        // An opened page without items doesn't count
+        partial_close_page(&mut flash, flash_range.clone(), 0).unwrap();
+        assert!(!any_items::<MockStorageItem, _>(&mut flash, flash_range.clone()).unwrap());
 
-        store_item::<_, _>(
-            &mut flash,
-            flash_range.clone(),
-            MockStorageItem {
-                key: 0,
-                value: vec![5],
-            },
-        )
-        .unwrap();
-        store_item::<_, _>(
+        store_item(
             &mut flash,
             flash_range.clone(),
             MockStorageItem {
                 key: 0,
-                value: vec![5, 6],
-            },
-        )
-        .unwrap();
-
-        let item = fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0)
-            .unwrap()
-            .unwrap();
-        assert_eq!(item.key, 0);
-        assert_eq!(item.value, vec![5, 6]);
-
-        store_item::<_, _>(
-            &mut flash,
-            flash_range.clone(),
-            MockStorageItem {
-                key: 1,
-                value: vec![2, 2, 2, 2, 2, 2],
+                value: vec![0; 10],
             },
         )
         .unwrap();
 
-        let item = fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 0)
-            .unwrap()
-            .unwrap();
-        assert_eq!(item.key, 0);
-        assert_eq!(item.value, vec![5, 6]);
-
-        let item = fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 1)
-            .unwrap()
-            .unwrap();
-        assert_eq!(item.key, 1);
-        assert_eq!(item.value, vec![2, 2, 2, 2, 2, 2]);
+        flash.reset_counters();
+        assert!(any_items::<MockStorageItem, _>(&mut flash, flash_range.clone()).unwrap());
+        // Only the page markers, the stamp and the first item are read
+        assert!(flash.reads < 10, "{}", flash.reads);
+    }
 
-        for index in 0..4000 {
-            store_item::<_, _>(
-                &mut flash,
-                flash_range.clone(),
-                MockStorageItem {
-                    key: (index % 10) as u8,
-                    value: vec![(index % 10) as u8 * 2; index % 10],
-                },
-            )
-            .unwrap();
-        }
+    #[test]
+    fn max_item_size() {
+        // Pages of 128 bytes with room for 120 bytes of items
+        type MockFlash = mock_flash::MockFlashBase<4, 4, 32>;
+        let flash_range = 0x000..0x200;
+
+        // An item that is serialized to exactly `len` bytes
+        let item = |key, len: usize| MockStorageItem {
+            key,
+            value: vec![key; len - 2],
+        };
+
+        let mut flash = MockFlash::new();
+        assert_eq!(
+            max_item_size_that_fits::<MockStorageItem, _>(&mut flash, flash_range.clone()),
+            Ok(120)
+        );
 
-        for i in 0..10 {
-            let item = fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), i)
-                .unwrap()
+        // Fill the map with new keys. An item of the returned size must always fit.
+        for key in 0.. {
+            let max_size =
+                max_item_size_that_fits::<MockStorageItem, _>(&mut flash, flash_range.clone())
+                    .unwrap();
+            assert_eq!(max_size % MockFlash::WRITE_SIZE, 0);
+
+            if max_size >= 2 {
+                let mut fitting_flash = flash.clone();
+                store_item(
+                    &mut fitting_flash,
+                    flash_range.clone(),
+                    item(0xFE, max_size),
+                )
                 .unwrap();
-            assert_eq!(item.key, i);
-            assert_eq!(item.value, vec![(i % 10) as u8 * 2; (i % 10) as usize]);
-        }
-
-        for _ in 0..4000 {
-            store_item::<_, _>(
-                &mut flash,
-                flash_range.clone(),
-                MockStorageItem {
-                    key: 11,
-                    value: vec![0; 10],
-                },
-            )
-            .unwrap();
-        }
+            }
 
-        for i in 0..10 {
-            let item = fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), i)
-                .unwrap()
-                .unwrap();
-            assert_eq!(item.key, i);
-            assert_eq!(item.value, vec![(i % 10) as u8 * 2; (i % 10) as usize]);
+            match store_item(&mut flash, flash_range.clone(), item(key, 40)) {
+                Ok(()) => {}
+                Err(MapError::FullStorage) => {
+                    assert!(max_size < 40, "{max_size} bytes should have fit");
+                    break;
+                }
+                Err(e) => panic!("{e:?}"),
+            }
         }
-
-        println!(
-            "Erases: {}, reads: {}, writes: {}",
-            flash.erases, flash.reads, flash.writes
-        );
     }
 
     #[test]
-    fn store_too_many_items() {
-        const UPPER_BOUND: u8 = 6;
+    fn prepare_buffer_page() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
 
-        let mut tiny_flash = MockFlashTiny::new();
+        let item = |key, value| MockStorageItem {
+            key,
+            value: vec![value; 10],
+        };
 
-        for i in 0..UPPER_BOUND {
-            let item = MockStorageItem {
-                key: i as u8,
-                value: vec![i as u8; i as usize],
-            };
-            println!("Storing {item:?}");
+        assert_eq!(
+            prepare_next_buffer_page::<MockStorageItem, _>(&mut flash, flash_range.clone()),
+            Ok(false)
+        );
 
-            store_item::<_, _>(&mut tiny_flash, 0x00..0x40, item).unwrap();
+        // This one stays live on the first page
+        store_item(&mut flash, flash_range.clone(), item(100, 100)).unwrap();
+
+        let mut i = 0;
+        let mut store_next = |flash: &mut MockFlashBig| {
+            store_item(flash, flash_range.clone(), item(i % 20, i)).unwrap();
+            i = i.wrapping_add(1);
+        };
+
+        // Go on until the first page is the next to be erased
+        while !next_buffer_page::<MockStorageItem, _>(&mut flash, flash_range.clone())
+            .unwrap()
+            .unwrap()
+            .needs_erase
+        {
+            store_next(&mut flash);
         }
 
+        let next = next_buffer_page::<MockStorageItem, _>(&mut flash, flash_range.clone())
+            .unwrap()
+            .unwrap();
         assert_eq!(
-            store_item::<_, _>(
-                &mut tiny_flash,
-                0x00..0x40,
-                MockStorageItem {
-                    key: UPPER_BOUND,
-                    value: vec![0; UPPER_BOUND as usize],
-                },
-            ),
-            Err(MapError::FullStorage)
+            next,
+            NextBufferPage {
+                page_index: 0,
+                needs_erase: true,
+                live_bytes: 12,
+            }
         );
 
-        for i in 0..UPPER_BOUND {
-            let item = fetch_item::<MockStorageItem, _>(&mut tiny_flash, 0x00..0x40, i as u8)
+        assert_eq!(
+            prepare_next_buffer_page::<MockStorageItem, _>(&mut flash, flash_range.clone()),
+            Ok(true)
+        );
+        assert_eq!(flash.erases, 1);
+        assert!(
+            !next_buffer_page::<MockStorageItem, _>(&mut flash, flash_range.clone())
                 .unwrap()
-                .unwrap();
+                .unwrap()
+                .needs_erase
+        );
 
-            println!("Fetched {item:?}");
+        // Moving to the next page doesn't erase anything now
+        while next_buffer_page::<MockStorageItem, _>(&mut flash, flash_range.clone())
+            .unwrap()
+            .unwrap()
+            .page_index
+            == 0
+        {
+            store_next(&mut flash);
+        }
+        assert_eq!(flash.erases, 1);
 
-            assert_eq!(item.value, vec![i as u8; i as usize]);
+        assert_eq!(
+            fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), 100).unwrap(),
+            Some(item(100, 100))
+        );
+        for key in 0..20 {
+            assert!(
+                fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), key)
+                    .unwrap()
+                    .is_some()
+            );
         }
     }
 
     #[test]
-    fn store_too_many_items_big() {
-        const UPPER_BOUND: u8 = 74;
-
-        let mut big_flash = MockFlashBig::new();
-
-        for i in 0..UPPER_BOUND {
-            let item = MockStorageItem {
-                key: i as u8,
-                value: vec![i as u8; i as usize],
-            };
-            println!("Storing {item:?}");
+    fn compact_map() {
+        let flash_range = 0x000..0x1000;
 
-            store_item::<_, _>(&mut big_flash, 0x0000..0x1000, item).unwrap();
-        }
+        // With the CRC, a torn copy is skipped instead of read as a wrong value.
+        // Every item takes 12 bytes.
+        let item = |key, value| {
+            items::Crc::new(MockStorageItem {
+                key,
+                value: vec![value; 8],
+            })
+        };
+        let all_items = |flash: &mut MockFlashBig| {
+            let mut items =
+                fetch_all_items::<items::Crc<MockStorageItem>, _>(flash, flash_range.clone())
+                    .unwrap()
+                    .collect::<Result<Vec<_>, _>>()
+                    .unwrap();
+            items.sort_by_key(|item| item.key());
+            items
+        };
+        let used_bytes = |flash: &mut MockFlashBig| {
+            storage_stats(flash, flash_range.clone())
+                .unwrap()
+                .used_bytes
+        };
 
+        let mut flash = MockFlashBig::new();
         assert_eq!(
-            store_item::<_, _>(
-                &mut big_flash,
-                0x0000..0x1000,
-                MockStorageItem {
-                    key: UPPER_BOUND,
-                    value: vec![0; UPPER_BOUND as usize],
-                },
-            ),
-            Err(MapError::FullStorage)
+            compact::<items::Crc<MockStorageItem>, _>(&mut flash, flash_range.clone()),
+            Ok(())
         );
+        assert_eq!(flash.erases, 0);
 
-        for i in 0..UPPER_BOUND {
-            let item = fetch_item::<MockStorageItem, _>(&mut big_flash, 0x0000..0x1000, i as u8)
-                .unwrap()
+        // Overwrite the keys until most of the map is old values
+        for i in 0..400u32 {
+            store_item(
+                &mut flash,
+                flash_range.clone(),
+                item((i % 20) as u8, i as u8),
+            )
+            .unwrap();
+        }
+        let items = all_items(&mut flash);
+        assert_eq!(items.len(), 20);
+        let used_bytes_before = used_bytes(&mut flash);
+
+        // Lose the power at every operation. The items never change and compacting again finishes the job.
+        for operations in 0.. {
+            let mut interrupted_flash = flash.clone();
+            interrupted_flash.fail_after(Some(operations));
+            let result = compact::<items::Crc<MockStorageItem>, _>(
+                &mut interrupted_flash,
+                flash_range.clone(),
+            );
+            interrupted_flash.fail_after(None);
+
+            assert_eq!(
+                all_items(&mut interrupted_flash),
+                items,
+                "After losing the power after {operations} operations"
+            );
+
+            match result {
+                Ok(()) => break,
+                Err(MapError::Storage(_)) => {}
+                Err(e) => panic!("{e:?}"),
+            }
+
+            compact::<items::Crc<MockStorageItem>, _>(&mut interrupted_flash, flash_range.clone())
                 .unwrap();
+            assert_eq!(all_items(&mut interrupted_flash), items);
+            assert_eq!(used_bytes(&mut interrupted_flash), 20 * 12);
+        }
 
-            println!("Fetched {item:?}");
+        compact::<items::Crc<MockStorageItem>, _>(&mut flash, flash_range.clone()).unwrap();
+        assert_eq!(all_items(&mut flash), items);
+        assert!(used_bytes_before > 20 * 12);
+        assert_eq!(used_bytes(&mut flash), 20 * 12);
 
-            assert_eq!(item.value, vec![i as u8; i as usize]);
-        }
+        // The map keeps working
+        store_item(&mut flash, flash_range.clone(), item(100, 100)).unwrap();
+        assert_eq!(
+            fetch_item::<items::Crc<MockStorageItem>, _>(&mut flash, flash_range.clone(), 100)
+                .unwrap(),
+            Some(item(100, 100))
+        );
     }
 
     #[test]
@@ -802,24 +8539,24 @@ mod tests {
         ];
 
         for _ in 0..1000 {
-            for i in 0..24 {
+            for (i, &length) in LENGHT_PER_KEY.iter().enumerate() {
                 let item = MockStorageItem {
                     key: i as u8,
-                    value: vec![i as u8; LENGHT_PER_KEY[i]],
+                    value: vec![i as u8; length],
                 };
 
                 store_item::<_, _>(&mut flash, 0x0000..0x4000, item).unwrap();
             }
         }
 
-        for i in 0..24 {
+        for (i, &length) in LENGHT_PER_KEY.iter().enumerate() {
             let item = fetch_item::<MockStorageItem, _>(&mut flash, 0x0000..0x4000, i as u8)
                 .unwrap()
                 .unwrap();
 
             println!("Fetched {item:?}");
 
-            assert_eq!(item.value, vec![i as u8; LENGHT_PER_KEY[i]]);
+            assert_eq!(item.value, vec![i as u8; length]);
         }
     }
 }
@@ -0,0 +1,270 @@
+//! A map that is stored in two ranges so the whole map can be replaced at once.
+
+use super::*;
+
+/// A map that is kept in two flash ranges of which only one is in use at a time.
+///
+/// This is for when a whole set of items has to be replaced at once, e.g. the full configuration in an update.
+/// The new items are written into the range that's not in use, the shadow range.
+/// Once that's done, [Self::commit_swap] switches over to it in a single store.
+/// If power is lost before that, the old items are still there and still in use.
+///
+/// Which range is in use is stored as an item in a separate map in the commit range, which needs at least 2 pages.
+/// All ranges must not overlap.
+///
+/// ```rust,ignore
+/// let map = DoubleBufferedMap::new([0x0000..0x4000, 0x4000..0x8000], 0x8000..0x9000);
+///
+/// let shadow_range = map.begin_swap(&mut flash)?;
+/// for item in new_config {
+///     store_item(&mut flash, shadow_range.clone(), item)?;
+/// }
+/// map.commit_swap(&mut flash)?;
+///
+/// let value = map.fetch::<MyItem, _>(&mut flash, key)?;
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DoubleBufferedMap {
+    ranges: [Range<u32>; 2],
+    commit_range: Range<u32>,
+}
+
+impl DoubleBufferedMap {
+    /// Create the map from its two data ranges and the commit range.
+    /// Nothing is written to flash.
+    pub fn new(ranges: [Range<u32>; 2], commit_range: Range<u32>) -> Self {
+        let overlaps = |a: &Range<u32>, b: &Range<u32>| a.start < b.end && b.start < a.end;
+
+        assert!(!overlaps(&ranges[0], &ranges[1]));
+        assert!(!overlaps(&ranges[0], &commit_range));
+        assert!(!overlaps(&ranges[1], &commit_range));
+
+        Self {
+            ranges,
+            commit_range,
+        }
+    }
+
+    /// The range that is currently in use. Before the first commit, this is the first range.
+    pub fn active_range<S: NorFlash>(
+        &self,
+        flash: &mut S,
+    ) -> Result<Range<u32>, MapError<Infallible, S::Error>> {
+        Ok(self.ranges[self.active_index(flash)?].clone())
+    }
+
+    /// Fetch an item from the range that is currently in use. See [fetch_item].
+    pub fn fetch<I: StorageItem, S: NorFlash>(
+        &self,
+        flash: &mut S,
+        search_key: I::Key,
+    ) -> Result<Option<I>, MapError<I::Error, S::Error>> {
+        let active_range = self.active_range(flash).map_err(map_commit_error)?;
+        fetch_item(flash, active_range, search_key)
+    }
+
+    /// Erase the shadow range and return it so the new items can be stored in it with [store_item].
+    ///
+    /// This is also how the old range gets reclaimed after a swap, so it can be called again at any time
+    /// to start over. The range that is in use is never touched.
+    pub fn begin_swap<S: NorFlash>(
+        &self,
+        flash: &mut S,
+    ) -> Result<Range<u32>, MapError<Infallible, S::Error>> {
+        let shadow_range = self.ranges[1 - self.active_index(flash)?].clone();
+        clear(flash, shadow_range.clone())?;
+        Ok(shadow_range)
+    }
+
+    /// Switch to the shadow range. From now on, [Self::fetch] reads from it.
+    ///
+    /// This is a single item store, so a power loss either leaves the old range in use or the new one.
+    pub fn commit_swap<S: NorFlash>(
+        &self,
+        flash: &mut S,
+    ) -> Result<(), MapError<Infallible, S::Error>> {
+        let shadow_index = 1 - self.active_index(flash)?;
+
+        store_item(
+            flash,
+            self.commit_range.clone(),
+            CommitRecord {
+                active_index: shadow_index as u8,
+            },
+        )
+        .map_err(map_commit_error)
+    }
+
+    fn active_index<S: NorFlash>(
+        &self,
+        flash: &mut S,
+    ) -> Result<usize, MapError<Infallible, S::Error>> {
+        let commit_record = fetch_item::<CommitRecord, S>(flash, self.commit_range.clone(), ())
+            .map_err(map_commit_error)?;
+
+        Ok(commit_record.map_or(0, |record| record.active_index as usize))
+    }
+}
+
+/// Convert the error of an operation on the commit range.
+/// A commit record that can't be read means the commit range is corrupted.
+fn map_commit_error<E, I, S>(error: MapError<E, S>) -> MapError<I, S> {
+    match error {
+        MapError::Item(_) => MapError::Corrupted,
+        MapError::Storage(e) => MapError::Storage(e),
+        MapError::FullStorage => MapError::FullStorage,
+        MapError::Corrupted => MapError::Corrupted,
+        MapError::BufferTooBig => MapError::BufferTooBig,
+        MapError::BufferTooSmall => MapError::BufferTooSmall,
+        MapError::ItemTooBig => MapError::ItemTooBig,
+        MapError::GeometryMismatch => MapError::GeometryMismatch,
+        MapError::InvalidFlashGeometry => MapError::InvalidFlashGeometry,
+    }
+}
+
+/// The item in the commit range that says which range is in use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CommitRecord {
+    active_index: u8,
+}
+
+#[derive(Debug)]
+enum CommitRecordError {
+    BufferTooSmall,
+    Invalid,
+}
+
+impl StorageItemError for CommitRecordError {
+    fn is_buffer_too_small(&self) -> bool {
+        matches!(self, CommitRecordError::BufferTooSmall)
+    }
+}
+
+impl StorageItem for CommitRecord {
+    type Key = ();
+    type Error = CommitRecordError;
+
+    const MAX_LEADING_ERASED_BYTES: usize = 0;
+
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        if buffer.is_empty() {
+            return Err(CommitRecordError::BufferTooSmall);
+        }
+
+        buffer[0] = self.active_index;
+        Ok(1)
+    }
+
+    fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error>
+    where
+        Self: Sized,
+    {
+        match buffer.first() {
+            None => Err(CommitRecordError::BufferTooSmall),
+            Some(active_index @ (0 | 1)) => Ok((
+                Self {
+                    active_index: *active_index,
+                },
+                1,
+            )),
+            Some(_) => Err(CommitRecordError::Invalid),
+        }
+    }
+
+    fn key(&self) -> Self::Key {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::tests::{MockFlashBig, MockStorageItem};
+
+    type MockFlash = mock_flash::MockFlashBase<10, 4, 256>;
+
+    #[test]
+    fn swap_whole_map() {
+        let mut flash = MockFlash::new();
+        let map = DoubleBufferedMap::new([0x0000..0x1000, 0x1000..0x2000], 0x2000..0x2800);
+
+        let item = |key, value| MockStorageItem {
+            key,
+            value: vec![value; 4],
+        };
+
+        assert_eq!(map.active_range(&mut flash).unwrap(), 0x0000..0x1000);
+        assert_eq!(
+            map.fetch::<MockStorageItem, _>(&mut flash, 0).unwrap(),
+            None
+        );
+
+        // The first generation
+        let shadow_range = map.begin_swap(&mut flash).unwrap();
+        assert_eq!(shadow_range, 0x1000..0x2000);
+        for key in 0..4 {
+            store_item(&mut flash, shadow_range.clone(), item(key, 1)).unwrap();
+        }
+
+        // Nothing changes until the commit
+        assert_eq!(
+            map.fetch::<MockStorageItem, _>(&mut flash, 0).unwrap(),
+            None
+        );
+        map.commit_swap(&mut flash).unwrap();
+        assert_eq!(map.active_range(&mut flash).unwrap(), 0x1000..0x2000);
+
+        for key in 0..4 {
+            assert_eq!(
+                map.fetch::<MockStorageItem, _>(&mut flash, key).unwrap(),
+                Some(item(key, 1))
+            );
+        }
+
+        // The second generation is interrupted before the commit
+        let shadow_range = map.begin_swap(&mut flash).unwrap();
+        assert_eq!(shadow_range, 0x0000..0x1000);
+        store_item(&mut flash, shadow_range.clone(), item(0, 2)).unwrap();
+
+        assert_eq!(
+            map.fetch::<MockStorageItem, _>(&mut flash, 0).unwrap(),
+            Some(item(0, 1))
+        );
+
+        // Starting over reclaims the shadow range
+        let shadow_range = map.begin_swap(&mut flash).unwrap();
+        assert!(flash.as_bytes()[0x0000..0x1000]
+            .iter()
+            .all(|byte| *byte == 0xFF));
+        store_item(&mut flash, shadow_range.clone(), item(0, 3)).unwrap();
+        map.commit_swap(&mut flash).unwrap();
+
+        assert_eq!(
+            map.fetch::<MockStorageItem, _>(&mut flash, 0).unwrap(),
+            Some(item(0, 3))
+        );
+        // Key 1 wasn't in the new generation
+        assert_eq!(
+            map.fetch::<MockStorageItem, _>(&mut flash, 1).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn overlapping_ranges() {
+        DoubleBufferedMap::new([0x0000..0x1000, 0x0800..0x2000], 0x2000..0x2800);
+    }
+
+    #[test]
+    fn corrupted_commit_record() {
+        let mut flash = MockFlashBig::new();
+        let map = DoubleBufferedMap::new([0x0000..0x0400, 0x0400..0x0800], 0x0800..0x1000);
+        map.commit_swap(&mut flash).unwrap();
+
+        // Overwrite the commit record with something that isn't a range index
+        let record_address = 0x0800 + 4;
+        assert_eq!(flash.as_bytes()[record_address], 1);
+        flash.as_bytes_mut()[record_address] = 7;
+
+        assert_eq!(map.active_range(&mut flash), Err(MapError::Corrupted));
+    }
+}
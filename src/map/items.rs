@@ -0,0 +1,1546 @@
+//! Ready-made [StorageItem] implementations and wrappers.
+
+use core::{marker::PhantomData, ops::Range};
+
+use arrayvec::ArrayVec;
+use embedded_storage::nor_flash::NorFlash;
+
+use super::{fetch_all_items, MapError, StorageItem, StorageItemError, MAX_STORAGE_ITEM_SIZE};
+
+/// A compression codec that can be used with [Compressed].
+///
+/// The crate doesn't ship any codec so you're free to pick one that fits your data and your code size budget.
+pub trait Codec {
+    /// Compress the `input` into the `output`.
+    /// Returns the number of bytes written to the output or None if the output is too small.
+    fn compress(input: &[u8], output: &mut [u8]) -> Option<usize>;
+    /// Decompress the `input` into the `output`.
+    /// Returns the number of bytes written to the output or None if the output is too small or the input is invalid.
+    fn decompress(input: &[u8], output: &mut [u8]) -> Option<usize>;
+}
+
+/// A wrapper that transparently compresses the serialized form of the inner item.
+///
+/// The inner item is serialized as normal after which the bytes are compressed with the codec `C`.
+/// The compressed data is stored with a big endian u16 length in front of it, so the stored bytes are never all `0xFF`.
+///
+/// The inner item is serialized into and deserialized from a stack buffer of [MAX_STORAGE_ITEM_SIZE] bytes,
+/// so this wrapper uses that much extra stack.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compressed<I, C> {
+    /// The wrapped item
+    pub item: I,
+    _codec: PhantomData<C>,
+}
+
+impl<I, C> Compressed<I, C> {
+    /// Wrap the item so it's stored compressed
+    pub const fn new(item: I) -> Self {
+        Self {
+            item,
+            _codec: PhantomData,
+        }
+    }
+}
+
+/// The error type of [Compressed]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CompressedError<E> {
+    /// An error of the inner item
+    Item(E),
+    /// The buffer is too small to fit the compressed item
+    BufferTooSmall,
+    /// The compressed data could not be decompressed
+    Codec,
+}
+
+impl<E: StorageItemError> StorageItemError for CompressedError<E> {
+    fn is_buffer_too_small(&self) -> bool {
+        match self {
+            CompressedError::Item(e) => e.is_buffer_too_small(),
+            CompressedError::BufferTooSmall => true,
+            CompressedError::Codec => false,
+        }
+    }
+}
+
+impl<E> core::fmt::Display for CompressedError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CompressedError::Item(_) => f.write_str("the inner item failed"),
+            CompressedError::BufferTooSmall => f.write_str("the buffer is too small"),
+            CompressedError::Codec => f.write_str("the data could not be decompressed"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E: std::error::Error + 'static> std::error::Error for CompressedError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CompressedError::Item(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+const COMPRESSED_LENGTH_SIZE: usize = 2;
+
+impl<I: StorageItem, C: Codec> StorageItem for Compressed<I, C> {
+    type Key = I::Key;
+    type Error = CompressedError<I::Error>;
+
+    // The length is never more than MAX_STORAGE_ITEM_SIZE, so the first byte is never 0xFF
+    const MAX_LEADING_ERASED_BYTES: usize = 0;
+
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        let mut uncompressed = [0xFF; MAX_STORAGE_ITEM_SIZE];
+        let uncompressed_len = self
+            .item
+            .serialize_into(&mut uncompressed)
+            .map_err(CompressedError::Item)?;
+
+        if buffer.len() < COMPRESSED_LENGTH_SIZE {
+            return Err(CompressedError::BufferTooSmall);
+        }
+
+        let (length, data) = buffer.split_at_mut(COMPRESSED_LENGTH_SIZE);
+        let compressed_len = C::compress(&uncompressed[..uncompressed_len], data)
+            .ok_or(CompressedError::BufferTooSmall)?;
+
+        // The compressed length is never more than MAX_STORAGE_ITEM_SIZE,
+        // so the first length byte can never be 0xFF
+        length.copy_from_slice(&(compressed_len as u16).to_be_bytes());
+
+        Ok(COMPRESSED_LENGTH_SIZE + compressed_len)
+    }
+
+    fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < COMPRESSED_LENGTH_SIZE {
+            return Err(CompressedError::BufferTooSmall);
+        }
+
+        let compressed_len = u16::from_be_bytes([buffer[0], buffer[1]]) as usize;
+
+        if compressed_len > MAX_STORAGE_ITEM_SIZE {
+            return Err(CompressedError::Codec);
+        }
+
+        let Some(compressed) = buffer[COMPRESSED_LENGTH_SIZE..].get(..compressed_len) else {
+            return Err(CompressedError::BufferTooSmall);
+        };
+
+        let mut uncompressed = [0xFF; MAX_STORAGE_ITEM_SIZE];
+        let uncompressed_len =
+            C::decompress(compressed, &mut uncompressed).ok_or(CompressedError::Codec)?;
+
+        let (item, _) = I::deserialize_from(&uncompressed[..uncompressed_len])
+            .map_err(CompressedError::Item)?;
+
+        Ok((Self::new(item), COMPRESSED_LENGTH_SIZE + compressed_len))
+    }
+
+    fn key(&self) -> Self::Key {
+        self.item.key()
+    }
+}
+
+/// A wrapper that makes sure the stored bytes of the inner item are never all `0xFF`, the erased state of the flash.
+///
+/// If the inner item serializes to only `0xFF` bytes, a `0x00` guard byte is appended. See [ensure_not_erased].
+/// So the inner item doesn't have to care about this at all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Guarded<I> {
+    /// The wrapped item
+    pub item: I,
+}
+
+impl<I> Guarded<I> {
+    /// Wrap the item so it's never stored as all `0xFF`
+    pub const fn new(item: I) -> Self {
+        Self { item }
+    }
+}
+
+/// The error type of [Guarded]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum GuardedError<E> {
+    /// An error of the inner item
+    Item(E),
+    /// The buffer is too small to fit the guard byte
+    BufferTooSmall,
+}
+
+impl<E: StorageItemError> StorageItemError for GuardedError<E> {
+    fn is_buffer_too_small(&self) -> bool {
+        match self {
+            GuardedError::Item(e) => e.is_buffer_too_small(),
+            GuardedError::BufferTooSmall => true,
+        }
+    }
+}
+
+impl<E> core::fmt::Display for GuardedError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            GuardedError::Item(_) => f.write_str("the inner item failed"),
+            GuardedError::BufferTooSmall => f.write_str("the buffer is too small"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E: std::error::Error + 'static> std::error::Error for GuardedError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GuardedError::Item(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl<I: StorageItem> StorageItem for Guarded<I> {
+    type Key = I::Key;
+    type Error = GuardedError<I::Error>;
+
+    // The guard byte comes on top of the inner item
+    const MAX_SIZE: usize = I::MAX_SIZE.saturating_add(1);
+    const MAX_LEADING_ERASED_BYTES: usize = I::MAX_LEADING_ERASED_BYTES;
+
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        let used = self
+            .item
+            .serialize_into(buffer)
+            .map_err(GuardedError::Item)?;
+
+        ensure_not_erased(buffer, used).ok_or(GuardedError::BufferTooSmall)
+    }
+
+    fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error>
+    where
+        Self: Sized,
+    {
+        let (item, used) = I::deserialize_from(buffer).map_err(GuardedError::Item)?;
+
+        let used = skip_erased_sentinel(buffer, used);
+        if buffer.len() < used {
+            return Err(GuardedError::BufferTooSmall);
+        }
+
+        Ok((Self::new(item), used))
+    }
+
+    fn key(&self) -> Self::Key {
+        self.item.key()
+    }
+}
+
+/// A wrapper that stores a CRC-16 after the inner item to detect bit flips in the flash.
+///
+/// The inner item is serialized as normal, followed by the big endian CRC-16/CCITT-FALSE of its bytes.
+/// When the CRC doesn't match, the map skips the record as if it was never stored,
+/// so [fetch_item](super::fetch_item) returns the previous value of the key instead of a damaged one.
+///
+/// The length of the record is taken from the inner item, so a bit flip that makes the inner item unreadable
+/// is still returned as a [CrcError::Item] error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Crc<I> {
+    /// The wrapped item
+    pub item: I,
+}
+
+impl<I> Crc<I> {
+    /// Wrap the item so it's stored with a CRC
+    pub const fn new(item: I) -> Self {
+        Self { item }
+    }
+}
+
+/// The error type of [Crc]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CrcError<E> {
+    /// An error of the inner item
+    Item(E),
+    /// The buffer is too small to fit the CRC
+    BufferTooSmall,
+    /// The stored CRC doesn't match the stored item, which is this many bytes long including the CRC
+    Mismatch {
+        /// The length of the damaged record
+        record_len: usize,
+    },
+}
+
+impl<E: StorageItemError> StorageItemError for CrcError<E> {
+    fn is_buffer_too_small(&self) -> bool {
+        match self {
+            CrcError::Item(e) => e.is_buffer_too_small(),
+            CrcError::BufferTooSmall => true,
+            CrcError::Mismatch { .. } => false,
+        }
+    }
+
+    fn damaged_record_len(&self) -> Option<usize> {
+        match self {
+            CrcError::Mismatch { record_len } => Some(*record_len),
+            _ => None,
+        }
+    }
+}
+
+impl<E> core::fmt::Display for CrcError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CrcError::Item(_) => f.write_str("the inner item failed"),
+            CrcError::BufferTooSmall => f.write_str("the buffer is too small"),
+            CrcError::Mismatch { .. } => f.write_str("the CRC doesn't match"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E: std::error::Error + 'static> std::error::Error for CrcError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CrcError::Item(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+const CRC_SIZE: usize = 2;
+
+/// The CRC-16/CCITT-FALSE of the data
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFF_u16;
+
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+impl<I: StorageItem> StorageItem for Crc<I> {
+    type Key = I::Key;
+    type Error = CrcError<I::Error>;
+
+    const MAX_SIZE: usize = I::MAX_SIZE.saturating_add(CRC_SIZE);
+    const MAX_LEADING_ERASED_BYTES: usize = I::MAX_LEADING_ERASED_BYTES;
+
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        let used = self.item.serialize_into(buffer).map_err(CrcError::Item)?;
+
+        let crc = crc16(&buffer[..used]);
+        buffer
+            .get_mut(used..used + CRC_SIZE)
+            .ok_or(CrcError::BufferTooSmall)?
+            .copy_from_slice(&crc.to_be_bytes());
+
+        Ok(used + CRC_SIZE)
+    }
+
+    fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error>
+    where
+        Self: Sized,
+    {
+        let (item, used) = I::deserialize_from(buffer).map_err(CrcError::Item)?;
+
+        let stored_crc = buffer
+            .get(used..used + CRC_SIZE)
+            .ok_or(CrcError::BufferTooSmall)?;
+
+        if u16::from_be_bytes([stored_crc[0], stored_crc[1]]) != crc16(&buffer[..used]) {
+            return Err(CrcError::Mismatch {
+                record_len: used + CRC_SIZE,
+            });
+        }
+
+        Ok((Self::new(item), used + CRC_SIZE))
+    }
+
+    fn key(&self) -> Self::Key {
+        self.item.key()
+    }
+}
+
+/// A wrapper that puts the inner item in a namespace, so multiple independent key spaces can share one flash range.
+///
+/// Every range needs at least 2 pages, so giving e.g. the calibration, the network config and the user settings a range
+/// each takes a lot of flash on a small part. With this wrapper they can share one range instead.
+/// The namespace byte is stored in front of the inner item and is part of the key,
+/// so the same key in different namespaces is a different item:
+///
+/// ```rust,ignore
+/// store_item(&mut flash, flash_range.clone(), Namespaced::new(CALIBRATION, item))?;
+/// let item = fetch_item::<Namespaced<MyItem>, _>(&mut flash, flash_range.clone(), (CALIBRATION, key))?;
+/// ```
+///
+/// All items in the range have to be wrapped. Use [fetch_all_items_in_namespace] to list the items of one namespace.
+///
+/// Namespace `0xFF` is reserved, so the stored bytes never start with `0xFF`.
+/// So they're never all `0xFF` and the map reads as little as possible to find the end of a page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Namespaced<I> {
+    /// The namespace of the item. It can't be `0xFF`.
+    pub namespace: u8,
+    /// The wrapped item
+    pub item: I,
+}
+
+impl<I> Namespaced<I> {
+    /// Wrap the item so it's stored in the given namespace
+    pub const fn new(namespace: u8, item: I) -> Self {
+        Self { namespace, item }
+    }
+}
+
+/// The error type of [Namespaced]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NamespacedError<E> {
+    /// An error of the inner item
+    Item(E),
+    /// The buffer is too small to fit the namespace
+    BufferTooSmall,
+    /// The namespace is `0xFF`, which is reserved
+    InvalidNamespace,
+}
+
+impl<E: StorageItemError> StorageItemError for NamespacedError<E> {
+    fn is_buffer_too_small(&self) -> bool {
+        match self {
+            NamespacedError::Item(e) => e.is_buffer_too_small(),
+            NamespacedError::BufferTooSmall => true,
+            NamespacedError::InvalidNamespace => false,
+        }
+    }
+
+    fn damaged_record_len(&self) -> Option<usize> {
+        match self {
+            // The namespace comes on top of the inner record
+            NamespacedError::Item(e) => e.damaged_record_len().map(|len| len + 1),
+            _ => None,
+        }
+    }
+}
+
+impl<E> core::fmt::Display for NamespacedError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            NamespacedError::Item(_) => f.write_str("the inner item failed"),
+            NamespacedError::BufferTooSmall => f.write_str("the buffer is too small"),
+            NamespacedError::InvalidNamespace => f.write_str("the namespace 0xFF is reserved"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E: std::error::Error + 'static> std::error::Error for NamespacedError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NamespacedError::Item(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl<I: StorageItem> StorageItem for Namespaced<I> {
+    type Key = (u8, I::Key);
+    type Error = NamespacedError<I::Error>;
+
+    const MAX_SIZE: usize = I::MAX_SIZE.saturating_add(1);
+    // The namespace is never 0xFF
+    const MAX_LEADING_ERASED_BYTES: usize = 0;
+
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        if self.namespace == 0xFF {
+            return Err(NamespacedError::InvalidNamespace);
+        }
+
+        let (namespace, rest) = buffer
+            .split_first_mut()
+            .ok_or(NamespacedError::BufferTooSmall)?;
+        *namespace = self.namespace;
+
+        let used = self
+            .item
+            .serialize_into(rest)
+            .map_err(NamespacedError::Item)?;
+
+        Ok(used + 1)
+    }
+
+    fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error>
+    where
+        Self: Sized,
+    {
+        let (namespace, rest) = buffer
+            .split_first()
+            .ok_or(NamespacedError::BufferTooSmall)?;
+
+        if *namespace == 0xFF {
+            return Err(NamespacedError::InvalidNamespace);
+        }
+
+        let (item, used) = I::deserialize_from(rest).map_err(NamespacedError::Item)?;
+
+        Ok((Self::new(*namespace, item), used + 1))
+    }
+
+    fn key(&self) -> Self::Key {
+        (self.namespace, self.item.key())
+    }
+}
+
+/// Iterate over the newest value of every key in one namespace, see [Namespaced] and [fetch_all_items].
+///
+/// The items of the other namespaces are still read, they're only skipped.
+#[allow(clippy::type_complexity)]
+pub fn fetch_all_items_in_namespace<I: StorageItem, S: NorFlash>(
+    flash: &mut S,
+    flash_range: Range<u32>,
+    namespace: u8,
+) -> Result<
+    impl Iterator<Item = Result<Namespaced<I>, MapError<NamespacedError<I::Error>, S::Error>>>
+        + use<'_, I, S>,
+    MapError<NamespacedError<I::Error>, S::Error>,
+> {
+    Ok(fetch_all_items::<Namespaced<I>, S>(flash, flash_range)?
+        .filter(move |item| !matches!(item, Ok(item) if item.namespace != namespace)))
+}
+
+/// An item with a `u8` key and a value of exactly `N` bytes, e.g. a hash. It doesn't need an allocator.
+///
+/// It's stored as a `0x00` tag byte, the key and then the value. The tag makes sure the stored bytes are never all `0xFF`,
+/// so the key and the value can be anything.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArrayItem<const N: usize> {
+    /// The key of the item
+    pub key: u8,
+    /// The value of the item
+    pub value: [u8; N],
+}
+
+/// The error type of [ArrayItem]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ArrayItemError {
+    /// The buffer is too small to fit the item
+    BufferTooSmall,
+    /// The stored tag is not the one of an [ArrayItem]
+    InvalidTag,
+}
+
+impl StorageItemError for ArrayItemError {
+    fn is_buffer_too_small(&self) -> bool {
+        matches!(self, ArrayItemError::BufferTooSmall)
+    }
+}
+
+impl core::fmt::Display for ArrayItemError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ArrayItemError::BufferTooSmall => f.write_str("the buffer is too small"),
+            ArrayItemError::InvalidTag => f.write_str("the stored tag is invalid"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ArrayItemError {}
+
+const ARRAY_ITEM_TAG: u8 = 0x00;
+const ARRAY_ITEM_HEADER_SIZE: usize = 2;
+
+impl<const N: usize> StorageItem for ArrayItem<N> {
+    type Key = u8;
+    type Error = ArrayItemError;
+
+    const MAX_SIZE: usize = ARRAY_ITEM_HEADER_SIZE + N;
+    // The tag is never 0xFF
+    const MAX_LEADING_ERASED_BYTES: usize = 0;
+
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        let used = ARRAY_ITEM_HEADER_SIZE + N;
+        let Some(buffer) = buffer.get_mut(..used) else {
+            return Err(ArrayItemError::BufferTooSmall);
+        };
+
+        buffer[0] = ARRAY_ITEM_TAG;
+        buffer[1] = self.key;
+        buffer[ARRAY_ITEM_HEADER_SIZE..].copy_from_slice(&self.value);
+
+        Ok(used)
+    }
+
+    fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error>
+    where
+        Self: Sized,
+    {
+        let used = ARRAY_ITEM_HEADER_SIZE + N;
+        let Some(buffer) = buffer.get(..used) else {
+            return Err(ArrayItemError::BufferTooSmall);
+        };
+
+        if buffer[0] != ARRAY_ITEM_TAG {
+            return Err(ArrayItemError::InvalidTag);
+        }
+
+        let mut value = [0; N];
+        value.copy_from_slice(&buffer[ARRAY_ITEM_HEADER_SIZE..]);
+
+        Ok((
+            Self {
+                key: buffer[1],
+                value,
+            },
+            used,
+        ))
+    }
+
+    fn key(&self) -> Self::Key {
+        self.key
+    }
+}
+
+/// An item with a `u64` key, e.g. a hash, and a byte value.
+///
+/// The key is stored as a LEB128 varint, so small keys take up only one byte and the biggest keys ten.
+/// The last byte of a varint never has its top bit set, so the stored bytes are never all `0xFF`.
+/// The value is stored after the key with a big endian u16 length in front of it.
+///
+/// Use [encode_varint_key] and [decode_varint_key] to get the same key encoding in your own item.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VarintKeyItem<const N: usize> {
+    /// The key of the item
+    pub key: u64,
+    /// The value of the item
+    pub value: ArrayVec<u8, N>,
+}
+
+/// The error type of [VarintKeyItem]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum VarintKeyItemError {
+    /// The buffer is too small to fit the item
+    BufferTooSmall,
+    /// The stored key is not a valid varint or doesn't fit in a `u64`
+    InvalidKey,
+    /// The stored value is longer than the value of the item can hold
+    ValueTooBig,
+}
+
+impl StorageItemError for VarintKeyItemError {
+    fn is_buffer_too_small(&self) -> bool {
+        matches!(self, VarintKeyItemError::BufferTooSmall)
+    }
+}
+
+impl core::fmt::Display for VarintKeyItemError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            VarintKeyItemError::BufferTooSmall => f.write_str("the buffer is too small"),
+            VarintKeyItemError::InvalidKey => f.write_str("the stored key is invalid"),
+            VarintKeyItemError::ValueTooBig => f.write_str("the stored value is too big"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for VarintKeyItemError {}
+
+/// The maximum length of a `u64` in LEB128
+const MAX_VARINT_LEN: usize = 10;
+const VALUE_LENGTH_SIZE: usize = 2;
+
+impl<const N: usize> StorageItem for VarintKeyItem<N> {
+    type Key = u64;
+    type Error = VarintKeyItemError;
+
+    const MAX_SIZE: usize = MAX_VARINT_LEN + VALUE_LENGTH_SIZE + N;
+    // Only the last byte of the key is guaranteed to not be 0xFF
+    const MAX_LEADING_ERASED_BYTES: usize = MAX_VARINT_LEN - 1;
+
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        let key_len =
+            encode_varint_key(self.key, buffer).ok_or(VarintKeyItemError::BufferTooSmall)?;
+
+        let used = key_len + VALUE_LENGTH_SIZE + self.value.len();
+        let Some(rest) = buffer.get_mut(key_len..used) else {
+            return Err(VarintKeyItemError::BufferTooSmall);
+        };
+
+        let (length, value) = rest.split_at_mut(VALUE_LENGTH_SIZE);
+        length.copy_from_slice(&(self.value.len() as u16).to_be_bytes());
+        value.copy_from_slice(&self.value);
+
+        Ok(used)
+    }
+
+    fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error>
+    where
+        Self: Sized,
+    {
+        let (key, key_len) = decode_varint_key(buffer)?;
+
+        let Some(length) = buffer.get(key_len..key_len + VALUE_LENGTH_SIZE) else {
+            return Err(VarintKeyItemError::BufferTooSmall);
+        };
+        let value_len = u16::from_be_bytes([length[0], length[1]]) as usize;
+
+        if value_len > N {
+            return Err(VarintKeyItemError::ValueTooBig);
+        }
+
+        let value_start = key_len + VALUE_LENGTH_SIZE;
+        let Some(value) = buffer.get(value_start..value_start + value_len) else {
+            return Err(VarintKeyItemError::BufferTooSmall);
+        };
+
+        Ok((
+            Self {
+                key,
+                value: value
+                    .try_into()
+                    .map_err(|_| VarintKeyItemError::ValueTooBig)?,
+            },
+            value_start + value_len,
+        ))
+    }
+
+    fn key(&self) -> Self::Key {
+        self.key
+    }
+}
+
+/// An item with a `u8`, `u16` or `u32` key and up to `N` bytes of raw data. The easiest way to store a few blobs.
+///
+/// It's stored as the big endian key, the big endian u16 length of the data and then the data.
+/// If that's all `0xFF`, a `0x00` guard byte is appended, see [ensure_not_erased].
+/// So the key and the data can be anything.
+///
+/// ```rust
+/// # #[cfg(feature = "mock-flash")]
+/// # {
+/// use sequential_storage::map::{fetch_item, store_item, items::RawItem};
+/// use sequential_storage::mock_flash::MockFlashBase;
+///
+/// let mut flash = MockFlashBase::<4, 4, 256>::new();
+/// let flash_range = 0x0000..0x1000;
+///
+/// let item = RawItem::<u16, 32>::new(0x1234, b"hello").unwrap();
+/// store_item(&mut flash, flash_range.clone(), item).unwrap();
+///
+/// let item = fetch_item::<RawItem<u16, 32>, _>(&mut flash, flash_range.clone(), 0x1234).unwrap();
+/// assert_eq!(item.unwrap().data.as_slice(), b"hello");
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawItem<K, const N: usize> {
+    /// The key of the item
+    pub key: K,
+    /// The data of the item
+    pub data: ArrayVec<u8, N>,
+}
+
+impl<K, const N: usize> RawItem<K, N> {
+    /// Create an item with a copy of the data. Returns None if the data is longer than `N` bytes.
+    pub fn new(key: K, data: &[u8]) -> Option<Self> {
+        Some(Self {
+            key,
+            data: data.try_into().ok()?,
+        })
+    }
+}
+
+/// The key of a [RawItem]. It's implemented for `u8`, `u16` and `u32` and can't be implemented outside of this crate.
+pub trait RawKey: sealed::Sealed + Copy + Eq {
+    /// The amount of bytes the key is stored in
+    #[doc(hidden)]
+    const SIZE: usize;
+
+    /// Write the key as big endian bytes into a buffer of [Self::SIZE] bytes
+    #[doc(hidden)]
+    fn write_be(self, buffer: &mut [u8]);
+
+    /// Read the key from a buffer of [Self::SIZE] big endian bytes
+    #[doc(hidden)]
+    fn read_be(buffer: &[u8]) -> Self;
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+macro_rules! impl_raw_key {
+    ($($key:ty),*) => {
+        $(
+            impl sealed::Sealed for $key {}
+
+            impl RawKey for $key {
+                const SIZE: usize = core::mem::size_of::<$key>();
+
+                fn write_be(self, buffer: &mut [u8]) {
+                    buffer.copy_from_slice(&self.to_be_bytes());
+                }
+
+                fn read_be(buffer: &[u8]) -> Self {
+                    Self::from_be_bytes(buffer.try_into().unwrap())
+                }
+            }
+        )*
+    };
+}
+
+impl_raw_key!(u8, u16, u32);
+
+/// The error type of [RawItem]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RawItemError {
+    /// The buffer is too small to fit the item
+    BufferTooSmall,
+    /// The stored data is longer than the data of the item can hold
+    DataTooBig,
+}
+
+impl StorageItemError for RawItemError {
+    fn is_buffer_too_small(&self) -> bool {
+        matches!(self, RawItemError::BufferTooSmall)
+    }
+}
+
+impl core::fmt::Display for RawItemError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            RawItemError::BufferTooSmall => f.write_str("the buffer is too small"),
+            RawItemError::DataTooBig => f.write_str("the stored data is too big"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for RawItemError {}
+
+impl<K: RawKey, const N: usize> StorageItem for RawItem<K, N> {
+    type Key = K;
+    type Error = RawItemError;
+
+    // The guard byte comes on top
+    const MAX_SIZE: usize = K::SIZE + VALUE_LENGTH_SIZE + N + 1;
+    // The first byte of the length is never 0xFF when the data can't be that long
+    const MAX_LEADING_ERASED_BYTES: usize = if N < 0xFF00 { K::SIZE } else { Self::MAX_SIZE };
+
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        let used = K::SIZE + VALUE_LENGTH_SIZE + self.data.len();
+        let Some(record) = buffer.get_mut(..used) else {
+            return Err(RawItemError::BufferTooSmall);
+        };
+
+        let (key, rest) = record.split_at_mut(K::SIZE);
+        let (length, data) = rest.split_at_mut(VALUE_LENGTH_SIZE);
+        self.key.write_be(key);
+        length.copy_from_slice(&(self.data.len() as u16).to_be_bytes());
+        data.copy_from_slice(&self.data);
+
+        ensure_not_erased(buffer, used).ok_or(RawItemError::BufferTooSmall)
+    }
+
+    fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error>
+    where
+        Self: Sized,
+    {
+        let Some(length) = buffer.get(K::SIZE..K::SIZE + VALUE_LENGTH_SIZE) else {
+            return Err(RawItemError::BufferTooSmall);
+        };
+        let data_len = u16::from_be_bytes([length[0], length[1]]) as usize;
+
+        if data_len > N {
+            return Err(RawItemError::DataTooBig);
+        }
+
+        let data_start = K::SIZE + VALUE_LENGTH_SIZE;
+        let Some(data) = buffer.get(data_start..data_start + data_len) else {
+            return Err(RawItemError::BufferTooSmall);
+        };
+
+        let used = skip_erased_sentinel(buffer, data_start + data_len);
+        if buffer.len() < used {
+            return Err(RawItemError::BufferTooSmall);
+        }
+
+        Ok((
+            Self {
+                key: K::read_be(&buffer[..K::SIZE]),
+                data: data.try_into().map_err(|_| RawItemError::DataTooBig)?,
+            },
+            used,
+        ))
+    }
+
+    fn key(&self) -> Self::Key {
+        self.key
+    }
+}
+
+/// Encode the key as a LEB128 varint into the buffer.
+/// Returns the amount of bytes used or None if the buffer is too small.
+///
+/// The last byte never has its top bit set, so the encoded key is never all `0xFF`.
+/// The first bytes can be `0xFF` though, so an item that starts with the key needs
+/// a [StorageItem::MAX_LEADING_ERASED_BYTES] of at least 9.
+pub fn encode_varint_key(mut key: u64, buffer: &mut [u8]) -> Option<usize> {
+    let mut used = 0;
+
+    loop {
+        let byte = (key & 0x7F) as u8;
+        key >>= 7;
+
+        if key == 0 {
+            *buffer.get_mut(used)? = byte;
+            return Some(used + 1);
+        }
+
+        *buffer.get_mut(used)? = byte | 0x80;
+        used += 1;
+    }
+}
+
+/// Decode a key that was encoded with [encode_varint_key].
+/// Returns the key and the amount of bytes it took up.
+pub fn decode_varint_key(buffer: &[u8]) -> Result<(u64, usize), VarintKeyItemError> {
+    let mut key = 0u64;
+
+    for (index, byte) in buffer.iter().take(MAX_VARINT_LEN).enumerate() {
+        let bits = (*byte & 0x7F) as u64;
+        let shift = index as u32 * 7;
+
+        // The tenth byte only has room for the top bit of the u64
+        if shift == 63 && bits > 1 {
+            return Err(VarintKeyItemError::InvalidKey);
+        }
+
+        key |= bits << shift;
+
+        if byte & 0x80 == 0 {
+            return Ok((key, index + 1));
+        }
+    }
+
+    if buffer.len() < MAX_VARINT_LEN {
+        Err(VarintKeyItemError::BufferTooSmall)
+    } else {
+        Err(VarintKeyItemError::InvalidKey)
+    }
+}
+
+/// Make sure the serialized bytes in `buffer[..used]` are not all `0xFF`, the erased state of the flash.
+///
+/// The storage can't tell an all `0xFF` record apart from erased flash, so [StorageItem::serialize_into] must never produce one.
+/// That doesn't mean a key or value can't be `0xFF`, only that the record as a whole can't consist of only `0xFF` bytes.
+///
+/// If the bytes are all `0xFF`, a `0x00` sentinel byte is appended. Otherwise nothing is changed.
+/// Returns the new used length or None if there's no room for the sentinel.
+/// Use [skip_erased_sentinel] in the deserialization to account for the sentinel.
+///
+/// ```rust
+/// # use sequential_storage::map::items::{ensure_not_erased, skip_erased_sentinel};
+/// let mut buffer = [0xFF; 4];
+/// // A key of 0xFF with a value of 0xFF
+/// assert_eq!(ensure_not_erased(&mut buffer, 2), Some(3));
+/// assert_eq!(buffer[..3], [0xFF, 0xFF, 0x00]);
+/// assert_eq!(skip_erased_sentinel(&buffer, 2), 3);
+///
+/// // A key of 0xFF with a value of 0x01
+/// buffer[1] = 0x01;
+/// assert_eq!(ensure_not_erased(&mut buffer, 2), Some(2));
+/// assert_eq!(skip_erased_sentinel(&buffer, 2), 2);
+/// ```
+pub fn ensure_not_erased(buffer: &mut [u8], used: usize) -> Option<usize> {
+    if !buffer[..used].iter().all(|byte| *byte == 0xFF) {
+        return Some(used);
+    }
+
+    *buffer.get_mut(used)? = 0x00;
+    Some(used + 1)
+}
+
+/// The counterpart of [ensure_not_erased] for deserialization.
+///
+/// Given the `used` bytes of a deserialized item, returns the amount of bytes the record takes up including the sentinel, if any.
+pub fn skip_erased_sentinel(buffer: &[u8], used: usize) -> usize {
+    if buffer[..used].iter().all(|byte| *byte == 0xFF) {
+        used + 1
+    } else {
+        used
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::tests::{MockFlashBig, MockFlashTiny, MockStorageItem};
+    use crate::map::{fetch_item, store_item};
+    use crate::mock_flash::assert_item_round_trip;
+
+    /// An item where both the key and the value can be `0xFF`
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct ByteItem {
+        key: u8,
+        value: u8,
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct ByteItemError;
+
+    impl StorageItemError for ByteItemError {
+        fn is_buffer_too_small(&self) -> bool {
+            true
+        }
+    }
+
+    impl StorageItem for ByteItem {
+        type Key = u8;
+        type Error = ByteItemError;
+
+        fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+            if buffer.len() < 2 {
+                return Err(ByteItemError);
+            }
+
+            buffer[0] = self.key;
+            buffer[1] = self.value;
+
+            ensure_not_erased(buffer, 2).ok_or(ByteItemError)
+        }
+
+        fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error>
+        where
+            Self: Sized,
+        {
+            if buffer.len() < 2 {
+                return Err(ByteItemError);
+            }
+
+            let used = skip_erased_sentinel(buffer, 2);
+            if buffer.len() < used {
+                return Err(ByteItemError);
+            }
+
+            Ok((
+                Self {
+                    key: buffer[0],
+                    value: buffer[1],
+                },
+                used,
+            ))
+        }
+
+        fn key(&self) -> Self::Key {
+            self.key
+        }
+    }
+
+    #[test]
+    fn erased_pattern_key() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        for (key, value) in [(0xFF, 0xFF), (0x00, 0xFF), (0xFF, 0x00), (0x01, 0x02)] {
+            store_item(&mut flash, flash_range.clone(), ByteItem { key, value }).unwrap();
+        }
+        store_item(
+            &mut flash,
+            flash_range.clone(),
+            ByteItem {
+                key: 0xFF,
+                value: 0xFF,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            fetch_item::<ByteItem, _>(&mut flash, flash_range.clone(), 0xFF).unwrap(),
+            Some(ByteItem {
+                key: 0xFF,
+                value: 0xFF
+            })
+        );
+        assert_eq!(
+            fetch_item::<ByteItem, _>(&mut flash, flash_range.clone(), 0x00).unwrap(),
+            Some(ByteItem {
+                key: 0x00,
+                value: 0xFF
+            })
+        );
+        assert_eq!(
+            fetch_item::<ByteItem, _>(&mut flash, flash_range.clone(), 0x01).unwrap(),
+            Some(ByteItem {
+                key: 0x01,
+                value: 0x02
+            })
+        );
+    }
+
+    /// An item that is stored as all `0xFF` if both the key and the value are `0xFF`
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct UnguardedByteItem(ByteItem);
+
+    impl StorageItem for UnguardedByteItem {
+        type Key = u8;
+        type Error = ByteItemError;
+
+        fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+            let buffer = buffer.get_mut(..2).ok_or(ByteItemError)?;
+            buffer.copy_from_slice(&[self.0.key, self.0.value]);
+            Ok(2)
+        }
+
+        fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error> {
+            let &[key, value, ..] = buffer else {
+                return Err(ByteItemError);
+            };
+            Ok((Self(ByteItem { key, value }), 2))
+        }
+
+        fn key(&self) -> Self::Key {
+            self.0.key
+        }
+    }
+
+    #[test]
+    fn guarded_erased_pattern() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let item = |key, value| Guarded::new(UnguardedByteItem(ByteItem { key, value }));
+
+        let mut buffer = [0xFF; 3];
+        assert_eq!(item(0xFF, 0xFF).serialize_into(&mut buffer), Ok(3));
+        assert_eq!(buffer, [0xFF, 0xFF, 0x00]);
+        assert_eq!(
+            item(0xFF, 0xFF).serialize_into(&mut buffer[..2]),
+            Err(GuardedError::BufferTooSmall)
+        );
+
+        for (key, value) in [(0xFF, 0xFF), (0x00, 0xFF), (0x01, 0x02), (0xFF, 0xFF)] {
+            store_item(&mut flash, flash_range.clone(), item(key, value)).unwrap();
+        }
+
+        for (key, value) in [(0xFF, 0xFF), (0x00, 0xFF), (0x01, 0x02)] {
+            assert_eq!(
+                fetch_item::<Guarded<UnguardedByteItem>, _>(&mut flash, flash_range.clone(), key)
+                    .unwrap(),
+                Some(item(key, value))
+            );
+        }
+    }
+
+    #[test]
+    fn crc_skips_damaged_records() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        // With the CRC, every item takes 12 bytes, so 84 fit in a page
+        let item = |key, value| {
+            Crc::new(MockStorageItem {
+                key,
+                value: vec![value; 8],
+            })
+        };
+        let fetch = |flash: &mut MockFlashBig, key| {
+            fetch_item::<Crc<MockStorageItem>, _>(flash, flash_range.clone(), key).unwrap()
+        };
+
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+
+        // Fill the first page, with the first value of key 0 on it
+        store_item(&mut flash, flash_range.clone(), item(0, 1)).unwrap();
+        for _ in 1..84 {
+            store_item(&mut flash, flash_range.clone(), item(1, 1)).unwrap();
+        }
+
+        // The second value of key 0 is the first item of the second page
+        store_item(&mut flash, flash_range.clone(), item(0, 2)).unwrap();
+        assert_eq!(fetch(&mut flash, 0), Some(item(0, 2)));
+
+        // Flip a bit in the value, the older value on the previous page is found instead
+        flash.as_bytes_mut()[1024 + 4 + 3] ^= 0x10;
+        assert_eq!(fetch(&mut flash, 0), Some(item(0, 1)));
+
+        // The damaged record still takes up its space, so the next items are stored after it
+        store_item(&mut flash, flash_range.clone(), item(2, 1)).unwrap();
+        store_item(&mut flash, flash_range.clone(), item(2, 2)).unwrap();
+        assert_eq!(fetch(&mut flash, 2), Some(item(2, 2)));
+
+        // The older value on the same page is found as well
+        flash.as_bytes_mut()[1024 + 4 + 2 * 12 + 4] ^= 0x01;
+        assert_eq!(fetch(&mut flash, 2), Some(item(2, 1)));
+
+        // A damaged record is dropped when its page is cleared, but the keys keep their values
+        for i in 0..(3 * 84) {
+            store_item(&mut flash, flash_range.clone(), item(3, i as u8)).unwrap();
+        }
+        assert_eq!(fetch(&mut flash, 0), Some(item(0, 1)));
+        assert_eq!(fetch(&mut flash, 1), Some(item(1, 1)));
+        assert_eq!(fetch(&mut flash, 2), Some(item(2, 1)));
+        assert_eq!(fetch(&mut flash, 3), Some(item(3, (3 * 84 - 1) as u8)));
+    }
+
+    #[test]
+    fn namespaces_share_a_range() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let item = |namespace, key, value| {
+            Namespaced::new(
+                namespace,
+                ArrayItem {
+                    key,
+                    value: [value; 4],
+                },
+            )
+        };
+        let fetch = |flash: &mut MockFlashBig, namespace, key| {
+            fetch_item::<Namespaced<ArrayItem<4>>, _>(flash, flash_range.clone(), (namespace, key))
+                .unwrap()
+        };
+
+        // The same key in different namespaces
+        for namespace in [0, 1, 0xFE] {
+            for key in 0..4 {
+                store_item(
+                    &mut flash,
+                    flash_range.clone(),
+                    item(namespace, key, namespace),
+                )
+                .unwrap();
+            }
+        }
+        store_item(&mut flash, flash_range.clone(), item(1, 2, 42)).unwrap();
+
+        assert_eq!(fetch(&mut flash, 0, 2), Some(item(0, 2, 0)));
+        assert_eq!(fetch(&mut flash, 1, 2), Some(item(1, 2, 42)));
+        assert_eq!(fetch(&mut flash, 0xFE, 2), Some(item(0xFE, 2, 0xFE)));
+        assert_eq!(fetch(&mut flash, 2, 2), None);
+
+        let mut items =
+            fetch_all_items_in_namespace::<ArrayItem<4>, _>(&mut flash, flash_range.clone(), 1)
+                .unwrap()
+                .map(|item| {
+                    let item = item.unwrap();
+                    (item.key(), item.item.value[0])
+                })
+                .collect::<Vec<_>>();
+        items.sort();
+        assert_eq!(items, [((1, 0), 1), ((1, 1), 1), ((1, 2), 42), ((1, 3), 1)]);
+
+        assert_eq!(
+            store_item(&mut flash, flash_range.clone(), item(0xFF, 0, 0)),
+            Err(MapError::Item(NamespacedError::InvalidNamespace))
+        );
+
+        assert_item_round_trip::<_, MockFlashBig>(&item(0xFE, 0xFF, 0xFF));
+        assert_item_round_trip::<_, MockFlashTiny>(&Namespaced::new(
+            0,
+            Crc::new(ArrayItem {
+                key: 0,
+                value: [0xFF; 2],
+            }),
+        ));
+    }
+
+    #[test]
+    fn array_item_tiny_flash() {
+        let mut flash = MockFlashTiny::new();
+        let flash_range = 0x00..0x40;
+
+        let item = |key, value| ArrayItem::<4> {
+            key,
+            value: [value; 4],
+        };
+
+        // An item that is all 0xFF can be stored thanks to the tag
+        for (key, value) in [(0xFF, 0xFF), (0, 0), (1, 0xFF), (0xFF, 2), (0, 3)] {
+            store_item(&mut flash, flash_range.clone(), item(key, value)).unwrap();
+        }
+
+        for (key, value) in [(0xFF, 2), (0, 3), (1, 0xFF)] {
+            assert_eq!(
+                fetch_item::<ArrayItem<4>, _>(&mut flash, flash_range.clone(), key).unwrap(),
+                Some(item(key, value))
+            );
+        }
+        assert_eq!(
+            fetch_item::<ArrayItem<4>, _>(&mut flash, flash_range.clone(), 2).unwrap(),
+            None
+        );
+
+        assert_eq!(
+            ArrayItem::<4>::deserialize_from(&[0xFF; 6]),
+            Err(ArrayItemError::InvalidTag)
+        );
+        assert_eq!(
+            item(0, 0).serialize_into(&mut [0; 5]),
+            Err(ArrayItemError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn items_survive_the_store_pipeline() {
+        let guarded = Guarded::new(UnguardedByteItem(ByteItem {
+            key: 0xFF,
+            value: 0xFF,
+        }));
+        let array = ArrayItem::<3> {
+            key: 0xFF,
+            value: [0xFF; 3],
+        };
+        let varint = VarintKeyItem::<4> {
+            key: u64::MAX,
+            value: [1, 2, 3].as_slice().try_into().unwrap(),
+        };
+
+        assert_item_round_trip::<_, MockFlashBig>(&guarded);
+        assert_item_round_trip::<_, MockFlashTiny>(&guarded);
+        assert_item_round_trip::<_, MockFlashBig>(&array);
+        assert_item_round_trip::<_, MockFlashTiny>(&array);
+        assert_item_round_trip::<_, MockFlashBig>(&varint);
+        assert_item_round_trip::<_, MockFlashTiny>(&varint);
+        assert_item_round_trip::<_, MockFlashBig>(&Crc::new(array.clone()));
+        assert_item_round_trip::<_, MockFlashTiny>(&Crc::new(array));
+        assert_item_round_trip::<_, MockFlashBig>(&RawItem::<u8, 4>::new(0xFF, &[]).unwrap());
+        assert_item_round_trip::<_, MockFlashTiny>(
+            &RawItem::<u32, 4>::new(u32::MAX, &[0xFF; 4]).unwrap(),
+        );
+    }
+
+    #[test]
+    fn raw_items() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let blobs: [&[u8]; 4] = [b"", b"a", &[0xFF; 16], b"a longer blob that is not erased"];
+        for (key, blob) in blobs.into_iter().enumerate() {
+            let key = u32::MAX - key as u32;
+            store_item(
+                &mut flash,
+                flash_range.clone(),
+                RawItem::<u32, 32>::new(key, blob).unwrap(),
+            )
+            .unwrap();
+        }
+
+        for (key, blob) in blobs.into_iter().enumerate() {
+            let key = u32::MAX - key as u32;
+            let item =
+                fetch_item::<RawItem<u32, 32>, _>(&mut flash, flash_range.clone(), key).unwrap();
+            assert_eq!(item.unwrap().data.as_slice(), blob);
+        }
+
+        assert_eq!(RawItem::<u8, 2>::new(0, b"abc"), None);
+
+        // Reading the items with a smaller N fails instead of cutting off the data
+        assert_eq!(
+            fetch_item::<RawItem<u32, 8>, _>(&mut flash, flash_range.clone(), 0),
+            Err(MapError::Item(RawItemError::DataTooBig))
+        );
+    }
+
+    /// An item that takes the rest of the buffer as its value
+    #[derive(Debug, PartialEq, Eq)]
+    struct GreedyItem(Vec<u8>);
+
+    impl StorageItem for GreedyItem {
+        type Key = ();
+
+        type Error = ByteItemError;
+
+        fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+            if buffer.len() < self.0.len() {
+                return Err(ByteItemError);
+            }
+
+            buffer[..self.0.len()].copy_from_slice(&self.0);
+            Ok(self.0.len())
+        }
+
+        fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), Self::Error> {
+            Ok((Self(buffer.to_vec()), buffer.len()))
+        }
+
+        fn key(&self) -> Self::Key {}
+    }
+
+    #[test]
+    #[should_panic(expected = "Read from 512 bytes")]
+    fn greedy_item_fails_the_store_pipeline() {
+        // A plain round trip passes
+        let item = GreedyItem(vec![1, 2, 3]);
+        let mut buffer = [0xFF; 3];
+        let used_bytes = item.serialize_into(&mut buffer).unwrap();
+        assert_eq!(
+            GreedyItem::deserialize_from(&buffer[..used_bytes])
+                .unwrap()
+                .0,
+            item
+        );
+
+        assert_item_round_trip::<_, MockFlashBig>(&item);
+    }
+
+    #[test]
+    fn varint_key_round_trip() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        let keys = [0, 1, 0x7F, 0x80, 0x3FFF, 0x1234_5678_9ABC, u64::MAX];
+        let item = |key: u64| VarintKeyItem::<8> {
+            key,
+            value: key.to_le_bytes().into(),
+        };
+
+        let mut buffer = [0xFF; MAX_VARINT_LEN];
+        for (key, len) in keys.into_iter().zip([1, 1, 1, 2, 2, 7, 10]) {
+            assert_eq!(encode_varint_key(key, &mut buffer), Some(len));
+            assert_eq!(decode_varint_key(&buffer[..len]), Ok((key, len)));
+            assert_ne!(buffer[len - 1], 0xFF);
+
+            store_item(&mut flash, flash_range.clone(), item(key)).unwrap();
+        }
+
+        for key in keys {
+            assert_eq!(
+                fetch_item::<VarintKeyItem<8>, _>(&mut flash, flash_range.clone(), key).unwrap(),
+                Some(item(key))
+            );
+        }
+        assert_eq!(
+            fetch_item::<VarintKeyItem<8>, _>(&mut flash, flash_range.clone(), 2).unwrap(),
+            None
+        );
+
+        // 0x3FFF starts with a 0xFF byte
+        assert_eq!(encode_varint_key(0x3FFF, &mut buffer), Some(2));
+        assert_eq!(buffer[..2], [0xFF, 0x7F]);
+
+        assert_eq!(encode_varint_key(0x80, &mut buffer[..1]), None);
+        assert_eq!(
+            decode_varint_key(&[0x80]),
+            Err(VarintKeyItemError::BufferTooSmall)
+        );
+        assert_eq!(
+            decode_varint_key(&[0xFF; MAX_VARINT_LEN]),
+            Err(VarintKeyItemError::InvalidKey)
+        );
+    }
+
+    #[test]
+    fn ensure_not_erased_no_room() {
+        let mut buffer = [0xFF; 2];
+        assert_eq!(ensure_not_erased(&mut buffer, 2), None);
+        assert_eq!(ensure_not_erased(&mut buffer, 0), Some(1));
+        assert_eq!(buffer, [0x00, 0xFF]);
+    }
+
+    /// A simple run length encoding of `(count, byte)` pairs
+    #[derive(Debug, PartialEq, Eq)]
+    struct Rle;
+
+    impl Codec for Rle {
+        fn compress(input: &[u8], output: &mut [u8]) -> Option<usize> {
+            let mut used = 0;
+
+            for chunk in input.chunk_by(|a, b| a == b) {
+                for run in chunk.chunks(u8::MAX as usize) {
+                    output
+                        .get_mut(used..used + 2)?
+                        .copy_from_slice(&[run.len() as u8, run[0]]);
+                    used += 2;
+                }
+            }
+
+            Some(used)
+        }
+
+        fn decompress(input: &[u8], output: &mut [u8]) -> Option<usize> {
+            let mut used = 0;
+
+            for pair in input.chunks(2) {
+                let &[count, byte] = pair else {
+                    return None;
+                };
+                output.get_mut(used..used + count as usize)?.fill(byte);
+                used += count as usize;
+            }
+
+            Some(used)
+        }
+    }
+
+    #[test]
+    fn compressed_store_and_fetch() {
+        let mut flash = MockFlashBig::new();
+        let flash_range = 0x000..0x1000;
+
+        for key in 0..4 {
+            store_item(
+                &mut flash,
+                flash_range.clone(),
+                Compressed::<_, Rle>::new(MockStorageItem {
+                    key,
+                    value: vec![key; 100],
+                }),
+            )
+            .unwrap();
+        }
+
+        for key in 0..4 {
+            let item = fetch_item::<Compressed<MockStorageItem, Rle>, _>(
+                &mut flash,
+                flash_range.clone(),
+                key,
+            )
+            .unwrap()
+            .unwrap();
+            assert_eq!(item.item.value, vec![key; 100]);
+        }
+
+        // Uncompressed, the four items would need more than 400 bytes.
+        // Compressed, each is 2 bytes of length and three runs of 2 bytes: key, length and value.
+        // The items start after the start marker
+        let used_bytes = flash.as_bytes()[4..0x100 - 4]
+            .iter()
+            .rposition(|byte| *byte != 0xFF)
+            .unwrap();
+        assert!(used_bytes < 4 * 8, "{used_bytes}");
+    }
+
+    #[test]
+    fn compressed_too_big() {
+        let item = Compressed::<_, Rle>::new(MockStorageItem {
+            key: 0,
+            value: (0..100).collect(),
+        });
+
+        let mut buffer = [0xFF; 64];
+        assert!(item
+            .serialize_into(&mut buffer)
+            .unwrap_err()
+            .is_buffer_too_small());
+    }
+}
@@ -1,8 +1,15 @@
+//! A mock flash implementation that can be used to test the storage.
+//!
+//! It keeps track of how often every word is written and counts the reads, writes and erases.
+//! This module is only available with the `mock-flash` feature.
+
+use crate::map::{StorageItem, StorageItemError, MAX_STORAGE_ITEM_SIZE};
 use core::ops::Range;
 use embedded_storage::nor_flash::{
     ErrorType, MultiwriteNorFlash, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
 };
 
+/// How often a word can still be written
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Writable {
     /// Twice
@@ -15,56 +22,256 @@ pub enum Writable {
 
 use Writable::*;
 
+/// A mock flash with `PAGES` pages of `PAGE_WORDS` words that are `BYTES_PER_WORD` bytes big.
+///
+/// The word size is the write size and the page size is the erase size. The read size is `READ_BYTES`, 1 by default.
 #[derive(Debug, Clone)]
-pub struct MockFlashBase<const PAGES: usize, const BYTES_PER_WORD: usize, const PAGE_WORDS: usize> {
+pub struct MockFlashBase<
+    const PAGES: usize,
+    const BYTES_PER_WORD: usize,
+    const PAGE_WORDS: usize,
+    const READ_BYTES: usize = 1,
+> {
     writable: Vec<Writable>,
     words: Vec<u32>,
+    /// The amount of erase operations
     pub erases: u32,
+    /// How often every page has been erased, see [Self::page_erase_counts]
+    page_erases: Vec<u32>,
+    /// The amount of read operations
     pub reads: u32,
+    /// The amount of write operations
     pub writes: u32,
+    op_log: Option<Vec<FlashOp>>,
+    /// The amount of writes and erases that still succeed, see [Self::fail_after]
+    fail_after: Option<u32>,
+    power_lost: bool,
 }
 
-impl<const PAGES: usize, const BYTES_PER_WORD: usize, const PAGE_WORDS: usize> Default
-    for MockFlashBase<PAGES, BYTES_PER_WORD, PAGE_WORDS>
+impl<
+        const PAGES: usize,
+        const BYTES_PER_WORD: usize,
+        const PAGE_WORDS: usize,
+        const READ_BYTES: usize,
+    > Default for MockFlashBase<PAGES, BYTES_PER_WORD, PAGE_WORDS, READ_BYTES>
 {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl<const PAGES: usize, const BYTES_PER_WORD: usize, const PAGE_WORDS: usize>
-    MockFlashBase<PAGES, BYTES_PER_WORD, PAGE_WORDS>
+impl<
+        const PAGES: usize,
+        const BYTES_PER_WORD: usize,
+        const PAGE_WORDS: usize,
+        const READ_BYTES: usize,
+    > MockFlashBase<PAGES, BYTES_PER_WORD, PAGE_WORDS, READ_BYTES>
 {
     const CAPACITY_WORDS: usize = PAGES * PAGE_WORDS;
     const CAPACITY_BYTES: usize = Self::CAPACITY_WORDS * BYTES_PER_WORD;
 
     const PAGE_BYTES: usize = PAGE_WORDS * BYTES_PER_WORD;
 
+    /// Create a new fully erased flash
     pub fn new() -> Self {
         Self {
             writable: vec![T; Self::CAPACITY_WORDS],
             words: vec![u32::MAX; Self::CAPACITY_WORDS],
             erases: 0,
+            page_erases: vec![0; PAGES],
             reads: 0,
             writes: 0,
+            op_log: None,
+            fail_after: None,
+            power_lost: false,
+        }
+    }
+
+    /// Start or stop recording every flash operation in the op log.
+    /// Stopping also clears the log.
+    ///
+    /// Recording is off by default, because the log grows with every operation.
+    pub fn record_ops(&mut self, enabled: bool) {
+        self.op_log = enabled.then(Vec::new);
+    }
+
+    /// Take the operations that have been recorded so far, in the order they happened.
+    /// Recording continues with an empty log.
+    ///
+    /// This shows exactly what a call does, e.g. how a page gets closed and its items are moved:
+    ///
+    /// ```rust,ignore
+    /// flash.record_ops(true);
+    /// store_item(&mut flash, flash_range.clone(), item).unwrap();
+    /// for op in flash.take_op_log() {
+    ///     println!("{op:?}");
+    /// }
+    /// ```
+    pub fn take_op_log(&mut self) -> Vec<FlashOp> {
+        self.op_log
+            .as_mut()
+            .map(core::mem::take)
+            .unwrap_or_default()
+    }
+
+    fn log_op(&mut self, op: FlashOp) {
+        if let Some(op_log) = self.op_log.as_mut() {
+            op_log.push(op);
+        }
+    }
+
+    /// Let the next `operations` writes and erases succeed and fail the one after that, like a power loss in the middle of it.
+    ///
+    /// The failing write only writes the first half of its words, rounded down. The failing erase doesn't erase anything.
+    /// Every write and erase after it fails too without touching the flash, until this is called with None,
+    /// which is like powering the flash back up. Reads keep working.
+    ///
+    /// Trying every amount of operations finds the states a power loss can leave behind:
+    ///
+    /// ```rust,ignore
+    /// for operations in 0.. {
+    ///     let mut flash = flash.clone();
+    ///     flash.fail_after(Some(operations));
+    ///     let result = store_item(&mut flash, flash_range.clone(), item);
+    ///     flash.fail_after(None);
+    ///     // Check the state of the flash here
+    ///     if result.is_ok() {
+    ///         break;
+    ///     }
+    /// }
+    /// ```
+    pub fn fail_after(&mut self, operations: Option<u32>) {
+        self.fail_after = operations;
+        self.power_lost = false;
+    }
+
+    /// Count a write or erase towards the power loss.
+    /// Returns None if the operation can go ahead, or else whether it's the operation during which the power is lost.
+    fn lose_power(&mut self) -> Option<bool> {
+        if self.power_lost {
+            return Some(false);
+        }
+
+        match self.fail_after.as_mut() {
+            None => None,
+            Some(0) => {
+                self.power_lost = true;
+                Some(true)
+            }
+            Some(operations) => {
+                *operations -= 1;
+                None
+            }
         }
     }
 
+    /// Set the erase, read and write counters back to 0, including the erase counts of the pages
+    pub fn reset_counters(&mut self) {
+        self.erases = 0;
+        self.page_erases.fill(0);
+        self.reads = 0;
+        self.writes = 0;
+    }
+
+    /// Run the given function and assert that it erased fewer than `max_erases` pages.
+    ///
+    /// This makes it easy to write wear regression tests:
+    ///
+    /// ```rust,ignore
+    /// flash.assert_erases_under(10, |flash| {
+    ///     for _ in 0..1000 {
+    ///         store_item(flash, flash_range.clone(), boot_config.clone()).unwrap();
+    ///     }
+    /// });
+    /// ```
+    #[track_caller]
+    pub fn assert_erases_under<R>(&mut self, max_erases: u32, f: impl FnOnce(&mut Self) -> R) -> R {
+        let erases_before = self.erases;
+        let result = f(self);
+        let erases = self.erases - erases_before;
+
+        assert!(
+            erases < max_erases,
+            "Expected fewer than {max_erases} erases, but there were {erases}"
+        );
+
+        result
+    }
+
+    /// How often every page has been erased, indexed by page.
+    ///
+    /// Unlike [Self::erases], which counts erase operations, this counts the pages that were actually erased.
+    /// An erase that fails, e.g. because of a power loss, isn't counted.
+    /// This shows how evenly the wear is spread over the pages.
+    pub fn page_erase_counts(&self) -> &[u32] {
+        &self.page_erases
+    }
+
+    /// Get the raw contents of the flash
     pub fn as_bytes(&self) -> &[u8] {
         let ptr_words = self.words.as_ptr();
         let ptr_bytes = ptr_words as *const u8;
         unsafe { core::slice::from_raw_parts(ptr_bytes, Self::CAPACITY_BYTES) }
     }
 
+    /// Get the raw contents of the flash mutably.
+    /// Changes made through this are not tracked.
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
         let ptr_words = self.words.as_mut_ptr();
         let ptr_bytes = ptr_words as *mut u8;
         unsafe { core::slice::from_raw_parts_mut(ptr_bytes, Self::CAPACITY_BYTES) }
     }
 
+    /// Flip a bit of the byte at the offset, like a bit flip in the flash cells.
+    ///
+    /// Like a change through [Self::as_bytes_mut], this is not an operation and not tracked.
+    /// Bit 0 is the least significant bit.
+    pub fn flip_bit(&mut self, offset: usize, bit: u8) {
+        assert!(bit < 8, "A byte only has 8 bits");
+        self.as_bytes_mut()[offset] ^= 1 << bit;
+    }
+
+    /// Flip `count` random bits in the byte range with [Self::flip_bit].
+    ///
+    /// The bits are picked by a simple pseudo random generator, so the same seed always flips the same bits
+    /// and a failing test can be reproduced. A bit that's picked twice is flipped back.
+    /// Returns the offset and bit of every flip, in the order they were done.
+    pub fn flip_random_bits(
+        &mut self,
+        range: Range<usize>,
+        count: usize,
+        seed: u64,
+    ) -> Vec<(usize, u8)> {
+        assert!(
+            !range.is_empty(),
+            "There are no bits to flip in an empty range"
+        );
+
+        // Xorshift64*, which is good enough to spread the flips and needs a state that's not 0
+        let mut state = (seed ^ 0x9E37_79B9_7F4A_7C15).max(1);
+        let mut next_random = move || {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+        };
+
+        (0..count)
+            .map(|_| {
+                let bit_index = next_random() % (range.len() as u64 * 8);
+                let flip = (
+                    range.start + (bit_index / 8) as usize,
+                    (bit_index % 8) as u8,
+                );
+                self.flip_bit(flip.0, flip.1);
+                flip
+            })
+            .collect()
+    }
+
     fn validate_read_operation(offset: u32, length: usize) -> Result<Range<usize>, MockFlashError> {
         let offset = offset as usize;
-        if (offset % Self::READ_SIZE) != 0 {
+        if offset % Self::READ_SIZE != 0 || length % Self::READ_SIZE != 0 {
             Err(MockFlashError::NotAligned)
         } else if offset > Self::CAPACITY_BYTES || offset + length > Self::CAPACITY_BYTES {
             Err(MockFlashError::OutOfBounds)
@@ -90,19 +297,31 @@ impl<const PAGES: usize, const BYTES_PER_WORD: usize, const PAGE_WORDS: usize>
     }
 }
 
-impl<const PAGES: usize, const BYTES_PER_WORD: usize, const PAGE_WORDS: usize> ErrorType
-    for MockFlashBase<PAGES, BYTES_PER_WORD, PAGE_WORDS>
+impl<
+        const PAGES: usize,
+        const BYTES_PER_WORD: usize,
+        const PAGE_WORDS: usize,
+        const READ_BYTES: usize,
+    > ErrorType for MockFlashBase<PAGES, BYTES_PER_WORD, PAGE_WORDS, READ_BYTES>
 {
     type Error = MockFlashError;
 }
 
-impl<const PAGES: usize, const BYTES_PER_WORD: usize, const PAGE_WORDS: usize> ReadNorFlash
-    for MockFlashBase<PAGES, BYTES_PER_WORD, PAGE_WORDS>
+impl<
+        const PAGES: usize,
+        const BYTES_PER_WORD: usize,
+        const PAGE_WORDS: usize,
+        const READ_BYTES: usize,
+    > ReadNorFlash for MockFlashBase<PAGES, BYTES_PER_WORD, PAGE_WORDS, READ_BYTES>
 {
-    const READ_SIZE: usize = 1;
+    const READ_SIZE: usize = READ_BYTES;
 
     fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
         self.reads += 1;
+        self.log_op(FlashOp::Read {
+            address: offset,
+            len: bytes.len(),
+        });
 
         let range = Self::validate_read_operation(offset, bytes.len())?;
 
@@ -116,13 +335,39 @@ impl<const PAGES: usize, const BYTES_PER_WORD: usize, const PAGE_WORDS: usize> R
     }
 }
 
-impl<const PAGES: usize, const BYTES_PER_WORD: usize, const PAGE_WORDS: usize> MultiwriteNorFlash
-    for MockFlashBase<PAGES, BYTES_PER_WORD, PAGE_WORDS>
+/// Shared reads are not counted in [MockFlashBase::reads] and not logged
+impl<
+        const PAGES: usize,
+        const BYTES_PER_WORD: usize,
+        const PAGE_WORDS: usize,
+        const READ_BYTES: usize,
+    > crate::map::SharedReadNorFlash
+    for MockFlashBase<PAGES, BYTES_PER_WORD, PAGE_WORDS, READ_BYTES>
+{
+    fn read_shared(&self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let range = Self::validate_read_operation(offset, bytes.len())?;
+
+        bytes.copy_from_slice(&self.as_bytes()[range]);
+
+        Ok(())
+    }
+}
+
+impl<
+        const PAGES: usize,
+        const BYTES_PER_WORD: usize,
+        const PAGE_WORDS: usize,
+        const READ_BYTES: usize,
+    > MultiwriteNorFlash for MockFlashBase<PAGES, BYTES_PER_WORD, PAGE_WORDS, READ_BYTES>
 {
 }
 
-impl<const PAGES: usize, const BYTES_PER_WORD: usize, const PAGE_WORDS: usize> NorFlash
-    for MockFlashBase<PAGES, BYTES_PER_WORD, PAGE_WORDS>
+impl<
+        const PAGES: usize,
+        const BYTES_PER_WORD: usize,
+        const PAGE_WORDS: usize,
+        const READ_BYTES: usize,
+    > NorFlash for MockFlashBase<PAGES, BYTES_PER_WORD, PAGE_WORDS, READ_BYTES>
 {
     const WRITE_SIZE: usize = BYTES_PER_WORD;
 
@@ -130,6 +375,10 @@ impl<const PAGES: usize, const BYTES_PER_WORD: usize, const PAGE_WORDS: usize> N
 
     fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
         self.erases += 1;
+        self.log_op(FlashOp::Erase {
+            address: from,
+            len: to.saturating_sub(from) as usize,
+        });
 
         let from = from as usize;
         let to = to as usize;
@@ -144,10 +393,18 @@ impl<const PAGES: usize, const BYTES_PER_WORD: usize, const PAGE_WORDS: usize> N
             return Err(MockFlashError::NotAligned);
         }
 
+        if self.lose_power().is_some() {
+            return Err(MockFlashError::PowerLoss);
+        }
+
         for byte in self.as_bytes_mut()[from..to].iter_mut() {
             *byte = u8::MAX;
         }
 
+        for page_erases in &mut self.page_erases[from / Self::PAGE_BYTES..to / Self::PAGE_BYTES] {
+            *page_erases += 1;
+        }
+
         let range = from / BYTES_PER_WORD..to / BYTES_PER_WORD;
         for word_writable in self.writable[range].iter_mut() {
             *word_writable = T;
@@ -158,6 +415,10 @@ impl<const PAGES: usize, const BYTES_PER_WORD: usize, const PAGE_WORDS: usize> N
 
     fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
         self.writes += 1;
+        self.log_op(FlashOp::Write {
+            address: offset,
+            len: bytes.len(),
+        });
 
         let range = self.validate_write_operation(offset, bytes.len())?;
 
@@ -165,8 +426,33 @@ impl<const PAGES: usize, const BYTES_PER_WORD: usize, const PAGE_WORDS: usize> N
             panic!("any write must be a multiple of Self::WRITE_SIZE bytes");
         }
 
+        match self.lose_power() {
+            None => {}
+            Some(true) => {
+                let torn_len = bytes.len() / BYTES_PER_WORD / 2 * BYTES_PER_WORD;
+                self.write_words(range.start..range.start + torn_len, &bytes[..torn_len]);
+                return Err(MockFlashError::PowerLoss);
+            }
+            Some(false) => return Err(MockFlashError::PowerLoss),
+        }
+
+        self.write_words(range, bytes);
+
+        Ok(())
+    }
+}
+
+impl<
+        const PAGES: usize,
+        const BYTES_PER_WORD: usize,
+        const PAGE_WORDS: usize,
+        const READ_BYTES: usize,
+    > MockFlashBase<PAGES, BYTES_PER_WORD, PAGE_WORDS, READ_BYTES>
+{
+    /// Write the bytes to the validated range
+    fn write_words(&mut self, range: Range<usize>, bytes: &[u8]) {
         let start_word = range.start / BYTES_PER_WORD;
-        let end_word = (range.end + BYTES_PER_WORD - 1) / BYTES_PER_WORD;
+        let end_word = range.end.div_ceil(BYTES_PER_WORD);
 
         for (target, source) in self.as_bytes_mut()[range].iter_mut().zip(bytes.iter()) {
             *target &= *source;
@@ -179,16 +465,112 @@ impl<const PAGES: usize, const BYTES_PER_WORD: usize, const PAGE_WORDS: usize> N
                 Writable::N => Writable::N,
             };
         }
+    }
+}
 
-        Ok(())
+/// Run the item through the same steps the map takes to store it in flash of type `S` and read it back,
+/// and assert that the same item comes back.
+///
+/// A plain serialize and deserialize round trip misses what the map does around it:
+/// - The serialized bytes are padded with `0xFF` up to a whole `WRITE_SIZE` word.
+/// - On reading, the buffer holds the padding and whatever comes after the item in the page,
+///   or ends right after the padding when the item is the last one of the page.
+/// - A page is read lazily, so the deserializer must report a buffer that's too small for a partial item.
+/// - The used bytes that are returned must be the length of the serialized item, not the padded length.
+///
+/// ```rust,ignore
+/// assert_item_round_trip::<_, MyFlash>(&my_item);
+/// ```
+#[track_caller]
+pub fn assert_item_round_trip<I, S>(item: &I)
+where
+    I: StorageItem + PartialEq + core::fmt::Debug,
+    S: NorFlash,
+{
+    // Like the buffers of the map, so an item that is bigger than its MAX_SIZE still fits to be caught below
+    let mut buffer = vec![
+        0xFF;
+        I::MAX_SIZE
+            .next_multiple_of(S::WRITE_SIZE)
+            .max(MAX_STORAGE_ITEM_SIZE)
+    ];
+    let used_bytes = item.serialize_into(&mut buffer).unwrap();
+    let padded_bytes = used_bytes.div_ceil(S::WRITE_SIZE) * S::WRITE_SIZE;
+
+    assert!(
+        used_bytes <= I::MAX_SIZE,
+        "The item takes {used_bytes} bytes, which is more than its MAX_SIZE of {}",
+        I::MAX_SIZE
+    );
+    assert!(
+        buffer[..used_bytes].iter().any(|byte| *byte != 0xFF),
+        "The item is serialized as all 0xFF, which can't be told apart from erased flash"
+    );
+    let leading_erased_bytes = buffer.iter().take_while(|byte| **byte == 0xFF).count();
+    assert!(
+        leading_erased_bytes <= I::MAX_LEADING_ERASED_BYTES,
+        "The item starts with {leading_erased_bytes} 0xFF bytes, but MAX_LEADING_ERASED_BYTES is {}",
+        I::MAX_LEADING_ERASED_BYTES
+    );
+
+    // The item is followed by erased flash, or it's the last item of the page
+    for read_bytes in [buffer.len(), padded_bytes] {
+        let (read_item, read_used_bytes) = I::deserialize_from(&buffer[..read_bytes]).unwrap();
+        assert_eq!(&read_item, item, "Read from {read_bytes} bytes");
+        assert_eq!(
+            read_used_bytes, used_bytes,
+            "The used bytes read from {read_bytes} bytes differ from the serialized length"
+        );
+    }
+
+    for read_bytes in 0..used_bytes {
+        match I::deserialize_from(&buffer[..read_bytes]) {
+            Err(e) if e.is_buffer_too_small() => {}
+            result => panic!(
+                "Reading only {read_bytes} of the {used_bytes} bytes must report a buffer that's too small, but got {:?}",
+                result.map(|(_, used_bytes)| used_bytes)
+            ),
+        }
     }
 }
 
+/// A flash operation as recorded by [MockFlashBase::take_op_log]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashOp {
+    /// A read of `len` bytes at the address
+    Read {
+        /// The start address
+        address: u32,
+        /// The amount of bytes
+        len: usize,
+    },
+    /// A write of `len` bytes at the address
+    Write {
+        /// The start address
+        address: u32,
+        /// The amount of bytes
+        len: usize,
+    },
+    /// An erase of `len` bytes at the address
+    Erase {
+        /// The start address
+        address: u32,
+        /// The amount of bytes
+        len: usize,
+    },
+}
+
+/// The errors of the mock flash
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MockFlashError {
+    /// The operation was out of the bounds of the flash
     OutOfBounds,
+    /// The operation was not aligned
     NotAligned,
+    /// The word at the address has been written too often since the last erase
     NotWritable(u32),
+    /// The power was lost during or before the operation, see [MockFlashBase::fail_after]
+    PowerLoss,
 }
 
 impl NorFlashError for MockFlashError {
@@ -197,6 +579,22 @@ impl NorFlashError for MockFlashError {
             MockFlashError::OutOfBounds => NorFlashErrorKind::OutOfBounds,
             MockFlashError::NotAligned => NorFlashErrorKind::NotAligned,
             MockFlashError::NotWritable(_) => NorFlashErrorKind::Other,
+            MockFlashError::PowerLoss => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+impl core::fmt::Display for MockFlashError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MockFlashError::OutOfBounds => f.write_str("the operation is out of bounds"),
+            MockFlashError::NotAligned => f.write_str("the operation is not aligned"),
+            MockFlashError::NotWritable(address) => {
+                write!(f, "the word at {address:#X} can't be written again")
+            }
+            MockFlashError::PowerLoss => f.write_str("the power was lost"),
         }
     }
 }
+
+impl std::error::Error for MockFlashError {}
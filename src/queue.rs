@@ -22,12 +22,7 @@ pub fn push<S: MultiwriteNorFlash>(
     data: &[u8],
     allow_overwrite_old_data: bool,
 ) -> Result<(), Error<S::Error>> {
-    assert_eq!(flash_range.start % S::ERASE_SIZE as u32, 0);
-    assert_eq!(flash_range.end % S::ERASE_SIZE as u32, 0);
-
-    assert!(S::ERASE_SIZE >= S::WRITE_SIZE * 4);
-    assert_eq!(S::READ_SIZE, 1);
-    assert!(S::WRITE_SIZE <= 16);
+    check_geometry::<S>(&flash_range)?;
 
     if data.is_empty() {
         return Err(Error::BufferTooSmall);
@@ -44,9 +39,9 @@ pub fn push<S: MultiwriteNorFlash>(
     let current_page = find_youngest_page(flash, flash_range.clone())?;
 
     let page_data_start_address =
-        calculate_page_address::<S>(flash_range.clone(), current_page) + S::WRITE_SIZE as u32;
+        calculate_page_data_start_address::<S>(flash_range.clone(), current_page);
     let page_data_end_address =
-        calculate_page_end_address::<S>(flash_range.clone(), current_page) - S::WRITE_SIZE as u32;
+        calculate_page_data_end_address::<S>(flash_range.clone(), current_page);
 
     partial_close_page(flash, flash_range.clone(), current_page)?;
 
@@ -76,8 +71,7 @@ pub fn push<S: MultiwriteNorFlash>(
                     close_page(flash, flash_range.clone(), current_page)?;
                 }
                 partial_close_page(flash, flash_range.clone(), next_page)?;
-                next_address =
-                    calculate_page_address::<S>(flash_range, next_page) + S::WRITE_SIZE as u32;
+                next_address = calculate_page_data_start_address::<S>(flash_range, next_page);
             }
             PageState::Closed => {
                 if !allow_overwrite_old_data {
@@ -93,13 +87,11 @@ pub fn push<S: MultiwriteNorFlash>(
 
                 close_page(flash, flash_range.clone(), current_page)?;
                 partial_close_page(flash, flash_range.clone(), next_page)?;
-                next_address =
-                    calculate_page_address::<S>(flash_range, next_page) + S::WRITE_SIZE as u32;
+                next_address = calculate_page_data_start_address::<S>(flash_range, next_page);
             }
             PageState::PartialOpen => {
                 // This should never happen
-                #[cfg(feature = "defmt")]
-                defmt::error!("Corrupted: A we expected an open or closed page, but found a partial open page");
+                error!("Corrupted: A we expected an open or closed page, but found a partial open page");
                 return Err(Error::Corrupted);
             }
         }
@@ -148,12 +140,7 @@ pub fn peek<S: MultiwriteNorFlash, const CAP: usize>(
     flash: &mut S,
     flash_range: Range<u32>,
 ) -> Result<Option<ArrayVec<u8, CAP>>, Error<S::Error>> {
-    assert_eq!(flash_range.start % S::ERASE_SIZE as u32, 0);
-    assert_eq!(flash_range.end % S::ERASE_SIZE as u32, 0);
-
-    assert!(S::ERASE_SIZE >= S::WRITE_SIZE * 4);
-    assert_eq!(S::READ_SIZE, 1);
-    assert!(S::WRITE_SIZE <= 16);
+    check_geometry::<S>(&flash_range)?;
 
     let oldest_page = find_oldest_page(flash, flash_range.clone())?;
 
@@ -199,12 +186,7 @@ pub fn pop<S: MultiwriteNorFlash, const CAP: usize>(
     flash: &mut S,
     flash_range: Range<u32>,
 ) -> Result<Option<ArrayVec<u8, CAP>>, Error<S::Error>> {
-    assert_eq!(flash_range.start % S::ERASE_SIZE as u32, 0);
-    assert_eq!(flash_range.end % S::ERASE_SIZE as u32, 0);
-
-    assert!(S::ERASE_SIZE >= S::WRITE_SIZE * 4);
-    assert_eq!(S::READ_SIZE, 1);
-    assert!(S::WRITE_SIZE <= 16);
+    check_geometry::<S>(&flash_range)?;
 
     let oldest_page = find_oldest_page(flash, flash_range.clone())?;
 
@@ -273,6 +255,23 @@ pub fn pop<S: MultiwriteNorFlash, const CAP: usize>(
     Ok(Some(return_data))
 }
 
+/// Check the flash geometry and the flash range at the start of every queue function.
+///
+/// With the `strict-asserts` feature a problem panics, otherwise [Error::InvalidFlashGeometry] is returned.
+#[track_caller]
+fn check_geometry<S: NorFlash>(flash_range: &Range<u32>) -> Result<(), Error<S::Error>> {
+    let valid = is_page_aligned_range::<S>(flash_range)
+        && min_aligned_page_size::<S>() >= S::WRITE_SIZE * 4
+        && S::READ_SIZE == 1
+        && S::WRITE_SIZE <= 16;
+
+    check_validated_geometry(if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidFlashGeometry)
+    })
+}
+
 /// Returns true if the page was erased
 fn erase_page_if_rest_is_empty<S: NorFlash>(
     flash: &mut S,
@@ -284,7 +283,7 @@ fn erase_page_if_rest_is_empty<S: NorFlash>(
     let current_page = calculate_page_index::<S>(flash_range.clone(), address);
 
     let page_data_end_address =
-        calculate_page_end_address::<S>(flash_range.clone(), current_page) - S::WRITE_SIZE as u32;
+        calculate_page_data_end_address::<S>(flash_range.clone(), current_page);
 
     while address != page_data_end_address {
         let read_length = ((page_data_end_address - address) as usize).min(buffer.len());
@@ -331,8 +330,7 @@ fn find_youngest_page<S: NorFlash>(
     }
 
     // All pages are closed... This is not correct.
-    #[cfg(feature = "defmt")]
-    defmt::error!("Corrupted: All pages are closed");
+    error!("Corrupted: All pages are closed");
 
     Err(Error::Corrupted)
 }
@@ -355,9 +353,8 @@ fn page_item_adresses_iter<S: NorFlash>(
     page_index: usize,
 ) -> impl Iterator<Item = Result<ItemAddress, Error<S::Error>>> + '_ {
     let page_data_start_address =
-        calculate_page_address::<S>(flash_range.clone(), page_index) + S::WRITE_SIZE as u32;
-    let page_data_end_address =
-        calculate_page_end_address::<S>(flash_range, page_index) - S::WRITE_SIZE as u32;
+        calculate_page_data_start_address::<S>(flash_range.clone(), page_index);
+    let page_data_end_address = calculate_page_data_end_address::<S>(flash_range, page_index);
 
     let mut current_address = page_data_start_address;
     let mut done = false;
@@ -405,8 +402,7 @@ fn page_item_adresses_iter<S: NorFlash>(
             if length as u32 > data_remaining {
                 // All data must fit in a page and this seems like it's not.
                 // Something is wrong. Make this the last thing this iterator yields
-                #[cfg(feature = "defmt")]
-                defmt::error!("Corrupted: A page item was found at 0x{:X} with a seemingly longer length than logical of {}", current_address, length);
+                error!("Corrupted: A page item was found at 0x{:X} with a seemingly longer length than logical of {}", current_address, length);
 
                 current_address = page_data_end_address;
                 return Some(Err(Error::Corrupted));
@@ -440,13 +436,6 @@ enum ItemAddress {
     Empty { address: u32 },
 }
 
-const fn next_multiple_of(value: u32, multiple: u32) -> u32 {
-    match value % multiple {
-        0 => value,
-        r => value + (multiple - r),
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,257 @@
+//! A flash wrapper that retries operations that fail with a transient error.
+//!
+//! Wrap your flash in a [RetryFlash] and pass that to the map or queue functions.
+//! Every `read`, `write` and `erase` that fails is retried for as long as the [RetryPolicy] allows it.
+//! Only the error of the last attempt is returned.
+//!
+//! ```rust,ignore
+//! let mut flash = RetryFlash::new(flash, |operation, _error: &_, attempt| {
+//!     // Retry twice and give the flash some time to settle
+//!     delay.delay_us(100 << attempt);
+//!     operation != FlashOperation::Write && attempt < 3
+//! });
+//!
+//! map::store_item(&mut flash, flash_range, item)?;
+//! ```
+//!
+//! ## Idempotency
+//!
+//! Reads and erases can always be retried.
+//!
+//! A failed write may have written some of the bytes already. Retrying it writes the same bytes again.
+//! Because a flash write can only turn bits from 1 to 0, writing the same bytes again gives the same result,
+//! but only if the flash allows a word to be written more than once between erases.
+//! Flash that implements [MultiwriteNorFlash] allows that. For other flash, only retry a write if the error
+//! guarantees nothing was written, e.g. because the flash was busy.
+
+use embedded_storage::nor_flash::{ErrorType, MultiwriteNorFlash, NorFlash, ReadNorFlash};
+
+/// A flash operation that can be retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FlashOperation {
+    /// A read
+    Read,
+    /// A write
+    Write,
+    /// An erase
+    Erase,
+}
+
+/// Decides whether a failed flash operation is tried again.
+///
+/// The policy is also the place to wait a bit before the next attempt, since this crate doesn't depend on a timer.
+/// It's implemented for closures with the same signature as [RetryPolicy::should_retry].
+pub trait RetryPolicy<E> {
+    /// Called after the operation failed for the `attempt`th time, starting at 1.
+    /// Return true to try it again.
+    fn should_retry(&mut self, operation: FlashOperation, error: &E, attempt: usize) -> bool;
+}
+
+impl<E, F: FnMut(FlashOperation, &E, usize) -> bool> RetryPolicy<E> for F {
+    fn should_retry(&mut self, operation: FlashOperation, error: &E, attempt: usize) -> bool {
+        self(operation, error, attempt)
+    }
+}
+
+/// A policy that never retries. This is the same as not wrapping the flash at all.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoRetry;
+
+impl<E> RetryPolicy<E> for NoRetry {
+    fn should_retry(&mut self, _operation: FlashOperation, _error: &E, _attempt: usize) -> bool {
+        false
+    }
+}
+
+/// A flash that retries the failed operations of the inner flash as long as the policy allows it
+#[derive(Debug)]
+pub struct RetryFlash<S, P> {
+    flash: S,
+    policy: P,
+}
+
+impl<S> RetryFlash<S, NoRetry> {
+    /// Wrap the flash without retrying anything
+    pub const fn without_retries(flash: S) -> Self {
+        Self::new(flash, NoRetry)
+    }
+}
+
+impl<S, P> RetryFlash<S, P> {
+    /// Wrap the flash with the given retry policy
+    pub const fn new(flash: S, policy: P) -> Self {
+        Self { flash, policy }
+    }
+
+    /// Get the inner flash back
+    pub fn into_inner(self) -> S {
+        self.flash
+    }
+}
+
+impl<S: ErrorType, P: RetryPolicy<S::Error>> RetryFlash<S, P> {
+    fn retry(
+        &mut self,
+        operation: FlashOperation,
+        mut f: impl FnMut(&mut S) -> Result<(), S::Error>,
+    ) -> Result<(), S::Error> {
+        let mut attempt = 0;
+
+        loop {
+            match f(&mut self.flash) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    attempt += 1;
+
+                    if !self.policy.should_retry(operation, &e, attempt) {
+                        return Err(e);
+                    }
+
+                    warn!("Retrying flash {:?} after attempt {}", operation, attempt);
+                }
+            }
+        }
+    }
+}
+
+impl<S: ErrorType, P> ErrorType for RetryFlash<S, P> {
+    type Error = S::Error;
+}
+
+impl<S: ReadNorFlash, P: RetryPolicy<S::Error>> ReadNorFlash for RetryFlash<S, P> {
+    const READ_SIZE: usize = S::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.retry(FlashOperation::Read, |flash| flash.read(offset, bytes))
+    }
+
+    fn capacity(&self) -> usize {
+        self.flash.capacity()
+    }
+}
+
+impl<S: NorFlash, P: RetryPolicy<S::Error>> NorFlash for RetryFlash<S, P> {
+    const WRITE_SIZE: usize = S::WRITE_SIZE;
+
+    const ERASE_SIZE: usize = S::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.retry(FlashOperation::Erase, |flash| flash.erase(from, to))
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.retry(FlashOperation::Write, |flash| flash.write(offset, bytes))
+    }
+}
+
+impl<S: MultiwriteNorFlash, P: RetryPolicy<S::Error>> MultiwriteNorFlash for RetryFlash<S, P> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::tests::{MockFlashBig, MockStorageItem};
+    use crate::map::{fetch_item, store_item, MapError};
+    use crate::mock_flash::MockFlashError;
+
+    /// Fails every other operation, after doing half of a write
+    struct FlakyFlash {
+        flash: MockFlashBig,
+        fail_next: bool,
+    }
+
+    impl FlakyFlash {
+        fn flake(&mut self) -> Result<(), MockFlashError> {
+            self.fail_next = !self.fail_next;
+
+            if self.fail_next {
+                Err(MockFlashError::OutOfBounds)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl ErrorType for FlakyFlash {
+        type Error = MockFlashError;
+    }
+
+    impl ReadNorFlash for FlakyFlash {
+        const READ_SIZE: usize = MockFlashBig::READ_SIZE;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            self.flake()?;
+            self.flash.read(offset, bytes)
+        }
+
+        fn capacity(&self) -> usize {
+            self.flash.capacity()
+        }
+    }
+
+    impl NorFlash for FlakyFlash {
+        const WRITE_SIZE: usize = MockFlashBig::WRITE_SIZE;
+
+        const ERASE_SIZE: usize = MockFlashBig::ERASE_SIZE;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            self.flake()?;
+            self.flash.erase(from, to)
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            if let Err(e) = self.flake() {
+                // Only get the first word written
+                let written = bytes.len().min(Self::WRITE_SIZE);
+                self.flash.write(offset, &bytes[..written])?;
+                return Err(e);
+            }
+
+            self.flash.write(offset, bytes)
+        }
+    }
+
+    impl MultiwriteNorFlash for FlakyFlash {}
+
+    #[test]
+    fn retry_transient_errors() {
+        let flash_range = 0x000..0x1000;
+        let flaky_flash = || FlakyFlash {
+            flash: MockFlashBig::new(),
+            fail_next: false,
+        };
+        let item = |key| MockStorageItem {
+            key,
+            value: vec![key; 10],
+        };
+
+        let mut flash = RetryFlash::without_retries(flaky_flash());
+        assert_eq!(
+            store_item(&mut flash, flash_range.clone(), item(0)),
+            Err(MapError::Storage(MockFlashError::OutOfBounds))
+        );
+
+        let mut attempts = 0;
+        let mut flash = RetryFlash::new(
+            flaky_flash(),
+            |_operation, _error: &MockFlashError, attempt| {
+                attempts += 1;
+                attempt < 2
+            },
+        );
+
+        // Enough to go around the pages a couple of times
+        for i in 0..1000 {
+            store_item(&mut flash, flash_range.clone(), item((i % 20) as u8)).unwrap();
+        }
+        for key in 0..20 {
+            assert_eq!(
+                fetch_item::<MockStorageItem, _>(&mut flash, flash_range.clone(), key).unwrap(),
+                Some(item(key))
+            );
+        }
+
+        drop(flash);
+        assert!(attempts > 1000, "{attempts}");
+    }
+}